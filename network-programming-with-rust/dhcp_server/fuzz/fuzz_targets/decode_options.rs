@@ -0,0 +1,21 @@
+#![no_main]
+//! 任意のバイト列をオプション領域として解釈する。
+//! 値が正しいオプションは、エンコードしてデコードすると同じになることを確かめる。
+
+use dhcp_server::options::{self, Code, DhcpOption};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let raw_options = match options::parse_raw_options(data) {
+        Ok(raw_options) => raw_options,
+        Err(_) => return,
+    };
+    for (code, value) in raw_options {
+        if let Ok(option) = DhcpOption::decode(code, &value) {
+            let mut encoded = options::encode_options(std::slice::from_ref(&option));
+            encoded.push(Code::End as u8);
+            let (code, value) = options::parse_raw_options(&encoded).unwrap().remove(0);
+            assert_eq!(option, DhcpOption::decode(code, &value).unwrap());
+        }
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+//! 任意のバイト列をDhcpMessageとして解釈する。
+//! 解釈できたものはバイト列に戻して、もう一度解釈すると同じになることを確かめる。
+
+use dhcp_server::message::DhcpMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = DhcpMessage::parse(data) {
+        let bytes = message.to_bytes(usize::MAX).unwrap();
+        assert_eq!(message, DhcpMessage::parse(&bytes).unwrap());
+        // 576オクテットに収まる場合はOption Overloadを使っても同じになる
+        if let Ok(bytes) = message.to_bytes(message.reply_size_limit()) {
+            assert_eq!(message, DhcpMessage::parse(&bytes).unwrap());
+        }
+    }
+});
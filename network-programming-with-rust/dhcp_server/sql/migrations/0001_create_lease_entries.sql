@@ -2,5 +2,5 @@ CREATE TABLE "lease_entries" (
     "id" INTEGER PRIMARY KEY AUTOINCREMENT,
     "mac_addr" TEXT NOT NULL UNIQUE,
     "ip_addr" TEXT NOT NULL,
-    "deleted" unsigned INTEGER NOT NULL DEFAULT0
-);
\ No newline at end of file
+    "deleted" unsigned INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,2 @@
+ALTER TABLE "lease_entries" ADD COLUMN "lease_start" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "lease_entries" ADD COLUMN "lease_expiry" INTEGER NOT NULL DEFAULT 0;
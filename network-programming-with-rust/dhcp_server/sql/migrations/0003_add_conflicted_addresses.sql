@@ -0,0 +1,5 @@
+CREATE TABLE "conflicted_addresses" (
+    "ip_addr" TEXT NOT NULL PRIMARY KEY,
+    "mac_addr" TEXT NOT NULL,
+    "conflict_until" INTEGER NOT NULL
+);
@@ -0,0 +1,6 @@
+CREATE TABLE "reservations" (
+    "ip_addr" TEXT NOT NULL PRIMARY KEY,
+    "mac_addr" TEXT UNIQUE,
+    "client_id" TEXT UNIQUE,
+    "hostname" TEXT
+);
@@ -0,0 +1,18 @@
+CREATE TABLE "lease6_entries" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "duid" TEXT NOT NULL,
+    "iaid" INTEGER NOT NULL,
+    "ia_type" TEXT NOT NULL,
+    "address" TEXT NOT NULL,
+    "prefix_len" INTEGER NOT NULL,
+    "deleted" unsigned INTEGER NOT NULL DEFAULT 0,
+    "lease_start" INTEGER NOT NULL DEFAULT 0,
+    "lease_expiry" INTEGER NOT NULL DEFAULT 0,
+    UNIQUE ("duid", "iaid", "ia_type")
+);
+
+CREATE TABLE "conflicted_addresses6" (
+    "address" TEXT NOT NULL PRIMARY KEY,
+    "duid" TEXT NOT NULL,
+    "conflict_until" INTEGER NOT NULL
+);
@@ -0,0 +1,21 @@
+CREATE TABLE "lease_entries_new" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "client_id" TEXT,
+    "htype" INTEGER NOT NULL DEFAULT 1,
+    "mac_addr" TEXT NOT NULL,
+    "ip_addr" TEXT NOT NULL,
+    "deleted" unsigned INTEGER NOT NULL DEFAULT 0,
+    "lease_start" INTEGER NOT NULL DEFAULT 0,
+    "lease_expiry" INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO "lease_entries_new"
+    ("id", "mac_addr", "ip_addr", "deleted", "lease_start", "lease_expiry")
+    SELECT "id", "mac_addr", "ip_addr", "deleted", "lease_start", "lease_expiry"
+    FROM "lease_entries";
+DROP TABLE "lease_entries";
+ALTER TABLE "lease_entries_new" RENAME TO "lease_entries";
+
+CREATE UNIQUE INDEX "lease_entries_client_id" ON "lease_entries" ("client_id")
+    WHERE "client_id" IS NOT NULL;
+CREATE UNIQUE INDEX "lease_entries_hw_addr" ON "lease_entries" ("htype", "mac_addr")
+    WHERE "client_id" IS NULL;
@@ -0,0 +1 @@
+ALTER TABLE "lease_entries" ADD COLUMN "hostname" TEXT;
@@ -0,0 +1,652 @@
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::config::{Config, ReservationConfig};
+use super::database::LeaseRecord;
+use super::dhcp::{Client, DhcpServer, Reservation, ReservationOwner};
+use super::util;
+
+// リクエストを待つ時間。応答の途中で止まったクライアントで管理APIが塞がらないようにする
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// リクエストラインとヘッダの行数・長さ、ボディの大きさの上限
+const MAX_HEADER_LINES: usize = 64;
+const MAX_LINE_LEN: u64 = 8192;
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/**
+ * HTTPのリクエストのうち管理APIで使う部分
+ */
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+}
+
+/**
+ * JSONで返す応答
+ */
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response { status: 200, body }
+    }
+
+    fn error<T: fmt::Display>(status: u16, message: T) -> Response {
+        Response {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+}
+
+/**
+ * 管理APIの接続を1つずつ処理する。
+ * config_pathは設定を読み込み直す際に読むファイル。
+ *
+ * [note] 管理APIの操作は少なくすぐに終わるので、DHCPと違い接続ごとにスレッドを作らない。
+ * DBやアドレスプールの変更は全てDhcpServerを通すので、DHCPの処理と並行しても整合性は保たれる。
+ */
+pub fn serve(
+    listener: TcpListener,
+    dhcp_server: Arc<DhcpServer>,
+    config_path: String,
+    allow_remote: bool,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Could not accept an admin connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = handle_connection(stream, &dhcp_server, &config_path, allow_remote) {
+            debug!("admin connection closed: {}", e);
+        }
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    dhcp_server: &DhcpServer,
+    config_path: &str,
+    allow_remote: bool,
+) -> Result<(), failure::Error> {
+    let peer = stream.peer_addr()?;
+    if !allow_remote && !peer.ip().is_loopback() {
+        warn!("admin connection from {} is refused", peer);
+        return Ok(());
+    }
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let response = match read_request(&mut BufReader::new(&stream)) {
+        Ok(request) => {
+            info!("admin: {} {} from {}", request.method, request.path, peer);
+            handle_request(dhcp_server, config_path, &request)
+        }
+        Err(e) => Response::error(400, e),
+    };
+    write_response(&mut stream, &response)
+}
+
+/**
+ * 1行読む。改行までが長すぎればErr
+ */
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, failure::Error> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(failure::err_msg("request line or header is too long or truncated"));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/**
+ * HTTP/1.1のリクエストを読む。ボディはContent-Lengthで長さが示されたものだけ受け付ける
+ */
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, failure::Error> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target)
+        }
+        _ => return Err(failure::format_err!("malformed request line {:?}", request_line)),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADER_LINES {
+        let header = read_line(reader)?;
+        if header.is_empty() {
+            if content_length > MAX_BODY_LEN {
+                return Err(failure::err_msg("request body is too large"));
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            return Ok(Request {
+                method,
+                path,
+                query,
+                body,
+            });
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    Err(failure::err_msg("too many headers"))
+}
+
+fn write_response<W: Write>(writer: &mut W, response: &Response) -> Result<(), failure::Error> {
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    };
+    let mut body = serde_json::to_vec_pretty(&response.body)?;
+    body.push(b'\n');
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        reason,
+        body.len()
+    )?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/**
+ * リクエストのメソッドとパスから操作を選んで実行する
+ */
+fn handle_request(dhcp_server: &DhcpServer, config_path: &str, request: &Request) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["leases"]) => list_leases(dhcp_server, request.query.as_deref()),
+        ("DELETE", ["leases", id]) => revoke_leases(dhcp_server, id),
+        ("GET", ["scopes"]) => list_scopes(dhcp_server),
+        ("GET", ["offers"]) => Ok(list_offers(dhcp_server)),
+        ("GET", ["conflicts"]) => list_conflicts(dhcp_server),
+        ("GET", ["reservations"]) => Ok(list_reservations(dhcp_server)),
+        ("POST", ["reservations"]) => add_reservation(dhcp_server, &request.body),
+        ("DELETE", ["reservations", ip_addr]) => remove_reservation(dhcp_server, ip_addr),
+        ("POST", ["reload"]) => Ok(reload(dhcp_server, config_path)),
+        _ => Ok(Response::error(
+            404,
+            format!("no such operation: {} {}", request.method, request.path),
+        )),
+    };
+    result.unwrap_or_else(|e| {
+        error!("admin: {} {}: {}", request.method, request.path, e);
+        Response::error(500, e)
+    })
+}
+
+/**
+ * クエリ文字列から指定の名前の値を取り出す
+ */
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/**
+ * クライアントを表すフィールド。mac_addrはhtypeに関わらずハードウェアアドレスを入れる
+ */
+fn client_json(client: &Client, ip_addr: Ipv4Addr) -> Value {
+    json!({
+        "mac_addr": client.hw_addr_string(),
+        "htype": client.htype,
+        "client_id": client.client_id.as_deref().map(util::encode_hex),
+        "ip_addr": ip_addr.to_string(),
+    })
+}
+
+fn lease_json(record: &LeaseRecord, now: i64) -> Value {
+    let mut value = client_json(&record.client, record.ip_addr);
+    value["state"] = json!(if record.is_active(now) { "active" } else { "expired" });
+    value["lease_start"] = json!(record.lease_start);
+    value["lease_expiry"] = json!(record.lease_expiry);
+    value["hostname"] = json!(record.hostname);
+    value
+}
+
+/**
+ * GET /leases[?state=active|expired]
+ * 論理削除されたものや期限の過ぎたものはexpiredとする
+ */
+fn list_leases(dhcp_server: &DhcpServer, query: Option<&str>) -> Result<Response, failure::Error> {
+    let state = query_param(query, "state");
+    if !matches!(state, None | Some("active") | Some("expired")) {
+        return Ok(Response::error(400, "state must be active or expired"));
+    }
+    let records = dhcp_server.lease_store.lock().unwrap().select_lease_records()?;
+    let now = util::unix_time_now();
+    let leases: Vec<Value> = records
+        .iter()
+        .filter(|record| match state {
+            Some("active") => record.is_active(now),
+            Some(_) => !record.is_active(now),
+            None => true,
+        })
+        .map(|record| lease_json(record, now))
+        .collect();
+    Ok(Response::ok(Value::from(leases)))
+}
+
+/**
+ * DELETE /leases/{id}
+ * idはハードウェアアドレスかclient identifierの16進数。一致するクライアントの有効なリースを全て取り消し、
+ * 取り消したリースの一覧を返す
+ *
+ * [note] 同じMACアドレスでもclient identifierを送るかどうかで別のリースになる(PXEとOSなど)
+ */
+fn revoke_leases(dhcp_server: &DhcpServer, id: &str) -> Result<Response, failure::Error> {
+    let id_bytes = match util::decode_hex(id) {
+        Ok(bytes) if !bytes.is_empty() => bytes,
+        _ => return Ok(Response::error(400, format!("invalid hardware address {:?}", id))),
+    };
+    let records = dhcp_server.lease_store.lock().unwrap().select_lease_records()?;
+    let now = util::unix_time_now();
+    let mut revoked = Vec::new();
+    for record in records
+        .iter()
+        .filter(|record| record.is_active(now) && record.client.is_identified_by(&id_bytes))
+    {
+        if let Some(ip_addr) = dhcp_server.revoke_lease(&record.client)? {
+            info!("admin: revoked the lease of {} for {}", ip_addr, record.client);
+            revoked.push(client_json(&record.client, ip_addr));
+        }
+    }
+    if revoked.is_empty() {
+        return Ok(Response::error(404, format!("{} has no active lease", id)));
+    }
+    Ok(Response::ok(Value::from(revoked)))
+}
+
+/**
+ * GET /scopes
+ * utilizationはアドレスプールから取り出されたIPアドレスの割合
+ */
+fn list_scopes(dhcp_server: &DhcpServer) -> Result<Response, failure::Error> {
+    let scopes: Vec<Value> = dhcp_server
+        .pool_usage()?
+        .iter()
+        .map(|usage| {
+            let utilization = match usage.total {
+                0 => 0.0,
+                total => (total - usage.available) as f64 / total as f64,
+            };
+            json!({
+                "name": usage.scope,
+                "subnet": usage.network_addr.to_string(),
+                "total": usage.total,
+                "available": usage.available,
+                "leased": usage.leased,
+                "reserved": usage.reserved,
+                "utilization": utilization,
+            })
+        })
+        .collect();
+    Ok(Response::ok(Value::from(scopes)))
+}
+
+/**
+ * GET /offers
+ */
+fn list_offers(dhcp_server: &DhcpServer) -> Response {
+    let now = Instant::now();
+    let offers: Vec<Value> = dhcp_server
+        .pending_offers()
+        .iter()
+        .map(|(client, xid, offer)| {
+            let mut value = client_json(client, offer.ip_addr);
+            value["xid"] = json!(format!("{:x}", xid));
+            value["expires_in"] = json!(offer.expires_in(now).as_secs());
+            value
+        })
+        .collect();
+    Response::ok(Value::from(offers))
+}
+
+/**
+ * GET /conflicts
+ */
+fn list_conflicts(dhcp_server: &DhcpServer) -> Result<Response, failure::Error> {
+    let records = dhcp_server.lease_store.lock().unwrap().select_conflicts()?;
+    let conflicts: Vec<Value> = records
+        .iter()
+        .map(|record| {
+            json!({
+                "ip_addr": record.ip_addr.to_string(),
+                "mac_addr": record.hw_addr,
+                "conflict_until": record.conflict_until,
+            })
+        })
+        .collect();
+    Ok(Response::ok(Value::from(conflicts)))
+}
+
+fn scope_name(dhcp_server: &DhcpServer, ip_addr: Ipv4Addr) -> String {
+    dhcp_server
+        .scope_of(ip_addr)
+        .map(|scope| scope.name.clone())
+        .unwrap_or_default()
+}
+
+/**
+ * 予約をPOST /reservationsのボディと同じ形にする
+ */
+fn reservation_json(scope: &str, reservation: &Reservation) -> Value {
+    let mut value = json!({
+        "scope": scope,
+        "ip": reservation.ip_addr.to_string(),
+        "hostname": reservation.hostname,
+    });
+    match &reservation.owner {
+        ReservationOwner::MacAddr(mac_addr) => value["mac"] = json!(mac_addr.to_string()),
+        ReservationOwner::ClientId(id) => value["client_id"] = json!(util::encode_hex(id)),
+    }
+    value
+}
+
+/**
+ * GET /reservations
+ * 設定ファイルの予約とDBの予約の両方を返す
+ */
+fn list_reservations(dhcp_server: &DhcpServer) -> Response {
+    let reservations: Vec<Value> = dhcp_server
+        .scopes()
+        .iter()
+        .flat_map(|scope| {
+            scope
+                .reservations()
+                .iter()
+                .map(|reservation| reservation_json(&scope.name, reservation))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    Response::ok(Value::from(reservations))
+}
+
+/**
+ * POST /reservations
+ * ボディは設定ファイルの予約と同じ形式のJSON。例: {"mac": "00:11:22:33:44:55", "ip": "192.168.0.10"}
+ */
+fn add_reservation(dhcp_server: &DhcpServer, body: &[u8]) -> Result<Response, failure::Error> {
+    let config: ReservationConfig = match serde_json::from_slice(body) {
+        Ok(config) => config,
+        Err(e) => return Ok(Response::error(400, e)),
+    };
+    if !config.options.is_empty() {
+        return Ok(Response::error(400, "options can be set only in the configuration file"));
+    }
+    let reservation = match config.to_reservation() {
+        Ok(reservation) => reservation,
+        Err(e) => return Ok(Response::error(400, e)),
+    };
+    let ip_addr = reservation.ip_addr;
+    let value = reservation_json(&scope_name(dhcp_server, ip_addr), &reservation);
+    match dhcp_server.add_reservation(reservation) {
+        Ok(()) => {
+            info!("admin: reserved {}", ip_addr);
+            Ok(Response { status: 201, body: value })
+        }
+        Err(e) => Ok(Response::error(409, e)),
+    }
+}
+
+/**
+ * DELETE /reservations/{ip_addr}
+ */
+fn remove_reservation(dhcp_server: &DhcpServer, ip_addr: &str) -> Result<Response, failure::Error> {
+    let ip_addr = match ip_addr.parse() {
+        Ok(ip_addr) => ip_addr,
+        Err(_) => return Ok(Response::error(400, format!("invalid ip address {:?}", ip_addr))),
+    };
+    match dhcp_server.remove_reservation(ip_addr)? {
+        Some(reservation) => {
+            info!("admin: removed the reservation of {}", ip_addr);
+            let value = reservation_json(&scope_name(dhcp_server, ip_addr), &reservation);
+            Ok(Response::ok(value))
+        }
+        None => Ok(Response::error(404, format!("{} is not reserved", ip_addr))),
+    }
+}
+
+/**
+ * POST /reload
+ * 設定に誤りがあれば今の設定のまま動き続ける
+ */
+fn reload(dhcp_server: &DhcpServer, config_path: &str) -> Response {
+    let config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(e) => return Response::error(400, format!("invalid configuration: {}", e)),
+    };
+    match dhcp_server.reload(&config) {
+        Ok(()) => Response::ok(json!({
+            "scopes": config.scopes.len(),
+            "scopes6": config.scopes6.len(),
+        })),
+        Err(e) => Response::error(409, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{test_server, TEST_CONFIG};
+    use std::fs;
+    use std::io::Cursor;
+    use std::process;
+    use pnet::util::MacAddr;
+    use std::thread;
+
+    const CLIENT_MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    /**
+     * リクエストを組み立てて管理APIを呼び出す
+     */
+    fn call(server: &DhcpServer, method: &str, target: &str, body: &str) -> Response {
+        let text = format!(
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        );
+        let request = read_request(&mut Cursor::new(text.as_bytes())).unwrap();
+        handle_request(server, "/nonexistent/dhcp_server.toml", &request)
+    }
+
+    #[test]
+    fn test_read_request() {
+        let text = "POST /leases?state=active HTTP/1.1\r\nHost: localhost\r\n\
+                    content-length: 2\r\n\r\n{}extra";
+        assert_eq!(
+            Request {
+                method: "POST".to_string(),
+                path: "/leases".to_string(),
+                query: Some("state=active".to_string()),
+                body: b"{}".to_vec(),
+            },
+            read_request(&mut Cursor::new(text.as_bytes())).unwrap()
+        );
+
+        let error_of = |text: &str| {
+            read_request(&mut Cursor::new(text.as_bytes()))
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error_of("GET /leases\r\n\r\n").contains("malformed request line"));
+        assert!(error_of("GET /leases HTTP/1.1\r\nHost: local").contains("truncated"));
+        assert!(error_of("POST /reservations HTTP/1.1\r\nContent-Length: 100000\r\n\r\n")
+            .contains("too large"));
+    }
+
+    #[test]
+    fn test_leases_offers_and_conflicts() {
+        let server = test_server();
+        let active_ip = Ipv4Addr::new(192, 168, 0, 10);
+        let client = Client::from_mac_addr(CLIENT_MAC);
+        {
+            let mut store = server.lease_store.lock().unwrap();
+            store.insert_entry(&client, active_ip, 0, i64::MAX).unwrap();
+            store.update_hostname(&client, Some("laptop")).unwrap();
+            let other = Client::from_mac_addr(MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x66));
+            store.insert_entry(&other, Ipv4Addr::new(192, 168, 0, 11), 0, 1).unwrap();
+        }
+        assert_eq!(2, call(&server, "GET", "/leases", "").body.as_array().unwrap().len());
+        let active = call(&server, "GET", "/leases?state=active", "").body;
+        assert_eq!(1, active.as_array().unwrap().len());
+        assert_eq!("00:11:22:33:44:55", active[0]["mac_addr"]);
+        assert_eq!(Value::Null, active[0]["client_id"]);
+        assert_eq!("laptop", active[0]["hostname"]);
+        assert_eq!("active", active[0]["state"]);
+        assert_eq!("expired", call(&server, "GET", "/leases?state=expired", "").body[0]["state"]);
+        assert_eq!(400, call(&server, "GET", "/leases?state=deleted", "").status);
+
+        let revoked = call(&server, "DELETE", "/leases/00:11:22:33:44:55", "");
+        assert_eq!(200, revoked.status);
+        assert_eq!("192.168.0.10", revoked.body[0]["ip_addr"]);
+        assert_eq!(404, call(&server, "DELETE", "/leases/00:11:22:33:44:55", "").status);
+        assert_eq!(400, call(&server, "DELETE", "/leases/00:11:2", "").status);
+
+        server.record_offer(&client, 0x1234, active_ip, true);
+        let offers = call(&server, "GET", "/offers", "").body;
+        assert_eq!("1234", offers[0]["xid"]);
+        assert_eq!("192.168.0.10", offers[0]["ip_addr"]);
+
+        server.decline_address(&client, active_ip).unwrap();
+        let conflicts = call(&server, "GET", "/conflicts", "").body;
+        assert_eq!("192.168.0.10", conflicts[0]["ip_addr"]);
+        assert_eq!("00:11:22:33:44:55", conflicts[0]["mac_addr"]);
+
+        assert_eq!(404, call(&server, "GET", "/unknown", "").status);
+        assert_eq!(404, call(&server, "PUT", "/leases", "").status);
+    }
+
+    #[test]
+    fn test_revoke_leases_by_client_id() {
+        let server = test_server();
+        // PXEファームウェアとOSが同じMACアドレスで別々にリースを受けている
+        let pxe = Client::from_mac_addr(CLIENT_MAC);
+        let os = Client {
+            client_id: Some(vec![0xff, 0, 0, 0, 1, 0, 1]),
+            ..pxe.clone()
+        };
+        let pxe_ip = Ipv4Addr::new(192, 168, 0, 10);
+        let os_ip = Ipv4Addr::new(192, 168, 0, 11);
+        {
+            let mut store = server.lease_store.lock().unwrap();
+            store.insert_entry(&pxe, pxe_ip, 0, i64::MAX).unwrap();
+            store.insert_entry(&os, os_ip, 0, i64::MAX).unwrap();
+        }
+        let leases = call(&server, "GET", "/leases", "").body;
+        assert_eq!("ff000000010001", leases[1]["client_id"]);
+        assert_eq!(leases[0]["mac_addr"], leases[1]["mac_addr"]);
+
+        // client identifierで指定すればそのクライアントだけ
+        let revoked = call(&server, "DELETE", "/leases/ff:00:00:00:01:00:01", "").body;
+        assert_eq!(1, revoked.as_array().unwrap().len());
+        assert_eq!("192.168.0.11", revoked[0]["ip_addr"]);
+
+        // MACアドレスで指定すれば両方
+        server.lease_store.lock().unwrap().update_entry(&os, os_ip, 0, 0, i64::MAX).unwrap();
+        let revoked = call(&server, "DELETE", "/leases/00:11:22:33:44:55", "").body;
+        assert_eq!(2, revoked.as_array().unwrap().len());
+    }
+
+    #[test]
+    fn test_reservations_and_scopes() {
+        let server = test_server();
+        let body = r#"{"mac": "00:11:22:33:44:55", "ip": "192.168.0.60", "hostname": "nas"}"#;
+        let added = call(&server, "POST", "/reservations", body);
+        assert_eq!(201, added.status);
+        assert_eq!("lan", added.body["scope"]);
+        assert_eq!(409, call(&server, "POST", "/reservations", body).status);
+        // 設定ファイルと同じ検証をする
+        for body in [
+            r#"{"ip": "192.168.0.61"}"#,
+            r#"{"mac": "00:11:22:33:44:66", "ip": "192.168.0.61", "name": "x"}"#,
+            r#"{"mac": "00:11:22:33:44:66", "ip": "192.168.0.61",
+                "options": [{"code": 15, "text": "x"}]}"#,
+        ] {
+            assert_eq!(400, call(&server, "POST", "/reservations", body).status);
+        }
+        let outside = r#"{"mac": "00:11:22:33:44:66", "ip": "172.16.0.1"}"#;
+        assert_eq!(409, call(&server, "POST", "/reservations", outside).status);
+
+        // 設定ファイルの2件とAPIで追加した1件
+        let reservations = call(&server, "GET", "/reservations", "").body;
+        assert_eq!(3, reservations.as_array().unwrap().len());
+        assert!(reservations.as_array().unwrap().contains(&json!({
+            "scope": "lan",
+            "ip": "192.168.0.60",
+            "mac": "00:11:22:33:44:55",
+            "hostname": "nas",
+        })));
+        let scopes = call(&server, "GET", "/scopes", "").body;
+        assert_eq!("lan", scopes[0]["name"]);
+        assert_eq!(3, scopes[0]["reserved"]);
+        assert_eq!(scopes[0]["total"], scopes[0]["available"]);
+        assert_eq!(0.0, scopes[1]["utilization"]);
+
+        assert_eq!(200, call(&server, "DELETE", "/reservations/192.168.0.60", "").status);
+        assert_eq!(404, call(&server, "DELETE", "/reservations/192.168.0.60", "").status);
+        assert_eq!(2, call(&server, "GET", "/scopes", "").body[0]["reserved"]);
+    }
+
+    #[test]
+    fn test_serve_and_reload() {
+        let config_path = std::env::temp_dir().join(format!("admin_test_{}.toml", process::id()));
+        fs::write(&config_path, TEST_CONFIG).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dhcp_server = Arc::new(test_server());
+        let path = config_path.to_str().unwrap().to_string();
+        thread::spawn(move || serve(listener, dhcp_server, path, false));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"POST /reload HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        fs::remove_file(&config_path).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(
+            json!({ "scopes": 2, "scopes6": 1 }),
+            serde_json::from_str::<Value>(body).unwrap()
+        );
+
+        // 設定ファイルが読めなければ今の設定のまま
+        let server = test_server();
+        assert_eq!(400, call(&server, "POST", "/reload", "").status);
+    }
+}
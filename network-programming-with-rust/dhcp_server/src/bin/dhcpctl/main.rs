@@ -0,0 +1,489 @@
+//! dhcp.dbのリースを確認・修正するコマンド。
+//! DBの操作はDHCPサーバと同じdatabaseモジュール(dhcp_serverライブラリ)を使う。
+
+mod lease_file;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process;
+
+use dhcp_server::database::{LeaseRecord, LeaseStore, SqliteLeaseStore};
+use dhcp_server::util;
+use lease_file::Format;
+
+// DHCPサーバと同じく、指定がなければカレントディレクトリのdhcp.dbを使う
+const DEFAULT_DATABASE_PATH: &str = "dhcp.db";
+
+const USAGE: &str = "\
+usage: dhcpctl [--database <path>] <command>
+
+commands:
+    list [--mac <hwaddr>] [--client-id <hex>] [--ip <ip>] [--active] [--expired] [--deleted]
+        show leases matching all of the given filters
+    release <hwaddr|client-id|ip>
+        release active leases (a running server picks it up on restart or POST /reload)
+    export [--format json|isc] [<filters>] [<file>]
+        write leases to the file (default: stdout) as JSON or ISC dhcpd.leases
+    import [--format json|isc] <file|->
+        add or update leases from the file
+    compact
+        delete released leases (and expired ones the server has reclaimed)
+        and shrink the database file";
+
+/**
+ * listとexportで表示するリースの条件。指定したものを全て満たすリースを選ぶ
+ */
+#[derive(Debug, Default, PartialEq)]
+struct LeaseFilter {
+    hw_addr: Option<Vec<u8>>, // htypeに関わらずハードウェアアドレスで選ぶ
+    client_id: Option<Vec<u8>>,
+    ip_addr: Option<Ipv4Addr>,
+    active: bool,
+    expired: bool, // 期限を過ぎたもの。論理削除されたものも含む
+    deleted: bool,
+}
+
+impl LeaseFilter {
+    fn matches(&self, record: &LeaseRecord, now: i64) -> bool {
+        self.hw_addr.as_ref().is_none_or(|hw_addr| *hw_addr == record.client.hw_addr)
+            && self.client_id.as_ref().is_none_or(|id| Some(id) == record.client.client_id.as_ref())
+            && self.ip_addr.is_none_or(|ip_addr| ip_addr == record.ip_addr)
+            && (!self.active || record.is_active(now))
+            && (!self.expired || record.lease_expiry <= now)
+            && (!self.deleted || record.deleted)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Help,
+    List(LeaseFilter),
+    Release(String),
+    Export {
+        format: Format,
+        filter: LeaseFilter,
+        path: Option<String>,
+    },
+    Import {
+        format: Format,
+        path: String,
+    },
+    Compact,
+}
+
+/**
+ * コマンドライン引数を解釈し、DBのパスとコマンドを返す
+ */
+fn parse_args(args: &[String]) -> Result<(String, Command), failure::Error> {
+    let mut args = args.iter().map(String::as_str).peekable();
+    let mut database = DEFAULT_DATABASE_PATH.to_string();
+    if let Some(&"--database") | Some(&"-d") = args.peek() {
+        args.next();
+        database = args
+            .next()
+            .ok_or_else(|| failure::err_msg("--database requires a path"))?
+            .to_string();
+    }
+    let command = args.next().unwrap_or("help");
+
+    let mut filter = LeaseFilter::default();
+    let mut format = Format::Json;
+    let mut operands = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| failure::format_err!("{} requires a value", arg))
+        };
+        match arg {
+            "--mac" => filter.hw_addr = Some(parse_id(value()?)?),
+            "--client-id" => filter.client_id = Some(parse_id(value()?)?),
+            "--ip" => filter.ip_addr = Some(value()?.parse()?),
+            "--active" => filter.active = true,
+            "--expired" => filter.expired = true,
+            "--deleted" => filter.deleted = true,
+            "--format" => format = value()?.parse()?,
+            "-" => operands.push(arg),
+            _ if arg.starts_with('-') => {
+                return Err(failure::format_err!("unknown option {}", arg))
+            }
+            _ => operands.push(arg),
+        }
+    }
+
+    let has_filter = filter != LeaseFilter::default();
+    let command = match (command, operands.as_slice()) {
+        ("help" | "--help" | "-h", []) => Command::Help,
+        ("list", []) => Command::List(filter),
+        ("release", [target]) if !has_filter => Command::Release(target.to_string()),
+        ("export", [] | [_]) => Command::Export {
+            format,
+            filter,
+            path: operands.first().map(|path| path.to_string()),
+        },
+        ("import", [path]) if !has_filter => Command::Import {
+            format,
+            path: path.to_string(),
+        },
+        ("compact", []) if !has_filter => Command::Compact,
+        _ => return Err(failure::format_err!("invalid arguments for {}", command)),
+    };
+    Ok((database, command))
+}
+
+/**
+ * ハードウェアアドレスやclient identifierの16進数(':'区切りも可)を解釈する
+ */
+fn parse_id(hex: &str) -> Result<Vec<u8>, failure::Error> {
+    match util::decode_hex(hex)? {
+        id if id.is_empty() => Err(failure::err_msg("empty hardware address or client id")),
+        id => Ok(id),
+    }
+}
+
+fn select_leases(
+    store: &dyn LeaseStore,
+    filter: &LeaseFilter,
+    now: i64,
+) -> Result<Vec<LeaseRecord>, failure::Error> {
+    Ok(store
+        .select_lease_records()?
+        .into_iter()
+        .filter(|record| filter.matches(record, now))
+        .collect())
+}
+
+fn print_leases(records: &[LeaseRecord], now: i64) {
+    println!(
+        "{:<17}  {:<15}  {:<7}  {:<19}  {:<19}  {:<15}  {:<9}",
+        "HARDWARE ADDRESS",
+        "IP ADDRESS",
+        "STATE",
+        "STARTS (UTC)",
+        "ENDS (UTC)",
+        "HOSTNAME",
+        "CLIENT ID"
+    );
+    for record in records.iter() {
+        let state = if record.deleted {
+            "deleted"
+        } else if record.lease_expiry <= now {
+            "expired"
+        } else {
+            "active"
+        };
+        println!(
+            "{:<17}  {:<15}  {:<7}  {:<19}  {:<19}  {:<15}  {}",
+            record.client.hw_addr_string(),
+            record.ip_addr.to_string(),
+            state,
+            lease_file::format_time(record.lease_start),
+            lease_file::format_time(record.lease_expiry),
+            record.hostname.as_deref().unwrap_or("-"),
+            record.client.client_id.as_deref().map_or("-".to_string(), util::encode_hex)
+        );
+    }
+}
+
+/**
+ * IPアドレス・ハードウェアアドレス・client identifierで指定した有効なリースを論理削除し、それらのリースを返す。
+ * DHCPRELEASEを受けたのと同じ扱いになる。
+ *
+ * [note] 同じMACアドレスでもclient identifierを送るかどうかで別のリースになるので、複数解放することがある
+ */
+fn release_leases(
+    store: &mut dyn LeaseStore,
+    target: &str,
+    now: i64,
+) -> Result<Vec<LeaseRecord>, failure::Error> {
+    let records = store.select_lease_records()?.into_iter();
+    let released: Vec<LeaseRecord> = match (target.parse::<Ipv4Addr>(), parse_id(target)) {
+        (Ok(ip_addr), _) => records
+            .filter(|record| record.ip_addr == ip_addr && record.is_active(now))
+            .collect(),
+        (_, Ok(id)) => records
+            .filter(|record| record.client.is_identified_by(&id) && record.is_active(now))
+            .collect(),
+        _ => {
+            return Err(failure::format_err!(
+                "{:?} is neither a hardware address, a client id nor an ip address",
+                target
+            ))
+        }
+    };
+    if released.is_empty() {
+        return Err(failure::format_err!("no active lease for {}", target));
+    }
+    for record in released.iter() {
+        store.delete_entry(&record.client)?;
+    }
+    Ok(released)
+}
+
+/**
+ * リースを取り込み、取り込んだ件数と取り込まなかったリースを返す。
+ *
+ * [note] dhcpd.leasesは変更を追記していく形式なので、同じIPアドレスやクライアントのリースが
+ * 複数あれば後のものを使う。DBの他のクライアントの有効なリースと重なる有効なリースは取り込まない。
+ */
+fn import_leases(
+    store: &mut dyn LeaseStore,
+    records: Vec<LeaseRecord>,
+    now: i64,
+) -> Result<(usize, Vec<LeaseRecord>), failure::Error> {
+    let mut latest: Vec<LeaseRecord> = Vec::new();
+    for record in records {
+        latest.retain(|other| {
+            other.ip_addr != record.ip_addr && !other.client.is_same(&record.client)
+        });
+        latest.push(record);
+    }
+
+    let existing = store.select_lease_records()?;
+    let (skipped, imported): (Vec<LeaseRecord>, Vec<LeaseRecord>) =
+        latest.iter().cloned().partition(|record| {
+            // 取り込むリースで上書きされるクライアントとは重ならない
+            record.is_active(now)
+                && existing.iter().any(|other| {
+                    other.ip_addr == record.ip_addr
+                        && other.is_active(now)
+                        && !latest.iter().any(|lease| lease.client.is_same(&other.client))
+                })
+        });
+    // 途中で失敗してもDBが取り込みかけの状態にならないよう、1つのトランザクションで書き込む
+    store.upsert_lease_records(&imported)?;
+    Ok((imported.len(), skipped))
+}
+
+fn run(database: &str, command: Command) -> Result<(), failure::Error> {
+    if command == Command::Help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    // SqliteLeaseStore::openは存在しないファイルを作ってしまうので先に確かめる
+    if !Path::new(database).exists() {
+        return Err(failure::format_err!("{} does not exist", database));
+    }
+    let mut store = SqliteLeaseStore::open(database)?;
+    let now = util::unix_time_now();
+    match command {
+        Command::Help => {}
+        Command::List(filter) => print_leases(&select_leases(&store, &filter, now)?, now),
+        Command::Release(target) => {
+            for record in release_leases(&mut store, &target, now)? {
+                println!("released {} ({})", record.ip_addr, record.client);
+            }
+        }
+        Command::Export {
+            format,
+            filter,
+            path,
+        } => {
+            let records = select_leases(&store, &filter, now)?;
+            let text = lease_file::write_leases(format, &records, now)?;
+            match path {
+                Some(path) if path != "-" => fs::write(&path, text)
+                    .map_err(|e| failure::format_err!("failed to write {}: {}", path, e))?,
+                _ => print!("{}", text),
+            }
+        }
+        Command::Import { format, path } => {
+            let text = match path.as_str() {
+                "-" => {
+                    let mut text = String::new();
+                    io::stdin().read_to_string(&mut text)?;
+                    text
+                }
+                path => fs::read_to_string(path)
+                    .map_err(|e| failure::format_err!("failed to read {}: {}", path, e))?,
+            };
+            let records = lease_file::read_leases(format, &text)?;
+            let (imported, skipped) = import_leases(&mut store, records, now)?;
+            for record in skipped.iter() {
+                eprintln!(
+                    "skipped {} ({}): leased to another client",
+                    record.ip_addr, record.client
+                );
+            }
+            println!("imported {} leases", imported);
+        }
+        Command::Compact => {
+            // 論理削除されたリースを物理削除し、空いた領域を解放してDBファイルを縮める
+            let count = store.purge_deleted_entries()?;
+            store.vacuum()?;
+            println!("deleted {} leases", count);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (database, command) = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("dhcpctl: {}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+    if let Err(e) = run(&database, command) {
+        eprintln!("dhcpctl: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dhcp_server::database::MemoryLeaseStore;
+    use dhcp_server::dhcp::Client;
+    use pnet::util::MacAddr;
+
+    const MAC_A: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+    const MAC_B: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x66);
+    const NOW: i64 = 1_700_000_000;
+
+    fn lease(mac_addr: MacAddr, ip_addr: [u8; 4], deleted: bool, lease_expiry: i64) -> LeaseRecord {
+        LeaseRecord {
+            client: Client::from_mac_addr(mac_addr),
+            ip_addr: Ipv4Addr::from(ip_addr),
+            deleted,
+            lease_start: NOW - 100,
+            lease_expiry,
+            hostname: None,
+        }
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            ("dhcp.db".to_string(), Command::List(LeaseFilter::default())),
+            parse_args(&args("list")).unwrap()
+        );
+        let line = "-d /var/lib/dhcp.db list --mac 00:11:22:33:44:55 --expired";
+        let (database, command) = parse_args(&args(line)).unwrap();
+        assert_eq!("/var/lib/dhcp.db", database);
+        assert_eq!(
+            Command::List(LeaseFilter {
+                hw_addr: Some(MAC_A.octets().to_vec()),
+                expired: true,
+                ..LeaseFilter::default()
+            }),
+            command
+        );
+        assert_eq!(
+            Command::Export {
+                format: Format::Isc,
+                filter: LeaseFilter {
+                    active: true,
+                    ..LeaseFilter::default()
+                },
+                path: Some("dhcpd.leases".to_string()),
+            },
+            parse_args(&args("export --format isc --active dhcpd.leases")).unwrap().1
+        );
+        assert_eq!(Command::Help, parse_args(&[]).unwrap().1);
+
+        for line in [
+            "list --mac 00:1",
+            "list --ip",
+            "list extra",
+            "release",
+            "release 192.168.0.10 --active",
+            "import --format xml leases.xml",
+            "compact --deleted",
+            "list --all",
+            "unknown",
+        ] {
+            assert!(parse_args(&args(line)).is_err(), "{}", line);
+        }
+        // 空の引数(シェルの '')はclient identifierにならない
+        let empty_id = ["list", "--client-id", ""].map(String::from);
+        let error = parse_args(&empty_id).unwrap_err();
+        assert_eq!("empty hardware address or client id", error.to_string());
+    }
+
+    #[test]
+    fn test_filter_and_release() {
+        let mut store = MemoryLeaseStore::default();
+        let records = vec![
+            lease(MAC_A, [192, 168, 0, 10], false, NOW + 100),
+            lease(MAC_B, [192, 168, 0, 11], true, NOW - 10),
+        ];
+        assert_eq!((2, vec![]), import_leases(&mut store, records.clone(), NOW).unwrap());
+        assert_eq!(records, select_leases(&store, &LeaseFilter::default(), NOW).unwrap());
+        let filter = LeaseFilter {
+            ip_addr: Some(Ipv4Addr::new(192, 168, 0, 11)),
+            deleted: true,
+            ..LeaseFilter::default()
+        };
+        assert_eq!(vec![records[1].clone()], select_leases(&store, &filter, NOW).unwrap());
+        let filter = LeaseFilter {
+            active: true,
+            expired: true,
+            ..LeaseFilter::default()
+        };
+        assert!(select_leases(&store, &filter, NOW).unwrap().is_empty());
+
+        // 有効なリースだけを解放できる
+        assert!(release_leases(&mut store, "00:11:22:33:44:66", NOW).is_err());
+        assert!(release_leases(&mut store, "192.168.0.11", NOW).is_err());
+        assert!(release_leases(&mut store, "printer", NOW).is_err());
+        assert_eq!(records[..1], release_leases(&mut store, "192.168.0.10", NOW).unwrap());
+        let entry = store.select_lease_entry(&records[0].client).unwrap().unwrap();
+        assert!(entry.deleted);
+
+        assert_eq!(2, store.purge_deleted_entries().unwrap());
+        assert!(store.select_lease_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_import_leases() {
+        let mut store = MemoryLeaseStore::default();
+        import_leases(&mut store, vec![lease(MAC_A, [192, 168, 0, 10], false, NOW + 100)], NOW)
+            .unwrap();
+
+        let records = vec![
+            // 後のリースで上書きされる
+            lease(MAC_B, [192, 168, 0, 20], false, NOW + 100),
+            LeaseRecord {
+                hostname: Some("printer".to_string()),
+                ..lease(MAC_B, [192, 168, 0, 21], false, NOW + 200)
+            },
+            // MAC_Aの有効なリースと重なる
+            lease(MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x77), [192, 168, 0, 10], false, NOW + 100),
+        ];
+        let (imported, skipped) = import_leases(&mut store, records.clone(), NOW).unwrap();
+        assert_eq!(1, imported);
+        assert_eq!(vec![records[2].clone()], skipped);
+
+        // 既存のクライアントのリースは更新する
+        let released = lease(MAC_A, [192, 168, 0, 30], true, NOW + 100);
+        import_leases(&mut store, vec![released.clone()], NOW).unwrap();
+        assert_eq!(
+            vec![released, records[1].clone()],
+            store.select_lease_records().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_client_id_leases() {
+        let mut store = MemoryLeaseStore::default();
+        // 同じMACアドレスでclient identifierを送るクライアントは別のリース
+        let mut with_id = lease(MAC_A, [192, 168, 0, 11], false, NOW + 100);
+        with_id.client.client_id = Some(vec![0xff, 0, 0, 0, 1]);
+        let records = vec![lease(MAC_A, [192, 168, 0, 10], false, NOW + 100), with_id];
+        assert_eq!((2, vec![]), import_leases(&mut store, records.clone(), NOW).unwrap());
+
+        let filter = LeaseFilter {
+            client_id: Some(vec![0xff, 0, 0, 0, 1]),
+            ..LeaseFilter::default()
+        };
+        assert_eq!(records[1..], select_leases(&store, &filter, NOW).unwrap()[..]);
+        assert_eq!(records[1..], release_leases(&mut store, "ff00000001", NOW).unwrap()[..]);
+        assert_eq!(records[..1], release_leases(&mut store, "00:11:22:33:44:55", NOW).unwrap()[..]);
+    }
+}
@@ -0,0 +1,965 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use pnet::util::MacAddr;
+use serde::Deserialize;
+
+use super::dhcp::{Reservation, ReservationOwner};
+use super::options::{self, ClasslessRoute, Code, DhcpOption};
+use super::util;
+
+// リース期間(秒)の既定値
+const DEFAULT_LEASE_TIME: u32 = 3600;
+// DHCPDECLINEで使用中と通知されたIPアドレスを割り当てない時間(秒)の既定値
+const DEFAULT_DECLINE_TIME: u32 = 3600;
+// DHCPv6のアドレスの推奨期間と有効期間(秒)の既定値
+const DEFAULT_PREFERRED_LIFETIME: u32 = 3600;
+const DEFAULT_VALID_LIFETIME: u32 = 7200;
+// 委譲するプレフィックス長の既定値
+const DEFAULT_PD_PREFIX_LENGTH: u8 = 56;
+// 管理APIの待ち受けアドレスの既定値。他のホストからは接続できない
+const DEFAULT_ADMIN_LISTEN: &str = "127.0.0.1:8067";
+// リースを保存するSQLiteのDBファイルの既定値。カレントディレクトリに作る
+const DEFAULT_DATABASE: &str = "dhcp.db";
+// 動的DNS更新で登録するレコードのTTL(秒)の既定値
+const DEFAULT_DDNS_TTL: u32 = 300;
+
+/**
+ * 設定ファイル(TOML)の内容。
+ *
+ * ```toml
+ * server_identifier = "192.168.0.2"
+ *
+ * [[scope]]
+ * name = "lan"
+ * subnet = "192.168.0.0/24"
+ * ranges = [{ start = "192.168.0.100", end = "192.168.0.199" }]
+ * routers = ["192.168.0.1"]
+ * ```
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // 各スコープで省略された場合のserver identifier
+    pub server_identifier: Option<Ipv4Addr>,
+    #[serde(default = "default_decline_time")]
+    pub decline_time: u32,
+    // リースを保存するSQLiteのDBファイル。":memory:" ならメモリ上に持ち、再起動すると消える
+    #[serde(default = "default_database")]
+    pub database: String,
+    #[serde(default, rename = "scope")]
+    pub scopes: Vec<ScopeConfig>,
+    // DHCPv6のサーバのDUID(16進数)。省略した場合はインターフェースのMACアドレスからDUID-LLを作る
+    pub server_duid: Option<String>,
+    #[serde(default, rename = "scope6")]
+    pub scopes6: Vec<Scope6Config>,
+    // 管理API。省略した場合は起動しない
+    pub admin: Option<AdminConfig>,
+    // 動的DNS更新。省略した場合はDNSを更新しない
+    pub ddns: Option<DdnsConfig>,
+}
+
+/**
+ * 管理用のHTTP APIの設定
+ *
+ * ```toml
+ * [admin]
+ * listen = "127.0.0.1:8067"
+ * ```
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    #[serde(default = "default_admin_listen")]
+    pub listen: SocketAddr,
+    // ループバック以外のアドレスで待ち受け、他のホストからの接続を受け付けるか
+    #[serde(default)]
+    pub allow_remote: bool,
+}
+
+/**
+ * 動的DNS更新(RFC2136)の設定。
+ * リースを割り当てた・延長した時にホスト名のAとPTRのレコードを登録し、解放・期限切れで削除する。
+ *
+ * ```toml
+ * [ddns]
+ * server = "192.168.0.1:53"
+ * forward_zone = "example.lan"
+ * reverse_zone = "0.168.192.in-addr.arpa"
+ * tsig = { name = "dhcp-key", secret = "c2VjcmV0LWtleQ==" }
+ * ```
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DdnsConfig {
+    pub server: SocketAddr,
+    // Aレコードを置くゾーン。名前は「ホスト名.forward_zone」になる
+    pub forward_zone: String,
+    // PTRレコードを置くゾーン。省略した場合はIPアドレスの上位24ビットのin-addr.arpaのゾーン
+    pub reverse_zone: Option<String>,
+    #[serde(default = "default_ddns_ttl")]
+    pub ttl: u32,
+    // 更新に署名するTSIGの鍵。省略した場合は署名しない
+    pub tsig: Option<TsigConfig>,
+}
+
+/**
+ * TSIG(RFC8945)の鍵。アルゴリズムはhmac-sha256
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TsigConfig {
+    pub name: String,
+    pub secret: String, // Base64
+}
+
+/**
+ * 1つのサブネットに対する割り当ての設定
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScopeConfig {
+    pub name: String,
+    pub subnet: String, // "192.168.0.0/24" の形式
+    // このスコープのクライアントを直接受け付けるインターフェース。
+    // リレーエージェント経由のスコープでは省略する。
+    pub interface: Option<String>,
+    pub server_identifier: Option<Ipv4Addr>,
+    // 割り当てるIPアドレスの範囲。省略した場合はサブネットの全てのホストアドレス
+    #[serde(default)]
+    pub ranges: Vec<AddressRange>,
+    #[serde(default)]
+    pub exclusions: Vec<Ipv4Addr>,
+    #[serde(default)]
+    pub routers: Vec<Ipv4Addr>,
+    #[serde(default)]
+    pub dns_servers: Vec<Ipv4Addr>,
+    #[serde(default = "default_lease_time")]
+    pub lease_time: u32,
+    #[serde(default)]
+    pub options: Vec<CustomOption>,
+    #[serde(default)]
+    pub reservations: Vec<ReservationConfig>,
+    // リレーエージェント経由のリクエストに応答するcircuit-id, remote-id(オプション82)。空なら全て応答する
+    #[serde(default)]
+    pub relay_circuit_ids: Vec<OptionValue>,
+    #[serde(default)]
+    pub relay_remote_ids: Vec<OptionValue>,
+}
+
+/**
+ * 特定のクライアントに固定で割り当てるIPアドレス。
+ * クライアントはMACアドレス(mac)かclient identifier(client_id, 16進数)のどちらか一方で指定する。
+ *
+ * ```toml
+ * reservations = [
+ *     { mac = "00:11:22:33:44:55", ip = "192.168.0.10", hostname = "printer" },
+ *     { client_id = "01:00:11:22:33:44:66", ip = "192.168.0.11" },
+ * ]
+ * ```
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReservationConfig {
+    pub mac: Option<String>,
+    pub client_id: Option<String>,
+    pub ip: Ipv4Addr,
+    pub hostname: Option<String>,
+    // スコープのオプションを上書き・追加するオプション
+    #[serde(default)]
+    pub options: Vec<CustomOption>,
+}
+
+impl ReservationConfig {
+    /**
+     * 予約の持ち主を解釈する
+     */
+    pub fn owner(&self) -> Result<ReservationOwner, failure::Error> {
+        match (&self.mac, &self.client_id) {
+            (Some(mac), None) => mac
+                .parse::<MacAddr>()
+                .map(ReservationOwner::MacAddr)
+                .map_err(|_| failure::format_err!("invalid mac address {:?}", mac)),
+            (None, Some(client_id)) => {
+                let client_id = util::decode_hex(client_id)?;
+                if client_id.is_empty() {
+                    return Err(failure::err_msg("client_id must not be empty"));
+                }
+                Ok(ReservationOwner::ClientId(client_id))
+            }
+            _ => Err(failure::err_msg("specify either mac or client_id")),
+        }
+    }
+
+    /**
+     * 実行時の予約に変換する
+     */
+    pub fn to_reservation(&self) -> Result<Reservation, failure::Error> {
+        Ok(Reservation {
+            owner: self.owner()?,
+            ip_addr: self.ip,
+            hostname: self.hostname.clone(),
+            options: self
+                .options
+                .iter()
+                .map(|option| option.to_option())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/**
+ * DHCPv6で1つのリンク(プレフィックス)に対する割り当ての設定。
+ *
+ * ```toml
+ * [[scope6]]
+ * name = "lan6"
+ * prefix = "2001:db8:1::/64"
+ * ranges = [{ start = "2001:db8:1::100", end = "2001:db8:1::1ff" }]
+ * pd_pool = "2001:db8:100::/48"
+ * ```
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scope6Config {
+    pub name: String,
+    pub prefix: String, // "2001:db8:1::/64" の形式
+    // このスコープのクライアントを受け付けるインターフェース
+    pub interface: Option<String>,
+    // 割り当てるアドレス(IA_NA)の範囲。省略した場合はプレフィックスの全てのアドレス
+    #[serde(default)]
+    pub ranges: Vec<AddressRange6>,
+    #[serde(default)]
+    pub dns_servers: Vec<Ipv6Addr>,
+    #[serde(default)]
+    pub domain_search: Vec<String>,
+    #[serde(default = "default_preferred_lifetime")]
+    pub preferred_lifetime: u32,
+    #[serde(default = "default_valid_lifetime")]
+    pub valid_lifetime: u32,
+    // Rapid Commit(SolicitにすぐReplyを返す)を受け付けるか
+    #[serde(default)]
+    pub rapid_commit: bool,
+    // 委譲するプレフィックス(IA_PD)を切り出すプール。省略した場合はプレフィックス委譲をしない
+    pub pd_pool: Option<String>,
+    #[serde(default = "default_pd_prefix_length")]
+    pub pd_prefix_length: u8,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddressRange6 {
+    pub start: Ipv6Addr,
+    pub end: Ipv6Addr,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddressRange {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+}
+
+impl AddressRange {
+    pub fn contains(&self, ip_addr: Ipv4Addr) -> bool {
+        self.start <= ip_addr && ip_addr <= self.end
+    }
+}
+
+/**
+ * 任意のコードのオプション。値は型ごとのキーで1つだけ指定する。
+ * RFC2132等で定義されたコードは値の長さなどを検証する。
+ *
+ * ```toml
+ * options = [
+ *     { code = 15, text = "example.com" },
+ *     { code = 42, ip = ["192.168.0.1"] },
+ *     { code = 26, u16 = 1500 },
+ *     { code = 43, hex = "0104c0a80001" },
+ *     { code = 119, domains = ["eng.example.com", "example.com"] },
+ *     { code = 121, routes = [{ destination = "10.0.0.0/8", router = "192.168.0.1" }] },
+ * ]
+ * ```
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomOption {
+    pub code: u8,
+    #[serde(flatten)]
+    pub value: OptionValue,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionValue {
+    Text(String),
+    Ip(Vec<Ipv4Addr>),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    Hex(String),
+    Domains(Vec<String>),   // ドメイン検索リスト(オプション119)の形式
+    Routes(Vec<RouteConfig>), // Classless Static Route(オプション121)の形式
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub destination: String, // "10.0.0.0/8" の形式
+    pub router: Ipv4Addr,
+}
+
+impl CustomOption {
+    /**
+     * 型付きのオプションにする。値がそのコードのオプションとして不正ならErr。
+     */
+    pub fn to_option(&self) -> Result<DhcpOption, failure::Error> {
+        options::from_config_value(self.code, &self.value.to_bytes()?)
+    }
+}
+
+impl OptionValue {
+    /**
+     * オプションの値をパケットに書き込むバイト列にする
+     */
+    pub fn to_bytes(&self) -> Result<Vec<u8>, failure::Error> {
+        let bytes = match self {
+            OptionValue::Text(text) => text.as_bytes().to_vec(),
+            OptionValue::Ip(addrs) => addrs.iter().flat_map(|addr| addr.octets()).collect(),
+            OptionValue::U8(i) => vec![*i],
+            OptionValue::U16(i) => i.to_be_bytes().to_vec(),
+            OptionValue::U32(i) => i.to_be_bytes().to_vec(),
+            OptionValue::Hex(hex) => util::decode_hex(hex)?,
+            OptionValue::Domains(domains) => {
+                for domain in domains.iter() {
+                    validate_domain_name(domain)?;
+                }
+                options::encode_domain_names(domains)
+            }
+            OptionValue::Routes(routes) => {
+                let routes = routes
+                    .iter()
+                    .map(|route| {
+                        Ok(ClasslessRoute {
+                            destination: route.destination.parse().map_err(|e| {
+                                failure::format_err!("invalid route {:?}: {}", route.destination, e)
+                            })?,
+                            router: route.router,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, failure::Error>>()?;
+                options::encode_classless_routes(&routes)
+            }
+        };
+        Ok(bytes)
+    }
+}
+
+fn default_lease_time() -> u32 {
+    DEFAULT_LEASE_TIME
+}
+
+fn default_decline_time() -> u32 {
+    DEFAULT_DECLINE_TIME
+}
+
+fn default_preferred_lifetime() -> u32 {
+    DEFAULT_PREFERRED_LIFETIME
+}
+
+fn default_valid_lifetime() -> u32 {
+    DEFAULT_VALID_LIFETIME
+}
+
+fn default_pd_prefix_length() -> u8 {
+    DEFAULT_PD_PREFIX_LENGTH
+}
+
+fn default_admin_listen() -> SocketAddr {
+    DEFAULT_ADMIN_LISTEN.parse().unwrap()
+}
+
+fn default_database() -> String {
+    DEFAULT_DATABASE.to_string()
+}
+
+fn default_ddns_ttl() -> u32 {
+    DEFAULT_DDNS_TTL
+}
+
+fn validate_domain_name(domain: &str) -> Result<(), failure::Error> {
+    let labels = domain.trim_end_matches('.').split('.');
+    if labels.clone().any(|label| label.is_empty() || label.len() > 63) {
+        return Err(failure::format_err!("invalid domain name {:?}", domain));
+    }
+    Ok(())
+}
+
+impl Config {
+    /**
+     * 設定ファイルを読み込んで検証する
+     */
+    pub fn load(path: &str) -> Result<Config, failure::Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| failure::format_err!("failed to read {}: {}", path, e))?;
+        Self::parse(&contents)
+    }
+
+    /**
+     * TOML文字列から設定を読み込んで検証する
+     */
+    pub fn parse(contents: &str) -> Result<Config, failure::Error> {
+        let config: Config = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /**
+     * 起動時に設定の誤りを見つけるための検証。
+     * 割り当て中に気付くと原因が分かりにくいので、問題があれば内容を示して起動させない。
+     */
+    fn validate(&self) -> Result<(), failure::Error> {
+        if self.scopes.is_empty() {
+            return Err(failure::err_msg("no [[scope]] is configured"));
+        }
+
+        let mut names = HashSet::new();
+        let mut networks: Vec<(&str, Ipv4Network)> = Vec::new();
+        for scope in self.scopes.iter() {
+            if !names.insert(scope.name.as_str()) {
+                return Err(failure::format_err!("duplicate scope name {:?}", scope.name));
+            }
+            let network = scope
+                .validate(self.server_identifier)
+                .map_err(|e| failure::format_err!("scope {:?}: {}", scope.name, e))?;
+            if let Some((other, _)) = networks
+                .iter()
+                .find(|(_, other)| other.contains(network.ip()) || network.contains(other.ip()))
+            {
+                return Err(failure::format_err!(
+                    "scope {:?}: subnet {} overlaps scope {:?}",
+                    scope.name,
+                    network,
+                    other
+                ));
+            }
+            networks.push((&scope.name, network));
+        }
+
+        if let Some(duid) = &self.server_duid {
+            let duid = util::decode_hex(duid)?;
+            if duid.len() < 3 || duid.len() > 130 {
+                return Err(failure::err_msg("server_duid must be 3 to 130 octets"));
+            }
+        }
+        let mut names = HashSet::new();
+        let mut networks: Vec<(&str, Ipv6Network)> = Vec::new();
+        for scope in self.scopes6.iter() {
+            if !names.insert(scope.name.as_str()) {
+                return Err(failure::format_err!("duplicate scope6 name {:?}", scope.name));
+            }
+            let (prefix, pd_pool) = scope
+                .validate()
+                .map_err(|e| failure::format_err!("scope6 {:?}: {}", scope.name, e))?;
+            for network in std::iter::once(prefix).chain(pd_pool) {
+                if let Some((other, _)) = networks
+                    .iter()
+                    .find(|(_, other)| other.contains(network.ip()) || network.contains(other.ip()))
+                {
+                    return Err(failure::format_err!(
+                        "scope6 {:?}: {} overlaps scope6 {:?}",
+                        scope.name,
+                        network,
+                        other
+                    ));
+                }
+                networks.push((&scope.name, network));
+            }
+        }
+
+        if let Some(admin) = &self.admin {
+            if !admin.allow_remote && !admin.listen.ip().is_loopback() {
+                return Err(failure::format_err!(
+                    "admin listen address {} is not a loopback address (set allow_remote = true)",
+                    admin.listen
+                ));
+            }
+        }
+
+        if let Some(ddns) = &self.ddns {
+            ddns.validate().map_err(|e| failure::format_err!("ddns: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * DHCPv6のサーバのDUID。設定がなければ最初のスコープのインターフェース(指定がなければ
+     * ループバック以外の最初のインターフェース)のMACアドレスからDUID-LLを作る。
+     */
+    pub fn server_duid(&self) -> Result<Vec<u8>, failure::Error> {
+        match &self.server_duid {
+            Some(duid) => util::decode_hex(duid),
+            None => {
+                let interface = self.scopes6.first().and_then(|scope| scope.interface.as_deref());
+                util::link_layer_duid(interface)
+            }
+        }
+    }
+}
+
+impl DdnsConfig {
+    /**
+     * TSIGの鍵をBase64から戻したもの
+     */
+    pub fn tsig_secret(&self) -> Result<Option<Vec<u8>>, failure::Error> {
+        let tsig = match &self.tsig {
+            Some(tsig) => tsig,
+            None => return Ok(None),
+        };
+        let secret = util::decode_base64(&tsig.secret)?;
+        if secret.is_empty() {
+            return Err(failure::err_msg("tsig secret must not be empty"));
+        }
+        Ok(Some(secret))
+    }
+
+    fn validate(&self) -> Result<(), failure::Error> {
+        if self.server.ip().is_unspecified() || self.server.port() == 0 {
+            return Err(failure::format_err!("invalid server address {}", self.server));
+        }
+        validate_domain_name(&self.forward_zone)?;
+        if let Some(reverse_zone) = &self.reverse_zone {
+            validate_domain_name(reverse_zone)?;
+            if !reverse_zone.trim_end_matches('.').ends_with("in-addr.arpa") {
+                return Err(failure::format_err!(
+                    "reverse_zone {:?} is not under in-addr.arpa",
+                    reverse_zone
+                ));
+            }
+        }
+        if let Some(tsig) = &self.tsig {
+            validate_domain_name(&tsig.name)?;
+        }
+        self.tsig_secret()?;
+        Ok(())
+    }
+}
+
+impl ScopeConfig {
+    /**
+     * サブネットを解釈する
+     */
+    pub fn network(&self) -> Result<Ipv4Network, failure::Error> {
+        let network: Ipv4Network = self
+            .subnet
+            .parse()
+            .map_err(|e| failure::format_err!("invalid subnet {:?}: {}", self.subnet, e))?;
+        if network.ip() != network.network() {
+            return Err(failure::format_err!(
+                "subnet {:?} is not a network address (did you mean {}/{}?)",
+                self.subnet,
+                network.network(),
+                network.prefix()
+            ));
+        }
+        Ok(network)
+    }
+
+    /**
+     * server identifierはスコープごとの指定を優先する
+     */
+    pub fn server_identifier(&self, default: Option<Ipv4Addr>) -> Option<Ipv4Addr> {
+        self.server_identifier.or(default)
+    }
+
+    fn validate(
+        &self,
+        default_server_identifier: Option<Ipv4Addr>,
+    ) -> Result<Ipv4Network, failure::Error> {
+        let network = self.network()?;
+        let server_identifier = self
+            .server_identifier(default_server_identifier)
+            .ok_or_else(|| failure::err_msg("server_identifier is not configured"))?;
+        if server_identifier.is_unspecified() {
+            return Err(failure::err_msg("server_identifier must not be 0.0.0.0"));
+        }
+        if self.lease_time == 0 {
+            return Err(failure::err_msg("lease_time must be greater than 0"));
+        }
+        for range in self.ranges.iter() {
+            if range.start > range.end {
+                return Err(failure::format_err!(
+                    "range {} - {} is reversed",
+                    range.start,
+                    range.end
+                ));
+            }
+            if !network.contains(range.start) || !network.contains(range.end) {
+                return Err(failure::format_err!(
+                    "range {} - {} is outside of subnet {}",
+                    range.start,
+                    range.end,
+                    network
+                ));
+            }
+        }
+        for exclusion in self.exclusions.iter() {
+            if !network.contains(*exclusion) {
+                return Err(failure::format_err!(
+                    "exclusion {} is outside of subnet {}",
+                    exclusion,
+                    network
+                ));
+            }
+        }
+        for router in self.routers.iter() {
+            if !network.contains(*router) {
+                return Err(failure::format_err!(
+                    "router {} is outside of subnet {}",
+                    router,
+                    network
+                ));
+            }
+        }
+        validate_options(&self.options)?;
+        for id in self.relay_circuit_ids.iter().chain(self.relay_remote_ids.iter()) {
+            id.to_bytes()
+                .map_err(|e| failure::format_err!("relay agent id: {}", e))?;
+        }
+
+        let mut owners = Vec::new();
+        let mut reserved_addrs = Vec::new();
+        for reservation in self.reservations.iter() {
+            let owner = reservation
+                .owner()
+                .map_err(|e| failure::format_err!("reservation {}: {}", reservation.ip, e))?;
+            let ip_addr = reservation.ip;
+            if !network.contains(ip_addr)
+                || ip_addr == network.network()
+                || ip_addr == network.broadcast()
+            {
+                return Err(failure::format_err!(
+                    "reservation {} is not a host address of subnet {}",
+                    ip_addr,
+                    network
+                ));
+            }
+            if ip_addr == server_identifier || self.routers.contains(&ip_addr) {
+                return Err(failure::format_err!(
+                    "reservation {} is the address of the server or a router",
+                    ip_addr
+                ));
+            }
+            if reserved_addrs.contains(&ip_addr) {
+                return Err(failure::format_err!("{} is reserved more than once", ip_addr));
+            }
+            if owners.contains(&owner) {
+                return Err(failure::format_err!(
+                    "reservation {}: {} already has a reservation",
+                    ip_addr,
+                    owner
+                ));
+            }
+            validate_options(&reservation.options)
+                .map_err(|e| failure::format_err!("reservation {}: {}", ip_addr, e))?;
+            owners.push(owner);
+            reserved_addrs.push(ip_addr);
+        }
+        Ok(network)
+    }
+}
+
+impl Scope6Config {
+    /**
+     * プレフィックスを解釈する
+     */
+    pub fn prefix(&self) -> Result<Ipv6Network, failure::Error> {
+        parse_ipv6_network(&self.prefix)
+    }
+
+    /**
+     * プレフィックス委譲のプールを解釈する
+     */
+    pub fn pd_pool(&self) -> Result<Option<Ipv6Network>, failure::Error> {
+        self.pd_pool.as_deref().map(parse_ipv6_network).transpose()
+    }
+
+    fn validate(&self) -> Result<(Ipv6Network, Option<Ipv6Network>), failure::Error> {
+        let prefix = self.prefix()?;
+        if prefix.prefix() == 0 || prefix.prefix() == 128 {
+            return Err(failure::format_err!("invalid prefix length of {}", prefix));
+        }
+        if self.valid_lifetime == 0 {
+            return Err(failure::err_msg("valid_lifetime must be greater than 0"));
+        }
+        if self.preferred_lifetime > self.valid_lifetime {
+            return Err(failure::err_msg(
+                "preferred_lifetime must not be greater than valid_lifetime",
+            ));
+        }
+        for range in self.ranges.iter() {
+            if range.start > range.end {
+                return Err(failure::format_err!(
+                    "range {} - {} is reversed",
+                    range.start,
+                    range.end
+                ));
+            }
+            if !prefix.contains(range.start) || !prefix.contains(range.end) {
+                return Err(failure::format_err!(
+                    "range {} - {} is outside of prefix {}",
+                    range.start,
+                    range.end,
+                    prefix
+                ));
+            }
+        }
+        OptionValue::Domains(self.domain_search.clone()).to_bytes()?;
+
+        let pd_pool = self.pd_pool()?;
+        if let Some(pd_pool) = pd_pool {
+            if pd_pool.prefix() == 0
+                || self.pd_prefix_length <= pd_pool.prefix()
+                || self.pd_prefix_length > 128
+            {
+                return Err(failure::format_err!(
+                    "pd_prefix_length {} must be longer than pd_pool {}",
+                    self.pd_prefix_length,
+                    pd_pool
+                ));
+            }
+        }
+        Ok((prefix, pd_pool))
+    }
+}
+
+fn parse_ipv6_network(network: &str) -> Result<Ipv6Network, failure::Error> {
+    let parsed: Ipv6Network = network
+        .parse()
+        .map_err(|e| failure::format_err!("invalid prefix {:?}: {}", network, e))?;
+    if parsed.ip() != parsed.network() {
+        return Err(failure::format_err!(
+            "prefix {:?} is not a network address (did you mean {}/{}?)",
+            network,
+            parsed.network(),
+            parsed.prefix()
+        ));
+    }
+    Ok(parsed)
+}
+
+/**
+ * 任意のオプションの値がそのコードのオプションとして解釈できるか。
+ * リースやメッセージの制御に使うオプションはサーバが決めるので設定できない。
+ */
+fn validate_options(options: &[CustomOption]) -> Result<(), failure::Error> {
+    let managed = [
+        Code::RequestedIpAddress as u8,
+        Code::IPAddressLeaseTime as u8,
+        Code::OptionOverload as u8,
+        Code::MessageType as u8,
+        Code::ServerIdentifier as u8,
+        Code::ParameterRequestList as u8,
+        Code::MaximumMessageSize as u8,
+        Code::RenewalTime as u8,
+        Code::RebindingTime as u8,
+        Code::ClientIdentifier as u8,
+        Code::RelayAgentInformation as u8,
+    ];
+    for option in options.iter() {
+        if managed.contains(&option.code) {
+            return Err(failure::format_err!(
+                "option {} is set by the server and cannot be configured",
+                option.code
+            ));
+        }
+        option
+            .to_option()
+            .map_err(|e| failure::format_err!("option {}: {}", option.code, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TEST_CONFIG;
+
+    fn error_of(contents: &str) -> String {
+        Config::parse(contents).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(TEST_CONFIG).unwrap();
+        assert_eq!(DEFAULT_DATABASE, config.database);
+        assert_eq!(2, config.scopes.len());
+        let office = &config.scopes[1];
+        assert_eq!(DEFAULT_LEASE_TIME, office.lease_time);
+        assert_eq!(Some("10.0.1.2".parse().unwrap()), office.server_identifier(None));
+        let options: Vec<_> = office
+            .options
+            .iter()
+            .map(|option| option.to_option().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                DhcpOption::DomainName("office.example".to_string()),
+                DhcpOption::InterfaceMtu(1500),
+                DhcpOption::VendorSpecific(vec![0x01, 0x04, 0xc0, 0xa8, 0x00, 0x01]),
+                DhcpOption::ClasslessStaticRoute(vec![ClasslessRoute {
+                    destination: "172.16.0.0/12".parse().unwrap(),
+                    router: "10.0.1.254".parse().unwrap(),
+                }]),
+            ],
+            options
+        );
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let scope = |body: &str| {
+            format!(
+                "server_identifier = \"192.168.0.2\"\n[[scope]]\nname = \"lan\"\n{}",
+                body
+            )
+        };
+        assert_eq!("no [[scope]] is configured", error_of(""));
+        assert!(error_of(&scope("subnet = \"192.168.0.1/24\""))
+            .contains("is not a network address"));
+        assert!(error_of(&scope(
+            "subnet = \"192.168.0.0/24\"\nranges = [{ start = \"192.168.1.1\", end = \"192.168.1.9\" }]"
+        ))
+        .contains("outside of subnet"));
+        assert!(error_of(&scope("subnet = \"192.168.0.0/24\"\nlease_time = 0"))
+            .starts_with("scope \"lan\": lease_time"));
+        assert!(error_of(&scope(
+            "subnet = \"192.168.0.0/24\"\n[[scope]]\nname = \"lan\"\nsubnet = \"10.0.0.0/8\""
+        ))
+        .contains("duplicate scope name"));
+        assert!(error_of(&scope(
+            "subnet = \"192.168.0.0/24\"\n[[scope]]\nname = \"wide\"\nsubnet = \"192.168.0.0/16\""
+        ))
+        .contains("overlaps"));
+        assert!(error_of(&scope("subnet = \"192.168.0.0/24\"\nleasetime = 10"))
+            .contains("unknown field"));
+        let reservation = |entries: &str| {
+            error_of(&scope(&format!(
+                "subnet = \"192.168.0.0/24\"\nreservations = [{}]",
+                entries
+            )))
+        };
+        assert!(reservation(r#"{ ip = "192.168.0.10" }"#).contains("either mac or client_id"));
+        assert!(reservation(r#"{ mac = "00:11:22:33:44:55", ip = "192.168.1.10" }"#)
+            .contains("not a host address"));
+        assert!(reservation(
+            r#"{ mac = "00:11:22:33:44:55", ip = "192.168.0.10" },
+               { client_id = "0100112233", ip = "192.168.0.10" }"#
+        )
+        .contains("reserved more than once"));
+        assert!(reservation(
+            r#"{ mac = "00:11:22:33:44:55", ip = "192.168.0.10" },
+               { mac = "00:11:22:33:44:55", ip = "192.168.0.11" }"#
+        )
+        .contains("already has a reservation"));
+        let option = |entry: &str| {
+            error_of(&scope(&format!("subnet = \"192.168.0.0/24\"\noptions = [{}]", entry)))
+        };
+        assert!(option("{ code = 26, u8 = 1 }").contains("must be 2 bytes"));
+        assert!(option("{ code = 51, u32 = 60 }").contains("set by the server"));
+        assert!(option(r#"{ code = 119, domains = ["a..example"] }"#)
+            .contains("invalid domain name"));
+    }
+
+    #[test]
+    fn test_scope6_validation_errors() {
+        let config = Config::parse(TEST_CONFIG).unwrap();
+        assert_eq!(
+            vec![0, 3, 0, 1, 0, 0x11, 0x22, 0x33, 0x44, 0x01],
+            config.server_duid().unwrap()
+        );
+        let lan6 = &config.scopes6[0];
+        assert_eq!(Some("2001:db8:100::/54".parse().unwrap()), lan6.pd_pool().unwrap());
+
+        let scope6 = |body: &str| {
+            error_of(&format!(
+                "server_identifier = \"192.168.0.2\"\n\
+                 [[scope]]\nname = \"lan\"\nsubnet = \"192.168.0.0/24\"\n\
+                 [[scope6]]\nname = \"lan6\"\n{}",
+                body
+            ))
+        };
+        assert!(scope6("prefix = \"2001:db8::1/64\"").contains("is not a network address"));
+        assert!(scope6(
+            "prefix = \"2001:db8::/64\"\n\
+             ranges = [{ start = \"2001:db8:1::1\", end = \"2001:db8:1::9\" }]"
+        )
+        .contains("outside of prefix"));
+        assert!(scope6("prefix = \"2001:db8::/64\"\npreferred_lifetime = 600\nvalid_lifetime = 300")
+            .contains("must not be greater than valid_lifetime"));
+        assert!(scope6("prefix = \"2001:db8::/64\"\npd_pool = \"2001:db8:100::/56\"")
+            .contains("must be longer than pd_pool"));
+        assert!(scope6(
+            "prefix = \"2001:db8::/64\"\npd_pool = \"2001:db8::/48\"\npd_prefix_length = 60"
+        )
+        .contains("overlaps"));
+        assert!(scope6("prefix = \"2001:db8::/64\"\ndomain_search = [\"a..lan\"]")
+            .contains("invalid domain name"));
+    }
+
+    #[test]
+    fn test_admin_config() {
+        let admin = |body: &str| {
+            Config::parse(&format!(
+                "server_identifier = \"192.168.0.2\"\n\
+                 [[scope]]\nname = \"lan\"\nsubnet = \"192.168.0.0/24\"\n\
+                 [admin]\n{}",
+                body
+            ))
+        };
+        assert_eq!(default_admin_listen(), admin("").unwrap().admin.unwrap().listen);
+        assert!(Config::parse(TEST_CONFIG).unwrap().admin.is_none());
+        // 他のホストに公開するには明示的に許可する
+        assert!(admin("listen = \"0.0.0.0:8067\"")
+            .unwrap_err()
+            .to_string()
+            .contains("is not a loopback address"));
+        assert!(admin("listen = \"0.0.0.0:8067\"\nallow_remote = true").is_ok());
+        assert!(admin("listen = \"[::1]:8067\"").is_ok());
+    }
+
+    #[test]
+    fn test_ddns_config() {
+        let ddns = |body: &str| {
+            Config::parse(&format!(
+                "server_identifier = \"192.168.0.2\"\n\
+                 [[scope]]\nname = \"lan\"\nsubnet = \"192.168.0.0/24\"\n\
+                 [ddns]\nserver = \"192.168.0.1:53\"\n{}",
+                body
+            ))
+        };
+        let config = ddns("forward_zone = \"example.lan\"").unwrap().ddns.unwrap();
+        assert_eq!(DEFAULT_DDNS_TTL, config.ttl);
+        assert_eq!(None, config.tsig_secret().unwrap());
+        let config = ddns(
+            "forward_zone = \"example.lan\"\n\
+             tsig = { name = \"dhcp-key\", secret = \"c2VjcmV0LWtleQ==\" }",
+        )
+        .unwrap()
+        .ddns
+        .unwrap();
+        assert_eq!(Some(b"secret-key".to_vec()), config.tsig_secret().unwrap());
+        assert!(Config::parse(TEST_CONFIG).unwrap().ddns.is_none());
+
+        let error_of = |body: &str| ddns(body).unwrap_err().to_string();
+        assert!(error_of("").contains("missing field `forward_zone`"));
+        assert!(error_of("forward_zone = \"example..lan\"").contains("invalid domain name"));
+        assert!(error_of("forward_zone = \"example.lan\"\nreverse_zone = \"example.lan\"")
+            .contains("not under in-addr.arpa"));
+        assert!(error_of(
+            "forward_zone = \"example.lan\"\ntsig = { name = \"dhcp-key\", secret = \"c2VjcmV0!\" }"
+        )
+        .contains("invalid base64"));
+    }
+}
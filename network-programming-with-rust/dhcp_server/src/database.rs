@@ -1,6 +1,32 @@
-use pnet::util::MacAddr;
+use ipnetwork::Ipv6Network;
 use rusqlite::{params, Connection, Rows, Transaction};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use super::dhcp::{Client, Reservation, ReservationOwner};
+use super::dhcp6::IaType;
+use super::util;
+
+// 設定のdatabaseにこれを指定すると、リースをDBファイルではなくメモリ上に持つ
+const MEMORY_DATABASE: &str = ":memory:";
+
+/**
+ * lease_entriesからクライアントのレコードを選ぶ条件。
+ * client identifier(?1)があればそれで、なければhtype(?2)とハードウェアアドレス(?3)で選ぶ
+ */
+const CLIENT_KEY: &str = "(client_id = ?1
+     OR (?1 IS NULL AND client_id IS NULL AND htype = ?2 AND mac_addr = ?3))";
+
+/**
+ * CLIENT_KEYの?1から?3に渡す値
+ */
+fn client_key(client: &Client) -> (Option<String>, u8, String) {
+    (
+        client.client_id.as_deref().map(util::encode_hex),
+        client.htype,
+        client.hw_addr_string(),
+    )
+}
 
 /**
  * 結果のレコードからIPアドレスのカラムを取り出し、そのベクタを返す。
@@ -24,7 +50,7 @@ fn get_addresses_from_row(mut ip_addrs: Rows) -> Result<Vec<Ipv4Addr>, failure::
  * 利用されているIPアドレスを返す。
  * deletedが渡された場合は`deleted`カラムをその条件で絞り込む
  */
-pub fn select_addresses(
+fn select_addresses(
     con: &Connection,
     deleted: Option<u8>,
 ) -> Result<Vec<Ipv4Addr>, failure::Error> {
@@ -40,14 +66,13 @@ pub fn select_addresses(
 }
 
 /**
- * 指定のMACアドレスを持つレコードの件数を返す
+ * 指定のクライアントのレコードの件数を返す
  */
-pub fn count_records_by_mac_addr(
-    tx: &Transaction,
-    mac_addr: MacAddr,
-) -> Result<u8, failure::Error> {
-    let mut stmnt = tx.prepare("SELECT COUNT (*) FROM lease_entries WHERE mac_addr = ?")?;
-    let mut count_result = stmnt.query(params![mac_addr.to_string()])?;
+fn count_records_by_client(con: &Connection, client: &Client) -> Result<u8, failure::Error> {
+    let (client_id, htype, hw_addr) = client_key(client);
+    let mut stmnt =
+        con.prepare(&format!("SELECT COUNT (*) FROM lease_entries WHERE {}", CLIENT_KEY))?;
+    let mut count_result = stmnt.query(params![client_id, htype, hw_addr])?;
 
     let count: u8 = match count_result.next()? {
         Some(row) => row.get(0)?,
@@ -59,55 +84,481 @@ pub fn count_records_by_mac_addr(
     Ok(count)
 }
 
+/**
+ * lease_entriesのレコードのうちリースの状態を表す部分
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaseEntry {
+    pub ip_addr: Ipv4Addr,
+    pub deleted: bool,
+    pub lease_expiry: i64,
+    pub hostname: Option<String>,
+}
+
+impl LeaseEntry {
+    /**
+     * 論理削除されておらず、期限(UNIX時間)を過ぎていなければ有効なリース
+     */
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.deleted && self.lease_expiry > now
+    }
+}
+
+/**
+ * 指定のクライアントのエントリ（論理削除されているものも含めて）を返す。
+ */
+fn select_lease_entry(
+    con: &Connection,
+    client: &Client,
+) -> Result<Option<LeaseEntry>, failure::Error> {
+    let (client_id, htype, hw_addr) = client_key(client);
+    let mut stmnt = con.prepare(&format!(
+        "SELECT ip_addr, deleted, lease_expiry, hostname FROM lease_entries WHERE {}",
+        CLIENT_KEY
+    ))?;
+    let mut row = stmnt.query(params![client_id, htype, hw_addr])?;
+    if let Some(entry) = row.next()? {
+        let ip_string: String = entry.get(0)?;
+        let deleted: u8 = entry.get(1)?;
+        Ok(Some(LeaseEntry {
+            ip_addr: ip_string.parse()?,
+            deleted: deleted != 0,
+            lease_expiry: entry.get(2)?,
+            hostname: entry.get(3)?,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
 /**
  * バインディングの追加
+ * lease_start, lease_expiry はUNIX時間(秒)
  */
-pub fn insert_entry(
+fn insert_entry(
     tx: &Transaction,
-    mac_addr: MacAddr,
+    client: &Client,
     ip_addr: Ipv4Addr,
+    lease_start: i64,
+    lease_expiry: i64,
+) -> Result<(), failure::Error> {
+    let (client_id, htype, hw_addr) = client_key(client);
+    tx.execute(
+        "INSERT INTO lease_entries (client_id, htype, mac_addr, ip_addr, lease_start, lease_expiry)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![client_id, htype, hw_addr, ip_addr.to_string(), lease_start, lease_expiry],
+    )?;
+    Ok(())
+}
+
+/**
+ * バインディングの更新。
+ * client identifierで識別するクライアントはハードウェアアドレスも更新する。
+ */
+fn update_entry(
+    tx: &Transaction,
+    client: &Client,
+    ip_addr: Ipv4Addr,
+    deleted: u8,
+    lease_start: i64,
+    lease_expiry: i64,
+) -> Result<(), failure::Error> {
+    let (client_id, htype, hw_addr) = client_key(client);
+    tx.execute(
+        &format!(
+            "UPDATE lease_entries SET htype = ?2, mac_addr = ?3, ip_addr = ?4, deleted = ?5,
+             lease_start = ?6, lease_expiry = ?7 WHERE {}",
+            CLIENT_KEY
+        ),
+        params![
+            client_id,
+            htype,
+            hw_addr,
+            ip_addr.to_string(),
+            deleted.to_string(),
+            lease_start,
+            lease_expiry
+        ],
+    )?;
+    Ok(())
+}
+
+/**
+ * リースの延長。
+ * 指定のクライアントとIPアドレスの組で有効な(論理削除されていない)バインディングがあれば期限を更新し、更新した件数を返す。
+ */
+fn renew_entry(
+    tx: &Transaction,
+    client: &Client,
+    ip_addr: Ipv4Addr,
+    lease_start: i64,
+    lease_expiry: i64,
+) -> Result<usize, failure::Error> {
+    let (client_id, htype, hw_addr) = client_key(client);
+    let count = tx.execute(
+        &format!(
+            "UPDATE lease_entries SET lease_start = ?5, lease_expiry = ?6
+             WHERE {} AND ip_addr = ?4 AND deleted = 0",
+            CLIENT_KEY
+        ),
+        params![
+            client_id,
+            htype,
+            hw_addr,
+            ip_addr.to_string(),
+            lease_start,
+            lease_expiry
+        ],
+    )?;
+    Ok(count)
+}
+
+/**
+ * クライアントが名乗ったホスト名を記録する。Noneなら消す
+ */
+fn update_hostname(
+    tx: &Transaction,
+    client: &Client,
+    hostname: Option<&str>,
+) -> Result<(), failure::Error> {
+    let (client_id, htype, hw_addr) = client_key(client);
+    tx.execute(
+        &format!("UPDATE lease_entries SET hostname = ?4 WHERE {}", CLIENT_KEY),
+        params![client_id, htype, hw_addr, hostname],
+    )?;
+    Ok(())
+}
+
+/**
+ * リースを追加または更新し、ホスト名も記録する。
+ * 同じクライアントのバインディングがなければ追加し、あれば論理削除の状態も含めて上書きする。
+ */
+fn upsert_lease_record(tx: &Transaction, record: &LeaseRecord) -> Result<(), failure::Error> {
+    let client = &record.client;
+    match count_records_by_client(tx, client)? {
+        0 => {
+            insert_entry(tx, client, record.ip_addr, record.lease_start, record.lease_expiry)?;
+            if record.deleted {
+                delete_entry(tx, client)?;
+            }
+        }
+        _ => update_entry(
+            tx,
+            client,
+            record.ip_addr,
+            record.deleted as u8,
+            record.lease_start,
+            record.lease_expiry,
+        )?,
+    }
+    update_hostname(tx, client, record.hostname.as_deref())
+}
+
+/**
+ * 期限(UNIX時間)がnow以前のバインディングを論理削除し、それらのIPアドレスを返す。
+ * RELEASEと同じく論理削除にするのは、同じクライアントが再び来た時に同じIPアドレスを割り当てられるようにするため。
+ */
+fn delete_expired_entries(tx: &Transaction, now: i64) -> Result<Vec<Ipv4Addr>, failure::Error> {
+    let expired_addrs = {
+        let mut stmnt = tx.prepare(
+            "SELECT ip_addr FROM lease_entries WHERE deleted = 0 AND lease_expiry <= ?1",
+        )?;
+        let ip_addrs = stmnt.query(params![now])?;
+        get_addresses_from_row(ip_addrs)?
+    };
+    tx.execute(
+        "UPDATE lease_entries SET deleted = 1 WHERE deleted = 0 AND lease_expiry <= ?1",
+        params![now],
+    )?;
+    Ok(expired_addrs)
+}
+
+/**
+ * バインディングの論理削除
+ */
+fn delete_entry(tx: &Transaction, client: &Client) -> Result<(), failure::Error> {
+    let (client_id, htype, hw_addr) = client_key(client);
+    tx.execute(
+        &format!("UPDATE lease_entries SET deleted = 1 WHERE {}", CLIENT_KEY),
+        params![client_id, htype, hw_addr],
+    )?;
+    Ok(())
+}
+
+/**
+ * 論理削除されたバインディングを物理削除し、削除した件数を返す。
+ * 削除したクライアントが再び来ても、以前のIPアドレスは優先して提案されなくなる。
+ */
+fn purge_deleted_entries(tx: &Transaction) -> Result<usize, failure::Error> {
+    let count = tx.execute("DELETE FROM lease_entries WHERE deleted = 1", params![])?;
+    Ok(count)
+}
+
+/**
+ * DHCPDECLINEで使用中と通知されたIPアドレスを、conflict_until(UNIX時間)まで割り当てないように記録する。
+ * 通知したクライアントはハードウェアアドレスで記録する。
+ */
+fn insert_conflict(
+    tx: &Transaction,
+    ip_addr: Ipv4Addr,
+    client: &Client,
+    conflict_until: i64,
+) -> Result<(), failure::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO conflicted_addresses (ip_addr, mac_addr, conflict_until)
+         VALUES (?1, ?2, ?3)",
+        params![ip_addr.to_string(), client.hw_addr_string(), conflict_until],
+    )?;
+    Ok(())
+}
+
+/**
+ * 割り当てを控えているIPアドレスを返す。
+ */
+fn select_conflicted_addresses(con: &Connection) -> Result<Vec<Ipv4Addr>, failure::Error> {
+    let mut stmnt = con.prepare("SELECT ip_addr FROM conflicted_addresses")?;
+    let ip_addrs = stmnt.query(params![])?;
+    get_addresses_from_row(ip_addrs)
+}
+
+/**
+ * 期限(UNIX時間)がnow以前の記録を削除し、それらのIPアドレスを返す。
+ */
+fn delete_expired_conflicts(
+    tx: &Transaction,
+    now: i64,
+) -> Result<Vec<Ipv4Addr>, failure::Error> {
+    let expired_addrs = {
+        let mut stmnt =
+            tx.prepare("SELECT ip_addr FROM conflicted_addresses WHERE conflict_until <= ?1")?;
+        let ip_addrs = stmnt.query(params![now])?;
+        get_addresses_from_row(ip_addrs)?
+    };
+    tx.execute(
+        "DELETE FROM conflicted_addresses WHERE conflict_until <= ?1",
+        params![now],
+    )?;
+    Ok(expired_addrs)
+}
+
+/**
+ * 予約の一覧を返す。
+ */
+fn select_reservations(con: &Connection) -> Result<Vec<Reservation>, failure::Error> {
+    let mut stmnt =
+        con.prepare("SELECT ip_addr, mac_addr, client_id, hostname FROM reservations")?;
+    let mut rows = stmnt.query(params![])?;
+    let mut reservations = Vec::new();
+    while let Some(row) = rows.next()? {
+        let ip_string: String = row.get(0)?;
+        let mac_addr: Option<String> = row.get(1)?;
+        let client_id: Option<String> = row.get(2)?;
+        let owner = match (mac_addr, client_id) {
+            (Some(mac_string), _) => ReservationOwner::MacAddr(
+                mac_string
+                    .parse()
+                    .map_err(|_| failure::format_err!("invalid mac address {:?}", mac_string))?,
+            ),
+            (None, Some(client_id)) => ReservationOwner::ClientId(util::decode_hex(&client_id)?),
+            (None, None) => {
+                return Err(failure::format_err!("reservation {} has no owner", ip_string))
+            }
+        };
+        reservations.push(Reservation {
+            owner,
+            ip_addr: ip_string.parse()?,
+            hostname: row.get(3)?,
+            options: Vec::new(),
+        });
+    }
+    Ok(reservations)
+}
+
+/**
+ * 予約を登録する
+ */
+fn insert_reservation(
+    tx: &Transaction,
+    reservation: &Reservation,
 ) -> Result<(), failure::Error> {
+    let (mac_addr, client_id) = match &reservation.owner {
+        ReservationOwner::MacAddr(mac_addr) => (Some(mac_addr.to_string()), None),
+        ReservationOwner::ClientId(id) => (None, Some(util::encode_hex(id))),
+    };
     tx.execute(
-        "INSERT INTO lease_entries (mac_addr, ip_addr) VALUES (?1, ?2)",
-        params![mac_addr.to_string(), ip_addr.to_string()],
+        "INSERT INTO reservations (ip_addr, mac_addr, client_id, hostname) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            reservation.ip_addr.to_string(),
+            mac_addr,
+            client_id,
+            reservation.hostname
+        ],
     )?;
     Ok(())
 }
 
 /**
- * 指定のMACアドレスをもつエントリ（論理削除されているものも含めて）のIPアドレスを返す。
+ * 予約を削除し、削除した件数を返す
+ */
+fn delete_reservation(tx: &Transaction, ip_addr: Ipv4Addr) -> Result<usize, failure::Error> {
+    let count = tx.execute(
+        "DELETE FROM reservations WHERE ip_addr = ?1",
+        params![ip_addr.to_string()],
+    )?;
+    Ok(count)
+}
+
+/**
+ * lease_entriesのレコード全体。リースの一覧に使う
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaseRecord {
+    pub client: Client,
+    pub ip_addr: Ipv4Addr,
+    pub deleted: bool,
+    pub lease_start: i64,
+    pub lease_expiry: i64,
+    pub hostname: Option<String>,
+}
+
+impl LeaseRecord {
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.deleted && self.lease_expiry > now
+    }
+}
+
+/**
+ * 全てのバインディング（論理削除されているものも含めて）を登録順に返す。
  */
-pub fn select_entry(
+fn select_lease_records(con: &Connection) -> Result<Vec<LeaseRecord>, failure::Error> {
+    let mut stmnt = con.prepare(
+        "SELECT client_id, htype, mac_addr, ip_addr, deleted, lease_start, lease_expiry, hostname
+         FROM lease_entries ORDER BY id",
+    )?;
+    let mut rows = stmnt.query(params![])?;
+    let mut records = Vec::new();
+    while let Some(row) = rows.next()? {
+        let client_id: Option<String> = row.get(0)?;
+        let hw_string: String = row.get(2)?;
+        let ip_string: String = row.get(3)?;
+        let deleted: u8 = row.get(4)?;
+        records.push(LeaseRecord {
+            client: Client {
+                client_id: client_id.as_deref().map(util::decode_hex).transpose()?,
+                htype: row.get(1)?,
+                hw_addr: util::decode_hex(&hw_string)?,
+            },
+            ip_addr: ip_string.parse()?,
+            deleted: deleted != 0,
+            lease_start: row.get(5)?,
+            lease_expiry: row.get(6)?,
+            hostname: row.get(7)?,
+        });
+    }
+    Ok(records)
+}
+
+/**
+ * conflicted_addressesのレコード
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConflictRecord {
+    pub ip_addr: Ipv4Addr,
+    pub hw_addr: String, // DHCPDECLINEを送ったクライアントのハードウェアアドレス
+    pub conflict_until: i64,
+}
+
+/**
+ * 割り当てを控えているIPアドレスの記録を返す。
+ */
+fn select_conflicts(con: &Connection) -> Result<Vec<ConflictRecord>, failure::Error> {
+    let mut stmnt =
+        con.prepare("SELECT ip_addr, mac_addr, conflict_until FROM conflicted_addresses")?;
+    let mut rows = stmnt.query(params![])?;
+    let mut records = Vec::new();
+    while let Some(row) = rows.next()? {
+        let ip_string: String = row.get(0)?;
+        records.push(ConflictRecord {
+            ip_addr: ip_string.parse()?,
+            hw_addr: row.get(1)?,
+            conflict_until: row.get(2)?,
+        });
+    }
+    Ok(records)
+}
+
+/**
+ * lease6_entriesのレコードのうちリースの状態を表す部分。
+ * IA_NAはアドレスをプレフィックス長128で、IA_PDは委譲したプレフィックスを持つ。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease6Entry {
+    pub prefix: Ipv6Network,
+    pub deleted: bool,
+    pub lease_expiry: i64,
+}
+
+impl Lease6Entry {
+    /**
+     * 論理削除されておらず、期限(UNIX時間)を過ぎていなければ有効なリース
+     */
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.deleted && self.lease_expiry > now
+    }
+}
+
+/**
+ * DUIDとIAIDの組(バインディング)のエントリ（論理削除されているものも含めて）を返す。
+ */
+fn select_lease6_entry(
     con: &Connection,
-    mac_addr: MacAddr,
-) -> Result<Option<Ipv4Addr>, failure::Error> {
-    let mut stmnt = con.prepare("SELECT ip_addr FROM lease_entries WHERE mac_addr = ?1")?;
-    let mut row = stmnt.query(params![mac_addr.to_string()])?;
+    duid: &[u8],
+    iaid: u32,
+    ia_type: IaType,
+) -> Result<Option<Lease6Entry>, failure::Error> {
+    let mut stmnt = con.prepare(
+        "SELECT address, prefix_len, deleted, lease_expiry FROM lease6_entries
+         WHERE duid = ?1 AND iaid = ?2 AND ia_type = ?3",
+    )?;
+    let mut row = stmnt.query(params![util::encode_hex(duid), iaid, ia_type.as_str()])?;
     if let Some(entry) = row.next()? {
-        let ip = entry.get(0)?;
-        let ip_string: String = ip;
-        Ok(Some(ip_string.parse()?))
+        let address: String = entry.get(0)?;
+        let deleted: u8 = entry.get(2)?;
+        Ok(Some(Lease6Entry {
+            prefix: Ipv6Network::new(address.parse()?, entry.get(1)?)?,
+            deleted: deleted != 0,
+            lease_expiry: entry.get(3)?,
+        }))
     } else {
-        info!("specified MAC addr was not found.");
         Ok(None)
     }
 }
 
 /**
- * バインディングの更新
+ * バインディングの追加・更新
+ * lease_start, lease_expiry はUNIX時間(秒)
  */
-pub fn update_entry(
+fn upsert_lease6_entry(
     tx: &Transaction,
-    mac_addr: MacAddr,
-    ip_addr: Ipv4Addr,
-    deleted: u8,
+    duid: &[u8],
+    iaid: u32,
+    ia_type: IaType,
+    prefix: Ipv6Network,
+    lease_start: i64,
+    lease_expiry: i64,
 ) -> Result<(), failure::Error> {
     tx.execute(
-        "UPDATE lease_entries SET ip_addr = ?2, deleted = ?3 WHERE mac_addr = ?1",
+        "INSERT OR REPLACE INTO lease6_entries
+         (duid, iaid, ia_type, address, prefix_len, deleted, lease_start, lease_expiry)
+         VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, ?7)",
         params![
-            mac_addr.to_string(),
-            ip_addr.to_string(),
-            deleted.to_string()
+            util::encode_hex(duid),
+            iaid,
+            ia_type.as_str(),
+            prefix.ip().to_string(),
+            prefix.prefix(),
+            lease_start,
+            lease_expiry
         ],
     )?;
     Ok(())
@@ -116,10 +567,1247 @@ pub fn update_entry(
 /**
  * バインディングの論理削除
  */
-pub fn delete_entry(tx: &Transaction, mac_addr: MacAddr) -> Result<(), failure::Error> {
+fn delete_lease6_entry(
+    tx: &Transaction,
+    duid: &[u8],
+    iaid: u32,
+    ia_type: IaType,
+) -> Result<(), failure::Error> {
     tx.execute(
-        "UPDATE lease_entries SET deleted = ?1 WHERE mac_addr = ?2",
-        params![1.to_string(), mac_addr.to_string()],
+        "UPDATE lease6_entries SET deleted = 1 WHERE duid = ?1 AND iaid = ?2 AND ia_type = ?3",
+        params![util::encode_hex(duid), iaid, ia_type.as_str()],
     )?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/**
+ * 期限(UNIX時間)がnow以前のバインディングを論理削除し、それらのアドレス(プレフィックス)を返す。
+ */
+fn delete_expired_lease6_entries(
+    tx: &Transaction,
+    now: i64,
+) -> Result<Vec<Ipv6Addr>, failure::Error> {
+    let expired_addrs = {
+        let mut stmnt = tx.prepare(
+            "SELECT address FROM lease6_entries WHERE deleted = 0 AND lease_expiry <= ?1",
+        )?;
+        let addrs = stmnt.query(params![now])?;
+        get_addresses6_from_row(addrs)?
+    };
+    tx.execute(
+        "UPDATE lease6_entries SET deleted = 1 WHERE deleted = 0 AND lease_expiry <= ?1",
+        params![now],
+    )?;
+    Ok(expired_addrs)
+}
+
+/**
+ * アドレス(プレフィックス)が他のバインディングで使われているか、DECLINEされて割り当てを控えているか。
+ */
+fn is_address6_in_use(
+    con: &Connection,
+    address: Ipv6Addr,
+    duid: &[u8],
+    iaid: u32,
+    now: i64,
+) -> Result<bool, failure::Error> {
+    let count: u32 = con.query_row(
+        "SELECT
+           (SELECT COUNT(*) FROM lease6_entries
+            WHERE address = ?1 AND deleted = 0 AND lease_expiry > ?4
+              AND NOT (duid = ?2 AND iaid = ?3))
+         + (SELECT COUNT(*) FROM conflicted_addresses6 WHERE address = ?1)",
+        params![address.to_string(), util::encode_hex(duid), iaid, now],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/**
+ * DECLINEで使用中と通知されたアドレスを、conflict_until(UNIX時間)まで割り当てないように記録する。
+ */
+fn insert_conflict6(
+    tx: &Transaction,
+    address: Ipv6Addr,
+    duid: &[u8],
+    conflict_until: i64,
+) -> Result<(), failure::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO conflicted_addresses6 (address, duid, conflict_until)
+         VALUES (?1, ?2, ?3)",
+        params![address.to_string(), util::encode_hex(duid), conflict_until],
+    )?;
+    Ok(())
+}
+
+/**
+ * 期限(UNIX時間)がnow以前の記録を削除し、それらのアドレスを返す。
+ */
+fn delete_expired_conflicts6(
+    tx: &Transaction,
+    now: i64,
+) -> Result<Vec<Ipv6Addr>, failure::Error> {
+    let expired_addrs = {
+        let mut stmnt =
+            tx.prepare("SELECT address FROM conflicted_addresses6 WHERE conflict_until <= ?1")?;
+        let addrs = stmnt.query(params![now])?;
+        get_addresses6_from_row(addrs)?
+    };
+    tx.execute(
+        "DELETE FROM conflicted_addresses6 WHERE conflict_until <= ?1",
+        params![now],
+    )?;
+    Ok(expired_addrs)
+}
+
+/**
+ * 結果のレコードからIPv6アドレスのカラムを取り出し、そのベクタを返す。
+ */
+fn get_addresses6_from_row(mut rows: Rows) -> Result<Vec<Ipv6Addr>, failure::Error> {
+    let mut addrs = Vec::new();
+    while let Some(row) = rows.next()? {
+        let address: String = row.get(0)?;
+        addrs.push(address.parse()?);
+    }
+    Ok(addrs)
+}
+
+/**
+ * スキーマの変更。versionの順に適用し、適用したバージョンをPRAGMA user_versionに記録する
+ */
+struct Migration {
+    version: u32,
+    sql: &'static str,
+    // バージョンを記録する前のDBに手で適用されていたかは、これがあるかで確かめる
+    creates: SchemaObject,
+}
+
+enum SchemaObject {
+    Table(&'static str),
+    Column(&'static str, &'static str), // (テーブル, カラム)
+}
+
+impl SchemaObject {
+    fn exists(&self, con: &Connection) -> Result<bool, failure::Error> {
+        let count: u32 = match self {
+            SchemaObject::Table(table) => con.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                params![table],
+                |row| row.get(0),
+            )?,
+            SchemaObject::Column(table, column) => con.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+                params![table, column],
+                |row| row.get(0),
+            )?,
+        };
+        Ok(count > 0)
+    }
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        sql: include_str!("../sql/migrations/0001_create_lease_entries.sql"),
+        creates: SchemaObject::Table("lease_entries"),
+    },
+    Migration {
+        version: 2,
+        sql: include_str!("../sql/migrations/0002_add_lease_time.sql"),
+        creates: SchemaObject::Column("lease_entries", "lease_expiry"),
+    },
+    Migration {
+        version: 3,
+        sql: include_str!("../sql/migrations/0003_add_conflicted_addresses.sql"),
+        creates: SchemaObject::Table("conflicted_addresses"),
+    },
+    Migration {
+        version: 4,
+        sql: include_str!("../sql/migrations/0004_add_reservations.sql"),
+        creates: SchemaObject::Table("reservations"),
+    },
+    Migration {
+        version: 5,
+        sql: include_str!("../sql/migrations/0005_add_leases6.sql"),
+        creates: SchemaObject::Table("lease6_entries"),
+    },
+    Migration {
+        version: 6,
+        sql: include_str!("../sql/migrations/0006_add_client_id.sql"),
+        creates: SchemaObject::Column("lease_entries", "client_id"),
+    },
+    Migration {
+        version: 7,
+        sql: include_str!("../sql/migrations/0007_add_hostname.sql"),
+        creates: SchemaObject::Column("lease_entries", "hostname"),
+    },
+];
+
+/**
+ * 未適用のマイグレーションを順に適用し、適用後のスキーマのバージョンを返す。
+ *
+ * [note] バージョンを記録していない(user_versionが0の)DBは、以前はsql/のファイルを手で適用していたもの。
+ * 作られているテーブルやカラムから、どこまで適用済みかを調べて続きから適用する。
+ */
+fn migrate(con: &mut Connection) -> Result<u32, failure::Error> {
+    let mut version: u32 = con.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if version > latest {
+        return Err(failure::format_err!(
+            "database schema version {} is newer than this server supports ({})",
+            version,
+            latest
+        ));
+    }
+    if version == 0 {
+        for migration in MIGRATIONS.iter() {
+            if !migration.creates.exists(con)? {
+                break;
+            }
+            version = migration.version;
+        }
+        if version > 0 {
+            con.pragma_update(None, "user_version", version)?;
+        }
+    }
+
+    let applied = version;
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > applied) {
+        let tx = con.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        info!("database schema migrated to version {}", migration.version);
+        version = migration.version;
+    }
+    Ok(version)
+}
+
+/**
+ * リース・割り当てを控えているアドレス・予約の保存先。
+ * DHCPサーバはスレッド間で共有するMutexに入れて使う。
+ *
+ * [note] 各メソッドはそれだけで完結する(SQLiteなら1つのトランザクションで実行する)。
+ * 複数の呼び出しをまとめて行う場合は、呼び出し側がMutexのロックを取ったまま呼ぶ。
+ */
+pub trait LeaseStore: Send {
+    /**
+     * 利用されているIPアドレスを返す。
+     * deletedが渡された場合は`deleted`カラムをその条件で絞り込む
+     */
+    fn select_addresses(&self, deleted: Option<u8>) -> Result<Vec<Ipv4Addr>, failure::Error>;
+
+    /**
+     * 指定のクライアントのレコードの件数を返す
+     */
+    fn count_records_by_client(&self, client: &Client) -> Result<u8, failure::Error>;
+
+    /**
+     * 指定のクライアントのエントリ（論理削除されているものも含めて）を返す。
+     */
+    fn select_lease_entry(&self, client: &Client) -> Result<Option<LeaseEntry>, failure::Error>;
+
+    /**
+     * 全てのバインディング（論理削除されているものも含めて）を登録順に返す。
+     */
+    fn select_lease_records(&self) -> Result<Vec<LeaseRecord>, failure::Error>;
+
+    /**
+     * バインディングの追加。同じクライアントのバインディングがあればErrを返す
+     */
+    fn insert_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<(), failure::Error>;
+
+    /**
+     * バインディングの更新
+     */
+    fn update_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        deleted: u8,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<(), failure::Error>;
+
+    /**
+     * リースの延長。有効なバインディングの期限を更新し、更新した件数を返す。
+     */
+    fn renew_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<usize, failure::Error>;
+
+    /**
+     * クライアントが名乗ったホスト名を記録する。Noneなら消す
+     */
+    fn update_hostname(
+        &mut self,
+        client: &Client,
+        hostname: Option<&str>,
+    ) -> Result<(), failure::Error>;
+
+    /**
+     * リースをまとめて追加・更新し、ホスト名も記録する。
+     * 途中で失敗した場合はどのリースも書き込まない
+     */
+    fn upsert_lease_records(&mut self, records: &[LeaseRecord]) -> Result<(), failure::Error>;
+
+    /**
+     * 期限(UNIX時間)がnow以前のバインディングを論理削除し、それらのIPアドレスを返す。
+     */
+    fn delete_expired_entries(&mut self, now: i64) -> Result<Vec<Ipv4Addr>, failure::Error>;
+
+    /**
+     * バインディングの論理削除
+     */
+    fn delete_entry(&mut self, client: &Client) -> Result<(), failure::Error>;
+
+    /**
+     * 論理削除されたバインディングを物理削除し、削除した件数を返す。
+     */
+    fn purge_deleted_entries(&mut self) -> Result<usize, failure::Error>;
+
+    /**
+     * IPアドレスをconflict_until(UNIX時間)まで割り当てないように記録する。
+     */
+    fn insert_conflict(
+        &mut self,
+        ip_addr: Ipv4Addr,
+        client: &Client,
+        conflict_until: i64,
+    ) -> Result<(), failure::Error>;
+
+    /**
+     * 割り当てを控えているIPアドレスを返す。
+     */
+    fn select_conflicted_addresses(&self) -> Result<Vec<Ipv4Addr>, failure::Error>;
+
+    /**
+     * 割り当てを控えているIPアドレスの記録を返す。
+     */
+    fn select_conflicts(&self) -> Result<Vec<ConflictRecord>, failure::Error>;
+
+    /**
+     * 期限(UNIX時間)がnow以前の記録を削除し、それらのIPアドレスを返す。
+     */
+    fn delete_expired_conflicts(&mut self, now: i64) -> Result<Vec<Ipv4Addr>, failure::Error>;
+
+    /**
+     * 予約の一覧を返す。オプションは保存しない
+     */
+    fn select_reservations(&self) -> Result<Vec<Reservation>, failure::Error>;
+
+    /**
+     * 予約を登録する。IPアドレスかクライアントが同じ予約があればErrを返す
+     */
+    fn insert_reservation(&mut self, reservation: &Reservation) -> Result<(), failure::Error>;
+
+    /**
+     * 予約を削除し、削除した件数を返す
+     */
+    fn delete_reservation(&mut self, ip_addr: Ipv4Addr) -> Result<usize, failure::Error>;
+
+    /**
+     * DUIDとIAIDの組(バインディング)のエントリ（論理削除されているものも含めて）を返す。
+     */
+    fn select_lease6_entry(
+        &self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+    ) -> Result<Option<Lease6Entry>, failure::Error>;
+
+    /**
+     * DHCPv6のバインディングの追加・更新。lease_periodは(lease_start, lease_expiry)
+     */
+    fn upsert_lease6_entry(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+        prefix: Ipv6Network,
+        lease_period: (i64, i64),
+    ) -> Result<(), failure::Error>;
+
+    /**
+     * DHCPv6のバインディングの論理削除
+     */
+    fn delete_lease6_entry(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+    ) -> Result<(), failure::Error>;
+
+    /**
+     * 期限(UNIX時間)がnow以前のDHCPv6のバインディングを論理削除し、それらのアドレスを返す。
+     */
+    fn delete_expired_lease6_entries(&mut self, now: i64) -> Result<Vec<Ipv6Addr>, failure::Error>;
+
+    /**
+     * アドレスが他のバインディングで使われているか、DECLINEされて割り当てを控えているか。
+     */
+    fn is_address6_in_use(
+        &self,
+        address: Ipv6Addr,
+        duid: &[u8],
+        iaid: u32,
+        now: i64,
+    ) -> Result<bool, failure::Error>;
+
+    /**
+     * DECLINEされたアドレスをconflict_until(UNIX時間)まで割り当てないように記録する。
+     */
+    fn insert_conflict6(
+        &mut self,
+        address: Ipv6Addr,
+        duid: &[u8],
+        conflict_until: i64,
+    ) -> Result<(), failure::Error>;
+
+    /**
+     * 期限(UNIX時間)がnow以前の記録を削除し、それらのアドレスを返す。
+     */
+    fn delete_expired_conflicts6(&mut self, now: i64) -> Result<Vec<Ipv6Addr>, failure::Error>;
+}
+
+/**
+ * 設定のdatabaseに従ってリースの保存先を開く。":memory:" ならメモリ上に持ち、再起動すると消える
+ */
+pub fn open_lease_store(path: &str) -> Result<Box<dyn LeaseStore>, failure::Error> {
+    if path == MEMORY_DATABASE {
+        warn!("leases are kept in memory and will be lost on restart");
+        return Ok(Box::new(MemoryLeaseStore::default()));
+    }
+    Ok(Box::new(SqliteLeaseStore::open(path)?))
+}
+
+/**
+ * SQLiteのファイルに保存するLeaseStore
+ */
+pub struct SqliteLeaseStore {
+    con: Connection,
+}
+
+impl SqliteLeaseStore {
+    /**
+     * DBファイルを開き(なければ作り)、スキーマを最新のバージョンにする
+     */
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteLeaseStore, failure::Error> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    fn from_connection(mut con: Connection) -> Result<SqliteLeaseStore, failure::Error> {
+        migrate(&mut con)?;
+        Ok(SqliteLeaseStore { con })
+    }
+
+    /**
+     * 削除したレコードの領域を解放してDBファイルを小さくする
+     */
+    pub fn vacuum(&self) -> Result<(), failure::Error> {
+        self.con.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /**
+     * トランザクションの中でfを実行し、成功したらコミットする
+     */
+    fn write<T, F>(&mut self, f: F) -> Result<T, failure::Error>
+    where
+        F: FnOnce(&Transaction) -> Result<T, failure::Error>,
+    {
+        let tx = self.con.transaction()?;
+        let result = f(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+}
+
+impl LeaseStore for SqliteLeaseStore {
+    fn select_addresses(&self, deleted: Option<u8>) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        select_addresses(&self.con, deleted)
+    }
+
+    fn count_records_by_client(&self, client: &Client) -> Result<u8, failure::Error> {
+        count_records_by_client(&self.con, client)
+    }
+
+    fn select_lease_entry(&self, client: &Client) -> Result<Option<LeaseEntry>, failure::Error> {
+        select_lease_entry(&self.con, client)
+    }
+
+    fn select_lease_records(&self) -> Result<Vec<LeaseRecord>, failure::Error> {
+        select_lease_records(&self.con)
+    }
+
+    fn insert_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<(), failure::Error> {
+        self.write(|tx| insert_entry(tx, client, ip_addr, lease_start, lease_expiry))
+    }
+
+    fn update_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        deleted: u8,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<(), failure::Error> {
+        self.write(|tx| update_entry(tx, client, ip_addr, deleted, lease_start, lease_expiry))
+    }
+
+    fn renew_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<usize, failure::Error> {
+        self.write(|tx| renew_entry(tx, client, ip_addr, lease_start, lease_expiry))
+    }
+
+    fn update_hostname(
+        &mut self,
+        client: &Client,
+        hostname: Option<&str>,
+    ) -> Result<(), failure::Error> {
+        self.write(|tx| update_hostname(tx, client, hostname))
+    }
+
+    fn upsert_lease_records(&mut self, records: &[LeaseRecord]) -> Result<(), failure::Error> {
+        self.write(|tx| records.iter().try_for_each(|record| upsert_lease_record(tx, record)))
+    }
+
+    fn delete_expired_entries(&mut self, now: i64) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        self.write(|tx| delete_expired_entries(tx, now))
+    }
+
+    fn delete_entry(&mut self, client: &Client) -> Result<(), failure::Error> {
+        self.write(|tx| delete_entry(tx, client))
+    }
+
+    fn purge_deleted_entries(&mut self) -> Result<usize, failure::Error> {
+        self.write(purge_deleted_entries)
+    }
+
+    fn insert_conflict(
+        &mut self,
+        ip_addr: Ipv4Addr,
+        client: &Client,
+        conflict_until: i64,
+    ) -> Result<(), failure::Error> {
+        self.write(|tx| insert_conflict(tx, ip_addr, client, conflict_until))
+    }
+
+    fn select_conflicted_addresses(&self) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        select_conflicted_addresses(&self.con)
+    }
+
+    fn select_conflicts(&self) -> Result<Vec<ConflictRecord>, failure::Error> {
+        select_conflicts(&self.con)
+    }
+
+    fn delete_expired_conflicts(&mut self, now: i64) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        self.write(|tx| delete_expired_conflicts(tx, now))
+    }
+
+    fn select_reservations(&self) -> Result<Vec<Reservation>, failure::Error> {
+        select_reservations(&self.con)
+    }
+
+    fn insert_reservation(&mut self, reservation: &Reservation) -> Result<(), failure::Error> {
+        self.write(|tx| insert_reservation(tx, reservation))
+    }
+
+    fn delete_reservation(&mut self, ip_addr: Ipv4Addr) -> Result<usize, failure::Error> {
+        self.write(|tx| delete_reservation(tx, ip_addr))
+    }
+
+    fn select_lease6_entry(
+        &self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+    ) -> Result<Option<Lease6Entry>, failure::Error> {
+        select_lease6_entry(&self.con, duid, iaid, ia_type)
+    }
+
+    fn upsert_lease6_entry(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+        prefix: Ipv6Network,
+        (lease_start, lease_expiry): (i64, i64),
+    ) -> Result<(), failure::Error> {
+        self.write(|tx| {
+            upsert_lease6_entry(tx, duid, iaid, ia_type, prefix, lease_start, lease_expiry)
+        })
+    }
+
+    fn delete_lease6_entry(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+    ) -> Result<(), failure::Error> {
+        self.write(|tx| delete_lease6_entry(tx, duid, iaid, ia_type))
+    }
+
+    fn delete_expired_lease6_entries(&mut self, now: i64) -> Result<Vec<Ipv6Addr>, failure::Error> {
+        self.write(|tx| delete_expired_lease6_entries(tx, now))
+    }
+
+    fn is_address6_in_use(
+        &self,
+        address: Ipv6Addr,
+        duid: &[u8],
+        iaid: u32,
+        now: i64,
+    ) -> Result<bool, failure::Error> {
+        is_address6_in_use(&self.con, address, duid, iaid, now)
+    }
+
+    fn insert_conflict6(
+        &mut self,
+        address: Ipv6Addr,
+        duid: &[u8],
+        conflict_until: i64,
+    ) -> Result<(), failure::Error> {
+        self.write(|tx| insert_conflict6(tx, address, duid, conflict_until))
+    }
+
+    fn delete_expired_conflicts6(&mut self, now: i64) -> Result<Vec<Ipv6Addr>, failure::Error> {
+        self.write(|tx| delete_expired_conflicts6(tx, now))
+    }
+}
+
+/**
+ * lease6_entriesのレコード。MemoryLeaseStoreで使う
+ */
+#[derive(Debug, Clone)]
+struct Lease6Record {
+    duid: Vec<u8>,
+    iaid: u32,
+    ia_type: IaType,
+    entry: Lease6Entry,
+}
+
+/**
+ * メモリ上に持つLeaseStore。テストや試用のためのもので、再起動すると内容は消える。
+ * 各テーブルのレコードを登録順に持ち、SQLiteと同じ結果を返す。
+ */
+#[derive(Debug, Default)]
+pub struct MemoryLeaseStore {
+    leases: Vec<LeaseRecord>,
+    conflicts: Vec<ConflictRecord>,
+    reservations: Vec<Reservation>,
+    leases6: Vec<Lease6Record>,
+    conflicts6: Vec<(Ipv6Addr, Vec<u8>, i64)>,
+}
+
+impl MemoryLeaseStore {
+    fn lease_mut(&mut self, client: &Client) -> Option<&mut LeaseRecord> {
+        self.leases.iter_mut().find(|lease| lease.client.is_same(client))
+    }
+
+    fn lease6_mut(&mut self, duid: &[u8], iaid: u32, ia_type: IaType) -> Option<&mut Lease6Record> {
+        self.leases6
+            .iter_mut()
+            .find(|lease| lease.duid == duid && lease.iaid == iaid && lease.ia_type == ia_type)
+    }
+}
+
+impl LeaseStore for MemoryLeaseStore {
+    fn select_addresses(&self, deleted: Option<u8>) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        Ok(self
+            .leases
+            .iter()
+            .filter(|lease| deleted.is_none_or(|deleted| lease.deleted == (deleted != 0)))
+            .map(|lease| lease.ip_addr)
+            .collect())
+    }
+
+    fn count_records_by_client(&self, client: &Client) -> Result<u8, failure::Error> {
+        Ok(self.leases.iter().filter(|lease| lease.client.is_same(client)).count() as u8)
+    }
+
+    fn select_lease_entry(&self, client: &Client) -> Result<Option<LeaseEntry>, failure::Error> {
+        Ok(self
+            .leases
+            .iter()
+            .find(|lease| lease.client.is_same(client))
+            .map(|lease| LeaseEntry {
+                ip_addr: lease.ip_addr,
+                deleted: lease.deleted,
+                lease_expiry: lease.lease_expiry,
+                hostname: lease.hostname.clone(),
+            }))
+    }
+
+    fn select_lease_records(&self) -> Result<Vec<LeaseRecord>, failure::Error> {
+        Ok(self.leases.clone())
+    }
+
+    fn insert_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<(), failure::Error> {
+        if self.lease_mut(client).is_some() {
+            return Err(failure::format_err!("lease of {} already exists", client));
+        }
+        self.leases.push(LeaseRecord {
+            client: client.clone(),
+            ip_addr,
+            deleted: false,
+            lease_start,
+            lease_expiry,
+            hostname: None,
+        });
+        Ok(())
+    }
+
+    fn update_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        deleted: u8,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<(), failure::Error> {
+        if let Some(lease) = self.lease_mut(client) {
+            lease.client = client.clone();
+            lease.ip_addr = ip_addr;
+            lease.deleted = deleted != 0;
+            lease.lease_start = lease_start;
+            lease.lease_expiry = lease_expiry;
+        }
+        Ok(())
+    }
+
+    fn renew_entry(
+        &mut self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+        lease_start: i64,
+        lease_expiry: i64,
+    ) -> Result<usize, failure::Error> {
+        match self.lease_mut(client) {
+            Some(lease) if lease.ip_addr == ip_addr && !lease.deleted => {
+                lease.lease_start = lease_start;
+                lease.lease_expiry = lease_expiry;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn update_hostname(
+        &mut self,
+        client: &Client,
+        hostname: Option<&str>,
+    ) -> Result<(), failure::Error> {
+        if let Some(lease) = self.lease_mut(client) {
+            lease.hostname = hostname.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    fn upsert_lease_records(&mut self, records: &[LeaseRecord]) -> Result<(), failure::Error> {
+        for record in records {
+            match self.lease_mut(&record.client) {
+                Some(lease) => *lease = record.clone(),
+                None => self.leases.push(record.clone()),
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_expired_entries(&mut self, now: i64) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        let mut expired_addrs = Vec::new();
+        for lease in self.leases.iter_mut() {
+            if !lease.deleted && lease.lease_expiry <= now {
+                lease.deleted = true;
+                expired_addrs.push(lease.ip_addr);
+            }
+        }
+        Ok(expired_addrs)
+    }
+
+    fn delete_entry(&mut self, client: &Client) -> Result<(), failure::Error> {
+        if let Some(lease) = self.lease_mut(client) {
+            lease.deleted = true;
+        }
+        Ok(())
+    }
+
+    fn purge_deleted_entries(&mut self) -> Result<usize, failure::Error> {
+        let count = self.leases.len();
+        self.leases.retain(|lease| !lease.deleted);
+        Ok(count - self.leases.len())
+    }
+
+    fn insert_conflict(
+        &mut self,
+        ip_addr: Ipv4Addr,
+        client: &Client,
+        conflict_until: i64,
+    ) -> Result<(), failure::Error> {
+        self.conflicts.retain(|conflict| conflict.ip_addr != ip_addr);
+        self.conflicts.push(ConflictRecord {
+            ip_addr,
+            hw_addr: client.hw_addr_string(),
+            conflict_until,
+        });
+        Ok(())
+    }
+
+    fn select_conflicted_addresses(&self) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        Ok(self.conflicts.iter().map(|conflict| conflict.ip_addr).collect())
+    }
+
+    fn select_conflicts(&self) -> Result<Vec<ConflictRecord>, failure::Error> {
+        Ok(self.conflicts.clone())
+    }
+
+    fn delete_expired_conflicts(&mut self, now: i64) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        let (expired, conflicts) = self
+            .conflicts
+            .drain(..)
+            .partition(|conflict| conflict.conflict_until <= now);
+        self.conflicts = conflicts;
+        Ok(expired.into_iter().map(|conflict: ConflictRecord| conflict.ip_addr).collect())
+    }
+
+    fn select_reservations(&self) -> Result<Vec<Reservation>, failure::Error> {
+        Ok(self.reservations.clone())
+    }
+
+    fn insert_reservation(&mut self, reservation: &Reservation) -> Result<(), failure::Error> {
+        if self.reservations.iter().any(|other| {
+            other.ip_addr == reservation.ip_addr || other.owner == reservation.owner
+        }) {
+            return Err(failure::format_err!(
+                "reservation of {} already exists",
+                reservation.ip_addr
+            ));
+        }
+        self.reservations.push(Reservation {
+            options: Vec::new(),
+            ..reservation.clone()
+        });
+        Ok(())
+    }
+
+    fn delete_reservation(&mut self, ip_addr: Ipv4Addr) -> Result<usize, failure::Error> {
+        let count = self.reservations.len();
+        self.reservations.retain(|reservation| reservation.ip_addr != ip_addr);
+        Ok(count - self.reservations.len())
+    }
+
+    fn select_lease6_entry(
+        &self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+    ) -> Result<Option<Lease6Entry>, failure::Error> {
+        Ok(self
+            .leases6
+            .iter()
+            .find(|lease| lease.duid == duid && lease.iaid == iaid && lease.ia_type == ia_type)
+            .map(|lease| lease.entry.clone()))
+    }
+
+    fn upsert_lease6_entry(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+        prefix: Ipv6Network,
+        (_, lease_expiry): (i64, i64),
+    ) -> Result<(), failure::Error> {
+        // INSERT OR REPLACEと同じく、置き換えたものは最後に登録したものになる
+        self.leases6.retain(|lease| {
+            !(lease.duid == duid && lease.iaid == iaid && lease.ia_type == ia_type)
+        });
+        self.leases6.push(Lease6Record {
+            duid: duid.to_vec(),
+            iaid,
+            ia_type,
+            entry: Lease6Entry {
+                prefix,
+                deleted: false,
+                lease_expiry,
+            },
+        });
+        Ok(())
+    }
+
+    fn delete_lease6_entry(
+        &mut self,
+        duid: &[u8],
+        iaid: u32,
+        ia_type: IaType,
+    ) -> Result<(), failure::Error> {
+        if let Some(lease) = self.lease6_mut(duid, iaid, ia_type) {
+            lease.entry.deleted = true;
+        }
+        Ok(())
+    }
+
+    fn delete_expired_lease6_entries(&mut self, now: i64) -> Result<Vec<Ipv6Addr>, failure::Error> {
+        let mut expired_addrs = Vec::new();
+        for lease in self.leases6.iter_mut() {
+            if !lease.entry.deleted && lease.entry.lease_expiry <= now {
+                lease.entry.deleted = true;
+                expired_addrs.push(lease.entry.prefix.ip());
+            }
+        }
+        Ok(expired_addrs)
+    }
+
+    fn is_address6_in_use(
+        &self,
+        address: Ipv6Addr,
+        duid: &[u8],
+        iaid: u32,
+        now: i64,
+    ) -> Result<bool, failure::Error> {
+        let leased = self.leases6.iter().any(|lease| {
+            lease.entry.prefix.ip() == address
+                && lease.entry.is_active(now)
+                && !(lease.duid == duid && lease.iaid == iaid)
+        });
+        let conflicted = self.conflicts6.iter().any(|(conflict, _, _)| *conflict == address);
+        Ok(leased || conflicted)
+    }
+
+    fn insert_conflict6(
+        &mut self,
+        address: Ipv6Addr,
+        duid: &[u8],
+        conflict_until: i64,
+    ) -> Result<(), failure::Error> {
+        self.conflicts6.retain(|(conflict, _, _)| *conflict != address);
+        self.conflicts6.push((address, duid.to_vec(), conflict_until));
+        Ok(())
+    }
+
+    fn delete_expired_conflicts6(&mut self, now: i64) -> Result<Vec<Ipv6Addr>, failure::Error> {
+        let (expired, conflicts) = self
+            .conflicts6
+            .drain(..)
+            .partition(|(_, _, conflict_until)| *conflict_until <= now);
+        self.conflicts6 = conflicts;
+        Ok(expired.into_iter().map(|(address, _, _): (Ipv6Addr, Vec<u8>, i64)| address).collect())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::options::DhcpOption;
+    use pnet::util::MacAddr;
+
+    pub(crate) fn open_database() -> Connection {
+        let mut con = Connection::open_in_memory().unwrap();
+        migrate(&mut con).unwrap();
+        con
+    }
+
+    const MAC_A: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+    const MAC_B: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x66);
+
+    fn client_a() -> Client {
+        Client::from_mac_addr(MAC_A)
+    }
+
+    fn client_b() -> Client {
+        Client::from_mac_addr(MAC_B)
+    }
+
+    #[test]
+    fn test_renew_entry_extends_own_lease_only() {
+        let mut con = open_database();
+        let tx = con.transaction().unwrap();
+        insert_entry(&tx, &client_a(), Ipv4Addr::new(192, 168, 0, 10), 1000, 1300).unwrap();
+
+        let renew = |client: Client, last| {
+            renew_entry(&tx, &client, Ipv4Addr::new(192, 168, 0, last), 1200, 1500).unwrap()
+        };
+        assert_eq!(0, renew(client_a(), 11));
+        assert_eq!(0, renew(client_b(), 10));
+        assert_eq!(1, renew(client_a(), 10));
+        // 期限を延長したので1300の時点では回収されない
+        assert!(delete_expired_entries(&tx, 1300).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conflicts_expire() {
+        let mut con = open_database();
+        let tx = con.transaction().unwrap();
+        insert_conflict(&tx, Ipv4Addr::new(192, 168, 0, 10), &client_a(), 1300).unwrap();
+        // 同じIPアドレスが再び通知されたら期限を更新する
+        insert_conflict(&tx, Ipv4Addr::new(192, 168, 0, 10), &client_b(), 1500).unwrap();
+        insert_conflict(&tx, Ipv4Addr::new(192, 168, 0, 11), &client_b(), 1400).unwrap();
+        assert_eq!(
+            vec![Ipv4Addr::new(192, 168, 0, 11)],
+            delete_expired_conflicts(&tx, 1400).unwrap()
+        );
+        tx.commit().unwrap();
+        assert_eq!(
+            vec![Ipv4Addr::new(192, 168, 0, 10)],
+            select_conflicted_addresses(&con).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_delete_expired_entries() {
+        let mut con = open_database();
+        let tx = con.transaction().unwrap();
+        insert_entry(&tx, &client_a(), Ipv4Addr::new(192, 168, 0, 10), 1000, 1300).unwrap();
+        insert_entry(&tx, &client_b(), Ipv4Addr::new(192, 168, 0, 11), 1100, 1400).unwrap();
+        tx.commit().unwrap();
+
+        let tx = con.transaction().unwrap();
+        assert_eq!(
+            vec![Ipv4Addr::new(192, 168, 0, 10)],
+            delete_expired_entries(&tx, 1300).unwrap()
+        );
+        // 回収済みのものは再び返さない
+        assert!(delete_expired_entries(&tx, 1300).unwrap().is_empty());
+        tx.commit().unwrap();
+
+        assert_eq!(
+            vec![Ipv4Addr::new(192, 168, 0, 11)],
+            select_addresses(&con, Some(0)).unwrap()
+        );
+        // 論理削除なので以前のIPアドレスは残っている
+        assert_eq!(
+            Some(LeaseEntry {
+                ip_addr: Ipv4Addr::new(192, 168, 0, 10),
+                deleted: true,
+                lease_expiry: 1300,
+                hostname: None,
+            }),
+            select_lease_entry(&con, &client_a()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_lease6_entries() {
+        let duid = [0, 3, 0, 1, 0, 0x11, 0x22, 0x33, 0x44, 0x55];
+        let other_duid = [0, 3, 0, 1, 0, 0x11, 0x22, 0x33, 0x44, 0x66];
+        let address: Ipv6Addr = "2001:db8::100".parse().unwrap();
+        let mut con = open_database();
+        let tx = con.transaction().unwrap();
+        upsert_lease6_entry(&tx, &duid, 1, IaType::Na, address.into(), 1000, 1300).unwrap();
+        // IA_NAとIA_PDは同じIAIDでも別のバインディング
+        let prefix: Ipv6Network = "2001:db8:100::/56".parse().unwrap();
+        upsert_lease6_entry(&tx, &duid, 1, IaType::Pd, prefix, 1000, 1300).unwrap();
+
+        // 自分のバインディングのアドレスは使用中ではない
+        assert!(!is_address6_in_use(&tx, address, &duid, 1, 1000).unwrap());
+        assert!(is_address6_in_use(&tx, address, &other_duid, 1, 1000).unwrap());
+        assert!(!is_address6_in_use(&tx, address, &other_duid, 1, 1300).unwrap());
+
+        // 延長は同じバインディングを上書きする
+        upsert_lease6_entry(&tx, &duid, 1, IaType::Na, address.into(), 1200, 1500).unwrap();
+        assert_eq!(vec![prefix.ip()], delete_expired_lease6_entries(&tx, 1300).unwrap());
+        assert_eq!(
+            Some(Lease6Entry {
+                prefix: address.into(),
+                deleted: false,
+                lease_expiry: 1500,
+            }),
+            select_lease6_entry(&tx, &duid, 1, IaType::Na).unwrap()
+        );
+        delete_lease6_entry(&tx, &duid, 1, IaType::Na).unwrap();
+        assert!(!is_address6_in_use(&tx, address, &other_duid, 1, 1000).unwrap());
+
+        // DECLINEされたアドレスは期限まで誰にも割り当てない
+        insert_conflict6(&tx, address, &other_duid, 1400).unwrap();
+        assert!(is_address6_in_use(&tx, address, &duid, 1, 1000).unwrap());
+        assert_eq!(vec![address], delete_expired_conflicts6(&tx, 1400).unwrap());
+        assert!(!is_address6_in_use(&tx, address, &duid, 1, 1000).unwrap());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut con = Connection::open_in_memory().unwrap();
+        assert_eq!(7, migrate(&mut con).unwrap());
+        // 適用済みなら何もしない
+        assert_eq!(7, migrate(&mut con).unwrap());
+
+        // バージョンを記録する前に、sql/のファイルを途中まで手で適用したDB
+        let mut con = Connection::open_in_memory().unwrap();
+        for migration in MIGRATIONS[..3].iter() {
+            con.execute_batch(migration.sql).unwrap();
+        }
+        con.execute(
+            "INSERT INTO lease_entries (mac_addr, ip_addr) VALUES (?1, '192.168.0.10')",
+            params![MAC_A.to_string()],
+        )
+        .unwrap();
+        assert_eq!(7, migrate(&mut con).unwrap());
+        let version: u32 =
+            con.query_row("PRAGMA user_version", params![], |row| row.get(0)).unwrap();
+        assert_eq!(7, version);
+        assert_eq!(vec![Ipv4Addr::new(192, 168, 0, 10)], select_addresses(&con, None).unwrap());
+        assert!(select_reservations(&con).unwrap().is_empty());
+        // 以前のリースはclient identifierを送らないイーサネットのクライアントのものになる
+        let records = select_lease_records(&con).unwrap();
+        assert_eq!(client_a(), records[0].client);
+        assert_eq!(None, records[0].hostname);
+        assert!(select_lease_entry(&con, &client_a()).unwrap().is_some());
+
+        // 新しいバージョンのサーバが更新したDBは扱わない
+        con.pragma_update(None, "user_version", 8).unwrap();
+        assert!(migrate(&mut con).is_err());
+    }
+
+    /**
+     * 一通りの操作をして、その結果を並べた文字列を返す
+     */
+    fn exercise(store: &mut dyn LeaseStore) -> String {
+        let ip = |last| Ipv4Addr::new(192, 168, 0, last);
+        let mut log = Vec::new();
+        log.push(format!("{}", store.insert_entry(&client_a(), ip(10), 1000, 1300).is_ok()));
+        log.push(format!("{}", store.insert_entry(&client_b(), ip(11), 1100, 1400).is_ok()));
+        log.push(format!("{}", store.insert_entry(&client_a(), ip(12), 1000, 1300).is_ok()));
+        store.update_hostname(&client_a(), Some("pc-a")).unwrap();
+        store.update_hostname(&client_b(), Some("pc-b")).unwrap();
+        log.push(format!("{:?}", store.count_records_by_client(&client_a()).unwrap()));
+        log.push(format!("{:?}", store.renew_entry(&client_a(), ip(11), 1200, 1500).unwrap()));
+        log.push(format!("{:?}", store.renew_entry(&client_a(), ip(10), 1200, 1500).unwrap()));
+        log.push(format!("{:?}", store.delete_expired_entries(1400).unwrap()));
+        log.push(format!("{:?}", store.select_addresses(Some(0)).unwrap()));
+        log.push(format!("{:?}", store.select_addresses(Some(1)).unwrap()));
+        store.update_entry(&client_b(), ip(12), 0, 1500, 1800).unwrap();
+        store.delete_entry(&client_a()).unwrap();
+        log.push(format!("{:?}", store.select_lease_entry(&client_a()).unwrap()));
+        log.push(format!("{:?}", store.select_lease_records().unwrap()));
+        log.push(format!("{:?}", store.purge_deleted_entries().unwrap()));
+        log.push(format!("{:?}", store.select_addresses(None).unwrap()));
+
+        // client identifierを送るクライアントは同じMACアドレスでも別のクライアント
+        let vm = Client {
+            client_id: Some(vec![0xff, 0, 0, 0, 1, 0, 1]),
+            ..client_b()
+        };
+        // イーサネット以外のハードウェア(InfiniBandはchaddrを空にする)
+        let infiniband = Client {
+            client_id: Some(vec![0xff, 0, 0, 0, 2]),
+            htype: 32,
+            hw_addr: Vec::new(),
+        };
+        log.push(format!("{}", store.insert_entry(&vm, ip(13), 1500, 1800).is_ok()));
+        log.push(format!("{}", store.insert_entry(&infiniband, ip(14), 1500, 1800).is_ok()));
+        let moved = Client {
+            hw_addr: MAC_A.octets().to_vec(),
+            ..vm.clone()
+        };
+        log.push(format!("{}", store.insert_entry(&moved, ip(15), 1500, 1800).is_ok()));
+        log.push(format!("{:?}", store.count_records_by_client(&client_b()).unwrap()));
+        log.push(format!("{:?}", store.count_records_by_client(&moved).unwrap()));
+        // client identifierが同じならハードウェアアドレスが変わっても同じリース
+        store.update_entry(&moved, ip(13), 0, 1600, 1900).unwrap();
+        log.push(format!("{:?}", store.renew_entry(&vm, ip(13), 1700, 2000).unwrap()));
+        store.update_hostname(&vm, Some("vm")).unwrap();
+        store.update_hostname(&client_b(), None).unwrap();
+        store.delete_entry(&infiniband).unwrap();
+        log.push(format!("{:?}", store.select_lease_entry(&infiniband).unwrap()));
+        log.push(format!("{:?}", store.select_lease_records().unwrap()));
+        let upserted = [
+            LeaseRecord {
+                client: vm.clone(),
+                ip_addr: ip(16),
+                deleted: false,
+                lease_start: 1800,
+                lease_expiry: 2100,
+                hostname: None,
+            },
+            LeaseRecord {
+                client: client_a(),
+                ip_addr: ip(17),
+                deleted: true,
+                lease_start: 1800,
+                lease_expiry: 2100,
+                hostname: Some("laptop".to_string()),
+            },
+        ];
+        store.upsert_lease_records(&upserted).unwrap();
+        log.push(format!("{:?}", store.select_lease_records().unwrap()));
+
+        store.insert_conflict(ip(20), &client_a(), 1300).unwrap();
+        store.insert_conflict(ip(21), &client_b(), 1400).unwrap();
+        store.insert_conflict(ip(20), &client_b(), 1500).unwrap();
+        log.push(format!("{:?}", store.delete_expired_conflicts(1400).unwrap()));
+        log.push(format!("{:?}", store.select_conflicted_addresses().unwrap()));
+        log.push(format!("{:?}", store.select_conflicts().unwrap()));
+
+        let reservation = |owner, ip_addr| Reservation {
+            owner,
+            ip_addr,
+            hostname: Some("printer".to_string()),
+            options: vec![DhcpOption::DomainName("office.example".to_string())],
+        };
+        for (owner, ip_addr) in [
+            (ReservationOwner::MacAddr(MAC_A), ip(50)),
+            (ReservationOwner::ClientId(vec![1, 2, 3]), ip(51)),
+            (ReservationOwner::MacAddr(MAC_B), ip(51)),
+            (ReservationOwner::MacAddr(MAC_A), ip(52)),
+        ] {
+            log.push(format!("{}", store.insert_reservation(&reservation(owner, ip_addr)).is_ok()));
+        }
+        log.push(format!("{:?}", store.delete_reservation(ip(50)).unwrap()));
+        log.push(format!("{:?}", store.delete_reservation(ip(50)).unwrap()));
+        log.push(format!("{:?}", store.select_reservations().unwrap()));
+
+        let duid = [0, 3, 0, 1, 0, 0x11, 0x22, 0x33, 0x44, 0x55];
+        let other_duid = [0, 3, 0, 1, 0, 0x11, 0x22, 0x33, 0x44, 0x66];
+        let address: Ipv6Addr = "2001:db8::100".parse().unwrap();
+        let prefix: Ipv6Network = "2001:db8:100::/56".parse().unwrap();
+        store.upsert_lease6_entry(&duid, 1, IaType::Na, address.into(), (1000, 1300)).unwrap();
+        store.upsert_lease6_entry(&duid, 1, IaType::Pd, prefix, (1000, 1300)).unwrap();
+        store.upsert_lease6_entry(&duid, 1, IaType::Na, address.into(), (1200, 1500)).unwrap();
+        for (duid, now) in [(&duid, 1000), (&other_duid, 1000), (&other_duid, 1500)] {
+            log.push(format!("{}", store.is_address6_in_use(address, duid, 1, now).unwrap()));
+        }
+        log.push(format!("{:?}", store.delete_expired_lease6_entries(1300).unwrap()));
+        log.push(format!("{:?}", store.select_lease6_entry(&duid, 1, IaType::Na).unwrap()));
+        log.push(format!("{:?}", store.select_lease6_entry(&duid, 1, IaType::Pd).unwrap()));
+        store.delete_lease6_entry(&duid, 1, IaType::Na).unwrap();
+        log.push(format!("{}", store.is_address6_in_use(address, &other_duid, 1, 1000).unwrap()));
+        store.insert_conflict6(address, &other_duid, 1400).unwrap();
+        log.push(format!("{}", store.is_address6_in_use(address, &duid, 1, 1000).unwrap()));
+        log.push(format!("{:?}", store.delete_expired_conflicts6(1400).unwrap()));
+        log.join("\n")
+    }
+
+    #[test]
+    fn test_lease_stores_agree() {
+        let con = Connection::open_in_memory().unwrap();
+        let mut sqlite = SqliteLeaseStore::from_connection(con).unwrap();
+        let mut memory = MemoryLeaseStore::default();
+        assert_eq!(exercise(&mut sqlite), exercise(&mut memory));
+    }
+}
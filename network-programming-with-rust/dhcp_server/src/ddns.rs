@@ -0,0 +1,798 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::slice;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use super::config::DdnsConfig;
+use super::dhcp::Client;
+use super::options;
+use super::tsig::TsigKey;
+use super::util;
+
+// DNSのヘッダのQRビットとOPCODE UPDATE(RFC2136 2.2)
+const QR: u16 = 0x8000;
+const OPCODE_UPDATE: u16 = 5;
+// ヘッダのTCビット。UDPに収まらなかった応答
+const TC: u16 = 0x0200;
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+const TYPE_DHCID: u16 = 49;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+// 更新のセクションでのクラス。NONEは指定のレコード、ANYは名前とタイプの全てのレコードの削除(RFC2136 2.5)。
+// 前提条件のセクションでは、NONEはレコードがないこと、ANYはあることを表す(RFC2136 2.4)
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+// 前提条件が満たされなかった時のRCODE(RFC2136 2.2)
+const RCODE_YXDOMAIN: u16 = 6;
+const RCODE_YXRRSET: u16 = 7;
+const RCODE_NXRRSET: u16 = 8;
+// DNSサーバの応答を待つ時間
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/**
+ * 名前の先頭のラベルを、DNSに登録するホスト名として使える形にする。
+ * 英数字とハイフンだけの63文字以下のラベル(RFC1123 2.1)でなければNone。
+ */
+pub fn hostname_label(name: &str) -> Option<String> {
+    let label = name.split('.').next()?.to_ascii_lowercase();
+    let valid = !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    valid.then_some(label)
+}
+
+/**
+ * IPアドレスの逆引きの名前(RFC1035 3.5)
+ */
+pub fn reverse_name(ip_addr: Ipv4Addr) -> String {
+    let [a, b, c, d] = ip_addr.octets();
+    format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+}
+
+/**
+ * クライアントと名前のDHCIDレコードのRDATA(RFC4701 3)。
+ * client identifierがあればその値、なければhtypeとハードウェアアドレスを識別子にし、
+ * 識別子と名前をSHA-256でまとめる。
+ */
+pub fn dhcid(client: &Client, fqdn: &str) -> Vec<u8> {
+    let (identifier_type, identifier): (u16, Vec<u8>) = match &client.client_id {
+        Some(client_id) => (0x0001, client_id.clone()),
+        None => (0x0000, [&[client.htype], client.hw_addr.as_slice()].concat()),
+    };
+    let mut digest = Sha256::new();
+    digest.update(&identifier);
+    // 名前は正規の形式(小文字で圧縮しない。RFC4034 6.2)にする
+    digest.update(options::encode_domain_names(&[fqdn.to_ascii_lowercase()]));
+    let mut rdata = identifier_type.to_be_bytes().to_vec();
+    // ダイジェストの種類。1はSHA-256
+    rdata.push(1);
+    rdata.extend_from_slice(&digest.finalize());
+    rdata
+}
+
+/**
+ * 名前がゾーンの中にあるか
+ */
+fn is_in_zone(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+    name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/**
+ * 前提条件と更新のセクションのリソースレコード
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+/**
+ * DNSのUPDATEメッセージ(RFC2136 2)。前提条件が全て満たされた時だけ更新が行われる
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateMessage {
+    pub id: u16,
+    pub zone: String,
+    pub prerequisites: Vec<Record>,
+    pub updates: Vec<Record>,
+}
+
+impl UpdateMessage {
+    /**
+     * 送信するバイト列にする。名前は圧縮しない
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&(OPCODE_UPDATE << 11).to_be_bytes());
+        // ZOCOUNT, PRCOUNT, UPCOUNT, ADCOUNT
+        for count in [1, self.prerequisites.len() as u16, self.updates.len() as u16, 0] {
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        buf.extend_from_slice(&options::encode_domain_names(slice::from_ref(&self.zone)));
+        buf.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for record in self.prerequisites.iter().chain(self.updates.iter()) {
+            buf.extend_from_slice(&options::encode_domain_names(slice::from_ref(&record.name)));
+            buf.extend_from_slice(&record.rtype.to_be_bytes());
+            buf.extend_from_slice(&record.class.to_be_bytes());
+            buf.extend_from_slice(&record.ttl.to_be_bytes());
+            buf.extend_from_slice(&(record.rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&record.rdata);
+        }
+        buf
+    }
+}
+
+/**
+ * 応答のRCODE(RFC2136 2.2)の名前
+ */
+fn rcode_name(rcode: u16) -> String {
+    let name = match rcode {
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        6 => "YXDOMAIN",
+        7 => "YXRRSET",
+        8 => "NXRRSET",
+        9 => "NOTAUTH",
+        10 => "NOTZONE",
+        _ => return rcode.to_string(),
+    };
+    name.to_string()
+}
+
+/**
+ * DNSサーバが更新を拒否したことを表すエラー
+ */
+fn rejected(zone: &str, rcode: u16) -> failure::Error {
+    failure::format_err!("update of zone {} is rejected: {}", zone, rcode_name(rcode))
+}
+
+/**
+ * 設定されたDNSサーバへUPDATEメッセージを送り、リースに合わせてAとPTRのレコードを更新する。
+ *
+ * [note] 名前の持ち主は、Aレコードと一緒に登録するDHCIDレコードで確かめる(RFC4703 5.3, 5.5)。
+ * 他のクライアントのDHCIDがある名前や、DHCIDのない(DHCPサーバ以外が登録した)名前は書き換えない。
+ * PTRレコードはリースしたIPアドレスの名前なので、正引きを登録できたら置き換える。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct DdnsUpdater {
+    server: SocketAddr,
+    forward_zone: String,
+    reverse_zone: Option<String>,
+    ttl: u32,
+    key: Option<TsigKey>,
+}
+
+impl DdnsUpdater {
+    pub fn from_config(config: &DdnsConfig) -> Result<DdnsUpdater, failure::Error> {
+        let key = match (&config.tsig, config.tsig_secret()?) {
+            (Some(tsig), Some(secret)) => Some(TsigKey {
+                name: tsig.name.clone(),
+                secret,
+            }),
+            _ => None,
+        };
+        Ok(DdnsUpdater {
+            server: config.server,
+            forward_zone: config.forward_zone.trim_end_matches('.').to_string(),
+            reverse_zone: config.reverse_zone.clone(),
+            ttl: config.ttl,
+            key,
+        })
+    }
+
+    /**
+     * ホスト名をforward_zoneで修飾した名前(末尾の'.'なし)
+     */
+    pub fn fqdn(&self, hostname: &str) -> String {
+        format!("{}.{}", hostname, self.forward_zone)
+    }
+
+    /**
+     * IPアドレスのPTRレコードを置くゾーン
+     */
+    fn reverse_zone_of(&self, ip_addr: Ipv4Addr) -> Result<String, failure::Error> {
+        let name = reverse_name(ip_addr);
+        match &self.reverse_zone {
+            Some(zone) if is_in_zone(&name, zone) => Ok(zone.clone()),
+            Some(zone) => Err(failure::format_err!("{} is not in reverse_zone {}", name, zone)),
+            None => Ok(name.split_once('.').map_or(name.clone(), |(_, zone)| zone.to_string())),
+        }
+    }
+
+    /**
+     * ホスト名のAレコードとIPアドレスのPTRレコードを登録する。
+     * 名前が使われていなければAとDHCIDのレコードを追加し、そのクライアントが登録した名前ならAレコードを置き換える。
+     * 他のクライアントが使っている名前なら何も更新せずにエラーを返す。
+     */
+    pub fn add_records(
+        &self,
+        client: &Client,
+        hostname: &str,
+        ip_addr: Ipv4Addr,
+    ) -> Result<(), failure::Error> {
+        let fqdn = self.fqdn(hostname);
+        let dhcid = dhcid(client, &fqdn);
+        let address = ip_addr.octets().to_vec();
+        let unused = self.forward_update(
+            vec![prerequisite(&fqdn, TYPE_ANY, CLASS_NONE, Vec::new())],
+            vec![
+                self.record(&fqdn, TYPE_A, CLASS_IN, address.clone()),
+                self.record(&fqdn, TYPE_DHCID, CLASS_IN, dhcid.clone()),
+            ],
+        );
+        match self.exchange(&unused)? {
+            0 => {}
+            // 名前が使われていれば、DHCIDが一致する(このクライアントが登録した)場合だけ置き換える
+            RCODE_YXDOMAIN => {
+                let owned = self.forward_update(
+                    vec![prerequisite(&fqdn, TYPE_DHCID, CLASS_IN, dhcid)],
+                    vec![
+                        self.record(&fqdn, TYPE_A, CLASS_ANY, Vec::new()),
+                        self.record(&fqdn, TYPE_A, CLASS_IN, address),
+                    ],
+                );
+                match self.exchange(&owned)? {
+                    0 => {}
+                    RCODE_NXRRSET => {
+                        return Err(failure::format_err!("{} is in use by another client", fqdn))
+                    }
+                    rcode => return Err(rejected(&owned.zone, rcode)),
+                }
+            }
+            rcode => return Err(rejected(&unused.zone, rcode)),
+        }
+
+        let reverse_name = reverse_name(ip_addr);
+        let ptr = options::encode_domain_names(&[fqdn]);
+        self.send(&UpdateMessage {
+            id: new_message_id(),
+            zone: self.reverse_zone_of(ip_addr)?,
+            prerequisites: Vec::new(),
+            updates: vec![
+                self.record(&reverse_name, TYPE_PTR, CLASS_ANY, Vec::new()),
+                self.record(&reverse_name, TYPE_PTR, CLASS_IN, ptr),
+            ],
+        })
+    }
+
+    /**
+     * add_recordsで登録したレコードを削除する。
+     * AレコードはDHCIDが一致する場合だけリースしたIPアドレスのものを削除し、Aレコードが残らなければDHCIDも削除する。
+     * PTRレコードは正引きを削除できなくても削除する。
+     */
+    pub fn remove_records(
+        &self,
+        client: &Client,
+        hostname: &str,
+        ip_addr: Ipv4Addr,
+    ) -> Result<(), failure::Error> {
+        let fqdn = self.fqdn(hostname);
+        let owned = prerequisite(&fqdn, TYPE_DHCID, CLASS_IN, dhcid(client, &fqdn));
+        let address = self.forward_update(
+            vec![owned.clone()],
+            vec![self.record(&fqdn, TYPE_A, CLASS_NONE, ip_addr.octets().to_vec())],
+        );
+        let forward = self.exchange(&address).and_then(|rcode| match rcode {
+            0 => {
+                let name = self.forward_update(
+                    vec![owned, prerequisite(&fqdn, TYPE_A, CLASS_NONE, Vec::new())],
+                    vec![self.record(&fqdn, TYPE_DHCID, CLASS_ANY, Vec::new())],
+                );
+                // 他のAレコードが残っていれば、名前はまだ使われているのでDHCIDも残す
+                match self.exchange(&name)? {
+                    0 | RCODE_YXRRSET => Ok(()),
+                    rcode => Err(rejected(&name.zone, rcode)),
+                }
+            }
+            RCODE_NXRRSET => Err(failure::format_err!("{} is not registered by the client", fqdn)),
+            rcode => Err(rejected(&address.zone, rcode)),
+        });
+        let reverse = self.reverse_zone_of(ip_addr).and_then(|zone| {
+            self.send(&UpdateMessage {
+                id: new_message_id(),
+                zone,
+                prerequisites: Vec::new(),
+                updates: vec![self.record(
+                    &reverse_name(ip_addr),
+                    TYPE_PTR,
+                    CLASS_NONE,
+                    options::encode_domain_names(&[fqdn]),
+                )],
+            })
+        });
+        forward.and(reverse)
+    }
+
+    /**
+     * 正引きのゾーンのUPDATEメッセージ
+     */
+    fn forward_update(&self, prerequisites: Vec<Record>, updates: Vec<Record>) -> UpdateMessage {
+        UpdateMessage {
+            id: new_message_id(),
+            zone: self.forward_zone.clone(),
+            prerequisites,
+            updates,
+        }
+    }
+
+    /**
+     * 更新のセクションのレコード。削除(クラスがNONE, ANY)のTTLは0にする(RFC2136 2.5)
+     */
+    fn record(&self, name: &str, rtype: u16, class: u16, rdata: Vec<u8>) -> Record {
+        Record {
+            name: name.to_string(),
+            rtype,
+            class,
+            ttl: if class == CLASS_IN { self.ttl } else { 0 },
+            rdata,
+        }
+    }
+
+    /**
+     * UPDATEメッセージを送り、応答のRCODEが0でなければエラーを返す
+     */
+    pub fn send(&self, message: &UpdateMessage) -> Result<(), failure::Error> {
+        match self.exchange(message)? {
+            0 => Ok(()),
+            rcode => Err(rejected(&message.zone, rcode)),
+        }
+    }
+
+    /**
+     * UPDATEメッセージを(鍵があれば署名して)送り、応答のRCODEを返す
+     */
+    fn exchange(&self, message: &UpdateMessage) -> Result<u16, failure::Error> {
+        let mut request = message.to_bytes();
+        let mut request_mac = None;
+        if let Some(key) = &self.key {
+            let (signed, mac) = key.sign(&request, None, util::unix_time_now() as u64);
+            request = signed;
+            request_mac = Some(mac);
+        }
+
+        let local: SocketAddr = match self.server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(self.server)?;
+        socket.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        socket.send(&request)?;
+
+        let mut buf = [0u8; 4096];
+        let response = loop {
+            let size = socket.recv(&mut buf).map_err(|e| {
+                let zone = &message.zone;
+                failure::format_err!("no response from {} for zone {}: {}", self.server, zone, e)
+            })?;
+            let response = &buf[..size];
+            // 別のメッセージへの応答は無視する
+            if size >= 12 && response[..2] == message.id.to_be_bytes() && response[2] & 0x80 != 0 {
+                break response;
+            }
+        };
+        if let (Some(key), Some(request_mac)) = (&self.key, &request_mac) {
+            key.verify(response, Some(request_mac), util::unix_time_now() as u64)?;
+        }
+        let flags = u16::from_be_bytes([response[2], response[3]]);
+        if flags & TC != 0 {
+            return Err(failure::err_msg("response is truncated"));
+        }
+        if flags & QR == 0 || (flags >> 11) & 0x0f != OPCODE_UPDATE {
+            return Err(failure::err_msg("response is not for UPDATE"));
+        }
+        Ok(flags & 0x0f)
+    }
+}
+
+/**
+ * 前提条件のセクションのレコード。TTLは0にする(RFC2136 2.4)
+ */
+fn prerequisite(name: &str, rtype: u16, class: u16, rdata: Vec<u8>) -> Record {
+    Record {
+        name: name.to_string(),
+        rtype,
+        class,
+        ttl: 0,
+        rdata,
+    }
+}
+
+/**
+ * メッセージのID。応答の偽造を難しくするため予測しにくい値にする(RFC5452 9.2)
+ */
+fn new_message_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::message::HTYPE_ETHER;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn read_u16(buf: &[u8], offset: usize) -> u16 {
+        u16::from_be_bytes([buf[offset], buf[offset + 1]])
+    }
+
+    fn read_name(buf: &[u8], mut offset: usize) -> (String, usize) {
+        let mut labels = Vec::new();
+        while buf[offset] != 0 {
+            let len = buf[offset] as usize;
+            labels.push(String::from_utf8(buf[offset + 1..offset + 1 + len].to_vec()).unwrap());
+            offset += 1 + len;
+        }
+        (labels.join("."), offset + 1)
+    }
+
+    fn read_records(buf: &[u8], mut offset: usize, count: u16) -> (Vec<Record>, usize) {
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let (name, next) = read_name(buf, offset);
+            let len = read_u16(buf, next + 8) as usize;
+            records.push(Record {
+                name,
+                rtype: read_u16(buf, next),
+                class: read_u16(buf, next + 2),
+                ttl: u32::from_be_bytes(buf[next + 4..next + 8].try_into().unwrap()),
+                rdata: buf[next + 10..next + 10 + len].to_vec(),
+            });
+            offset = next + 10 + len;
+        }
+        (records, offset)
+    }
+
+    /**
+     * 圧縮されていないUPDATEメッセージを解釈する。TSIGは含めない
+     */
+    pub(crate) fn parse_update(buf: &[u8]) -> UpdateMessage {
+        assert_eq!(OPCODE_UPDATE, (read_u16(buf, 2) >> 11) & 0x0f);
+        assert_eq!(1, read_u16(buf, 4));
+        let (zone, offset) = read_name(buf, 12);
+        assert_eq!((TYPE_SOA, CLASS_IN), (read_u16(buf, offset), read_u16(buf, offset + 2)));
+        let (prerequisites, offset) = read_records(buf, offset + 4, read_u16(buf, 6));
+        let (updates, _) = read_records(buf, offset, read_u16(buf, 8));
+        UpdateMessage {
+            id: read_u16(buf, 0),
+            zone,
+            prerequisites,
+            updates,
+        }
+    }
+
+    /**
+     * 前提条件(RFC2136 3.2)を確かめ、満たされなければそのRCODEを返す
+     */
+    fn check_prerequisites(zone: &[Record], prerequisites: &[Record]) -> u16 {
+        let matches = |record: &Record, other: &Record| {
+            record.name.eq_ignore_ascii_case(&other.name)
+                && (other.rtype == TYPE_ANY || record.rtype == other.rtype)
+        };
+        for prerequisite in prerequisites.iter() {
+            let exists = zone.iter().any(|record| matches(record, prerequisite));
+            // 値を指定した前提条件は、名前とタイプが同じレコードの集合と比べる
+            let rdata = |records: &[Record]| {
+                let mut rdata: Vec<Vec<u8>> = records
+                    .iter()
+                    .filter(|record| matches(record, prerequisite))
+                    .map(|record| record.rdata.clone())
+                    .collect();
+                rdata.sort();
+                rdata
+            };
+            let rcode = match (prerequisite.class, prerequisite.rtype) {
+                (CLASS_ANY, TYPE_ANY) if !exists => 3, // NXDOMAIN
+                (CLASS_ANY, _) if !exists => RCODE_NXRRSET,
+                (CLASS_NONE, TYPE_ANY) if exists => RCODE_YXDOMAIN,
+                (CLASS_NONE, _) if exists => RCODE_YXRRSET,
+                (CLASS_IN, _) if rdata(zone) != rdata(prerequisites) => RCODE_NXRRSET,
+                _ => 0,
+            };
+            if rcode != 0 {
+                return rcode;
+            }
+        }
+        0
+    }
+
+    /**
+     * 更新のセクション(RFC2136 3.4.2)をゾーンのレコードに適用する
+     */
+    fn apply_updates(zone: &mut Vec<Record>, updates: &[Record]) {
+        for update in updates.iter() {
+            let matches = |record: &Record| {
+                record.name.eq_ignore_ascii_case(&update.name)
+                    && (update.rtype == TYPE_ANY || record.rtype == update.rtype)
+            };
+            match update.class {
+                CLASS_ANY => zone.retain(|record| !matches(record)),
+                CLASS_NONE => {
+                    zone.retain(|record| !matches(record) || record.rdata != update.rdata)
+                }
+                _ => {
+                    if !zone.iter().any(|record| matches(record) && record.rdata == update.rdata) {
+                        zone.push(update.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * テスト用のDNSサーバ。受信したUPDATEメッセージを送り、rcodeで応答する。
+     * rcodeが0なら、受け取ったレコードを覚えておき、前提条件を満たす更新だけを適用する。
+     * 鍵があれば要求の署名を確かめ(一致しなければNOTAUTH)、応答に署名する。
+     */
+    pub(crate) fn stand_in_dns_server(
+        key: Option<TsigKey>,
+        rcode: u16,
+    ) -> (SocketAddr, mpsc::Receiver<UpdateMessage>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut zone = Vec::new();
+            while let Ok((size, src)) = socket.recv_from(&mut buf) {
+                let request = &buf[..size];
+                let update = parse_update(request);
+                let now = util::unix_time_now() as u64;
+                let verified = key.as_ref().map(|key| key.verify(request, None, now));
+                let rcode = match verified {
+                    Some(Err(_)) => 9, // NOTAUTH
+                    _ if rcode != 0 => rcode,
+                    _ => match check_prerequisites(&zone, &update.prerequisites) {
+                        0 => {
+                            apply_updates(&mut zone, &update.updates);
+                            0
+                        }
+                        rcode => rcode,
+                    },
+                };
+                // 応答はヘッダとゾーンのセクション(RFC2136 3.8)
+                let zone_end = read_name(request, 12).1 + 4;
+                let mut response = request[..zone_end].to_vec();
+                let flags = QR | (OPCODE_UPDATE << 11) | rcode;
+                response[2..4].copy_from_slice(&flags.to_be_bytes());
+                response[6..12].copy_from_slice(&[0; 6]);
+                if let (Some(key), Some(Ok(request_mac))) = (&key, &verified) {
+                    response = key.sign(&response, Some(request_mac), now).0;
+                }
+                socket.send_to(&response, src).unwrap();
+                if sender.send(update).is_err() {
+                    break;
+                }
+            }
+        });
+        (addr, receiver)
+    }
+
+    pub(crate) fn test_key() -> TsigKey {
+        TsigKey {
+            name: "dhcp-key".to_string(),
+            secret: b"secret-key".to_vec(),
+        }
+    }
+
+    fn updater(server: SocketAddr, key: Option<TsigKey>) -> DdnsUpdater {
+        DdnsUpdater {
+            server,
+            forward_zone: "example.lan".to_string(),
+            reverse_zone: None,
+            ttl: 300,
+            key,
+        }
+    }
+
+    const LEASED_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 10);
+
+    fn client(last: u8) -> Client {
+        Client {
+            client_id: None,
+            htype: HTYPE_ETHER,
+            hw_addr: vec![0x00, 0x11, 0x22, 0x33, 0x44, last],
+        }
+    }
+
+    #[test]
+    fn test_hostname_label() {
+        assert_eq!(Some("pc-1".to_string()), hostname_label("PC-1"));
+        assert_eq!(Some("pc".to_string()), hostname_label("pc.example.com."));
+        assert_eq!(None, hostname_label(""));
+        assert_eq!(None, hostname_label("-pc"));
+        assert_eq!(None, hostname_label("my_pc"));
+        assert_eq!(None, hostname_label(&"a".repeat(64)));
+        assert_eq!("10.0.168.192.in-addr.arpa", reverse_name(LEASED_IP));
+    }
+
+    #[test]
+    fn test_dhcid() {
+        // RFC4701 3.6の例
+        let client_id = Client {
+            client_id: Some(vec![0x01, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c]),
+            ..client(0x66)
+        };
+        assert_eq!(
+            util::decode_base64("AAEBOSD+XR3Os/0LozeXVqcNc7FwCfQdWL3b/NaiUDlW2No=").unwrap(),
+            dhcid(&client_id, "chi.example.com")
+        );
+        let ether = Client {
+            client_id: None,
+            htype: HTYPE_ETHER,
+            hw_addr: vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+        };
+        assert_eq!(
+            util::decode_base64("AAABxLmlskllE0MVjd57zHcWmEH3pCQ6VytcKD//7es/deY=").unwrap(),
+            dhcid(&ether, "Client.Example.com")
+        );
+    }
+
+    #[test]
+    fn test_add_and_remove_records() {
+        let (server, received) = stand_in_dns_server(None, 0);
+        let ddns = updater(server, None);
+        ddns.add_records(&client(0x66), "pc", LEASED_IP).unwrap();
+
+        let pc = encode_name("pc.example.lan");
+        let dhcid = dhcid(&client(0x66), "pc.example.lan");
+        let address = LEASED_IP.octets();
+        // 名前が使われていないことを前提条件にして、AとDHCIDのレコードを追加する
+        let forward = received.recv().unwrap();
+        assert_eq!("example.lan", forward.zone);
+        let unused = record("pc.example.lan", TYPE_ANY, CLASS_NONE, 0, &[]);
+        assert_eq!(vec![unused.clone()], forward.prerequisites);
+        assert_eq!(
+            vec![
+                record("pc.example.lan", TYPE_A, CLASS_IN, 300, &address),
+                record("pc.example.lan", TYPE_DHCID, CLASS_IN, 300, &dhcid),
+            ],
+            forward.updates
+        );
+        let reverse = received.recv().unwrap();
+        assert_eq!("0.168.192.in-addr.arpa", reverse.zone);
+        assert!(reverse.prerequisites.is_empty());
+        assert_eq!(
+            vec![
+                record("10.0.168.192.in-addr.arpa", TYPE_PTR, CLASS_ANY, 0, &[]),
+                record("10.0.168.192.in-addr.arpa", TYPE_PTR, CLASS_IN, 300, &pc),
+            ],
+            reverse.updates
+        );
+        assert_ne!(forward.id, reverse.id);
+
+        // 名前が使われていても、DHCIDが一致すればAレコードを置き換える
+        ddns.add_records(&client(0x66), "pc", LEASED_IP).unwrap();
+        assert_eq!(vec![unused], received.recv().unwrap().prerequisites);
+        let owned = record("pc.example.lan", TYPE_DHCID, CLASS_IN, 0, &dhcid);
+        let replaced = received.recv().unwrap();
+        assert_eq!(vec![owned.clone()], replaced.prerequisites);
+        assert_eq!(
+            vec![
+                record("pc.example.lan", TYPE_A, CLASS_ANY, 0, &[]),
+                record("pc.example.lan", TYPE_A, CLASS_IN, 300, &address),
+            ],
+            replaced.updates
+        );
+        assert_eq!("0.168.192.in-addr.arpa", received.recv().unwrap().zone);
+
+        // 削除はリースしたIPアドレスのレコードだけ。Aレコードが残らなければDHCIDも削除する
+        ddns.remove_records(&client(0x66), "pc", LEASED_IP).unwrap();
+        let removed = received.recv().unwrap();
+        assert_eq!(vec![owned.clone()], removed.prerequisites);
+        assert_eq!(
+            vec![record("pc.example.lan", TYPE_A, CLASS_NONE, 0, &address)],
+            removed.updates
+        );
+        let released = received.recv().unwrap();
+        assert_eq!(
+            vec![owned, record("pc.example.lan", TYPE_A, CLASS_NONE, 0, &[])],
+            released.prerequisites
+        );
+        assert_eq!(
+            vec![record("pc.example.lan", TYPE_DHCID, CLASS_ANY, 0, &[])],
+            released.updates
+        );
+        assert_eq!(
+            vec![record("10.0.168.192.in-addr.arpa", TYPE_PTR, CLASS_NONE, 0, &pc)],
+            received.recv().unwrap().updates
+        );
+    }
+
+    #[test]
+    fn test_name_in_use() {
+        let (server, received) = stand_in_dns_server(None, 0);
+        let ddns = updater(server, None);
+        ddns.add_records(&client(0x66), "pc", LEASED_IP).unwrap();
+        assert_eq!(2, received.iter().take(2).count());
+
+        // 他のクライアントが同じ名前を求めても、登録済みのレコードは書き換えない
+        let other_ip = Ipv4Addr::new(192, 168, 0, 11);
+        let error = ddns.add_records(&client(0x77), "pc", other_ip).unwrap_err();
+        assert_eq!("pc.example.lan is in use by another client", error.to_string());
+        assert_eq!(TYPE_ANY, received.recv().unwrap().prerequisites[0].rtype);
+        let other_dhcid = dhcid(&client(0x77), "pc.example.lan");
+        assert_eq!(other_dhcid, received.recv().unwrap().prerequisites[0].rdata);
+        // 削除もできない。逆引きは更新していないので、次に届くのは削除の要求
+        let error = ddns.remove_records(&client(0x77), "pc", other_ip).unwrap_err();
+        assert_eq!("pc.example.lan is not registered by the client", error.to_string());
+        assert_eq!(other_dhcid, received.recv().unwrap().prerequisites[0].rdata);
+        assert_eq!("0.168.192.in-addr.arpa", received.recv().unwrap().zone);
+
+        // 登録したクライアントは延長・削除でき、名前が空けば他のクライアントも登録できる
+        ddns.add_records(&client(0x66), "pc", LEASED_IP).unwrap();
+        ddns.remove_records(&client(0x66), "pc", LEASED_IP).unwrap();
+        ddns.add_records(&client(0x77), "pc", other_ip).unwrap();
+    }
+
+    #[test]
+    fn test_signed_update() {
+        let (server, received) = stand_in_dns_server(Some(test_key()), 0);
+        updater(server, Some(test_key())).add_records(&client(0x66), "pc", LEASED_IP).unwrap();
+        assert_eq!("example.lan", received.recv().unwrap().zone);
+        assert_eq!("0.168.192.in-addr.arpa", received.recv().unwrap().zone);
+
+        // 鍵が違うとNOTAUTHになる。応答に署名がないことも検出する
+        let other = TsigKey { secret: b"other".to_vec(), ..test_key() };
+        let ddns = updater(server, Some(other));
+        assert!(ddns.add_records(&client(0x66), "pc", LEASED_IP).is_err());
+        // 署名しない要求
+        let ddns = updater(server, None);
+        let error = ddns.add_records(&client(0x66), "pc", LEASED_IP).unwrap_err();
+        assert!(error.to_string().contains("NOTAUTH"));
+    }
+
+    #[test]
+    fn test_rejected_update() {
+        let (server, received) = stand_in_dns_server(None, 5);
+        let ddns = updater(server, None);
+        let error = ddns.add_records(&client(0x66), "pc", LEASED_IP).unwrap_err();
+        assert_eq!("update of zone example.lan is rejected: REFUSED", error.to_string());
+        // 正引きを登録できなければ逆引きも更新しない。削除では正引きが失敗しても逆引きは更新する
+        assert!(ddns.remove_records(&client(0x66), "pc", LEASED_IP).is_err());
+        let zones: Vec<_> = received.iter().take(3).map(|message| message.zone).collect();
+        assert_eq!(vec!["example.lan", "example.lan", "0.168.192.in-addr.arpa"], zones);
+
+        // 逆引きのゾーンの外のIPアドレス
+        let (server, received) = stand_in_dns_server(None, 0);
+        let ddns = DdnsUpdater {
+            reverse_zone: Some("1.168.192.in-addr.arpa".to_string()),
+            ..updater(server, None)
+        };
+        let error = ddns.add_records(&client(0x66), "pc", LEASED_IP).unwrap_err();
+        assert!(error.to_string().contains("is not in reverse_zone"));
+        assert_eq!("example.lan", received.recv().unwrap().zone);
+    }
+
+    fn encode_name(name: &str) -> Vec<u8> {
+        options::encode_domain_names(&[name.to_string()])
+    }
+
+    fn record(name: &str, rtype: u16, class: u16, ttl: u32, rdata: &[u8]) -> Record {
+        Record {
+            name: name.to_string(),
+            rtype,
+            class,
+            ttl,
+            rdata: rdata.to_vec(),
+        }
+    }
+}
@@ -1,322 +1,366 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::net::Ipv4Addr;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use ipnetwork::Ipv4Network;
-use pnet::packet::PrimitiveValues;
 use pnet::util::MacAddr;
-use rusqlite::Connection;
-
-// [note] 以降のconstはRFC2131で記載の以下図でのDHCPパケットの構成である。
-// https://datatracker.ietf.org/doc/html/rfc2131#autoid-8
-
-/*
-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-   |     op (1)    |   htype (1)   |   hlen (1)    |   hops (1)    |
-   +---------------+---------------+---------------+---------------+
-   |                            xid (4)                            |
-   +-------------------------------+-------------------------------+
-   |           secs (2)            |           flags (2)           |
-   +-------------------------------+-------------------------------+
-   |                          ciaddr  (4)                          |
-   +---------------------------------------------------------------+
-   |                          yiaddr  (4)                          |
-   +---------------------------------------------------------------+
-   |                          siaddr  (4)                          |
-   +---------------------------------------------------------------+
-   |                          giaddr  (4)                          |
-   +---------------------------------------------------------------+
-   |                                                               |
-   |                          chaddr  (16)                         |
-   |                                                               |
-   |                                                               |
-   +---------------------------------------------------------------+
-   |                                                               |
-   |                          sname   (64)                         |
-   +---------------------------------------------------------------+
-   |                                                               |
-   |                          file    (128)                        |
-   +---------------------------------------------------------------+
-   |                                                               |
-   |                          options (variable)                   |
-   +---------------------------------------------------------------+
-
-                  Figure 1:  Format of a DHCP message
- */
-const OP: usize = 0;
-const HTYPE: usize = 1;
-const HLEN: usize = 2;
-/* const HOPS: usize = 3; 今回は利用しない */
-const XID: usize = 4;
-const SECS: usize = 8;
-const FLAGS: usize = 10;
-const CIADDR: usize = 12;
-const YIADDR: usize = 16;
-const SIADDR: usize = 20;
-const GIADDR: usize = 24;
-const CHADDR: usize = 28;
-const SNAME: usize = 44;
-/* const FILE: usize = 108; 今回は利用しない */
-pub const OPTIONS: usize = 236;
-
-const DHCP_MINIMUM_SIZE: usize = 237;
-const OPTION_END: u8 = 255;
-
-use super::database;
+
+use super::config::{AddressRange, Config, OptionValue, ScopeConfig};
+use super::database::{self, LeaseStore};
+use super::ddns::{self, DdnsUpdater};
+use super::dhcp6::Scope6;
+use super::message::{DhcpMessage, HTYPE_ETHER};
+use super::options::{ClientFqdn, DhcpOption, FQDN_E, FQDN_N, FQDN_O, FQDN_S};
+use super::relay::{AllowListPolicy, RelayAgentPolicy};
 use super::util;
 
+// DHCPOFFERで提案したIPアドレスをDHCPREQUESTが来るまで確保しておく時間
+const OFFER_HOLD_TIME: Duration = Duration::from_secs(60);
+
 /**
- * DHCPのパケットを表現する。
+ * DHCPOFFERで提案中のIPアドレス
  */
-pub struct DhcpPacket {
-    buffer: Vec<u8>,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PendingOffer {
+    pub ip_addr: Ipv4Addr,
+    from_pool: bool,     // アドレスプールから取り出したか。クライアントの有効なリースを提案した場合はfalse
+    expires_at: Instant, // これを過ぎても応答がなければ提案を取り消す
 }
 
-impl DhcpPacket {
-    pub fn new(buf: Vec<u8>) -> Option<DhcpPacket> {
-        if buf.len() > DHCP_MINIMUM_SIZE {
-            let packet = DhcpPacket { buffer: buf };
-            return Some(packet);
-        }
-        None
-    }
-
-    pub fn get_buffer(&self) -> &[u8] {
-        self.buffer.as_ref()
-    }
-
-    pub fn get_op(&self) -> u8 {
-        self.buffer[OP]
+impl PendingOffer {
+    /**
+     * 提案を取り消すまでの残り時間
+     */
+    pub fn expires_in(&self, now: Instant) -> Duration {
+        self.expires_at.saturating_duration_since(now)
     }
+}
 
-    pub fn get_options(&self) -> &[u8] {
-        &self.buffer[OPTIONS..]
-    }
+/**
+ * 提案中のIPアドレスの一覧。クライアントとトランザクションIDの組で管理する。
+ *
+ * [note] DHCPREQUESTが来ないまま提案したIPアドレスがアドレスプールから失われないように、
+ * 一定時間応答がなければアドレスプールに戻す。
+ */
+#[derive(Default)]
+pub struct OfferTable {
+    offers: HashMap<(Client, u32), PendingOffer>,
+}
 
-    pub fn get_xid(&self) -> &[u8] {
-        &self.buffer[XID..SECS]
+impl OfferTable {
+    /**
+     * 提案を記録する。同じクライアントへの別のトランザクションでの提案は取り消し、
+     * アドレスプールに戻すべきIPアドレスを返す。
+     */
+    pub fn insert(
+        &mut self,
+        client: &Client,
+        xid: u32,
+        ip_addr: Ipv4Addr,
+        from_pool: bool,
+        now: Instant,
+    ) -> Vec<Ipv4Addr> {
+        let superseded = self.remove_client(client, xid);
+        self.offers.insert(
+            (client.clone(), xid),
+            PendingOffer {
+                ip_addr,
+                from_pool,
+                expires_at: now + OFFER_HOLD_TIME,
+            },
+        );
+        superseded
     }
 
-    pub fn get_flags(&self) -> &[u8] {
-        &self.buffer[FLAGS..CIADDR]
+    pub fn get(&self, client: &Client, xid: u32) -> Option<&PendingOffer> {
+        self.offers.get(&(client.clone(), xid))
     }
 
-    pub fn get_giaddr(&self) -> Ipv4Addr {
-        let b = &self.buffer[GIADDR..CHADDR];
-        Ipv4Addr::new(b[0], b[1], b[2], b[3])
+    /**
+     * 提案を取り出す。DHCPREQUESTで要求されたIPアドレスが提案と一致すればOk、
+     * 一致しなければ提案を取り消してErrでアドレスプールに戻すべきIPアドレスを返す。
+     */
+    pub fn claim(
+        &mut self,
+        client: &Client,
+        xid: u32,
+        requested_ip: Ipv4Addr,
+    ) -> Result<PendingOffer, Option<Ipv4Addr>> {
+        match self.offers.remove(&(client.clone(), xid)) {
+            Some(offer) if offer.ip_addr == requested_ip => Ok(offer),
+            Some(offer) => Err(offer.from_pool.then_some(offer.ip_addr)),
+            None => Err(None),
+        }
     }
 
-    pub fn get_chaddr(&self) -> MacAddr {
-        let b = &self.buffer[CHADDR..SNAME];
-        // [note] ↓ 本来は16オクテット分だが書籍の実装ではMACアドレスしか使わないから6オクテットだけにしている。
-        MacAddr::new(b[0], b[1], b[2], b[3], b[4], b[5])
+    /**
+     * 提案を取り消し、アドレスプールに戻すべきIPアドレスを返す。
+     */
+    pub fn remove(&mut self, client: &Client, xid: u32) -> Option<Ipv4Addr> {
+        let offer = self.offers.remove(&(client.clone(), xid))?;
+        offer.from_pool.then_some(offer.ip_addr)
     }
 
-    pub fn get_ciaddr(&self) -> Ipv4Addr {
-        let b = &self.buffer[CIADDR..YIADDR];
-        Ipv4Addr::new(b[0], b[1], b[2], b[3])
+    /**
+     * クライアントへのIPアドレスの提案をトランザクションに関わらず取り消し、提案していたかを返す。
+     * IPアドレスはアドレスプールに戻さない(DHCPDECLINEされたIPアドレスのため)。
+     */
+    pub fn remove_address(&mut self, client: &Client, ip_addr: Ipv4Addr) -> bool {
+        let count = self.offers.len();
+        self.offers
+            .retain(|(offered, _), offer| !(offered.is_same(client) && offer.ip_addr == ip_addr));
+        self.offers.len() != count
     }
 
-    pub fn set_op(&mut self, op: u8) {
-        self.buffer[OP] = op;
+    /**
+     * 期限の過ぎた提案を取り消し、アドレスプールに戻すべきIPアドレスを返す。
+     */
+    pub fn expire(&mut self, now: Instant) -> Vec<Ipv4Addr> {
+        let mut released = Vec::new();
+        self.offers.retain(|_, offer| {
+            if offer.expires_at > now {
+                return true;
+            }
+            if offer.from_pool {
+                released.push(offer.ip_addr);
+            }
+            false
+        });
+        released
     }
 
-    pub fn set_htype(&mut self, htype: u8) {
-        self.buffer[HTYPE] = htype;
+    /**
+     * 指定のクライアントへのxid以外のトランザクションでの提案を取り消す
+     */
+    fn remove_client(&mut self, client: &Client, xid: u32) -> Vec<Ipv4Addr> {
+        let mut released = Vec::new();
+        self.offers.retain(|(offered, offer_xid), offer| {
+            if !offered.is_same(client) || *offer_xid == xid {
+                return true;
+            }
+            if offer.from_pool {
+                released.push(offer.ip_addr);
+            }
+            false
+        });
+        released
     }
+}
 
-    pub fn set_hlen(&mut self, hlen: u8) {
-        self.buffer[HLEN] = hlen;
-    }
+/**
+ * 予約したIPアドレスを受け取るクライアント
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReservationOwner {
+    MacAddr(MacAddr),
+    ClientId(Vec<u8>), // client identifier(オプション61)の値
+}
 
-    pub fn set_xid(&mut self, xid: &[u8]) {
-        self.buffer[XID..SECS].copy_from_slice(xid);
+impl ReservationOwner {
+    /**
+     * リクエストを送ったクライアントがこの予約の持ち主か
+     */
+    pub fn matches(&self, client: &Client) -> bool {
+        match self {
+            ReservationOwner::MacAddr(mac_addr) => client.mac_addr() == Some(*mac_addr),
+            ReservationOwner::ClientId(id) => client.client_id.as_ref() == Some(id),
+        }
     }
+}
 
-    pub fn set_ciaddr(&mut self, ciaddr: Ipv4Addr) {
-        self.buffer[CIADDR..YIADDR].copy_from_slice(&ciaddr.octets());
+impl fmt::Display for ReservationOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReservationOwner::MacAddr(mac_addr) => write!(f, "mac {}", mac_addr),
+            ReservationOwner::ClientId(id) => write!(f, "client_id {}", util::encode_hex(id)),
+        }
     }
+}
 
-    pub fn set_yiaddr(&mut self, yiaddr: Ipv4Addr) {
-        self.buffer[YIADDR..SIADDR].copy_from_slice(&yiaddr.octets());
-    }
+/**
+ * リースを受け取るクライアント。
+ * client identifier(オプション61)があればそれで識別し、なければハードウェアアドレス
+ * (htypeとchaddrのうちhlenの長さの部分)で識別する (RFC2131 4.2, RFC4361 6.1)。
+ * どちらもDBに記録する。
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Client {
+    pub client_id: Option<Vec<u8>>,
+    pub htype: u8,
+    pub hw_addr: Vec<u8>,
+}
 
-    pub fn set_flags(&mut self, flags: &[u8]) {
-        self.buffer[FLAGS..CIADDR].copy_from_slice(flags);
+impl Client {
+    pub fn from_message(message: &DhcpMessage) -> Client {
+        Client {
+            // 長さ0のclient identifierでは識別できないので、ないものとして扱う
+            client_id: message
+                .client_identifier()
+                .filter(|id| !id.is_empty())
+                .map(<[u8]>::to_vec),
+            htype: message.htype,
+            hw_addr: message.hardware_address().to_vec(),
+        }
     }
 
-    pub fn set_giaddr(&mut self, giaddr: Ipv4Addr) {
-        self.buffer[GIADDR..CHADDR].copy_from_slice(&giaddr.octets());
+    /**
+     * client identifierを送らないイーサネットのクライアント
+     */
+    pub fn from_mac_addr(mac_addr: MacAddr) -> Client {
+        Client {
+            client_id: None,
+            htype: HTYPE_ETHER,
+            hw_addr: mac_addr.octets().to_vec(),
+        }
     }
 
-    pub fn set_chaddr(&mut self, chaddr: MacAddr) {
-        let t = chaddr.to_primitive_values();
-        let macaddr_value = [t.0, t.1, t.2, t.3, t.4, t.5];
-        // [著者コメント] ここだけCHADDR..SNAMEでないのは、chaddrフィールドが16オクテット確保されているため。
-        // 今回はMACアドレスしかこのフィールドに入らないので、MACアドレスのサイズである6オクテット確保している。
-        //
-        // [note] ↓ 本来は16オクテット分だがMACアドレスしか使わないから6オクテットだけにしている。
-        self.buffer[CHADDR..CHADDR + 6].copy_from_slice(&macaddr_value);
+    /**
+     * 同じクライアントか。client identifierがあればそれだけを比べる
+     */
+    pub fn is_same(&self, other: &Client) -> bool {
+        match (&self.client_id, &other.client_id) {
+            (Some(id), Some(other_id)) => id == other_id,
+            (None, None) => self.htype == other.htype && self.hw_addr == other.hw_addr,
+            _ => false,
+        }
     }
 
-    pub fn set_magic_cookie(&mut self, cursor: &mut usize) {
-        self.buffer[*cursor..*cursor + 4].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
-        *cursor += 4;
+    /**
+     * client identifierかハードウェアアドレスがidと一致するか。管理操作でクライアントを指定するのに使う
+     */
+    pub fn is_identified_by(&self, id: &[u8]) -> bool {
+        self.client_id.as_deref() == Some(id) || self.hw_addr == id
     }
 
     /**
-     * DHCPパケットのoptionフィールドをセットする。
-     * optionフィールドは複数のコードが存在する。
-     * [note] DHCPパケットにおいてOptionは仕様が詰まっているが、可変長である。
-     * 以下のように CodeとLenが先頭にありそれがそれぞれ1オクテットで固定あり、その後に可変の値が続く。
-     * optionフィールドとしては複数の
-     * 
-        Code   Len         Address 1               Address 2
-        +-----+-----+-----+-----+-----+-----+-----+-----+--
-        |  6  |  n  |  a1 |  a2 |  a3 |  a4 |  a1 |  a2 |  ...
-        +-----+-----+-----+-----+-----+-----+-----+-----+--
+     * イーサネットのMACアドレス。他の種類のハードウェアならNone
      */
-    pub fn set_option(
-        &mut self,
-        cursor: &mut usize,
-        code: u8,
-        len: usize,
-        contents: Option<&[u8]>,
-    ) {
-        // goal 概要
-        // self.buffer[0] = code;// codeを入れる
-        // self.buffer[1] = len as u8;// lenを入れる
-        // self.buffer[2..len] = contents; //コンテンツを入れる。
-
-        // Codeを埋める。
-        self.buffer[*cursor] = code;
-        *cursor += 1;
-        // Optionのタイプによってコンテンツがあったりなかったりする。
-        if code == OPTION_END {
-            return;
-        }
-
-        // Lenを埋める。
-        self.buffer[*cursor] = len as u8;
-        *cursor += 1;
-
-        // コンテンツを埋める。
-        if let Some(contents) = contents {
-            self.buffer[*cursor..*cursor+contents.len()].copy_from_slice(contents);
+    pub fn mac_addr(&self) -> Option<MacAddr> {
+        match (self.htype, self.hw_addr.as_slice()) {
+            (HTYPE_ETHER, &[a, b, c, d, e, f]) => Some(MacAddr::new(a, b, c, d, e, f)),
+            _ => None,
         }
-        *cursor += len;
     }
 
-    pub fn get_option(&self, code: u8) -> Option<Vec<u8>> {
-        // [やろうとしていること]
-        // パケットの中のcode の位置を探り、そのコンテンツを返す。
-        let options = self.get_options();
-
-        let mut index: usize = 4; // 最初の4バイトはクッキーなので飛ばす
+    /**
+     * ハードウェアアドレスを "00:11:22:33:44:55" の形式で返す。長さはhlenに従う
+     */
+    pub fn hw_addr_string(&self) -> String {
+        let octets: Vec<String> = self.hw_addr.iter().map(|b| format!("{:02x}", b)).collect();
+        octets.join(":")
+    }
+}
 
-        while options[index] != OPTION_END { // 走査の終了条件はindexがENDにたどり着いたとき
-            if options[index] == code {
-                // 目的のcodeを見つけたとき
-                let len: u8 = options[index+1];
-                let buf_index = index + 2;
-                let option = options[buf_index..buf_index+(len as usize)].to_vec();
-                return Some(option);
-            } else if options[index] == 0 {
-                // 0 は仕様上Paddingという埋める用のものなのでindexをインクリメントしてloopに戻る。
-                index += 1;
-            } else {
-                // 目的ではないcodeを見つけたとき → indexを次のcodeがある位置まで引き上げる
-                let len = options[index+1] as usize;
-                index += 2 + len; // 2 はlenへの移動とcodeへの移動の2オクテット分
-            }
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.client_id {
+            Some(id) if self.hw_addr.is_empty() => write!(f, "client_id {}", util::encode_hex(id)),
+            Some(id) => write!(f, "{} (client_id {})", self.hw_addr_string(), util::encode_hex(id)),
+            None => write!(f, "{}", self.hw_addr_string()),
         }
-        None
     }
+}
 
+/**
+ * 特定のクライアントに固定で割り当てるIPアドレス
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reservation {
+    pub owner: ReservationOwner,
+    pub ip_addr: Ipv4Addr,
+    pub hostname: Option<String>,
+    pub options: Vec<DhcpOption>, // スコープのオプションを上書き・追加するオプション
 }
 
 /**
- * DHCPサーバの情報を保持する。
- * 複数のスレッドで共有されるため、フィールドにmutアクセスする際はロックを取得する必要がある。
- * 読み出しだけならフィールドにロックは必要ない。
+ * 1つのサブネットに対する割り当ての情報とアドレスプール
  */
-pub struct DhcpServer {
-    address_pool: RwLock<Vec<Ipv4Addr>>,  // 利用(割当)可能なアドレス。[note] DHCPサーバにおいて一番のメインのフィールド。
-    pub db_connection: Mutex<Connection>, // データベースのコネクション。ConnectionはSyncを実装しないのでRwLockではだめ。
+pub struct Scope {
+    pub name: String,
+    pub interface: Option<String>,
     pub network_addr: Ipv4Network,
     pub server_address: Ipv4Addr,
-    pub default_gateway: Ipv4Addr,
-    pub subnet_mask: Ipv4Addr,
-    pub dns_server: Ipv4Addr,
-    pub lease_time: Vec<u8>,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub lease_time: u32,              // リース期間(秒)
+    pub options: Vec<DhcpOption>,     // 設定ファイルで追加されたオプション
+    ranges: Vec<AddressRange>,
+    exclusions: Vec<Ipv4Addr>,
+    reservations: RwLock<Vec<Reservation>>, // 予約されたIPアドレスはアドレスプールに入れない
+    pub relay_circuit_ids: Vec<Vec<u8>>,     // 応答するリレーエージェントのcircuit-id。空なら全て
+    pub relay_remote_ids: Vec<Vec<u8>>,      // 応答するリレーエージェントのremote-id。空なら全て
+    address_pool: RwLock<Vec<Ipv4Addr>>, // 利用(割当)可能なアドレス。[note] DHCPサーバにおいて一番のメインのフィールド。
 }
 
-impl DhcpServer {
-    pub fn new() -> Result<DhcpServer, failure::Error> {
-        let env = util::load_env();
-
-        // DNSやゲートウェイなどのアドレス
-        let static_addresses = util::obtain_static_addresses(&env)?;
-
-        let network_addr_with_prefix: Ipv4Network = Ipv4Network::new(
-            static_addresses["network_addr"],
-            ipnetwork::ipv4_mask_to_prefix(static_addresses["subnet_mask"])?,
-        )?;
-
-        let con = Connection::open("dhcp.db")?;
-
-        let addr_pool = Self::init_address_pool(&con, &static_addresses, network_addr_with_prefix)?;
+impl Scope {
+    /**
+     * 検証済みの設定からスコープを作り、アドレスプールを初期化する
+     */
+    fn from_config(
+        config: &ScopeConfig,
+        default_server_identifier: Option<Ipv4Addr>,
+        store: &dyn LeaseStore,
+    ) -> Result<Scope, failure::Error> {
+        let options = config
+            .options
+            .iter()
+            .map(|option| option.to_option())
+            .collect::<Result<_, _>>()?;
+        let reservations = config
+            .reservations
+            .iter()
+            .map(|reservation| reservation.to_reservation())
+            .collect::<Result<_, _>>()?;
+        let relay_ids = |ids: &[OptionValue]| {
+            ids.iter()
+                .map(|id| id.to_bytes())
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut scope = Scope {
+            name: config.name.clone(),
+            interface: config.interface.clone(),
+            network_addr: config.network()?,
+            server_address: config
+                .server_identifier(default_server_identifier)
+                .ok_or_else(|| failure::err_msg("server_identifier is not configured"))?,
+            routers: config.routers.clone(),
+            dns_servers: config.dns_servers.clone(),
+            lease_time: config.lease_time,
+            options,
+            ranges: config.ranges.clone(),
+            exclusions: config.exclusions.clone(),
+            reservations: RwLock::new(reservations),
+            relay_circuit_ids: relay_ids(&config.relay_circuit_ids)?,
+            relay_remote_ids: relay_ids(&config.relay_remote_ids)?,
+            address_pool: RwLock::new(Vec::new()),
+        };
+        let addr_pool = scope.init_address_pool(store)?;
         info!(
-            "There are {} addresses in the address pool",
+            "scope {}: There are {} addresses in the address pool",
+            scope.name,
             addr_pool.len()
         );
-
-        // [note] IPアドレスやサブネットマスクはRustの標準ライブラリ提供のIPアドレス型が裏でビッグエンディアンでバイト化してくれるが、
-        // lease_time は普通に整数値なので自分(このプログラム)で u32型 をビッグエンディアンでバイト化する必要がある。
-        let lease_time = util::make_big_endian_vec_from_u32(
-            env.get("LEASE_TIME").expect("Missing lease_time").parse()?,
-        )?;
-
-        Ok(DhcpServer {
-            address_pool: RwLock::new(addr_pool),
-            db_connection: Mutex::new(con),
-            network_addr: network_addr_with_prefix,
-            server_address: static_addresses["dhcp_server_addr"],
-            default_gateway: static_addresses["default_gateway"],
-            subnet_mask: static_addresses["subnet_mask"],
-            dns_server: static_addresses["dns_addr"],
-            lease_time,
-        })
+        scope.address_pool = RwLock::new(addr_pool);
+        Ok(scope)
     }
 
     /**
      * 新たなホストに割り当て可能なアドレスプールを初期化
      */
-    fn init_address_pool(
-        con: &Connection,
-        static_addresses: &HashMap<String, Ipv4Addr>,
-        network_addr_with_prefix: Ipv4Network,
-    ) -> Result<Vec<Ipv4Addr>, failure::Error> {
-        let network_addr = static_addresses.get("network_addr").unwrap();
-        let default_gateway = static_addresses.get("default_gateway").unwrap();
-        let dhcp_server_addr = static_addresses.get("dhcp_server_addr").unwrap();
-        let dns_server_addr = static_addresses.get("dns_addr").unwrap();
-        let broadcast = network_addr_with_prefix.broadcast();
-
+    fn init_address_pool(&self, store: &dyn LeaseStore) -> Result<Vec<Ipv4Addr>, failure::Error> {
         // すでに使用されていて、解放もされていないIPアドレス
-        let mut used_ip_addrs = database::select_addresses(con, Some(0))?;
-
-        used_ip_addrs.push(*network_addr);
-        used_ip_addrs.push(*default_gateway);
-        used_ip_addrs.push(*dhcp_server_addr);
-        used_ip_addrs.push(*dns_server_addr);
-        used_ip_addrs.push(broadcast);
+        let mut used_ip_addrs = store.select_addresses(Some(0))?;
+        // 他のホストが使っているとDHCPDECLINEで通知されたIPアドレス
+        used_ip_addrs.extend(store.select_conflicted_addresses()?);
 
-        // ネットワークの全てのIPアドレスから、使用されているIPアドレスを除いたものを
+        // 割り当て範囲のIPアドレスから、使用されているIPアドレスを除いたものを
         // アドレスプールとする。
-        let mut addr_pool: Vec<Ipv4Addr> = network_addr_with_prefix
+        let mut addr_pool: Vec<Ipv4Addr> = self
+            .network_addr
             .iter()
-            .filter(|addr| !used_ip_addrs.contains(addr))
+            .filter(|addr| self.is_assignable(*addr) && !used_ip_addrs.contains(addr))
             .collect();
 
         // 気持ち的にIPアドレスの若い方から割り当てたいので、逆順にする。
@@ -326,6 +370,121 @@ impl DhcpServer {
         Ok(addr_pool)
     }
 
+    /**
+     * 新たなクライアントに割り当ててよいIPアドレスか。
+     * 割り当て範囲内で、除外アドレスや予約されたアドレス、ネットワーク・ブロードキャスト・ルータ等のアドレスでないもの。
+     */
+    pub fn is_assignable(&self, ip_addr: Ipv4Addr) -> bool {
+        let in_ranges = if self.ranges.is_empty() {
+            self.network_addr.contains(ip_addr)
+        } else {
+            self.ranges.iter().any(|range| range.contains(ip_addr))
+        };
+        in_ranges
+            && ip_addr != self.network_addr.network()
+            && ip_addr != self.network_addr.broadcast()
+            && ip_addr != self.server_address
+            && !self.routers.contains(&ip_addr)
+            && !self.dns_servers.contains(&ip_addr)
+            && !self.exclusions.contains(&ip_addr)
+            && !self.is_reserved(ip_addr)
+    }
+
+    pub fn is_reserved(&self, ip_addr: Ipv4Addr) -> bool {
+        self.reservations
+            .read()
+            .unwrap()
+            .iter()
+            .any(|reservation| reservation.ip_addr == ip_addr)
+    }
+
+    /**
+     * リクエストを送ったクライアントの予約を返す
+     */
+    pub fn find_reservation(&self, client: &Client) -> Option<Reservation> {
+        self.reservations
+            .read()
+            .unwrap()
+            .iter()
+            .find(|reservation| reservation.owner.matches(client))
+            .cloned()
+    }
+
+    /**
+     * 予約を追加し、IPアドレスをアドレスプールから取り除く。
+     * IPアドレスか持ち主が既に予約されていればErr。
+     */
+    fn add_reservation(&self, reservation: Reservation) -> Result<(), failure::Error> {
+        {
+            let mut reservations = self.reservations.write().unwrap();
+            if let Some(existing) = reservations.iter().find(|existing| {
+                existing.ip_addr == reservation.ip_addr || existing.owner == reservation.owner
+            }) {
+                return Err(failure::format_err!(
+                    "{} is already reserved for {}",
+                    existing.ip_addr,
+                    existing.owner
+                ));
+            }
+            reservations.push(reservation.clone());
+        }
+        self.pick_specified_ip(reservation.ip_addr);
+        Ok(())
+    }
+
+    /**
+     * 予約を取り除き、取り除いた予約を返す。
+     * IPアドレスはアドレスプールに戻さないので、必要なら呼び出し側でrelease_addressする。
+     */
+    fn remove_reservation(&self, ip_addr: Ipv4Addr) -> Option<Reservation> {
+        let mut reservations = self.reservations.write().unwrap();
+        let index = reservations
+            .iter()
+            .position(|reservation| reservation.ip_addr == ip_addr)?;
+        Some(reservations.remove(index))
+    }
+
+    pub fn reservations(&self) -> Vec<Reservation> {
+        self.reservations.read().unwrap().clone()
+    }
+
+    /**
+     * リースに記録するホスト名。予約のホスト名、Client FQDN(オプション81)、Host Name(オプション12)の順に使う。
+     * DNSのラベルとして使えない名前は使わない。
+     */
+    pub fn lease_hostname(&self, packet: &DhcpMessage) -> Option<String> {
+        let reservation = self.find_reservation(&Client::from_message(packet));
+        reservation
+            .and_then(|reservation| reservation.hostname)
+            .or_else(|| packet.client_fqdn().map(|fqdn| fqdn.name.clone()))
+            .or_else(|| packet.host_name().map(str::to_string))
+            .and_then(|name| ddns::hostname_label(&name))
+    }
+
+    /**
+     * T1: クライアントがリースを割り当てたサーバへ延長を要求(RENEWING)し始めるまでの時間(秒)
+     * RFC2131 4.4.5 の既定値であるリース期間の0.5倍
+     */
+    pub fn renewal_time(&self) -> u32 {
+        self.lease_time / 2
+    }
+
+    /**
+     * T2: クライアントが任意のサーバへ延長を要求(REBINDING)し始めるまでの時間(秒)
+     * RFC2131 4.4.5 の既定値であるリース期間の0.875倍
+     */
+    pub fn rebinding_time(&self) -> u32 {
+        (self.lease_time as u64 * 7 / 8) as u32
+    }
+
+    /**
+     * 現在時刻から始まるリースの(開始, 期限)をUNIX時間で返す
+     */
+    pub fn new_lease_period(&self) -> (i64, i64) {
+        let now = util::unix_time_now();
+        (now, now + self.lease_time as i64)
+    }
+
     /*
     * 
     * 以降はメインであるアドレスプールの操作。
@@ -360,9 +519,1055 @@ impl DhcpServer {
     /**
      * アドレスプールの先頭にIPアドレスを返す。
      * 取り出しは後方から行われるため、返されたアドレスは当分他のホストに割り当てられない
+     *
+     * [note] 設定の変更で割り当て範囲から外れたIPアドレスはアドレスプールに戻さない
      */
     pub fn release_address(&self, released_ip: Ipv4Addr) {
+        if !self.is_assignable(released_ip) {
+            return;
+        }
         let mut lock = self.address_pool.write().unwrap();
-        lock.insert(0, released_ip);
+        if !lock.contains(&released_ip) {
+            lock.insert(0, released_ip);
+        }
+    }
+}
+
+/**
+ * スコープのアドレスプールの使用状況
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolUsage {
+    pub scope: String,
+    pub network_addr: Ipv4Network,
+    pub total: usize,     // 新たなクライアントに割り当てられるIPアドレスの数
+    pub available: usize, // そのうちアドレスプールに残っている数
+    pub leased: usize,    // サブネット内の有効なリースの数。予約されたIPアドレスのリースも含む
+    pub reserved: usize,
+}
+
+/**
+ * DHCPサーバの情報を保持する。
+ * 複数のスレッドで共有されるため、フィールドにmutアクセスする際はロックを取得する必要がある。
+ * 読み出しだけならフィールドにロックは必要ない。
+ */
+pub struct DhcpServer {
+    scopes: RwLock<Vec<Arc<Scope>>>, // サブネットごとの割り当ての情報。設定の再読み込みで差し替える
+    pub lease_store: Mutex<Box<dyn LeaseStore>>, // リースの保存先。SQLiteのConnectionはSyncでない
+    pending_offers: Mutex<OfferTable>,    // DHCPOFFERで提案中のIPアドレス
+    pub decline_time: u32, // DHCPDECLINEされたIPアドレスを割り当てない期間(秒)
+    pub relay_policy: Box<dyn RelayAgentPolicy>, // リレーエージェント経由のリクエストに応答するか決める
+    scopes6: RwLock<Vec<Arc<Scope6>>>, // DHCPv6のリンクごとの割り当ての情報。リースの保存先は共有する
+    pub server_duid: Vec<u8>, // DHCPv6のサーバのDUID。scopes6がなければ空
+    database: String,         // 開いたリースの保存先(設定のdatabase)
+    ddns: Option<DdnsUpdater>, // 動的DNS更新の送り先。設定がなければNone
+}
+
+/**
+ * 設定とDBの予約からスコープを作る
+ */
+fn build_scopes(
+    config: &Config,
+    store: &dyn LeaseStore,
+) -> Result<Vec<Arc<Scope>>, failure::Error> {
+    let scopes = config
+        .scopes
+        .iter()
+        .map(|scope| Scope::from_config(scope, config.server_identifier, store))
+        .collect::<Result<Vec<Scope>, _>>()?;
+
+    // DBに登録された予約を加える。設定ファイルの予約と重なるものは設定ファイルを優先する
+    for reservation in store.select_reservations()? {
+        let ip_addr = reservation.ip_addr;
+        match scopes.iter().find(|scope| scope.network_addr.contains(ip_addr)) {
+            Some(scope) => {
+                if let Err(e) = scope.add_reservation(reservation) {
+                    warn!("reservation of {} in the database is ignored: {}", ip_addr, e);
+                }
+            }
+            None => warn!("reservation of {} in the database has no scope", ip_addr),
+        }
+    }
+    Ok(scopes.into_iter().map(Arc::new).collect())
+}
+
+fn build_scopes6(config: &Config) -> Result<Vec<Arc<Scope6>>, failure::Error> {
+    config
+        .scopes6
+        .iter()
+        .map(|scope| Scope6::from_config(scope).map(Arc::new))
+        .collect()
+}
+
+/**
+ * DHCPv4で待ち受けるインターフェース。インターフェースを指定したスコープがなければ全て(None)
+ */
+fn interfaces_of(scopes: &[Arc<Scope>]) -> Vec<Option<String>> {
+    let mut interfaces: Vec<Option<String>> = Vec::new();
+    for scope in scopes.iter() {
+        if scope.interface.is_some() && !interfaces.contains(&scope.interface) {
+            interfaces.push(scope.interface.clone());
+        }
+    }
+    if interfaces.is_empty() {
+        interfaces.push(None);
+    }
+    interfaces
+}
+
+/**
+ * DHCPv6で待ち受けるインターフェース。[[scope6]]がなければ空
+ */
+fn interfaces6_of(scopes6: &[Arc<Scope6>]) -> Vec<Option<String>> {
+    let mut interfaces: Vec<Option<String>> = Vec::new();
+    for scope in scopes6.iter() {
+        if !interfaces.contains(&scope.interface) {
+            interfaces.push(scope.interface.clone());
+        }
+    }
+    interfaces
+}
+
+impl DhcpServer {
+    /**
+     * 設定のdatabaseを開き(SQLiteならスキーマを最新にして)DHCPサーバを作る
+     */
+    pub fn new(config: &Config) -> Result<DhcpServer, failure::Error> {
+        let store = database::open_lease_store(&config.database)?;
+        Self::from_config(config, store)
     }
-}
\ No newline at end of file
+
+    /**
+     * 設定とリースの保存先からDHCPサーバを作る
+     */
+    pub fn from_config(
+        config: &Config,
+        store: Box<dyn LeaseStore>,
+    ) -> Result<DhcpServer, failure::Error> {
+        let scopes = build_scopes(config, store.as_ref())?;
+        let scopes6 = build_scopes6(config)?;
+        let server_duid = match scopes6.is_empty() {
+            true => Vec::new(),
+            false => config.server_duid()?,
+        };
+        let ddns = config.ddns.as_ref().map(DdnsUpdater::from_config).transpose()?;
+
+        Ok(DhcpServer {
+            scopes: RwLock::new(scopes),
+            lease_store: Mutex::new(store),
+            pending_offers: Mutex::new(OfferTable::default()),
+            decline_time: config.decline_time,
+            relay_policy: Box::new(AllowListPolicy),
+            scopes6: RwLock::new(scopes6),
+            server_duid,
+            database: config.database.clone(),
+            ddns,
+        })
+    }
+
+    /**
+     * 現在のスコープの一覧。設定を再読み込みしても、取得済みのスコープはそのまま使える
+     */
+    pub fn scopes(&self) -> Vec<Arc<Scope>> {
+        self.scopes.read().unwrap().clone()
+    }
+
+    pub fn scopes6(&self) -> Vec<Arc<Scope6>> {
+        self.scopes6.read().unwrap().clone()
+    }
+
+    /**
+     * DHCPv4で待ち受けるインターフェース。Noneは全てのインターフェース
+     */
+    pub fn interfaces(&self) -> Vec<Option<String>> {
+        interfaces_of(&self.scopes.read().unwrap())
+    }
+
+    /**
+     * DHCPv6で待ち受けるインターフェース
+     */
+    pub fn interfaces6(&self) -> Vec<Option<String>> {
+        interfaces6_of(&self.scopes6.read().unwrap())
+    }
+
+    /**
+     * 設定を読み込み直してスコープを差し替える。
+     * 有効なリースと提案中のIPアドレスは新しいアドレスプールからも除く。
+     *
+     * [note] ソケットやDBを開き直さないので、待ち受けるインターフェースやdecline_time、
+     * server_duid、database、ddnsの変更には再起動が必要。その場合はErrを返し、今の設定のまま動き続ける。
+     */
+    pub fn reload(&self, config: &Config) -> Result<(), failure::Error> {
+        if config.database != self.database {
+            return Err(failure::err_msg("changing database requires a restart"));
+        }
+        // 新しいアドレスプールを作る間にリースが変わらないようにDBのロックを取っておく
+        let store = self.lease_store.lock().unwrap();
+        let scopes = build_scopes(config, store.as_ref())?;
+        let scopes6 = build_scopes6(config)?;
+        if interfaces_of(&scopes) != self.interfaces()
+            || interfaces6_of(&scopes6) != self.interfaces6()
+        {
+            return Err(failure::err_msg("changing interfaces requires a restart"));
+        }
+        if config.decline_time != self.decline_time {
+            return Err(failure::err_msg("changing decline_time requires a restart"));
+        }
+        if !scopes6.is_empty() && config.server_duid()? != self.server_duid {
+            return Err(failure::err_msg("changing server_duid requires a restart"));
+        }
+        if config.ddns.as_ref().map(DdnsUpdater::from_config).transpose()? != self.ddns {
+            return Err(failure::err_msg("changing ddns requires a restart"));
+        }
+
+        let pending_offers = self.pending_offers.lock().unwrap();
+        for offer in pending_offers.offers.values().filter(|offer| offer.from_pool) {
+            let ip_addr = offer.ip_addr;
+            if let Some(scope) = scopes.iter().find(|scope| scope.network_addr.contains(ip_addr)) {
+                scope.pick_specified_ip(ip_addr);
+            }
+        }
+        *self.scopes.write().unwrap() = scopes;
+        *self.scopes6.write().unwrap() = scopes6;
+        info!("configuration reloaded");
+        Ok(())
+    }
+
+    /**
+     * 受信したパケットに応答するスコープを選ぶ。
+     * 1. リレーエージェントを経由した場合はgiaddrを含むサブネット
+     * 2. ciaddrがあればそれを含むサブネット(DHCPINFORMや延長要求のクライアント)
+     * 3. 受信したインターフェースのスコープ
+     * 4. インターフェースを指定していないスコープが1つだけならそれ
+     */
+    pub fn select_scope(
+        &self,
+        packet: &DhcpMessage,
+        interface: Option<&str>,
+    ) -> Option<Arc<Scope>> {
+        let giaddr = packet.giaddr;
+        if !giaddr.is_unspecified() {
+            return self.scope_of(giaddr);
+        }
+        let ciaddr = packet.ciaddr;
+        if !ciaddr.is_unspecified() {
+            if let Some(scope) = self.scope_of(ciaddr) {
+                return Some(scope);
+            }
+        }
+        let scopes = self.scopes.read().unwrap();
+        if let Some(interface) = interface {
+            if let Some(scope) = scopes
+                .iter()
+                .find(|scope| scope.interface.as_deref() == Some(interface))
+            {
+                return Some(scope.clone());
+            }
+        }
+        let mut unbound = scopes.iter().filter(|scope| scope.interface.is_none());
+        match (unbound.next(), unbound.next()) {
+            (Some(scope), None) => Some(scope.clone()),
+            _ => None,
+        }
+    }
+
+    /**
+     * IPアドレスを含むサブネットのスコープ
+     */
+    pub fn scope_of(&self, ip_addr: Ipv4Addr) -> Option<Arc<Scope>> {
+        self.scopes
+            .read()
+            .unwrap()
+            .iter()
+            .find(|scope| scope.network_addr.contains(ip_addr))
+            .cloned()
+    }
+
+    /**
+     * 期限切れのリースをDBから論理削除し、そのIPアドレスをアドレスプールに戻す。
+     * ホスト名のあるリースはDNSのレコードも削除する。回収したIPアドレスを返す。
+     */
+    pub fn reclaim_expired_leases(&self) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        let now = util::unix_time_now();
+        let (expired_addrs, hostnames) = {
+            let mut store = self.lease_store.lock().unwrap();
+            // 論理削除する前にホスト名を読んでおく
+            let hostnames: Vec<(Client, String, Ipv4Addr)> = match self.ddns {
+                Some(_) => store
+                    .select_lease_records()?
+                    .into_iter()
+                    .filter(|record| !record.deleted && record.lease_expiry <= now)
+                    .filter_map(|record| Some((record.client, record.hostname?, record.ip_addr)))
+                    .collect(),
+                None => Vec::new(),
+            };
+            (store.delete_expired_entries(now)?, hostnames)
+        };
+        for ip_addr in expired_addrs.iter() {
+            self.release_address(*ip_addr);
+        }
+        for (client, hostname, ip_addr) in hostnames.iter() {
+            self.unregister_dns(client, hostname, *ip_addr);
+        }
+        Ok(expired_addrs)
+    }
+
+    /**
+     * Client FQDN(オプション81)を送ってきたクライアントの名前をDNSに登録するか。
+     * 動的DNS更新の設定がないか、クライアントが更新しないよう求めた(Nフラグ)場合は登録しない。
+     */
+    pub fn updates_dns_for(&self, packet: &DhcpMessage) -> bool {
+        self.ddns.is_some()
+            && packet.client_fqdn().is_none_or(|fqdn| fqdn.flags & FQDN_N == 0)
+    }
+
+    /**
+     * Client FQDN(オプション81)への応答(RFC4702 4)。hostnameはリースに記録したホスト名。
+     * 名前を登録する場合はSを立て、クライアントがS=0で自分で更新するつもりだった場合はOも立てる。
+     * 登録しない場合はNを立て、クライアントの名前をそのまま返す。
+     */
+    pub fn client_fqdn_reply(
+        &self,
+        packet: &DhcpMessage,
+        hostname: Option<&str>,
+    ) -> Option<ClientFqdn> {
+        let requested = packet.client_fqdn()?;
+        let encoding = requested.flags & FQDN_E;
+        match (&self.ddns, hostname) {
+            (Some(ddns), Some(hostname)) if self.updates_dns_for(packet) => {
+                let overridden = match requested.flags & FQDN_S {
+                    0 => FQDN_O,
+                    _ => 0,
+                };
+                Some(ClientFqdn {
+                    flags: FQDN_S | overridden | encoding,
+                    name: format!("{}.", ddns.fqdn(hostname)),
+                })
+            }
+            _ => Some(ClientFqdn {
+                flags: FQDN_N | encoding,
+                name: requested.name.clone(),
+            }),
+        }
+    }
+
+    /**
+     * ホスト名のAレコードとリースしたIPアドレスのPTRレコードを登録する。動的DNS更新の設定がなければ何もしない。
+     *
+     * [note] DNSを更新できなくても(他のクライアントが使っている名前でも)リースは有効なので、
+     * 失敗はログに残すだけにする
+     */
+    pub fn register_dns(&self, client: &Client, hostname: &str, ip_addr: Ipv4Addr) {
+        if let Some(ddns) = &self.ddns {
+            match ddns.add_records(client, hostname, ip_addr) {
+                Ok(()) => info!("registered {} as {} in DNS", ddns.fqdn(hostname), ip_addr),
+                Err(e) => warn!("failed to register {} in DNS: {}", ddns.fqdn(hostname), e),
+            }
+        }
+    }
+
+    /**
+     * register_dnsで登録したレコードを削除する。動的DNS更新の設定がなければ何もしない
+     */
+    pub fn unregister_dns(&self, client: &Client, hostname: &str, ip_addr: Ipv4Addr) {
+        if let Some(ddns) = &self.ddns {
+            match ddns.remove_records(client, hostname, ip_addr) {
+                Ok(()) => info!("removed {} ({}) from DNS", ddns.fqdn(hostname), ip_addr),
+                Err(e) => warn!("failed to remove {} from DNS: {}", ddns.fqdn(hostname), e),
+            }
+        }
+    }
+
+    /**
+     * DHCPDECLINEで通知されたIPアドレスをdecline_timeの間割り当てないようにする。
+     * クライアントのリースは論理削除し、IPアドレスはアドレスプールに戻さない。
+     *
+     * [note] 他のクライアントのIPアドレスを使えなくさせないように、
+     * そのクライアントに提案中か有効なリースのIPアドレスだけを受け付ける。受け付けたかを返す。
+     */
+    pub fn decline_address(
+        &self,
+        client: &Client,
+        ip_addr: Ipv4Addr,
+    ) -> Result<bool, failure::Error> {
+        {
+            let now = util::unix_time_now();
+            let mut store = self.lease_store.lock().unwrap();
+            let leased = match store.select_lease_entry(client)? {
+                Some(entry) if entry.is_active(now) && entry.ip_addr == ip_addr => {
+                    store.delete_entry(client)?;
+                    true
+                }
+                _ => false,
+            };
+            if !leased && !self.pending_offers.lock().unwrap().remove_address(client, ip_addr) {
+                return Ok(false);
+            }
+            store.insert_conflict(ip_addr, client, now + self.decline_time as i64)?;
+        }
+        // まだアドレスプールにあれば取り除く
+        self.pick_specified_ip(ip_addr);
+        Ok(true)
+    }
+
+    /**
+     * 割り当てを控える期間の過ぎたIPアドレスをアドレスプールに戻し、それらを返す。
+     * 有効なリースのあるIPアドレスは戻さない。
+     */
+    pub fn release_expired_conflicts(&self) -> Result<Vec<Ipv4Addr>, failure::Error> {
+        let now = util::unix_time_now();
+        let released_addrs: Vec<Ipv4Addr> = {
+            let mut store = self.lease_store.lock().unwrap();
+            let expired_addrs = store.delete_expired_conflicts(now)?;
+            let records = store.select_lease_records()?;
+            expired_addrs
+                .into_iter()
+                .filter(|ip_addr| {
+                    !records.iter().any(|record| record.ip_addr == *ip_addr && record.is_active(now))
+                })
+                .collect()
+        };
+        for ip_addr in released_addrs.iter() {
+            self.release_address(*ip_addr);
+        }
+        Ok(released_addrs)
+    }
+
+    /**
+     * DHCPOFFERで提案したIPアドレスを記録する。
+     * from_poolはIPアドレスをアドレスプールから取り出したかどうか。
+     */
+    pub fn record_offer(&self, client: &Client, xid: u32, ip_addr: Ipv4Addr, from_pool: bool) {
+        let superseded = self.pending_offers.lock().unwrap().insert(
+            client,
+            xid,
+            ip_addr,
+            from_pool,
+            Instant::now(),
+        );
+        for ip_addr in superseded {
+            self.release_address(ip_addr);
+        }
+    }
+
+    /**
+     * 同じトランザクションで提案中のIPアドレスを返す(DHCPDISCOVERの再送に同じIPアドレスを提案するため)
+     */
+    pub fn pending_offer(&self, client: &Client, xid: u32) -> Option<Ipv4Addr> {
+        self.pending_offers
+            .lock()
+            .unwrap()
+            .get(client, xid)
+            .map(|offer| offer.ip_addr)
+    }
+
+    /**
+     * DHCPREQUESTで要求されたIPアドレスが提案中のものと一致するか確認し、提案を取り消す。
+     * 一致しない場合は提案していたIPアドレスをアドレスプールに戻してfalseを返す。
+     */
+    pub fn claim_offer(&self, client: &Client, xid: u32, requested_ip: Ipv4Addr) -> bool {
+        let claimed = self
+            .pending_offers
+            .lock()
+            .unwrap()
+            .claim(client, xid, requested_ip);
+        match claimed {
+            Ok(_) => true,
+            Err(released) => {
+                if let Some(ip_addr) = released {
+                    self.release_address(ip_addr);
+                }
+                false
+            }
+        }
+    }
+
+    /**
+     * クライアントが別のサーバを選んだ提案を取り消し、IPアドレスをすぐにアドレスプールに戻す。
+     */
+    pub fn cancel_offer(&self, client: &Client, xid: u32) {
+        let released = self.pending_offers.lock().unwrap().remove(client, xid);
+        if let Some(ip_addr) = released {
+            self.release_address(ip_addr);
+        }
+    }
+
+    /**
+     * 応答のないまま保持時間を過ぎた提案を取り消し、IPアドレスをアドレスプールに戻す。
+     */
+    pub fn expire_pending_offers(&self) -> Vec<Ipv4Addr> {
+        let released = self.pending_offers.lock().unwrap().expire(Instant::now());
+        for ip_addr in released.iter() {
+            self.release_address(*ip_addr);
+        }
+        released
+    }
+
+    /**
+     * アドレスプールから指定のIPアドレスを引き抜く。IPアドレスを含むスコープのアドレスプールから探す。
+     */
+    pub fn pick_specified_ip(&self, requested_ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        self.scope_of(requested_ip)?.pick_specified_ip(requested_ip)
+    }
+
+    /**
+     * IPアドレスを含むスコープのアドレスプールに返す
+     */
+    pub fn release_address(&self, released_ip: Ipv4Addr) {
+        if let Some(scope) = self.scope_of(released_ip) {
+            scope.release_address(released_ip);
+        }
+    }
+
+    /*
+     *
+     * 以降は管理APIからの操作。DBとアドレスプールを揃えて変更する。
+     *
+     */
+
+    /**
+     * スコープごとのアドレスプールの使用状況
+     */
+    pub fn pool_usage(&self) -> Result<Vec<PoolUsage>, failure::Error> {
+        let records = self.lease_store.lock().unwrap().select_lease_records()?;
+        let now = util::unix_time_now();
+        let usage = self
+            .scopes()
+            .iter()
+            .map(|scope| PoolUsage {
+                scope: scope.name.clone(),
+                network_addr: scope.network_addr,
+                total: scope
+                    .network_addr
+                    .iter()
+                    .filter(|addr| scope.is_assignable(*addr))
+                    .count(),
+                available: scope.address_pool.read().unwrap().len(),
+                leased: records
+                    .iter()
+                    .filter(|record| {
+                        record.is_active(now) && scope.network_addr.contains(record.ip_addr)
+                    })
+                    .count(),
+                reserved: scope.reservations.read().unwrap().len(),
+            })
+            .collect();
+        Ok(usage)
+    }
+
+    /**
+     * DHCPOFFERで提案中のIPアドレスを(クライアント, トランザクションID, 提案)の一覧で返す
+     */
+    pub fn pending_offers(&self) -> Vec<(Client, u32, PendingOffer)> {
+        self.pending_offers
+            .lock()
+            .unwrap()
+            .offers
+            .iter()
+            .map(|((client, xid), offer)| (client.clone(), *xid, *offer))
+            .collect()
+    }
+
+    /**
+     * クライアントの有効なリースを取り消し、IPアドレスをアドレスプールに戻す。
+     * DHCPRELEASEを受けたのと同じく論理削除するので、クライアントの延長要求にはDHCPNAKを返す。
+     * ホスト名を登録していたらDNSのレコードも削除する。有効なリースがなければNone。
+     */
+    pub fn revoke_lease(&self, client: &Client) -> Result<Option<Ipv4Addr>, failure::Error> {
+        let revoked = {
+            let mut store = self.lease_store.lock().unwrap();
+            match store.select_lease_entry(client)? {
+                Some(entry) if entry.is_active(util::unix_time_now()) => {
+                    store.delete_entry(client)?;
+                    Some(entry)
+                }
+                _ => None,
+            }
+        };
+        if let Some(entry) = &revoked {
+            self.release_address(entry.ip_addr);
+            if let Some(hostname) = &entry.hostname {
+                self.unregister_dns(client, hostname, entry.ip_addr);
+            }
+        }
+        Ok(revoked.map(|entry| entry.ip_addr))
+    }
+
+    /**
+     * 予約をDBに登録し、スコープに加える。
+     * 他のクライアントが有効なリースを持っているIPアドレスは予約できない。
+     */
+    pub fn add_reservation(&self, reservation: Reservation) -> Result<(), failure::Error> {
+        let ip_addr = reservation.ip_addr;
+        let scope = self
+            .scope_of(ip_addr)
+            .ok_or_else(|| failure::format_err!("no scope contains {}", ip_addr))?;
+        let network_addr = scope.network_addr;
+        if ip_addr == network_addr.network() || ip_addr == network_addr.broadcast() {
+            return Err(failure::format_err!(
+                "{} is not a host address of subnet {}",
+                ip_addr,
+                network_addr
+            ));
+        }
+        if ip_addr == scope.server_address || scope.routers.contains(&ip_addr) {
+            return Err(failure::format_err!(
+                "{} is the address of the server or a router",
+                ip_addr
+            ));
+        }
+
+        let mut store = self.lease_store.lock().unwrap();
+        let now = util::unix_time_now();
+        if let Some(lease) = store
+            .select_lease_records()?
+            .into_iter()
+            .find(|record| record.ip_addr == ip_addr && record.is_active(now))
+        {
+            if !reservation.owner.matches(&lease.client) {
+                return Err(failure::format_err!(
+                    "{} is leased to {}",
+                    ip_addr,
+                    lease.client
+                ));
+            }
+        }
+        store.insert_reservation(&reservation)?;
+        if let Err(e) = scope.add_reservation(reservation) {
+            // スコープに加えられなければDBの予約も取り消す
+            store.delete_reservation(ip_addr)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /**
+     * 予約を取り除き、DBからも削除する。取り除いた予約を返す。
+     * 有効なリースがなければIPアドレスはアドレスプールに戻す。
+     *
+     * [note] 設定ファイルの予約は設定を読み込み直すと元に戻る
+     */
+    pub fn remove_reservation(
+        &self,
+        ip_addr: Ipv4Addr,
+    ) -> Result<Option<Reservation>, failure::Error> {
+        let scope = match self.scope_of(ip_addr) {
+            Some(scope) => scope,
+            None => return Ok(None),
+        };
+        let leased = {
+            let mut store = self.lease_store.lock().unwrap();
+            store.delete_reservation(ip_addr)?;
+            let now = util::unix_time_now();
+            store
+                .select_lease_records()?
+                .iter()
+                .any(|record| record.ip_addr == ip_addr && record.is_active(now))
+        };
+        let removed = scope.remove_reservation(ip_addr);
+        if removed.is_some() && !leased {
+            scope.release_address(ip_addr);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DdnsConfig;
+    use crate::database::MemoryLeaseStore;
+    use crate::ddns::tests::stand_in_dns_server;
+    use crate::message::DhcpMessageBuilder;
+    use crate::test_support::{test_server, TEST_CONFIG};
+
+    const CLIENT_MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+    const OTHER_MAC: MacAddr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x66);
+    const OFFERED_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 10);
+
+    fn client() -> Client {
+        Client::from_mac_addr(CLIENT_MAC)
+    }
+
+    fn other_client() -> Client {
+        Client::from_mac_addr(OTHER_MAC)
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let message = |htype, hw_addr: &[u8], client_id: Option<Vec<u8>>| {
+            let options = client_id.map(DhcpOption::ClientIdentifier).into_iter().collect();
+            DhcpMessageBuilder::new(1).hardware_address(htype, hw_addr).options(options).build()
+        };
+        let ether = Client::from_message(&message(HTYPE_ETHER, &CLIENT_MAC.octets(), None));
+        assert_eq!(client(), ether);
+        assert_eq!(Some(CLIENT_MAC), ether.mac_addr());
+        // 長さ0のclient identifierはないものとする
+        let empty_id = message(HTYPE_ETHER, &CLIENT_MAC.octets(), Some(Vec::new()));
+        assert!(Client::from_message(&empty_id).is_same(&ether));
+
+        // client identifierがあればそれだけで比べる
+        let vm = Client::from_message(&message(HTYPE_ETHER, &CLIENT_MAC.octets(), Some(vec![1])));
+        assert!(!vm.is_same(&ether));
+        let moved = Client::from_message(&message(HTYPE_ETHER, &OTHER_MAC.octets(), Some(vec![1])));
+        assert!(vm.is_same(&moved));
+        let infiniband = Client::from_message(&message(32, &[], Some(vec![0xff, 0, 0, 0, 1])));
+        assert!(infiniband.hw_addr.is_empty());
+        assert_eq!(None, infiniband.mac_addr());
+        assert_eq!("00:11:22:33:44:55", ether.to_string());
+        assert_eq!("client_id ff00000001", infiniband.to_string());
+    }
+
+    #[test]
+    fn test_offer_table_claim() {
+        let mut offers = OfferTable::default();
+        let now = Instant::now();
+        offers.insert(&client(), 1, OFFERED_IP, true, now);
+        offers.insert(&other_client(), 1, Ipv4Addr::new(192, 168, 0, 11), true, now);
+
+        // トランザクションが違えば提案はない
+        assert_eq!(Err(None), offers.claim(&client(), 2, OFFERED_IP));
+        assert_eq!(OFFERED_IP, offers.claim(&client(), 1, OFFERED_IP).unwrap().ip_addr);
+        assert_eq!(Err(None), offers.claim(&client(), 1, OFFERED_IP));
+        // 提案と違うIPアドレスの要求は拒否し、提案していたIPアドレスを戻す
+        assert_eq!(
+            Err(Some(Ipv4Addr::new(192, 168, 0, 11))),
+            offers.claim(&other_client(), 1, OFFERED_IP)
+        );
+        assert!(offers.offers.is_empty());
+
+        // 別のサーバが選ばれた提案は取り消す。有効なリースを提案した場合は戻さない
+        offers.insert(&client(), 3, OFFERED_IP, true, now);
+        offers.insert(&other_client(), 3, Ipv4Addr::new(192, 168, 0, 11), false, now);
+        assert_eq!(None, offers.remove(&client(), 4));
+        assert_eq!(Some(OFFERED_IP), offers.remove(&client(), 3));
+        assert_eq!(None, offers.remove(&other_client(), 3));
+        assert!(offers.offers.is_empty());
+    }
+
+    #[test]
+    fn test_offer_table_supersede_and_expire() {
+        let mut offers = OfferTable::default();
+        let now = Instant::now();
+        assert!(offers.insert(&client(), 1, OFFERED_IP, true, now).is_empty());
+        // 同じトランザクションの再送では取り消さない
+        assert!(offers.insert(&client(), 1, OFFERED_IP, true, now).is_empty());
+        // 新しいトランザクションを始めたら以前の提案は取り消す
+        assert_eq!(
+            vec![OFFERED_IP],
+            offers.insert(&client(), 2, Ipv4Addr::new(192, 168, 0, 11), true, now)
+        );
+        // 有効なリースを提案した場合はアドレスプールに戻さない
+        offers.insert(&other_client(), 1, Ipv4Addr::new(192, 168, 0, 12), false, now);
+
+        assert!(offers.expire(now + OFFER_HOLD_TIME / 2).is_empty());
+        assert_eq!(
+            vec![Ipv4Addr::new(192, 168, 0, 11)],
+            offers.expire(now + OFFER_HOLD_TIME)
+        );
+        assert!(offers.offers.is_empty());
+    }
+
+    #[test]
+    fn test_decline_address_quarantines_until_expired() {
+        let server = test_server();
+        let ip_addr = server.scopes()[0].pick_available_ip().unwrap();
+        {
+            let mut store = server.lease_store.lock().unwrap();
+            store.insert_entry(&client(), ip_addr, 0, i64::MAX).unwrap();
+        }
+
+        // 他のクライアントに割り当てたIPアドレスは受け付けない
+        assert!(!server.decline_address(&other_client(), ip_addr).unwrap());
+        assert!(server.decline_address(&client(), ip_addr).unwrap());
+        {
+            let store = server.lease_store.lock().unwrap();
+            assert!(store.select_lease_entry(&client()).unwrap().unwrap().deleted);
+            assert_eq!(vec![ip_addr], store.select_conflicted_addresses().unwrap());
+        }
+        assert!(!server.scopes()[0].address_pool.read().unwrap().contains(&ip_addr));
+
+        // DECLINE_TIMEを過ぎたらアドレスプールに戻す
+        assert_eq!(vec![ip_addr], server.release_expired_conflicts().unwrap());
+        assert!(server.scopes()[0].address_pool.read().unwrap().contains(&ip_addr));
+
+        // 提案中のIPアドレスも受け付ける。その間に他のクライアントにリースしたら戻さない
+        let offered_ip = server.scopes()[0].pick_available_ip().unwrap();
+        server.record_offer(&client(), 1, offered_ip, true);
+        assert!(server.decline_address(&client(), offered_ip).unwrap());
+        assert!(server.pending_offer(&client(), 1).is_none());
+        {
+            let mut store = server.lease_store.lock().unwrap();
+            store.insert_entry(&other_client(), offered_ip, 0, i64::MAX).unwrap();
+        }
+        assert!(server.release_expired_conflicts().unwrap().is_empty());
+        assert!(!server.scopes()[0].address_pool.read().unwrap().contains(&offered_ip));
+    }
+
+    #[test]
+    fn test_scope_address_pool() {
+        let server = test_server();
+        let office = &server.scopes()[1];
+        let pool = office.address_pool.read().unwrap().clone();
+        // 割り当て範囲から除外アドレスを除いたもの。若い順に取り出す
+        let expected: Vec<Ipv4Addr> = (100..110)
+            .filter(|i| *i != 105)
+            .rev()
+            .map(|i| Ipv4Addr::new(10, 0, 1, i))
+            .collect();
+        assert_eq!(expected, pool);
+
+        // 範囲外や除外アドレスはアドレスプールに戻さない
+        office.release_address(Ipv4Addr::new(10, 0, 1, 105));
+        office.release_address(Ipv4Addr::new(10, 0, 1, 200));
+        assert_eq!(expected, *office.address_pool.read().unwrap());
+
+        // ルータ・DNSサーバ・server identifier・予約は範囲を指定しなくても割り当てない
+        let lan = &server.scopes()[0];
+        let unassignable = ["192.168.0.0", "192.168.0.1", "192.168.0.2", "192.168.0.3", "192.168.0.255"];
+        for ip_addr in unassignable {
+            assert!(!lan.is_assignable(ip_addr.parse().unwrap()));
+        }
+        assert_eq!(Some(Ipv4Addr::new(192, 168, 0, 4)), lan.pick_available_ip());
+    }
+
+    #[test]
+    fn test_select_scope() {
+        let mut config = Config::parse(TEST_CONFIG).unwrap();
+        config.scopes[1].interface = Some("eth1".to_string());
+        let store = Box::new(MemoryLeaseStore::default());
+        let server = DhcpServer::from_config(&config, store).unwrap();
+        let mut packet = DhcpMessageBuilder::new(1).build();
+        let scope_name = |packet: &DhcpMessage, interface| {
+            server.select_scope(packet, interface).map(|scope| scope.name.clone())
+        };
+
+        assert_eq!(Some("office"), scope_name(&packet, Some("eth1")).as_deref());
+        // インターフェースを指定していないスコープ
+        assert_eq!(Some("lan"), scope_name(&packet, Some("eth0")).as_deref());
+        assert_eq!(Some("lan"), scope_name(&packet, None).as_deref());
+
+        packet.ciaddr = Ipv4Addr::new(10, 0, 1, 100);
+        assert_eq!(Some("office"), scope_name(&packet, None).as_deref());
+        // リレーエージェントを経由した場合はgiaddrで選ぶ
+        packet.giaddr = Ipv4Addr::new(192, 168, 0, 254);
+        assert_eq!(Some("lan"), scope_name(&packet, Some("eth1")).as_deref());
+        packet.giaddr = Ipv4Addr::new(172, 16, 0, 1);
+        assert_eq!(None, scope_name(&packet, None).as_deref());
+    }
+
+    #[test]
+    fn test_reservations() {
+        let mut store = MemoryLeaseStore::default();
+        for (ip_addr, mac_addr, hostname) in [
+            ([192, 168, 0, 50], CLIENT_MAC, Some("router")),
+            ([192, 168, 0, 51], MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x77), None),
+        ] {
+            let reservation = Reservation {
+                owner: ReservationOwner::MacAddr(mac_addr),
+                ip_addr: Ipv4Addr::from(ip_addr),
+                hostname: hostname.map(String::from),
+                options: Vec::new(),
+            };
+            store.insert_reservation(&reservation).unwrap();
+        }
+        let config = Config::parse(TEST_CONFIG).unwrap();
+        let server = DhcpServer::from_config(&config, Box::new(store)).unwrap();
+        let lan = &server.scopes()[0];
+
+        // DBの予約もアドレスプールから除く
+        let pool = lan.address_pool.read().unwrap().clone();
+        for ip_addr in ["192.168.0.3", "192.168.0.50", "192.168.0.200"] {
+            assert!(!pool.contains(&ip_addr.parse().unwrap()));
+        }
+        // 設定ファイルで予約済みのクライアントのDBの予約は無視する
+        assert!(pool.contains(&Ipv4Addr::new(192, 168, 0, 51)));
+
+        let reservation = lan.find_reservation(&client()).unwrap();
+        assert_eq!(Ipv4Addr::new(192, 168, 0, 50), reservation.ip_addr);
+        assert_eq!(Some("router".to_string()), reservation.hostname);
+        let vm = Client {
+            client_id: Some(vec![0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x88]),
+            ..other_client()
+        };
+        assert_eq!(
+            Ipv4Addr::new(192, 168, 0, 200),
+            lan.find_reservation(&vm).unwrap().ip_addr
+        );
+        assert_eq!(None, lan.find_reservation(&other_client()));
+        // MACアドレスの予約はイーサネット以外のハードウェアには当てはまらない
+        let token_ring = Client {
+            htype: 6,
+            ..client()
+        };
+        assert_eq!(None, lan.find_reservation(&token_ring));
+
+        // 予約されたIPアドレスは解放されてもアドレスプールに戻さない
+        lan.release_address(Ipv4Addr::new(192, 168, 0, 50));
+        assert!(!lan.address_pool.read().unwrap().contains(&Ipv4Addr::new(192, 168, 0, 50)));
+    }
+
+    #[test]
+    fn test_revoke_lease_and_reservations() {
+        let server = test_server();
+        let lan = &server.scopes()[0];
+        let ip_addr = lan.pick_available_ip().unwrap();
+        {
+            let mut store = server.lease_store.lock().unwrap();
+            store.insert_entry(&client(), ip_addr, 0, i64::MAX).unwrap();
+        }
+        let reservation = |mac_addr| Reservation {
+            owner: ReservationOwner::MacAddr(mac_addr),
+            ip_addr,
+            hostname: None,
+            options: Vec::new(),
+        };
+        // 他のクライアントが使っているIPアドレスは予約できない
+        let e = server.add_reservation(reservation(OTHER_MAC)).unwrap_err();
+        assert!(e.to_string().contains("is leased to"));
+
+        assert_eq!(Some(ip_addr), server.revoke_lease(&client()).unwrap());
+        assert_eq!(None, server.revoke_lease(&client()).unwrap());
+        assert!(lan.address_pool.read().unwrap().contains(&ip_addr));
+        let usage = server.pool_usage().unwrap()[0].clone();
+        assert_eq!(0, usage.leased);
+
+        server.add_reservation(reservation(OTHER_MAC)).unwrap();
+        assert!(!lan.address_pool.read().unwrap().contains(&ip_addr));
+        assert_eq!(Some(reservation(OTHER_MAC)), lan.find_reservation(&other_client()));
+        assert!(server.add_reservation(reservation(CLIENT_MAC)).is_err());
+        {
+            let store = server.lease_store.lock().unwrap();
+            let reservations = store.select_reservations().unwrap();
+            assert_eq!(vec![reservation(OTHER_MAC)], reservations);
+        }
+        let reserved = server.pool_usage().unwrap()[0].clone();
+        assert_eq!(usage.total - 1, reserved.total);
+        assert_eq!(usage.reserved + 1, reserved.reserved);
+
+        assert_eq!(Some(reservation(OTHER_MAC)), server.remove_reservation(ip_addr).unwrap());
+        assert_eq!(None, server.remove_reservation(ip_addr).unwrap());
+        assert!(lan.address_pool.read().unwrap().contains(&ip_addr));
+        let store = server.lease_store.lock().unwrap();
+        assert!(store.select_reservations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dynamic_dns() {
+        let (dns_server, received) = stand_in_dns_server(None, 0);
+        let mut config = Config::parse(TEST_CONFIG).unwrap();
+        config.ddns = Some(DdnsConfig {
+            server: dns_server,
+            forward_zone: "example.lan".to_string(),
+            reverse_zone: None,
+            ttl: 300,
+            tsig: None,
+        });
+        let server =
+            DhcpServer::from_config(&config, Box::new(MemoryLeaseStore::default())).unwrap();
+        let lan = &server.scopes()[0];
+        let message = |mac_addr: MacAddr, options| {
+            DhcpMessageBuilder::new(1)
+                .hardware_address(HTYPE_ETHER, &mac_addr.octets())
+                .options(options)
+                .build()
+        };
+        let fqdn = |flags, name: &str| ClientFqdn { flags, name: name.to_string() };
+
+        // 予約のホスト名、Client FQDN、Host Nameの順に使う
+        let host_name = DhcpOption::HostName("PC-1".to_string());
+        let packet = message(CLIENT_MAC, vec![host_name.clone()]);
+        assert_eq!(Some("pc-1".to_string()), lan.lease_hostname(&packet));
+        assert_eq!(None, server.client_fqdn_reply(&packet, Some("pc-1")));
+        let options = vec![host_name.clone(), DhcpOption::ClientFqdn(fqdn(FQDN_S, "laptop."))];
+        let packet = message(CLIENT_MAC, options);
+        assert_eq!(Some("laptop".to_string()), lan.lease_hostname(&packet));
+        let printer = message(MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x77), vec![host_name]);
+        assert_eq!(Some("printer".to_string()), lan.lease_hostname(&printer));
+        let invalid = message(CLIENT_MAC, vec![DhcpOption::HostName("my pc".to_string())]);
+        assert_eq!(None, lan.lease_hostname(&invalid));
+
+        // サーバが登録する。クライアントが自分で更新するつもりだった場合はOも立てる
+        assert!(server.updates_dns_for(&packet));
+        assert_eq!(
+            Some(fqdn(FQDN_S, "laptop.example.lan.")),
+            server.client_fqdn_reply(&packet, Some("laptop"))
+        );
+        let options = vec![DhcpOption::ClientFqdn(fqdn(FQDN_E, "laptop"))];
+        assert_eq!(
+            Some(fqdn(FQDN_S | FQDN_O | FQDN_E, "laptop.example.lan.")),
+            server.client_fqdn_reply(&message(CLIENT_MAC, options), Some("laptop"))
+        );
+        // 更新しないよう求められたら登録しない
+        let options = vec![DhcpOption::ClientFqdn(fqdn(FQDN_N, "laptop"))];
+        let no_update = message(CLIENT_MAC, options);
+        assert!(!server.updates_dns_for(&no_update));
+        assert_eq!(
+            Some(fqdn(FQDN_N, "laptop")),
+            server.client_fqdn_reply(&no_update, Some("laptop"))
+        );
+        assert!(!test_server().updates_dns_for(&packet));
+
+        // 取り消したリースと期限切れのリースのレコードは削除する
+        let now = util::unix_time_now();
+        {
+            let mut store = server.lease_store.lock().unwrap();
+            store.insert_entry(&client(), OFFERED_IP, now, now + 300).unwrap();
+            store.update_hostname(&client(), Some("laptop")).unwrap();
+            let expired_ip = Ipv4Addr::new(192, 168, 0, 11);
+            store.insert_entry(&other_client(), expired_ip, now - 300, now - 1).unwrap();
+            store.update_hostname(&other_client(), Some("tablet")).unwrap();
+        }
+        server.register_dns(&client(), "laptop", OFFERED_IP);
+        server.register_dns(&other_client(), "tablet", Ipv4Addr::new(192, 168, 0, 11));
+        let added: Vec<_> = received.iter().take(4).collect();
+        assert_eq!("example.lan", added[0].zone);
+        assert_eq!(2, added[0].updates.len());
+        assert_eq!("0.168.192.in-addr.arpa", added[1].zone);
+        // 他のクライアントが使っている名前は登録しない
+        server.register_dns(&other_client(), "laptop", Ipv4Addr::new(192, 168, 0, 12));
+        let refused: Vec<_> = received.iter().take(2).collect();
+        assert!(refused.iter().all(|message| message.zone == "example.lan"));
+
+        // 正引きはAとDHCIDのレコードを2回に分けて削除する
+        assert_eq!(Some(OFFERED_IP), server.revoke_lease(&client()).unwrap());
+        let removed: Vec<_> = received.iter().take(3).collect();
+        assert_eq!("laptop.example.lan", removed[0].updates[0].name);
+        assert_eq!(1, removed[0].updates.len());
+        assert_eq!("laptop.example.lan", removed[1].updates[0].name);
+        assert_eq!("10.0.168.192.in-addr.arpa", removed[2].updates[0].name);
+
+        let expired_ip = Ipv4Addr::new(192, 168, 0, 11);
+        assert_eq!(vec![expired_ip], server.reclaim_expired_leases().unwrap());
+        let removed: Vec<_> = received.iter().take(3).collect();
+        assert_eq!("tablet.example.lan", removed[0].updates[0].name);
+        assert_eq!("11.0.168.192.in-addr.arpa", removed[2].updates[0].name);
+    }
+
+    #[test]
+    fn test_reload() {
+        let server = test_server();
+        let old_office = server.scopes()[1].clone();
+        let offered = old_office.pick_available_ip().unwrap();
+        server.record_offer(&client(), 1, offered, true);
+
+        let mut config = Config::parse(TEST_CONFIG).unwrap();
+        config.scopes[1].lease_time = 600;
+        server.reload(&config).unwrap();
+        let office = &server.scopes()[1];
+        assert_eq!(600, office.lease_time);
+        // 提案中のIPアドレスは新しいアドレスプールにも入れない
+        assert!(!office.address_pool.read().unwrap().contains(&offered));
+        // 取得済みのスコープは元のまま
+        assert_ne!(600, old_office.lease_time);
+
+        // 再起動の必要な変更は反映しない
+        config.decline_time += 1;
+        assert!(server.reload(&config).unwrap_err().to_string().contains("requires a restart"));
+        config.decline_time -= 1;
+        config.scopes[1].interface = Some("eth1".to_string());
+        config.scopes[1].lease_time = 900;
+        assert!(server.reload(&config).is_err());
+        assert_eq!(600, server.scopes()[1].lease_time);
+    }
+}
@@ -0,0 +1,975 @@
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+
+use ipnetwork::Ipv6Network;
+
+use super::config::{AddressRange6, Scope6Config};
+use super::database::LeaseStore;
+use super::dhcp::DhcpServer;
+use super::message6::{self, Dhcpv6Message, Dhcpv6Option, Ia, IaAddress, IaPrefix};
+use super::util;
+
+// 割り当てるアドレスを探す時に調べる候補の数の上限。
+// [note] IPv6のアドレス範囲はアドレスプールとして持てないほど広いので、
+// DUIDとIAIDから決めた位置から空いているものを探す。
+const MAX_PROBES: u128 = 1024;
+
+/**
+ * IAの種類。同じIAIDでもIA_NAとIA_PDは別のバインディング
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IaType {
+    Na, // 非一時アドレス(IA_NA)
+    Pd, // プレフィックス委譲(IA_PD)
+}
+
+impl IaType {
+    /**
+     * DBのia_typeカラムの値
+     */
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IaType::Na => "na",
+            IaType::Pd => "pd",
+        }
+    }
+
+    /**
+     * 割り当てられるものがない時にIAに入れるステータスコード
+     */
+    fn unavailable_status(&self) -> (u16, &'static str) {
+        match self {
+            IaType::Na => (message6::STATUS_NO_ADDRS_AVAIL, "no addresses available"),
+            IaType::Pd => (message6::STATUS_NO_PREFIX_AVAIL, "no prefixes available"),
+        }
+    }
+}
+
+/**
+ * DHCPv6で1つのリンク(プレフィックス)に対する割り当ての情報
+ */
+pub struct Scope6 {
+    pub name: String,
+    pub interface: Option<String>,
+    pub prefix: Ipv6Network,
+    ranges: Vec<AddressRange6>,
+    pub dns_servers: Vec<Ipv6Addr>,
+    pub domain_search: Vec<String>,
+    pub preferred_lifetime: u32, // 秒
+    pub valid_lifetime: u32,     // 秒。リースの期限はこれで決める
+    pub rapid_commit: bool,
+    pub pd_pool: Option<Ipv6Network>, // 委譲するプレフィックスを切り出すプール
+    pub pd_prefix_length: u8,
+}
+
+impl Scope6 {
+    /**
+     * 検証済みの設定からスコープを作る
+     */
+    pub fn from_config(config: &Scope6Config) -> Result<Scope6, failure::Error> {
+        Ok(Scope6 {
+            name: config.name.clone(),
+            interface: config.interface.clone(),
+            prefix: config.prefix()?,
+            ranges: config.ranges.clone(),
+            dns_servers: config.dns_servers.clone(),
+            domain_search: config.domain_search.clone(),
+            preferred_lifetime: config.preferred_lifetime,
+            valid_lifetime: config.valid_lifetime,
+            rapid_commit: config.rapid_commit,
+            pd_pool: config.pd_pool()?,
+            pd_prefix_length: config.pd_prefix_length,
+        })
+    }
+
+    /**
+     * T1: クライアントが割り当てたサーバへ延長(Renew)を要求し始めるまでの時間(秒)
+     * RFC8415 21.4 の推奨値である推奨期間の0.5倍
+     */
+    pub fn renewal_time(&self) -> u32 {
+        self.preferred_lifetime / 2
+    }
+
+    /**
+     * T2: クライアントが任意のサーバへ延長(Rebind)を要求し始めるまでの時間(秒)
+     * RFC8415 21.4 の推奨値である推奨期間の0.8倍
+     */
+    pub fn rebinding_time(&self) -> u32 {
+        (self.preferred_lifetime as u64 * 4 / 5) as u32
+    }
+
+    /**
+     * 現在時刻から始まるリースの(開始, 期限)をUNIX時間で返す
+     */
+    pub fn new_lease_period(&self) -> (i64, i64) {
+        let now = util::unix_time_now();
+        (now, now + self.valid_lifetime as i64)
+    }
+
+    /**
+     * 割り当て範囲の(先頭, 個数)の一覧。
+     * 範囲を指定していなければプレフィックスのSubnet-Router anycastアドレス(先頭)を除く全て。
+     */
+    fn address_ranges(&self) -> Vec<(u128, u128)> {
+        if self.ranges.is_empty() {
+            let network = u128::from(self.prefix.network());
+            return vec![(network + 1, u128::MAX >> self.prefix.prefix())];
+        }
+        self.ranges
+            .iter()
+            .map(|range| {
+                let start = u128::from(range.start);
+                (start, u128::from(range.end) - start + 1)
+            })
+            .collect()
+    }
+
+    /**
+     * 割り当てられるアドレス(IA_NA)かプレフィックス(IA_PD)の数
+     */
+    fn lease_count(&self, ia_type: IaType) -> u128 {
+        match (ia_type, self.pd_pool) {
+            (IaType::Na, _) => self
+                .address_ranges()
+                .iter()
+                .fold(0u128, |count, (_, len)| count.saturating_add(*len)),
+            (IaType::Pd, Some(pool)) => 1 << (self.pd_prefix_length - pool.prefix()),
+            (IaType::Pd, None) => 0,
+        }
+    }
+
+    /**
+     * 割り当てられるもののうちindex番目。IA_NAのアドレスはプレフィックス長128で表す。
+     */
+    fn nth_lease(&self, ia_type: IaType, mut index: u128) -> Option<Ipv6Network> {
+        match ia_type {
+            IaType::Na => {
+                for (start, len) in self.address_ranges() {
+                    if index < len {
+                        return Some(Ipv6Addr::from(start + index).into());
+                    }
+                    index -= len;
+                }
+                None
+            }
+            IaType::Pd => {
+                let pool = self.pd_pool?;
+                let offset = index.checked_shl(128 - self.pd_prefix_length as u32)?;
+                let prefix = Ipv6Addr::from(u128::from(pool.network()) + offset);
+                Ipv6Network::new(prefix, self.pd_prefix_length).ok()
+            }
+        }
+    }
+
+    /**
+     * このスコープで割り当ててよいアドレス(プレフィックス)か。
+     * 設定の変更で範囲から外れたものは延長しない。
+     */
+    pub fn is_assignable(&self, ia_type: IaType, lease: Ipv6Network) -> bool {
+        match ia_type {
+            IaType::Na => {
+                let address = u128::from(lease.ip());
+                lease.prefix() == 128
+                    && self
+                        .address_ranges()
+                        .iter()
+                        .any(|(start, len)| *start <= address && address - start < *len)
+            }
+            IaType::Pd => self.pd_pool.is_some_and(|pool| {
+                lease.prefix() == self.pd_prefix_length
+                    && lease.ip() == lease.network()
+                    && pool.contains(lease.ip())
+            }),
+        }
+    }
+
+    /**
+     * IAADDRかIAPREFIXのオプションにする
+     */
+    fn lease_option(&self, ia_type: IaType, lease: Ipv6Network, valid: bool) -> Dhcpv6Option {
+        // 使わせないものは期間を0にして返す(RFC8415 18.3.4)
+        let (preferred_lifetime, valid_lifetime) = match valid {
+            true => (self.preferred_lifetime, self.valid_lifetime),
+            false => (0, 0),
+        };
+        match ia_type {
+            IaType::Na => Dhcpv6Option::IaAddr(IaAddress {
+                address: lease.ip(),
+                preferred_lifetime,
+                valid_lifetime,
+                options: Vec::new(),
+            }),
+            IaType::Pd => Dhcpv6Option::IaPrefix(IaPrefix {
+                preferred_lifetime,
+                valid_lifetime,
+                prefix_len: lease.prefix(),
+                prefix: lease.ip(),
+                options: Vec::new(),
+            }),
+        }
+    }
+}
+
+/**
+ * 受信したインターフェースのスコープを選ぶ。
+ * なければインターフェースを指定していないスコープが1つだけならそれ
+ */
+pub fn select_scope(server: &DhcpServer, interface: Option<&str>) -> Option<Arc<Scope6>> {
+    let scopes6 = server.scopes6();
+    if let Some(interface) = interface {
+        if let Some(scope) = scopes6
+            .iter()
+            .find(|scope| scope.interface.as_deref() == Some(interface))
+        {
+            return Some(scope.clone());
+        }
+    }
+    let mut unbound = scopes6.iter().filter(|scope| scope.interface.is_none());
+    match (unbound.next(), unbound.next()) {
+        (Some(scope), None) => Some(scope.clone()),
+        _ => None,
+    }
+}
+
+/**
+ * DHCPv6のリクエストを処理して応答を返す。応答しない場合はNone。
+ */
+pub fn handle_message(
+    server: &DhcpServer,
+    request: &Dhcpv6Message,
+    interface: Option<&str>,
+) -> Result<Option<Dhcpv6Message>, failure::Error> {
+    let xid = request.transaction_id;
+    let scope = &select_scope(server, interface).ok_or_else(|| {
+        failure::format_err!("{:x}: no scope6 for the request (interface: {:?})", xid, interface)
+    })?;
+    if !is_for_this_server(server, request) {
+        // クライアントが別のサーバを選んだ場合や、必要なDUIDがないメッセージ(RFC8415 16)
+        info!("{:x}: message type {} is not for this server", xid, request.msg_type);
+        return Ok(None);
+    }
+
+    let reply = match request.msg_type {
+        message6::SOLICIT if scope.rapid_commit && request.has_rapid_commit() => {
+            info!("{:x}: received Solicit with Rapid Commit", xid);
+            let mut reply = assign_leases(server, scope, request, message6::REPLY, true)?;
+            reply.options.push(Dhcpv6Option::RapidCommit);
+            reply
+        }
+        message6::SOLICIT => {
+            info!("{:x}: received Solicit", xid);
+            // Advertiseでは提案するだけで、Requestが来るまで記録しない
+            assign_leases(server, scope, request, message6::ADVERTISE, false)?
+        }
+        message6::REQUEST => {
+            info!("{:x}: received Request", xid);
+            assign_leases(server, scope, request, message6::REPLY, true)?
+        }
+        message6::CONFIRM => {
+            info!("{:x}: received Confirm", xid);
+            match confirm_leases(server, scope, request) {
+                Some(reply) => reply,
+                None => return Ok(None),
+            }
+        }
+        message6::RENEW | message6::REBIND => {
+            info!("{:x}: received Renew/Rebind", xid);
+            extend_leases(server, scope, request)?
+        }
+        message6::RELEASE => {
+            info!("{:x}: received Release", xid);
+            release_leases(server, scope, request)?
+        }
+        message6::DECLINE => {
+            info!("{:x}: received Decline", xid);
+            decline_leases(server, scope, request)?
+        }
+        message6::INFORMATION_REQUEST => {
+            info!("{:x}: received Information-request", xid);
+            // 設定だけを返し、IAは扱わない
+            make_reply(server, scope, request, message6::REPLY, Vec::new(), true)
+        }
+        msg_type => {
+            return Err(failure::format_err!(
+                "{:x}: received unimplemented message, message_type:{}",
+                xid,
+                msg_type
+            ))
+        }
+    };
+    Ok(Some(reply))
+}
+
+/**
+ * Client IdentifierとServer Identifierの有無がメッセージタイプの要件を満たすか(RFC8415 16)。
+ * Server Identifierはこのサーバのものでなければならない。
+ */
+fn is_for_this_server(server: &DhcpServer, request: &Dhcpv6Message) -> bool {
+    let has_client_id = request.client_id().is_some();
+    let server_id = request.server_id();
+    let is_ours = server_id == Some(server.server_duid.as_slice());
+    match request.msg_type {
+        message6::SOLICIT | message6::CONFIRM | message6::REBIND => {
+            has_client_id && server_id.is_none()
+        }
+        message6::REQUEST | message6::RENEW | message6::RELEASE | message6::DECLINE => {
+            has_client_id && is_ours
+        }
+        message6::INFORMATION_REQUEST => server_id.is_none() || is_ours,
+        _ => true,
+    }
+}
+
+/**
+ * リクエストに含まれるIA_NAとIA_PD
+ */
+fn requested_ias(request: &Dhcpv6Message) -> Vec<(IaType, &Ia)> {
+    request
+        .options
+        .iter()
+        .filter_map(|option| match option {
+            Dhcpv6Option::IaNa(ia) => Some((IaType::Na, ia)),
+            Dhcpv6Option::IaPd(ia) => Some((IaType::Pd, ia)),
+            _ => None,
+        })
+        .collect()
+}
+
+/**
+ * クライアントがIAに入れてきたアドレス(プレフィックス)
+ */
+fn leases_in_ia(ia_type: IaType, ia: &Ia) -> Vec<Ipv6Network> {
+    match ia_type {
+        IaType::Na => ia.addresses().map(|address| address.address.into()).collect(),
+        IaType::Pd => ia
+            .prefixes()
+            .filter_map(|prefix| Ipv6Network::new(prefix.prefix, prefix.prefix_len).ok())
+            .collect(),
+    }
+}
+
+/**
+ * 応答に入れるIAを作る。T1/T2は割り当てるものがある場合だけ設定する
+ */
+fn ia_option(
+    scope: &Scope6,
+    ia_type: IaType,
+    iaid: u32,
+    options: Vec<Dhcpv6Option>,
+) -> Dhcpv6Option {
+    let has_lease = options
+        .iter()
+        .any(|option| matches!(option, Dhcpv6Option::IaAddr(_) | Dhcpv6Option::IaPrefix(_)));
+    let (t1, t2) = match has_lease {
+        true => (scope.renewal_time(), scope.rebinding_time()),
+        false => (0, 0),
+    };
+    let ia = Ia {
+        iaid,
+        t1,
+        t2,
+        options,
+    };
+    match ia_type {
+        IaType::Na => Dhcpv6Option::IaNa(ia),
+        IaType::Pd => Dhcpv6Option::IaPd(ia),
+    }
+}
+
+fn status_option((status, message): (u16, &str)) -> Dhcpv6Option {
+    Dhcpv6Option::StatusCode(status, message.to_string())
+}
+
+/**
+ * 応答を作る。with_configがtrueならORO(Option Request)で要求された設定のオプションを含める。
+ */
+fn make_reply(
+    server: &DhcpServer,
+    scope: &Scope6,
+    request: &Dhcpv6Message,
+    msg_type: u8,
+    ias: Vec<Dhcpv6Option>,
+    with_config: bool,
+) -> Dhcpv6Message {
+    let mut options = vec![Dhcpv6Option::ServerId(server.server_duid.clone())];
+    if let Some(client_id) = request.client_id() {
+        options.push(Dhcpv6Option::ClientId(client_id.to_vec()));
+    }
+    options.extend(ias);
+    if with_config {
+        let requested = request.requested_options();
+        if requested.contains(&message6::OPTION_DNS_SERVERS) && !scope.dns_servers.is_empty() {
+            options.push(Dhcpv6Option::DnsServers(scope.dns_servers.clone()));
+        }
+        if requested.contains(&message6::OPTION_DOMAIN_LIST) && !scope.domain_search.is_empty() {
+            options.push(Dhcpv6Option::DomainList(scope.domain_search.clone()));
+        }
+    }
+    Dhcpv6Message::new(msg_type, request.transaction_id, options)
+}
+
+/**
+ * Solicit・RequestのIAにアドレス(プレフィックス)を割り当てる。
+ * commitがtrueならバインディングをDBに記録する。
+ */
+fn assign_leases(
+    server: &DhcpServer,
+    scope: &Scope6,
+    request: &Dhcpv6Message,
+    msg_type: u8,
+    commit: bool,
+) -> Result<Dhcpv6Message, failure::Error> {
+    let xid = request.transaction_id;
+    let duid = request.client_id().unwrap_or_default();
+    let mut ias = Vec::new();
+    let mut store = server.lease_store.lock().unwrap();
+    for (ia_type, ia) in requested_ias(request) {
+        let hints = leases_in_ia(ia_type, ia);
+        let options = match select_lease(store.as_ref(), scope, duid, ia.iaid, ia_type, &hints)? {
+            Some(lease) => {
+                if commit {
+                    let lease_period = scope.new_lease_period();
+                    store.upsert_lease6_entry(duid, ia.iaid, ia_type, lease, lease_period)?;
+                    debug!("{:x}: leased {} to iaid {}", xid, lease, ia.iaid);
+                }
+                vec![scope.lease_option(ia_type, lease, true)]
+            }
+            None => {
+                info!(
+                    "{:x}: scope6 {} has nothing to assign to iaid {} ({:?})",
+                    xid, scope.name, ia.iaid, ia_type
+                );
+                vec![status_option(ia_type.unavailable_status())]
+            }
+        };
+        ias.push(ia_option(scope, ia_type, ia.iaid, options));
+    }
+    Ok(make_reply(server, scope, request, msg_type, ias, true))
+}
+
+/**
+ * バインディングに割り当てるアドレス(プレフィックス)を選ぶ。
+ * 1.以前そのバインディングに割り当てたもの(解放されたものも含め)
+ * 2.クライアントがIAに入れて希望したもの
+ * 3.DUIDとIAIDのハッシュで決めた位置から順に探して、最初に空いていたもの
+ * の優先順位で、他のバインディングが使っておらずDECLINEもされていないものを返す。
+ */
+fn select_lease(
+    store: &dyn LeaseStore,
+    scope: &Scope6,
+    duid: &[u8],
+    iaid: u32,
+    ia_type: IaType,
+    hints: &[Ipv6Network],
+) -> Result<Option<Ipv6Network>, failure::Error> {
+    let now = util::unix_time_now();
+    let is_available = |lease: Ipv6Network| -> Result<bool, failure::Error> {
+        Ok(scope.is_assignable(ia_type, lease)
+            && !store.is_address6_in_use(lease.ip(), duid, iaid, now)?)
+    };
+
+    // 1. 以前のバインディング
+    let previous = store.select_lease6_entry(duid, iaid, ia_type)?;
+    // 2. クライアントの希望
+    let candidates = previous.map(|entry| entry.prefix).into_iter().chain(hints.iter().copied());
+    for lease in candidates {
+        if is_available(lease)? {
+            return Ok(Some(lease));
+        }
+    }
+
+    // 3. 同じクライアントには同じものを割り当てやすいように、探し始める位置をハッシュで決める
+    let count = scope.lease_count(ia_type);
+    if count == 0 {
+        return Ok(None);
+    }
+    let start = binding_hash(duid, iaid, ia_type) as u128 % count;
+    for i in 0..count.min(MAX_PROBES) {
+        let index = (start + i) % count;
+        if let Some(lease) = scope.nth_lease(ia_type, index) {
+            if is_available(lease)? {
+                return Ok(Some(lease));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/**
+ * バインディングのハッシュ(FNV-1a)。再起動しても変わらないように標準のHasherは使わない
+ */
+fn binding_hash(duid: &[u8], iaid: u32, ia_type: IaType) -> u64 {
+    let iaid = iaid.to_be_bytes();
+    let bytes = duid.iter().chain(iaid.iter()).chain(ia_type.as_str().as_bytes());
+    bytes.fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/**
+ * Confirmで送られたアドレスがこのリンクで使えるか(プレフィックスに含まれるか)を返す(RFC8415 18.3.3)。
+ * アドレスが1つもなければ応答しない。
+ */
+fn confirm_leases(
+    server: &DhcpServer,
+    scope: &Scope6,
+    request: &Dhcpv6Message,
+) -> Option<Dhcpv6Message> {
+    let addresses: Vec<Ipv6Network> = requested_ias(request)
+        .into_iter()
+        .filter(|(ia_type, _)| *ia_type == IaType::Na)
+        .flat_map(|(ia_type, ia)| leases_in_ia(ia_type, ia))
+        .collect();
+    if addresses.is_empty() {
+        return None;
+    }
+    let status = match addresses.iter().all(|address| scope.prefix.contains(address.ip())) {
+        true => (message6::STATUS_SUCCESS, "all addresses are on link"),
+        false => (message6::STATUS_NOT_ON_LINK, "some addresses are not on link"),
+    };
+    let options = vec![status_option(status)];
+    Some(make_reply(server, scope, request, message6::REPLY, options, false))
+}
+
+/**
+ * Renew・Rebindで有効なバインディングの期限を延長する。
+ * バインディングがなければそのIAにNoBindingを返す(RFC8415 18.3.4, 18.3.5)。
+ */
+fn extend_leases(
+    server: &DhcpServer,
+    scope: &Scope6,
+    request: &Dhcpv6Message,
+) -> Result<Dhcpv6Message, failure::Error> {
+    let xid = request.transaction_id;
+    let duid = request.client_id().unwrap_or_default();
+    let now = util::unix_time_now();
+    let mut ias = Vec::new();
+    let mut store = server.lease_store.lock().unwrap();
+    for (ia_type, ia) in requested_ias(request) {
+        let entry = store.select_lease6_entry(duid, ia.iaid, ia_type)?;
+        let lease = match entry {
+            Some(entry) if entry.is_active(now) => entry.prefix,
+            _ => {
+                info!("{:x}: no binding of iaid {} ({:?})", xid, ia.iaid, ia_type);
+                let status = (message6::STATUS_NO_BINDING, "no binding");
+                ias.push(ia_option(scope, ia_type, ia.iaid, vec![status_option(status)]));
+                continue;
+            }
+        };
+        let mut options = Vec::new();
+        if scope.is_assignable(ia_type, lease) {
+            let (lease_start, lease_expiry) = scope.new_lease_period();
+            let lease_period = (lease_start, lease_expiry);
+            store.upsert_lease6_entry(duid, ia.iaid, ia_type, lease, lease_period)?;
+            debug!("{:x}: extended lease of {} until {}", xid, lease, lease_expiry);
+            options.push(scope.lease_option(ia_type, lease, true));
+        } else {
+            // 設定の変更で範囲から外れたものは使わせない
+            store.delete_lease6_entry(duid, ia.iaid, ia_type)?;
+            options.push(scope.lease_option(ia_type, lease, false));
+        }
+        // バインディングと違うアドレスを使い続けようとしている場合は使わせない
+        for other in leases_in_ia(ia_type, ia).into_iter().filter(|other| *other != lease) {
+            options.push(scope.lease_option(ia_type, other, false));
+        }
+        ias.push(ia_option(scope, ia_type, ia.iaid, options));
+    }
+    Ok(make_reply(server, scope, request, message6::REPLY, ias, true))
+}
+
+/**
+ * Releaseされたバインディングを論理削除する。
+ * 論理削除にするのは、同じクライアントが再び来た時に同じものを割り当てられるようにするため。
+ */
+fn release_leases(
+    server: &DhcpServer,
+    scope: &Scope6,
+    request: &Dhcpv6Message,
+) -> Result<Dhcpv6Message, failure::Error> {
+    let xid = request.transaction_id;
+    let duid = request.client_id().unwrap_or_default();
+    let mut ias = Vec::new();
+    let mut store = server.lease_store.lock().unwrap();
+    for (ia_type, ia) in requested_ias(request) {
+        match store.select_lease6_entry(duid, ia.iaid, ia_type)? {
+            Some(entry) if !entry.deleted => {
+                store.delete_lease6_entry(duid, ia.iaid, ia_type)?;
+                debug!("{:x}: released {}", xid, entry.prefix);
+            }
+            _ => {
+                let status = (message6::STATUS_NO_BINDING, "no binding");
+                ias.push(ia_option(scope, ia_type, ia.iaid, vec![status_option(status)]));
+            }
+        }
+    }
+    ias.push(status_option((message6::STATUS_SUCCESS, "released")));
+    Ok(make_reply(server, scope, request, message6::REPLY, ias, false))
+}
+
+/**
+ * Declineで他のノードが使っていると通知されたアドレスをdecline_timeの間割り当てないようにする。
+ * バインディングは論理削除する(RFC8415 18.3.8)。
+ */
+fn decline_leases(
+    server: &DhcpServer,
+    scope: &Scope6,
+    request: &Dhcpv6Message,
+) -> Result<Dhcpv6Message, failure::Error> {
+    let xid = request.transaction_id;
+    let duid = request.client_id().unwrap_or_default();
+    let conflict_until = util::unix_time_now() + server.decline_time as i64;
+    let mut ias = Vec::new();
+    let mut store = server.lease_store.lock().unwrap();
+    for (ia_type, ia) in requested_ias(request) {
+        let entry = store.select_lease6_entry(duid, ia.iaid, ia_type)?;
+        let declined = match entry {
+            // [note] Declineの対象はアドレスだけで、委譲したプレフィックスは含まれない
+            Some(entry) if ia_type == IaType::Na && !entry.deleted => {
+                ia.addresses().find(|address| address.address == entry.prefix.ip())
+            }
+            _ => None,
+        };
+        match declined {
+            Some(address) => {
+                store.delete_lease6_entry(duid, ia.iaid, ia_type)?;
+                store.insert_conflict6(address.address, duid, conflict_until)?;
+                warn!(
+                    "{:x}: {} reported that {} is already in use, quarantined for {} seconds",
+                    xid,
+                    util::encode_hex(duid),
+                    address.address,
+                    server.decline_time
+                );
+            }
+            None => {
+                let status = (message6::STATUS_NO_BINDING, "no binding");
+                ias.push(ia_option(scope, ia_type, ia.iaid, vec![status_option(status)]));
+            }
+        }
+    }
+    ias.push(status_option((message6::STATUS_SUCCESS, "declined")));
+    Ok(make_reply(server, scope, request, message6::REPLY, ias, false))
+}
+
+/**
+ * 期限切れのバインディングをDBから論理削除し、そのアドレス(プレフィックス)を返す。
+ */
+pub fn reclaim_expired_leases(server: &DhcpServer) -> Result<Vec<Ipv6Addr>, failure::Error> {
+    let mut store = server.lease_store.lock().unwrap();
+    store.delete_expired_lease6_entries(util::unix_time_now())
+}
+
+/**
+ * 割り当てを控える期間の過ぎたアドレスの記録を削除し、それらを返す。
+ */
+pub fn release_expired_conflicts(server: &DhcpServer) -> Result<Vec<Ipv6Addr>, failure::Error> {
+    let mut store = server.lease_store.lock().unwrap();
+    store.delete_expired_conflicts6(util::unix_time_now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support::{test_server, TEST_CONFIG};
+
+    fn client_duid(client: u8) -> Vec<u8> {
+        vec![0, 3, 0, 1, 0, 0x11, 0x22, 0x33, 0x44, client]
+    }
+
+    fn request(
+        server: &DhcpServer,
+        msg_type: u8,
+        client: u8,
+        ias: Vec<Dhcpv6Option>,
+    ) -> Dhcpv6Message {
+        let mut options = vec![
+            Dhcpv6Option::ClientId(client_duid(client)),
+            Dhcpv6Option::ElapsedTime(0),
+        ];
+        // SolicitとRebind以外はServer Identifierで送り先のサーバを指定する
+        if ![message6::SOLICIT, message6::REBIND, message6::CONFIRM].contains(&msg_type) {
+            options.push(Dhcpv6Option::ServerId(server.server_duid.clone()));
+        }
+        options.extend(ias);
+        Dhcpv6Message::new(msg_type, 0x1234, options)
+    }
+
+    fn ia_na(iaid: u32, addresses: &[&str]) -> Dhcpv6Option {
+        let options = addresses
+            .iter()
+            .map(|address| {
+                Dhcpv6Option::IaAddr(IaAddress {
+                    address: address.parse().unwrap(),
+                    preferred_lifetime: 0,
+                    valid_lifetime: 0,
+                    options: Vec::new(),
+                })
+            })
+            .collect();
+        Dhcpv6Option::IaNa(Ia {
+            iaid,
+            t1: 0,
+            t2: 0,
+            options,
+        })
+    }
+
+    fn ia_pd(iaid: u32) -> Dhcpv6Option {
+        Dhcpv6Option::IaPd(Ia {
+            iaid,
+            t1: 0,
+            t2: 0,
+            options: Vec::new(),
+        })
+    }
+
+    fn handle(server: &DhcpServer, request: &Dhcpv6Message) -> Option<Dhcpv6Message> {
+        handle_message(server, request, None).unwrap()
+    }
+
+    /**
+     * 応答のIAの割り当てられたアドレス(プレフィックス)と期間。なければステータスコード
+     */
+    fn leases(reply: &Dhcpv6Message) -> Vec<Result<(Ipv6Network, u32), u16>> {
+        requested_ias(reply)
+            .into_iter()
+            .flat_map(|(ia_type, ia)| {
+                let status = ia.options.iter().find_map(|option| match option {
+                    Dhcpv6Option::StatusCode(status, _) => Some(Err(*status)),
+                    _ => None,
+                });
+                let leases: Vec<_> = match ia_type {
+                    IaType::Na => ia
+                        .addresses()
+                        .map(|address| Ok((address.address.into(), address.valid_lifetime)))
+                        .collect(),
+                    IaType::Pd => ia
+                        .prefixes()
+                        .map(|prefix| {
+                            let network = Ipv6Network::new(prefix.prefix, prefix.prefix_len);
+                            Ok((network.unwrap(), prefix.valid_lifetime))
+                        })
+                        .collect(),
+                };
+                leases.into_iter().chain(status)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_solicit_and_request() {
+        let server = test_server();
+        let mut solicit = request(&server, message6::SOLICIT, 1, vec![ia_na(1, &[]), ia_pd(2)]);
+        solicit.options.push(Dhcpv6Option::OptionRequest(vec![
+            message6::OPTION_DNS_SERVERS,
+            message6::OPTION_DOMAIN_LIST,
+        ]));
+
+        let advertise = handle(&server, &solicit).unwrap();
+        assert_eq!(message6::ADVERTISE, advertise.msg_type);
+        assert_eq!(solicit.transaction_id, advertise.transaction_id);
+        assert_eq!(Some(&server.server_duid[..]), advertise.server_id());
+        assert_eq!(Some(&client_duid(1)[..]), advertise.client_id());
+        let offered = leases(&advertise);
+        let (address, valid_lifetime) = offered[0].unwrap();
+        assert!(server.scopes6()[0].is_assignable(IaType::Na, address));
+        assert_eq!(600, valid_lifetime);
+        let (prefix, _) = offered[1].unwrap();
+        assert_eq!(56, prefix.prefix());
+        assert!("2001:db8:100::/54".parse::<Ipv6Network>().unwrap().contains(prefix.ip()));
+        match advertise.option(message6::OPTION_IA_NA) {
+            Some(Dhcpv6Option::IaNa(ia)) => assert_eq!((1, 150, 240), (ia.iaid, ia.t1, ia.t2)),
+            option => panic!("unexpected option {:?}", option),
+        }
+        assert_eq!(
+            Some(&Dhcpv6Option::DnsServers(vec!["2001:db8:1::1".parse().unwrap()])),
+            advertise.option(message6::OPTION_DNS_SERVERS)
+        );
+        assert_eq!(
+            Some(&Dhcpv6Option::DomainList(vec!["example.lan".to_string()])),
+            advertise.option(message6::OPTION_DOMAIN_LIST)
+        );
+        // Advertiseでは記録しない
+        {
+            let store = server.lease_store.lock().unwrap();
+            let entry = store.select_lease6_entry(&client_duid(1), 1, IaType::Na);
+            assert_eq!(None, entry.unwrap());
+        }
+
+        // 提案したものを記録して返す
+        let request = request(&server, message6::REQUEST, 1, vec![ia_na(1, &[]), ia_pd(2)]);
+        let reply = handle(&server, &request).unwrap();
+        assert_eq!(message6::REPLY, reply.msg_type);
+        assert_eq!(offered, leases(&reply));
+        // ORO(Option Request)がなければ設定のオプションは含めない
+        assert_eq!(None, reply.option(message6::OPTION_DNS_SERVERS));
+        {
+            let store = server.lease_store.lock().unwrap();
+            let entry = store.select_lease6_entry(&client_duid(1), 2, IaType::Pd);
+            assert_eq!(prefix, entry.unwrap().unwrap().prefix);
+        }
+
+        // 他のクライアントには他のアドレスを割り当てる
+        let other = request_of(&server, 2, &["2001:db8:1::100", "2001:db8:1::101"]);
+        assert!(!other.contains(&offered[0]));
+    }
+
+    /**
+     * 希望するアドレスをIAに入れたRequestを送って割り当てられたものを返す
+     */
+    fn request_of(
+        server: &DhcpServer,
+        client: u8,
+        hints: &[&str],
+    ) -> Vec<Result<(Ipv6Network, u32), u16>> {
+        let request = request(server, message6::REQUEST, client, vec![ia_na(1, hints)]);
+        leases(&handle(server, &request).unwrap())
+    }
+
+    #[test]
+    fn test_messages_for_other_servers_are_ignored() {
+        let server = test_server();
+        let mut request = request(&server, message6::REQUEST, 1, vec![ia_na(1, &[])]);
+        request.options.retain(|option| option.code() != message6::OPTION_SERVERID);
+        request.options.push(Dhcpv6Option::ServerId(vec![0, 3, 0, 1, 0, 0, 0, 0, 0, 9]));
+        assert_eq!(None, handle(&server, &request));
+
+        // SolicitにServer Identifierがあってはいけない
+        let mut solicit = self::request(&server, message6::SOLICIT, 1, vec![ia_na(1, &[])]);
+        solicit.options.push(Dhcpv6Option::ServerId(server.server_duid.clone()));
+        assert_eq!(None, handle(&server, &solicit));
+        // Client Identifierのないものも捨てる
+        solicit.options.clear();
+        assert_eq!(None, handle(&server, &solicit));
+    }
+
+    #[test]
+    fn test_renew_rebind_and_release() {
+        let server = test_server();
+        let leased = request_of(&server, 1, &["2001:db8:1::102"]);
+        let address = "2001:db8:1::102".parse::<Ipv6Addr>().unwrap().into();
+        assert_eq!(vec![Ok((address, 600))], leased);
+
+        // 延長する。バインディングと違うアドレスは期間0で返して使わせない
+        let renew = request(&server, message6::RENEW, 1, vec![ia_na(1, &["2001:db8:1::103"])]);
+        let other = "2001:db8:1::103".parse::<Ipv6Addr>().unwrap().into();
+        assert_eq!(
+            vec![Ok((address, 600)), Ok((other, 0))],
+            leases(&handle(&server, &renew).unwrap())
+        );
+        // バインディングのないIA
+        let rebind = request(&server, message6::REBIND, 1, vec![ia_na(2, &[]), ia_pd(1)]);
+        assert_eq!(
+            vec![Err(message6::STATUS_NO_BINDING), Err(message6::STATUS_NO_BINDING)],
+            leases(&handle(&server, &rebind).unwrap())
+        );
+
+        let release = request(&server, message6::RELEASE, 1, vec![ia_na(1, &["2001:db8:1::102"])]);
+        let reply = handle(&server, &release).unwrap();
+        assert_eq!(
+            Some(&Dhcpv6Option::StatusCode(message6::STATUS_SUCCESS, "released".to_string())),
+            reply.option(message6::OPTION_STATUS_CODE)
+        );
+        assert!(leases(&reply).is_empty());
+        assert_eq!(
+            vec![Err(message6::STATUS_NO_BINDING)],
+            leases(&handle(&server, &renew).unwrap())
+        );
+        // 解放したアドレスは他のクライアントに割り当てられる
+        assert_eq!(vec![Ok((address, 600))], request_of(&server, 2, &["2001:db8:1::102"]));
+    }
+
+    #[test]
+    fn test_decline_and_exhaustion() {
+        let server = test_server();
+        let leased = request_of(&server, 1, &[]);
+        let declined = leased[0].unwrap().0;
+        let decline = request(
+            &server,
+            message6::DECLINE,
+            1,
+            vec![ia_na(1, &[&declined.ip().to_string()])],
+        );
+        handle(&server, &decline).unwrap();
+        {
+            let store = server.lease_store.lock().unwrap();
+            let entry = store.select_lease6_entry(&client_duid(1), 1, IaType::Na);
+            assert!(entry.unwrap().unwrap().deleted);
+        }
+
+        // 割り当て範囲の4つのうちDECLINEされた1つを除いた3つまで割り当てる
+        let mut assigned = Vec::new();
+        for client in 1..=3 {
+            let (address, _) = request_of(&server, client, &[])[0].unwrap();
+            assert_ne!(declined, address);
+            assert!(!assigned.contains(&address));
+            assigned.push(address);
+        }
+        assert_eq!(vec![Err(message6::STATUS_NO_ADDRS_AVAIL)], request_of(&server, 4, &[]));
+    }
+
+    #[test]
+    fn test_rapid_commit_confirm_and_information_request() {
+        let server = test_server();
+        let mut solicit = request(&server, message6::SOLICIT, 1, vec![ia_na(1, &[])]);
+        solicit.options.push(Dhcpv6Option::RapidCommit);
+        // Rapid Commitを設定していなければAdvertiseを返す
+        assert_eq!(message6::ADVERTISE, handle(&server, &solicit).unwrap().msg_type);
+        // 設定を読み込み直せば次のリクエストから反映される
+        let mut config = Config::parse(TEST_CONFIG).unwrap();
+        config.scopes6[0].rapid_commit = true;
+        server.reload(&config).unwrap();
+        let reply = handle(&server, &solicit).unwrap();
+        assert_eq!(message6::REPLY, reply.msg_type);
+        assert!(reply.has_rapid_commit());
+        {
+            let store = server.lease_store.lock().unwrap();
+            let entry = store.select_lease6_entry(&client_duid(1), 1, IaType::Na);
+            assert!(entry.unwrap().is_some());
+        }
+
+        let confirm = |addresses: &[&str]| {
+            let confirm = request(&server, message6::CONFIRM, 1, vec![ia_na(1, addresses)]);
+            let reply = handle(&server, &confirm);
+            reply.map(|reply| reply.option(message6::OPTION_STATUS_CODE).cloned())
+        };
+        assert_eq!(
+            Some(Some(Dhcpv6Option::StatusCode(
+                message6::STATUS_SUCCESS,
+                "all addresses are on link".to_string()
+            ))),
+            confirm(&["2001:db8:1::1234"])
+        );
+        match confirm(&["2001:db8:1::1", "2001:db8:2::1"]) {
+            Some(Some(Dhcpv6Option::StatusCode(status, _))) => {
+                assert_eq!(message6::STATUS_NOT_ON_LINK, status)
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        assert_eq!(None, confirm(&[]));
+
+        let mut information_request = request(&server, message6::INFORMATION_REQUEST, 1, vec![]);
+        let requested = vec![message6::OPTION_DNS_SERVERS];
+        information_request.options.push(Dhcpv6Option::OptionRequest(requested));
+        let reply = handle(&server, &information_request).unwrap();
+        assert!(leases(&reply).is_empty());
+        assert!(reply.option(message6::OPTION_DNS_SERVERS).is_some());
+        assert_eq!(None, reply.option(message6::OPTION_DOMAIN_LIST));
+    }
+
+    #[test]
+    fn test_nth_lease() {
+        let server = test_server();
+        let scope = &server.scopes6()[0];
+        assert_eq!(4, scope.lease_count(IaType::Na));
+        assert_eq!(Some("2001:db8:1::103/128".parse().unwrap()), scope.nth_lease(IaType::Na, 3));
+        assert_eq!(None, scope.nth_lease(IaType::Na, 4));
+        // /54から/56を切り出すので4つ
+        assert_eq!(4, scope.lease_count(IaType::Pd));
+        assert_eq!(Some("2001:db8:100:300::/56".parse().unwrap()), scope.nth_lease(IaType::Pd, 3));
+        assert!(!scope.is_assignable(IaType::Pd, "2001:db8:100:380::/57".parse().unwrap()));
+        let out_of_range: Ipv6Addr = "2001:db8:1::ff".parse().unwrap();
+        assert!(!scope.is_assignable(IaType::Na, out_of_range.into()));
+    }
+}
@@ -0,0 +1,19 @@
+//! DHCPサーバ(dhcp_server)と管理コマンド(dhcpctl)が共有するモジュール。
+
+#[macro_use]
+extern crate log;
+
+pub mod admin;
+pub mod config;
+pub mod database;
+pub mod ddns;
+pub mod dhcp;
+pub mod dhcp6;
+pub mod message;
+pub mod message6;
+pub mod options;
+pub mod relay;
+#[doc(hidden)]
+pub mod test_support;
+pub mod tsig;
+pub mod util;
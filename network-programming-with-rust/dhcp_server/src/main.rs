@@ -1,47 +1,44 @@
-use byteorder::{BigEndian, ByteOrder};
-use env_logger;
-use failure;
 use log::{debug, error, info};
-use pnet::util::MacAddr;
 use std::env;
-use std::net::{Ipv4Addr, UdpSocket};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV6, TcpListener, UdpSocket};
+use std::process;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 #[macro_use]
 extern crate log;
 
-use dhcp::DhcpPacket;
-use dhcp::DhcpServer;
-
-mod dhcp;
-mod database;
-mod util;
-
-const HTYPE_ETHER: u8 = 1;
-
-// オプションの数を考慮して決定する。
-// 大きめにとっても問題はない。
-const DHCP_SIZE: usize = 400;
-
-// [note] 以下のRFCにDHCPのオプションの各コードの定義がある。以下のenum Codeはその一覧。
-// https://datatracker.ietf.org/doc/html/rfc2132#autoid-73
-enum Code {
-    MessageType = 53,
-    IPAddressLeaseTime = 51,
-    ServerIdentifier = 54,
-    RequestedIpAddress = 50,
-    SubnetMask = 1,
-    Router = 3,
-    DNS = 6,
-    End = 255,
-}
+use dhcp_server::config::Config;
+use dhcp_server::database::{LeaseEntry, LeaseRecord};
+use dhcp_server::dhcp::{Client, DhcpServer, Scope};
+use dhcp_server::message::{DhcpMessage, DhcpMessageBuilder};
+use dhcp_server::message6::Dhcpv6Message;
+use dhcp_server::options::DhcpOption;
+use dhcp_server::relay::RelayAgentInformation;
+use dhcp_server::{admin, dhcp6, options, util};
+use ipnetwork::Ipv4Network;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCPV6_CLIENT_PORT: u16 = 546;
+
+// flagsフィールドのBROADCASTビット(RFC2131 2)
+const BROADCAST_FLAG: u16 = 0x8000;
+
+// 引数で指定されなかった場合の設定ファイル
+const DEFAULT_CONFIG_PATH: &str = "dhcp_server.toml";
+
+// 期限切れのリースを回収する間隔
+const LEASE_REAPER_INTERVAL: Duration = Duration::from_secs(10);
 
 const DHCPDISCOVER: u8 = 1;
 const DHCPOFFER: u8 = 2;
 const DHCPREQUEST: u8 = 3;
+const DHCPDECLINE: u8 = 4;
 const DHCPACK: u8 = 5;
 const DHCPNAK: u8 = 6;
 const DHCPRELEASE: u8 = 7;
+const DHCPINFORM: u8 = 8;
 
 const BOOTREQUEST: u8 = 1;
 const BOOTREPLY: u8 = 2;
@@ -50,20 +47,109 @@ fn main() {
     env::set_var("RUST_LOG", "debug");
     env_logger::init();
 
-    let server_socket = UdpSocket::bind("0.0.0.0:67").expect("Failed to bind socket");
-
-    // [note] Udpソケットを(IPレイヤーでの)ブロードキャストに設定する。
-    // → クライアントはIPアドレスが設定されていないので必然的にブロードキャストになる。
-    // ref from Rust std library
-    // > Sets the value of the `SO_BROADCAST` option for this socket.
-    // > When enabled, this socket is allowed to send packets to a broadcast address.
-    server_socket.set_broadcast(true).unwrap();
+    let config_path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+    // [note] 設定の誤りはpanicではなく内容を示して終了する
+    let config = Config::load(&config_path).unwrap_or_else(|e| {
+        error!("Invalid configuration {}: {}", config_path, e);
+        process::exit(1);
+    });
 
     // ヒープ上にDhcpServer構造体を確保し、複数のスレッドから共有するためArcを利用している。
-    let dhcp_server = Arc::new(
-        DhcpServer::new().unwrap_or_else(|e| panic!("Failed to start dhcp server. {:?}", e)),
-    );
+    let dhcp_server = Arc::new(DhcpServer::new(&config).unwrap_or_else(|e| {
+        error!("Failed to start dhcp server: {}", e);
+        process::exit(1);
+    }));
+
+    // 期限切れのリースを定期的にアドレスプールへ戻すスレッド
+    let reaper_dhcp_server = dhcp_server.clone();
+    thread::spawn(move || loop {
+        thread::sleep(LEASE_REAPER_INTERVAL);
+        for ip_addr in reaper_dhcp_server.expire_pending_offers() {
+            info!("offer expired: {}", ip_addr);
+        }
+        match reaper_dhcp_server.reclaim_expired_leases() {
+            Ok(expired_addrs) => {
+                for ip_addr in expired_addrs {
+                    info!("lease expired: {}", ip_addr);
+                }
+            }
+            Err(e) => error!("Failed to reclaim expired leases: {}", e),
+        }
+        match reaper_dhcp_server.release_expired_conflicts() {
+            Ok(released_addrs) => {
+                for ip_addr in released_addrs {
+                    info!("conflict expired: {}", ip_addr);
+                }
+            }
+            Err(e) => error!("Failed to release conflicted addresses: {}", e),
+        }
+        match dhcp6::reclaim_expired_leases(&reaper_dhcp_server) {
+            Ok(expired_addrs) => {
+                for address in expired_addrs {
+                    info!("lease expired: {}", address);
+                }
+            }
+            Err(e) => error!("Failed to reclaim expired DHCPv6 leases: {}", e),
+        }
+        match dhcp6::release_expired_conflicts(&reaper_dhcp_server) {
+            Ok(released_addrs) => {
+                for address in released_addrs {
+                    info!("conflict expired: {}", address);
+                }
+            }
+            Err(e) => error!("Failed to release conflicted DHCPv6 addresses: {}", e),
+        }
+    });
+
+    // 管理APIは設定があるときだけ起動する
+    if let Some(admin) = &config.admin {
+        let listener = TcpListener::bind(admin.listen).unwrap_or_else(|e| {
+            error!("Failed to bind admin API on {}: {}", admin.listen, e);
+            process::exit(1);
+        });
+        info!("admin API is listening on {}", admin.listen);
+        let dhcp_server = dhcp_server.clone();
+        let config_path = config_path.clone();
+        let allow_remote = admin.allow_remote;
+        thread::spawn(move || admin::serve(listener, dhcp_server, config_path, allow_remote));
+    }
+
+    // インターフェースを指定したスコープごとにソケットを用意する。
+    // 指定がなければ全てのインターフェースで受け付ける。
+    let mut listeners = Vec::new();
+    for interface in dhcp_server.interfaces() {
+        let server_socket = util::bind_dhcp_socket(interface.as_deref()).unwrap_or_else(|e| {
+            error!("Failed to bind socket on {:?}: {}", interface, e);
+            process::exit(1);
+        });
+        let dhcp_server = dhcp_server.clone();
+        listeners.push(thread::spawn(move || {
+            serve(server_socket, interface, dhcp_server)
+        }));
+    }
+
+    // DHCPv6も同様にインターフェースごとにソケットを用意する。[[scope6]]がなければ待ち受けない
+    for interface in dhcp_server.interfaces6() {
+        let server_socket = util::bind_dhcpv6_socket(interface.as_deref()).unwrap_or_else(|e| {
+            error!("Failed to bind DHCPv6 socket on {:?}: {}", interface, e);
+            process::exit(1);
+        });
+        let dhcp_server = dhcp_server.clone();
+        listeners.push(thread::spawn(move || {
+            serve6(server_socket, interface, dhcp_server)
+        }));
+    }
+    for listener in listeners {
+        listener.join().unwrap();
+    }
+}
 
+/**
+ * ソケットでDHCPリクエストを待ち受ける。interfaceは受信したインターフェース。
+ */
+fn serve(server_socket: UdpSocket, interface: Option<String>, dhcp_server: Arc<DhcpServer>) {
     loop {
         let mut recv_buf = [0u8; 1024];
         // [note] ここの socket.recv_from は libc::recvfrom のシステムコールを同期(待つ)でする。
@@ -77,18 +163,28 @@ fn main() {
                 // [note] サーバオブジェクトをCloneして別スレッドで動かす。
                 // これによって非同期処理を実現させる。
                 let cloned_dhcp_server = dhcp_server.clone();
+                let interface = interface.clone();
 
                 thread::spawn(move || {
-                    if let Some(dhcp_packet) = DhcpPacket::new(recv_buf[..size].to_vec()) {
-                        if dhcp_packet.get_op() != BOOTREQUEST {
-                            // クライアントからのリクエストでなければ無視
+                    let dhcp_packet = match DhcpMessage::parse(&recv_buf[..size]) {
+                        Ok(dhcp_packet) => dhcp_packet,
+                        Err(e) => {
+                            // DHCPのメッセージとして解釈できないものは捨てる
+                            debug!("dropped invalid message from {}: {}", src, e);
                             return;
                         }
-                        if let Err(e) =
-                            dhcp_handler(&dhcp_packet, &transmission_socket, cloned_dhcp_server)
-                        {
-                            error!("{}", e);
-                        }
+                    };
+                    if dhcp_packet.op != BOOTREQUEST {
+                        // クライアントからのリクエストでなければ無視
+                        return;
+                    }
+                    if let Err(e) = dhcp_handler(
+                        &dhcp_packet,
+                        &transmission_socket,
+                        cloned_dhcp_server,
+                        interface.as_deref(),
+                    ) {
+                        error!("{}", e);
                     }
                 });
             }
@@ -99,48 +195,144 @@ fn main() {
     }
 }
 
+/**
+ * ソケットでDHCPv6のリクエストを待ち受ける。interfaceは受信したインターフェース。
+ *
+ * [note] DHCPv4と違いICMPでの確認をしないので、スレッドを分けずにその場で応答する。
+ */
+fn serve6(server_socket: UdpSocket, interface: Option<String>, dhcp_server: Arc<DhcpServer>) {
+    loop {
+        let mut recv_buf = [0u8; 1500];
+        let (size, src) = match server_socket.recv_from(&mut recv_buf) {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Could not recieve a datagram: {}", e);
+                continue;
+            }
+        };
+        debug!("received data from {}, size: {}", src, size);
+        let request = match Dhcpv6Message::parse(&recv_buf[..size]) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("dropped invalid message from {}: {}", src, e);
+                continue;
+            }
+        };
+        let reply = match dhcp6::handle_message(&dhcp_server, &request, interface.as_deref()) {
+            Ok(Some(reply)) => reply,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        // 応答はクライアントのリンクローカルアドレスのポート546へユニキャストする(RFC8415 18.3)
+        let destination = match src {
+            SocketAddr::V6(src) => {
+                SocketAddrV6::new(*src.ip(), DHCPV6_CLIENT_PORT, 0, src.scope_id())
+            }
+            SocketAddr::V4(_) => continue,
+        };
+        if let Err(e) = util::send_dhcp_response(
+            &server_socket,
+            &reply.to_bytes(),
+            SocketAddr::V6(destination),
+        ) {
+            error!("{:x}: {}", request.transaction_id, e);
+        }
+    }
+}
+
 /**
  * DHCPリクエストを解析してレスポンスを返す。
  */
 fn dhcp_handler(
-    packet: &DhcpPacket,
+    packet: &DhcpMessage,
     soc: &UdpSocket,
     dhcp_server: Arc<DhcpServer>,
+    interface: Option<&str>,
 ) -> Result<(), failure::Error> {
-    let message = packet.get_option(Code::MessageType as u8)
+    let message_type = packet
+        .message_type()
         .ok_or_else(|| failure::err_msg("specified option was not found"))?;
-    let message_type = message[0];
-    let transaction_id = BigEndian::read_u32(packet.get_xid());
-    let client_macaddr = packet.get_chaddr();
+    let transaction_id = packet.xid;
+    let client = Client::from_message(packet);
+    let scope = &dhcp_server.select_scope(packet, interface).ok_or_else(|| {
+        failure::format_err!(
+            "{:x}: no scope for the request (interface: {:?}, giaddr: {})",
+            transaction_id,
+            interface,
+            packet.giaddr
+        )
+    })?;
+
+    // リレーエージェントを経由したリクエストはポリシーで応答するかを決める
+    let relay_info = match packet.relay_agent_information() {
+        Some(buf) => Some(RelayAgentInformation::parse(buf).map_err(|e| {
+            failure::format_err!("{:x}: invalid relay agent information: {}", transaction_id, e)
+        })?),
+        None => None,
+    };
+    let giaddr = packet.giaddr;
+    if !giaddr.is_unspecified() {
+        debug!(
+            "{:x}: relayed by {}, circuit-id: {:?}, remote-id: {:?}",
+            transaction_id,
+            giaddr,
+            relay_info.as_ref().and_then(|info| info.circuit_id()),
+            relay_info.as_ref().and_then(|info| info.remote_id())
+        );
+        if !dhcp_server.relay_policy.accept(scope, giaddr, relay_info.as_ref()) {
+            info!(
+                "{:x}: request relayed by {} is not accepted by the policy",
+                transaction_id, giaddr
+            );
+            return Ok(());
+        }
+    }
 
     match message_type {
         // 最初のクライアントからのリクエストタイプ で 割り当てるIPアドレス
-        DHCPDISCOVER => dhcp_discover_message_handler(transaction_id, dhcp_server, &packet, soc),
+        DHCPDISCOVER => {
+            dhcp_discover_message_handler(transaction_id, &dhcp_server, scope, packet, soc)
+        }
 
         // 汎用的なクライアントからのリクエストタイプ
-        DHCPREQUEST => match packet.get_option(Code::ServerIdentifier as u8) {
+        DHCPREQUEST => match packet.server_identifier() {
             Some(server_id) => dhcp_request_message_handler_responded_to_offer(
                 transaction_id,
-                dhcp_server,
-                &packet,
-                client_macaddr,
+                &dhcp_server,
+                scope,
+                packet,
+                &client,
                 soc,
                 server_id,
             ),
             None => dhcp_request_message_handler_to_reallocate(
                 transaction_id,
-                dhcp_server,
-                &packet,
-                client_macaddr,
+                &dhcp_server,
+                scope,
+                packet,
+                &client,
                 soc,
             ),
         },
 
         // IPアドレスをクライアントから外すときのクライアントからのリクエストタイプ
         DHCPRELEASE => {
-            dhcp_release_message_handler(transaction_id, dhcp_server, &packet, client_macaddr)
+            dhcp_release_message_handler(transaction_id, &dhcp_server, packet, &client)
+        },
+
+        // 割り当てたIPアドレスが既に使われていた時のクライアントからのリクエストタイプ
+        DHCPDECLINE => {
+            dhcp_decline_message_handler(transaction_id, &dhcp_server, scope, packet, &client)
         },
 
+        // IPアドレスを持つクライアントが設定情報だけを求めるリクエストタイプ
+        DHCPINFORM => {
+            dhcp_inform_message_handler(transaction_id, &dhcp_server, scope, packet, soc)
+        }
+
         _ => {
             // 未実装のメッセージを受信した場合。
             Err(failure::format_err!(
@@ -159,19 +351,35 @@ fn dhcp_handler(
  */
 fn dhcp_discover_message_handler(
     xid: u32,
-    dhcp_server: Arc<DhcpServer>,
-    received_packet: &DhcpPacket,
+    dhcp_server: &DhcpServer,
+    scope: &Scope,
+    received_packet: &DhcpMessage,
     soc: &UdpSocket,
 ) -> Result<(), failure::Error> {
     info!("{:x}: received DHCPDISCOVER", xid);
 
     // IPアドレスの決定
-    let ip_to_be_leased = select_lease_ip(&dhcp_server, &received_packet)?;
+    let client = Client::from_message(received_packet);
+    let ip_to_be_leased = match dhcp_server.pending_offer(&client, xid) {
+        // DHCPDISCOVERの再送には同じIPアドレスを提案する
+        Some(ip_addr) => ip_addr,
+        None => {
+            let (ip_addr, from_pool) = select_lease_ip(dhcp_server, scope, received_packet)?;
+            // DHCPREQUESTが来るまで提案したIPアドレスを確保しておく
+            dhcp_server.record_offer(&client, xid, ip_addr, from_pool);
+            ip_addr
+        }
+    };
 
     // 決定したIPアドレスでDHCPパケットの作成
     // DHCPOFFERメッセージを返却する
-    let dhcp_packet = make_dhcp_packet(&received_packet, &dhcp_server, DHCPOFFER, ip_to_be_leased)?;
-    util::send_dhcp_broadcast_response(soc, dhcp_packet.get_buffer())?;
+    let dhcp_packet =
+        make_dhcp_packet(dhcp_server, received_packet, scope, DHCPOFFER, ip_to_be_leased)?;
+    util::send_dhcp_response(
+        soc,
+        &dhcp_packet.to_bytes(received_packet.reply_size_limit())?,
+        reply_destination(received_packet, DHCPOFFER),
+    )?;
 
     info!("{:x}: sent DHCPOFFER", xid);
     Ok(())
@@ -179,51 +387,65 @@ fn dhcp_discover_message_handler(
 
 /**
  * 利用可能なIPアドレスを選ぶ。
+ * 0.そのクライアントに予約されたIPアドレス
  * 1.以前そのクライアントにリースされたIPアドレス(解放されたものも含め)
  * 2.クライアントから要求されたIPアドレス(アドレスプールから探す)
  * 3.アドレスプール
- * の優先順位で利用可能なIPアドレスを返却する。
+ * の優先順位で利用可能なIPアドレスと、それをアドレスプールから取り出したかどうかを返却する。
  */
 fn select_lease_ip(
-    dhcp_server: &Arc<DhcpServer>,
-    received_packet: &DhcpPacket,
-) -> Result<Ipv4Addr, failure::Error> {
+    dhcp_server: &DhcpServer,
+    scope: &Scope,
+    received_packet: &DhcpMessage,
+) -> Result<(Ipv4Addr, bool), failure::Error> {
+    // 0. 予約されたIPアドレスはアドレスプールにないので、使用中かどうかに関わらず必ずそれにする
+    let client = Client::from_message(received_packet);
+    if let Some(reservation) = scope.find_reservation(&client) {
+        return Ok((reservation.ip_addr, false));
+    }
+
     // 1. 以前そのクライアントにリースしたものがあればそれにする → DB内を見に行く。
     {
         // [note] DBコネクションをLockで扱っておりクリティカルセクションを短くするために必要範囲をスコープで囲む
 
-        let con = dhcp_server.db_connection.lock().unwrap();
-        if let Some(ip_addr) = database::select_entry(&con, received_packet.get_chaddr())? {
-            // 対象クライアント(MACアドレス)が持つIPアドレスがDB内に既にあればそれを返す
+        let store = dhcp_server.lease_store.lock().unwrap();
+        if let Some(entry) = store.select_lease_entry(&client)? {
+            let ip_addr = entry.ip_addr;
+            // 対象クライアントが持つIPアドレスがDB内に既にあればそれを返す
             //
             // IPアドレスが重複していないか
-            // .envに記載されたネットワークアドレスの変更があった時のために、
-            // 現在のネットワークに含まれているかを合わせて確認する
-            if dhcp_server.network_addr.contains(ip_addr)
-                && util::is_ipaddr_available(ip_addr).is_ok() {
-                    return Ok(ip_addr);
+            // 設定ファイルの割り当て範囲の変更があった時のために、
+            // 現在のスコープで割り当て可能かを合わせて確認する
+            if !entry.deleted {
+                // 有効なリースはこのクライアントのものなのでアドレスプールにはない
+                if scope.is_assignable(ip_addr) && util::is_ipaddr_available(ip_addr).is_ok() {
+                    return Ok((ip_addr, false));
                 }
+            } else if let Some(ip_addr) = scope.pick_specified_ip(ip_addr) {
+                // 解放済みのIPアドレスはまだ他のクライアントに割り当てられていなければ使う
+                if util::is_ipaddr_available(ip_addr).is_ok() {
+                    return Ok((ip_addr, true));
+                }
+            }
         }
     }
 
     // 2.クライアントから要求されたIPアドレス(アドレスプールから探す)
     // // Requested Ip Addrオプションがあり、利用可能ならばそのIPアドレスを返却。
-    match received_packet.get_option(Code::RequestedIpAddress as u8) {
-        Some(ip) => {
-            let requested_ip = util::u8_to_ipv4addr(&ip).unwrap();
-            // Search from address pool
-            let ip_from_pool = dhcp_server.pick_specified_ip(requested_ip).unwrap();
+    if let Some(requested_ip) = received_packet.requested_ip_address() {
+        // Search from address pool
+        let ip_from_pool = scope.pick_specified_ip(requested_ip);
+        if let Some(ip_from_pool) = ip_from_pool {
             if util::is_ipaddr_available(ip_from_pool).is_ok() {
-                return Ok(ip_from_pool);
+                return Ok((ip_from_pool, true));
             }
         }
-        None => (),
     }
 
     // 3.アドレスプール から新規に見つけて返す
-    while let Some(ip_addr) = dhcp_server.pick_available_ip() {
+    while let Some(ip_addr) = scope.pick_available_ip() {
         if util::is_ipaddr_available(ip_addr).is_ok() {
-            return Ok(ip_addr);
+            return Ok((ip_addr, true));
         }
     }
 
@@ -237,62 +459,100 @@ fn select_lease_ip(
 *
 * [note] DHCPOFFER はサーバからの割当IPアドレスのクライアントへの提案であり、
 * その提案に対してクライアントから応答(承諾 or NG)がREQUESTメッセージである。
-* 承諾であれば、サーバはDBへ対象クライアントのID(MACアドレス)とIPのペアを登録する。
+* 承諾であれば、サーバはDBへ対象クライアントのID(client identifierかハードウェアアドレス)とIPのペアを登録する。
 */
 fn dhcp_request_message_handler_responded_to_offer(
     xid: u32,
-    dhcp_server: Arc<DhcpServer>,
-    received_packet: &DhcpPacket,
-    client_macaddr: MacAddr,
+    dhcp_server: &DhcpServer,
+    scope: &Scope,
+    received_packet: &DhcpMessage,
+    client: &Client,
     soc: &UdpSocket,
-    server_id: Vec<u8>,
+    server_ip: Ipv4Addr,
 ) -> Result<(), failure::Error> {
     info!("{:x}: received DHCPREQUEST with server_id", xid);
 
-    let server_ip = util::u8_to_ipv4addr(&server_id)
-        .ok_or_else(|| failure::err_msg("Failed to convert ip addr."))?;
-
-    if server_ip != dhcp_server.server_address {
+    if server_ip != scope.server_address {
         /* クライアントが別のDHCPサーバを選択した場合。[1] */
         info!("Client has chosen another dhcp server.");
+        // 提案していたIPアドレスは保持時間を待たずにアドレスプールに戻す
+        dhcp_server.cancel_offer(client, xid);
         return Ok(());
     }
 
     // DHCPOFFERメッセージに対する応答の場合、必ず'requested IP address'に
     // 割り当て予定のIPアドレスが含まれる
-    let ip_bin = received_packet
-        .get_option(Code::RequestedIpAddress as u8)
-        .unwrap();
-    let ip_to_be_leased = util::u8_to_ipv4addr(&ip_bin)
-        .ok_or_else(|| failure::err_msg("Failed to convert ip addr."))?;
-
-    // DBへMACアドレスとIPアドレスのペアを登録する
-    let mut con = dhcp_server.db_connection.lock().unwrap();
-    let count = {
-        // トランザクションのクリティカルセクションを短く保つためにブロックにする。
-
-        let tx = con.transaction()?;
-        let count = database::count_records_by_mac_addr(&tx, client_macaddr)?;
-        match count {
-            // レコードがないならinsert
-            0 => database::insert_entry(&tx, client_macaddr, ip_to_be_leased)?,
-            // レコードがあるならupdate
-            _ => database::update_entry(&tx, client_macaddr, ip_to_be_leased, 0)?,
-        }
+    let ip_to_be_leased = received_packet
+        .requested_ip_address()
+        .ok_or_else(|| failure::err_msg("DHCPREQUEST without requested ip address."))?;
+
+    // 提案したIPアドレスへの応答であることを確認する
+    if !dhcp_server.claim_offer(client, xid, ip_to_be_leased) {
+        // 提案していないIPアドレスの要求か、保持時間を過ぎた提案への応答
+        let dhcp_packet = make_dhcp_packet(
+            dhcp_server,
+            received_packet,
+            scope,
+            DHCPNAK,
+            Ipv4Addr::UNSPECIFIED,
+        )?;
+        util::send_dhcp_response(
+            soc,
+            &dhcp_packet.to_bytes(received_packet.reply_size_limit())?,
+            reply_destination(received_packet, DHCPNAK),
+        )?;
+        info!("{:x}: sent DHCPNAK, {} was not offered", xid, ip_to_be_leased);
+        return Ok(());
+    }
 
-        let dhcp_packet =
-            make_dhcp_packet(&received_packet, &dhcp_server, DHCPACK, ip_to_be_leased)?;
-        util::send_dhcp_broadcast_response(soc, dhcp_packet.get_buffer())?;
-        info!("{:x}: sent DHCPACK", xid);
+    let dhcp_packet =
+        make_dhcp_packet(dhcp_server, received_packet, scope, DHCPACK, ip_to_be_leased)?;
+    let reply = dhcp_packet.to_bytes(received_packet.reply_size_limit())?;
 
-        tx.commit()?;
-        count
+    // DBへクライアントとIPアドレスのペアを、クライアントが名乗ったホスト名と一緒に登録する
+    let hostname = scope.lease_hostname(received_packet);
+    let (lease_start, lease_expiry) = scope.new_lease_period();
+    let lease = LeaseRecord {
+        client: client.clone(),
+        ip_addr: ip_to_be_leased,
+        deleted: false,
+        lease_start,
+        lease_expiry,
+        hostname: hostname.clone(),
     };
+    let mut store = dhcp_server.lease_store.lock().unwrap();
+    let previous_entry = store.select_lease_entry(client)?;
+    store.upsert_lease_records(&[lease])?;
+    // ロックのクリティカルセクションを短く保つため、ACKを送る前にロックを外す
+    drop(store);
+
+    // 予約の追加などで別のIPアドレスに移った場合は、以前の有効なリースのIPアドレスをアドレスプールに戻す
+    if let Some(previous_entry) = &previous_entry {
+        if !previous_entry.deleted && previous_entry.ip_addr != ip_to_be_leased {
+            dhcp_server.release_address(previous_entry.ip_addr);
+        }
+    }
+
+    // [note] 以前はACKの送信に失敗したらリースの登録をロールバックしていたが、送信中にロックを
+    // 持たないように、登録を終えてから送るように変えている。送信に失敗してもリースは残るが、
+    // ACKを受け取れなかったクライアントはDHCPDISCOVERからやり直し、有効なリースを持つクライアントには
+    // 同じIPアドレスを提案するので、他のクライアントのアドレスと重なることはない。
+    util::send_dhcp_response(soc, &reply, reply_destination(received_packet, DHCPACK))?;
+    info!("{:x}: sent DHCPACK", xid);
+
+    update_dns_records(
+        dhcp_server,
+        received_packet,
+        client,
+        previous_entry.as_ref(),
+        hostname.as_deref(),
+        ip_to_be_leased,
+    );
 
     debug!("{:x}: leased address: {}", xid, ip_to_be_leased);
-    match count {
-        0 => debug!("{:x}: inserted into DB", xid),
-        _ => debug!("{:x}: updated DB", xid),
+    match previous_entry {
+        None => debug!("{:x}: inserted into DB", xid),
+        Some(_) => debug!("{:x}: updated DB", xid),
     }
     Ok(())
 }
@@ -303,163 +563,707 @@ fn dhcp_request_message_handler_responded_to_offer(
  */
 fn dhcp_request_message_handler_to_reallocate(
     xid: u32,
-    dhcp_server: Arc<DhcpServer>,
-    received_packet: &DhcpPacket,
-    client_macaddr: MacAddr,
+    dhcp_server: &DhcpServer,
+    scope: &Scope,
+    received_packet: &DhcpMessage,
+    client: &Client,
     soc: &UdpSocket,
 ) -> Result<(), failure::Error> {
     info!("{:x}: received DHCPREQUEST without server_id", xid);
 
-    if let Some(requested_ip) = received_packet.get_option(Code::RequestedIpAddress as u8) {
-        /* [2] */
-        debug!("client is in INIT-REBOOT");
-        // クライアントが以前割り当てられたIPアドレスを記憶していて、
-        // 再起動状態にあるとき
-        let requested_ip = util::u8_to_ipv4addr(&requested_ip)
-            .ok_or_else(|| failure::err_msg("Failed to convert ip addr."))?;
-        let con = dhcp_server.db_connection.lock().unwrap();
-        match database::select_entry(&con, client_macaddr)? {
-            Some(ip) => {
-                if ip == requested_ip && dhcp_server.network_addr.contains(ip) {
-                    // 以前割り当てたIPアドレスと要求されたIPアドレスが一致しており、
-                    // ネットワークに含まれている時はACKを返す
-                    let dhcp_packet =
-                        make_dhcp_packet(&received_packet, &dhcp_server, DHCPACK, ip)?;
-                    util::send_dhcp_broadcast_response(soc, dhcp_packet.get_buffer())?;
-                    info!("{:x}: sent DHCPACK", xid);
-                    Ok(())
-                } else {
-                    // 不適切なIPアドレスが要求されるとNAKを返す
-                    let dhcp_packet = make_dhcp_packet(
-                        &received_packet,
-                        &dhcp_server,
-                        DHCPNAK,
-                        "0.0.0.0".parse()?,
-                    )?;
-                    util::send_dhcp_broadcast_response(soc, dhcp_packet.get_buffer())?;
-                    info!("{:x}: sent DHCPACK", xid);
-                    Ok(())
-                }
-            }
-            None => {
-                // レコードがないなら何もしてはいけない(RFC2131 P32)
-                Ok(())
+    let ip_from_client = match received_packet.requested_ip_address() {
+        Some(requested_ip) => {
+            /* [2] */
+            debug!("client is in INIT-REBOOT");
+            // クライアントが以前割り当てられたIPアドレスを記憶していて、
+            // 再起動状態にあるとき
+            requested_ip
+        }
+        None => {
+            /* [3] */
+            debug!("client is in RENEWING or REBINDING");
+            // リース延長要求、リース切れによる再要求。使っているIPアドレスはciaddrに入っている
+            received_packet.ciaddr
+        }
+    };
+
+    let mut store = dhcp_server.lease_store.lock().unwrap();
+    let entry = store.select_lease_entry(client)?;
+    match check_lease_ownership(
+        entry.as_ref(),
+        ip_from_client,
+        scope.network_addr,
+        util::unix_time_now(),
+    ) {
+        LeaseOwnership::Owned => {
+            // DBに記録されたリースの期限を延長してACKを返す
+            let (lease_start, lease_expiry) = scope.new_lease_period();
+            store.renew_entry(client, ip_from_client, lease_start, lease_expiry)?;
+            let hostname = scope.lease_hostname(received_packet);
+            store.update_hostname(client, hostname.as_deref())?;
+            drop(store);
+            debug!("{:x}: extended lease of {} until {}", xid, ip_from_client, lease_expiry);
+
+            let dhcp_packet =
+                make_dhcp_packet(dhcp_server, received_packet, scope, DHCPACK, ip_from_client)?;
+            util::send_dhcp_response(
+                soc,
+                &dhcp_packet.to_bytes(received_packet.reply_size_limit())?,
+                reply_destination(received_packet, DHCPACK),
+            )?;
+            info!("{:x}: sent DHCPACK", xid);
+            update_dns_records(
+                dhcp_server,
+                received_packet,
+                client,
+                entry.as_ref(),
+                hostname.as_deref(),
+                ip_from_client,
+            );
+        }
+        LeaseOwnership::NotOwned => {
+            // 不適切なIPアドレスが要求されるとNAKを返す
+            drop(store);
+            let dhcp_packet = make_dhcp_packet(
+                dhcp_server,
+                received_packet,
+                scope,
+                DHCPNAK,
+                Ipv4Addr::UNSPECIFIED,
+            )?;
+            util::send_dhcp_response(
+                soc,
+                &dhcp_packet.to_bytes(received_packet.reply_size_limit())?,
+                reply_destination(received_packet, DHCPNAK),
+            )?;
+            info!("{:x}: sent DHCPNAK, {} is not leased to {}", xid, ip_from_client, client);
+        }
+        LeaseOwnership::Unknown => {
+            // レコードがないなら何もしてはいけない(RFC2131 P32)
+            info!("{:x}: no lease record of {}, ignored", xid, client);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * リースしたIPアドレスとホスト名をDNSに登録する。
+ * 以前の有効なリースからIPアドレスかホスト名が変わった場合は、以前のレコードを削除する。
+ */
+fn update_dns_records(
+    dhcp_server: &DhcpServer,
+    received_packet: &DhcpMessage,
+    client: &Client,
+    previous_entry: Option<&LeaseEntry>,
+    hostname: Option<&str>,
+    ip_addr: Ipv4Addr,
+) {
+    if let Some(previous_entry) = previous_entry.filter(|entry| !entry.deleted) {
+        if let Some(previous_hostname) = previous_entry.hostname.as_deref() {
+            if previous_entry.ip_addr != ip_addr || Some(previous_hostname) != hostname {
+                dhcp_server.unregister_dns(client, previous_hostname, previous_entry.ip_addr);
             }
         }
-    } else {
-        /* [3] */
-        debug!("client is in RENEWING or REBINDING");
-        // リース延長要求、リース切れによる再要求
-        // 本来はこれらの状態で処理を分けるべきだが、簡略化のため同じように処理する。
-        let ip_from_client = received_packet.get_ciaddr();
-        if !dhcp_server.network_addr.contains(ip_from_client) {
-            return Err(failure::err_msg(
-                "Invalid ciaddr. Mismatched network address.",
-            ));
+    }
+    if let Some(hostname) = hostname {
+        if dhcp_server.updates_dns_for(received_packet) {
+            dhcp_server.register_dns(client, hostname, ip_addr);
         }
-        let dhcp_packet = make_dhcp_packet(
-            &received_packet,
-            &dhcp_server,
-            DHCPACK,
-            received_packet.get_ciaddr(),
-        )?;
-        util::send_dhcp_broadcast_response(soc, dhcp_packet.get_buffer())?;
-        info!("{:x}: sent DHCPACK", xid);
-        Ok(())
+    }
+}
+
+/**
+ * 使い続けたいIPアドレスがクライアントにリースしたものかどうか
+ */
+#[derive(Debug, PartialEq)]
+enum LeaseOwnership {
+    Owned,    // 有効なリースと一致する
+    NotOwned, // 記録はあるが、IPアドレスが違う・期限切れ・解放済み・別のネットワーク
+    Unknown,  // クライアントの記録がない
+}
+
+/**
+ * INIT-REBOOT・RENEWING・REBINDINGのクライアントが使い続けたいIPアドレスを、DBのリースと照合する。
+ *
+ * [note] RFC2131 4.3.2 では記録のないクライアントには応答してはいけない。
+ * 記録と一致しない場合はNAKを返し、クライアントにDHCPDISCOVERからやり直させる。
+ */
+fn check_lease_ownership(
+    entry: Option<&LeaseEntry>,
+    ip_from_client: Ipv4Addr,
+    network_addr: Ipv4Network,
+    now: i64,
+) -> LeaseOwnership {
+    match entry {
+        None => LeaseOwnership::Unknown,
+        Some(entry)
+            if entry.ip_addr == ip_from_client
+                && entry.is_active(now)
+                && network_addr.contains(ip_from_client) =>
+        {
+            LeaseOwnership::Owned
+        }
+        Some(_) => LeaseOwnership::NotOwned,
+    }
+}
+
+/**
+ * 応答の宛先を決める(RFC2131 4.1)
+ *
+ * [note] リレーエージェントを経由した場合(giaddrが0でない)はリレーエージェントのサーバポートへユニキャストする。
+ * DHCPNAKはブロードキャストする。RENEWING・REBINDINGのクライアントはciaddrのIPアドレスを使えるので、
+ * ciaddrへユニキャストする。それ以外のクライアントはまだIPアドレスを持たないのでブロードキャストする。
+ * (本来はbroadcastフラグが立っていなければyiaddrへユニキャストできるが、ARPテーブルを操作する必要があるので行わない)
+ */
+fn reply_destination(received_packet: &DhcpMessage, message_type: u8) -> SocketAddr {
+    let giaddr = received_packet.giaddr;
+    if !giaddr.is_unspecified() {
+        return SocketAddr::from((giaddr, DHCP_SERVER_PORT));
+    }
+    let ciaddr = received_packet.ciaddr;
+    if message_type != DHCPNAK && !ciaddr.is_unspecified() {
+        SocketAddr::from((ciaddr, DHCP_CLIENT_PORT))
+    } else {
+        SocketAddr::from((Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT))
     }
 }
 
 /**
  * DHCPRELEASEメッセージを受け取った時のハンドラ
  * DBからリース記録を論理削除し、割り当てていたIPアドレスをアドレスプールに戻す。
+ * ホスト名を登録していたらDNSのレコードも削除する。
+ *
+ * [note] ciaddrはクライアントが自由に書けるので、そのクライアントの有効なリースと一致する場合だけ解放する。
+ * 一致しなければ他のクライアントのIPアドレスを解放させないように無視する。
  */
 fn dhcp_release_message_handler(
     xid: u32,
-    dhcp_server: Arc<DhcpServer>,
-    received_packet: &DhcpPacket,
-    client_macaddr: MacAddr,
+    dhcp_server: &DhcpServer,
+    received_packet: &DhcpMessage,
+    client: &Client,
 ) -> Result<(), failure::Error> {
     info!("{:x}: received DHCPRELEASE", xid);
 
-    let mut con = dhcp_server.db_connection.lock().unwrap();
-    let tx = con.transaction()?;
-
     // 論理削除。DHCPOFFERメッセージを返す際に解放済のIPアドレスを再割り当てする場合があるから
-    database::delete_entry(&tx, client_macaddr)?;
-    tx.commit()?;
+    let entry = {
+        let mut store = dhcp_server.lease_store.lock().unwrap();
+        match store.select_lease_entry(client)? {
+            Some(entry)
+                if entry.is_active(util::unix_time_now())
+                    && entry.ip_addr == received_packet.ciaddr =>
+            {
+                store.delete_entry(client)?;
+                entry
+            }
+            _ => {
+                info!("{:x}: {} is not leased to the client", xid, received_packet.ciaddr);
+                return Ok(());
+            }
+        }
+    };
 
     debug!("{:x}: deleted from DB", xid);
     // 解放されたIPアドレスをアドレスプールに戻す。
-    dhcp_server.release_address(received_packet.get_ciaddr());
+    dhcp_server.release_address(entry.ip_addr);
+    if let Some(hostname) = &entry.hostname {
+        dhcp_server.unregister_dns(client, hostname, entry.ip_addr);
+    }
+    Ok(())
+}
+
+/**
+ * DHCPDECLINEメッセージを受け取った時のハンドラ
+ * クライアントがARP等で割り当てられたIPアドレスが既に使われていることを検出した場合に送られる。
+ * そのIPアドレスを一定期間割り当てないようにする(RFC2131 4.3.3)。
+ */
+fn dhcp_decline_message_handler(
+    xid: u32,
+    dhcp_server: &DhcpServer,
+    scope: &Scope,
+    received_packet: &DhcpMessage,
+    client: &Client,
+) -> Result<(), failure::Error> {
+    info!("{:x}: received DHCPDECLINE", xid);
+
+    if received_packet.server_identifier() != Some(scope.server_address) {
+        // 別のDHCPサーバが割り当てたIPアドレスに対するもの
+        info!("{:x}: DHCPDECLINE is not for this server", xid);
+        return Ok(());
+    }
+
+    let declined_ip = received_packet
+        .requested_ip_address()
+        .ok_or_else(|| failure::err_msg("DHCPDECLINE without requested ip address."))?;
+    if !scope.network_addr.contains(declined_ip) {
+        return Err(failure::format_err!(
+            "{:x}: declined address {} is not in the network",
+            xid,
+            declined_ip
+        ));
+    }
+
+    if !dhcp_server.decline_address(client, declined_ip)? {
+        info!("{:x}: {} is neither offered nor leased to {}", xid, declined_ip, client);
+        return Ok(());
+    }
+    // [note] 他のホストが静的に設定している可能性があるので管理者に知らせる
+    warn!(
+        "{:x}: {} reported that {} is already in use, quarantined for {} seconds",
+        xid, client, declined_ip, dhcp_server.decline_time
+    );
+    Ok(())
+}
+
+/**
+ * DHCPINFORMメッセージを受け取った時のハンドラ
+ * 既にIPアドレスを持つクライアントに、リース情報を含まない設定情報だけをDHCPACKで返す(RFC2131 4.3.5)。
+ */
+fn dhcp_inform_message_handler(
+    xid: u32,
+    dhcp_server: &DhcpServer,
+    scope: &Scope,
+    received_packet: &DhcpMessage,
+    soc: &UdpSocket,
+) -> Result<(), failure::Error> {
+    info!("{:x}: received DHCPINFORM", xid);
+
+    if received_packet.ciaddr.is_unspecified() {
+        return Err(failure::err_msg("DHCPINFORM without ciaddr."));
+    }
+    // IPアドレスは割り当てないのでyiaddrは0
+    let dhcp_packet =
+        make_dhcp_packet(dhcp_server, received_packet, scope, DHCPACK, Ipv4Addr::UNSPECIFIED)?;
+    util::send_dhcp_response(
+        soc,
+        &dhcp_packet.to_bytes(received_packet.reply_size_limit())?,
+        reply_destination(received_packet, DHCPACK),
+    )?;
+    info!("{:x}: sent DHCPACK to {}", xid, received_packet.ciaddr);
     Ok(())
 }
 
 /**
  * DHCPのパケットを作成して返す。
+ * ip_to_be_leasedが 0.0.0.0 の場合(DHCPNAK、DHCPINFORMへの応答)はリースに関するオプションを含めない。
  */
 fn make_dhcp_packet(
-    received_packet: &DhcpPacket,
-    dhcp_server: &Arc<DhcpServer>,
+    dhcp_server: &DhcpServer,
+    received_packet: &DhcpMessage,
+    scope: &Scope,
     message_type: u8,
     ip_to_be_leased: Ipv4Addr,
-) -> Result<DhcpPacket, failure::Error> {
-    // パケットの本体となるバッファ。ヒープに確保する。
-    let buffer = vec![0u8; DHCP_SIZE];
-    let mut dhcp_packet = DhcpPacket::new(buffer).unwrap();
+) -> Result<DhcpMessage, failure::Error> {
+    // 書き込むオプションの一覧
+    let mut dhcp_options = vec![DhcpOption::MessageType(message_type)];
+    if !ip_to_be_leased.is_unspecified() {
+        // [note] リース期間とT1/T2はDHCPNAKとDHCPINFORMへの応答に含めてはいけない(RFC2131 Table 3)
+        dhcp_options.push(DhcpOption::IpAddressLeaseTime(scope.lease_time));
+        dhcp_options.push(DhcpOption::RenewalTime(scope.renewal_time()));
+        dhcp_options.push(DhcpOption::RebindingTime(scope.rebinding_time()));
+        // Client FQDNを送ってきたクライアントには、サーバがDNSを更新するかを返す(RFC4702 4)
+        let hostname = scope.lease_hostname(received_packet);
+        if let Some(fqdn) = dhcp_server.client_fqdn_reply(received_packet, hostname.as_deref()) {
+            dhcp_options.push(DhcpOption::ClientFqdn(fqdn));
+        }
+    }
+    dhcp_options.push(DhcpOption::ServerIdentifier(scope.server_address));
+    dhcp_options.push(DhcpOption::SubnetMask(scope.network_addr.mask()));
+    // ルータとDNSサーバは複数指定できる。設定がなければオプションを含めない
+    if !scope.routers.is_empty() {
+        dhcp_options.push(DhcpOption::Router(scope.routers.clone()));
+    }
+    if !scope.dns_servers.is_empty() {
+        dhcp_options.push(DhcpOption::DomainNameServer(scope.dns_servers.clone()));
+    }
+    dhcp_options.extend(scope.options.iter().cloned());
+    // 予約のホスト名とオプションはスコープのオプションを上書きする
+    if let Some(reservation) = scope.find_reservation(&Client::from_message(received_packet)) {
+        let hostname = reservation.hostname.map(DhcpOption::HostName);
+        for option in hostname.into_iter().chain(reservation.options) {
+            dhcp_options.retain(|existing| existing.code() != option.code());
+            dhcp_options.push(option);
+        }
+    }
+    // [note] リレーエージェントが付加したオプション82はそのまま返す。リレーエージェントはこれを取り除いてクライアントへ転送する(RFC3046 2.2)
+    if let Some(relay_info) = received_packet.relay_agent_information() {
+        dhcp_options.push(DhcpOption::RelayAgentInformation(relay_info.to_vec()));
+    }
+    // クライアントが要求したオプションだけを返す
+    let dhcp_options = options::select_requested_options(
+        dhcp_options,
+        received_packet.parameter_request_list(),
+    );
 
+    let mut flags = received_packet.flags;
+    if message_type == DHCPNAK && !received_packet.giaddr.is_unspecified() {
+        // リレーエージェントにDHCPNAKをブロードキャストさせる(RFC2131 4.3.2)
+        flags |= BROADCAST_FLAG;
+    }
     // 各種フィールドの設定
-    dhcp_packet.set_op(BOOTREPLY);
-    dhcp_packet.set_htype(HTYPE_ETHER);
-    dhcp_packet.set_hlen(6); //MACアドレスのオクテット長
-    dhcp_packet.set_xid(received_packet.get_xid());
+    let mut builder = DhcpMessageBuilder::new(BOOTREPLY)
+        .xid(received_packet.xid)
+        .hardware_address(received_packet.htype, received_packet.hardware_address())
+        .yiaddr(ip_to_be_leased)
+        .flags(flags)
+        .giaddr(received_packet.giaddr)
+        .options(dhcp_options);
     if message_type == DHCPACK {
-        dhcp_packet.set_ciaddr(received_packet.get_ciaddr());
-    }
-    dhcp_packet.set_yiaddr(ip_to_be_leased);
-    dhcp_packet.set_flags(received_packet.get_flags());
-    dhcp_packet.set_giaddr(received_packet.get_giaddr());
-    dhcp_packet.set_chaddr(received_packet.get_chaddr());
-
-    // 各種オプションの設定
-    let mut cursor = dhcp::OPTIONS;
-    dhcp_packet.set_magic_cookie(&mut cursor);
-    dhcp_packet.set_option(
-        &mut cursor,
-        Code::MessageType as u8,
-        1,
-        Some(&[message_type]),
-    );
-    dhcp_packet.set_option(
-        &mut cursor,
-        Code::IPAddressLeaseTime as u8,
-        4,
-        Some(&dhcp_server.lease_time),
-    );
-    dhcp_packet.set_option(
-        &mut cursor,
-        Code::ServerIdentifier as u8,
-        4,
-        Some(&dhcp_server.server_address.octets()),
-    );
-    dhcp_packet.set_option(
-        &mut cursor,
-        Code::SubnetMask as u8,
-        4,
-        Some(&dhcp_server.subnet_mask.octets()),
-    );
-    dhcp_packet.set_option(
-        &mut cursor,
-        Code::Router as u8,
-        4,
-        Some(&dhcp_server.default_gateway.octets()),
-    );
-    dhcp_packet.set_option(
-        &mut cursor,
-        Code::DNS as u8,
-        4,
-        Some(&dhcp_server.dns_server.octets()),
-    );
+        builder = builder.ciaddr(received_packet.ciaddr);
+    }
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dhcp_server::message::HTYPE_ETHER;
+    use dhcp_server::options::{ClientFqdn, Code};
+    use dhcp_server::relay;
+    use dhcp_server::test_support::test_server;
+    use pnet::util::MacAddr;
+
+    const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 10);
+
+    fn network() -> Ipv4Network {
+        Ipv4Network::new(Ipv4Addr::new(192, 168, 0, 0), 24).unwrap()
+    }
+
+    fn lease(ip_addr: Ipv4Addr, deleted: bool, lease_expiry: i64) -> LeaseEntry {
+        LeaseEntry {
+            ip_addr,
+            deleted,
+            lease_expiry,
+            hostname: None,
+        }
+    }
+
+    #[test]
+    fn test_check_lease_ownership() {
+        let now = 1000;
+        assert_eq!(
+            LeaseOwnership::Owned,
+            check_lease_ownership(Some(&lease(CLIENT_IP, false, 1300)), CLIENT_IP, network(), now)
+        );
+        // 他のクライアントに割り当てたIPアドレスは延長させない
+        assert_eq!(
+            LeaseOwnership::NotOwned,
+            check_lease_ownership(
+                Some(&lease(Ipv4Addr::new(192, 168, 0, 11), false, 1300)),
+                CLIENT_IP,
+                network(),
+                now
+            )
+        );
+        // 期限切れ・解放済みのリース
+        assert_eq!(
+            LeaseOwnership::NotOwned,
+            check_lease_ownership(Some(&lease(CLIENT_IP, false, 1000)), CLIENT_IP, network(), now)
+        );
+        assert_eq!(
+            LeaseOwnership::NotOwned,
+            check_lease_ownership(Some(&lease(CLIENT_IP, true, 1300)), CLIENT_IP, network(), now)
+        );
+        assert_eq!(
+            LeaseOwnership::Unknown,
+            check_lease_ownership(None, CLIENT_IP, network(), now)
+        );
+    }
+
+    #[test]
+    fn test_release_handler_checks_ciaddr() {
+        let dhcp_server = test_server();
+        let mac_addr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let client = Client::from_mac_addr(mac_addr);
+        let other = Client::from_mac_addr(MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x66));
+        let other_ip = Ipv4Addr::new(192, 168, 0, 11);
+        {
+            let mut store = dhcp_server.lease_store.lock().unwrap();
+            store.insert_entry(&client, CLIENT_IP, 0, i64::MAX).unwrap();
+            store.insert_entry(&other, other_ip, 0, i64::MAX).unwrap();
+        }
+        dhcp_server.pick_specified_ip(CLIENT_IP);
+        dhcp_server.pick_specified_ip(other_ip);
+        let release = |ciaddr| {
+            DhcpMessageBuilder::new(BOOTREQUEST)
+                .hardware_address(HTYPE_ETHER, &mac_addr.octets())
+                .ciaddr(ciaddr)
+                .options(vec![DhcpOption::MessageType(DHCPRELEASE)])
+                .build()
+        };
+
+        // 他のクライアントのIPアドレスを書いたDHCPRELEASEは無視する
+        dhcp_release_message_handler(1, &dhcp_server, &release(other_ip), &client).unwrap();
+        let store = dhcp_server.lease_store.lock().unwrap();
+        assert!(!store.select_lease_entry(&client).unwrap().unwrap().deleted);
+        assert!(!store.select_lease_entry(&other).unwrap().unwrap().deleted);
+        drop(store);
+        assert!(dhcp_server.pick_specified_ip(other_ip).is_none());
+
+        dhcp_release_message_handler(2, &dhcp_server, &release(CLIENT_IP), &client).unwrap();
+        let store = dhcp_server.lease_store.lock().unwrap();
+        assert!(store.select_lease_entry(&client).unwrap().unwrap().deleted);
+        drop(store);
+        assert!(dhcp_server.pick_specified_ip(CLIENT_IP).is_some());
+    }
+
+    #[test]
+    fn test_decline_handler_checks_server_identifier() {
+        let dhcp_server = test_server();
+        let mac_addr = MacAddr(0, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let client = Client::from_mac_addr(mac_addr);
+        let decline = |server_id: Ipv4Addr| {
+            DhcpMessageBuilder::new(BOOTREQUEST)
+                .hardware_address(HTYPE_ETHER, &mac_addr.octets())
+                .options(vec![
+                    DhcpOption::MessageType(DHCPDECLINE),
+                    DhcpOption::ServerIdentifier(server_id),
+                    DhcpOption::RequestedIpAddress(CLIENT_IP),
+                ])
+                .build()
+        };
+
+        // 他のサーバ宛てのDHCPDECLINEは無視する
+        let other_server = Ipv4Addr::new(192, 168, 0, 3);
+        let scope = &dhcp_server.scopes()[0];
+        dhcp_decline_message_handler(1, &dhcp_server, scope, &decline(other_server), &client)
+            .unwrap();
+        assert!(dhcp_server.pick_specified_ip(CLIENT_IP).is_some());
+        dhcp_server.release_address(CLIENT_IP);
+
+        // 提案もリースもしていないIPアドレスも無視する
+        let this_server = scope.server_address;
+        dhcp_decline_message_handler(2, &dhcp_server, scope, &decline(this_server), &client)
+            .unwrap();
+        assert!(dhcp_server.pick_specified_ip(CLIENT_IP).is_some());
+
+        dhcp_server.record_offer(&client, 3, CLIENT_IP, true);
+        dhcp_decline_message_handler(3, &dhcp_server, scope, &decline(this_server), &client)
+            .unwrap();
+        let store = dhcp_server.lease_store.lock().unwrap();
+        assert_eq!(vec![CLIENT_IP], store.select_conflicted_addresses().unwrap());
+    }
+
+    #[test]
+    fn test_inform_reply_has_no_lease() {
+        let dhcp_server = test_server();
+        let request = DhcpMessageBuilder::new(BOOTREQUEST)
+            .ciaddr(CLIENT_IP)
+            .options(vec![DhcpOption::MessageType(DHCPINFORM)])
+            .build();
+        let scope = &dhcp_server.select_scope(&request, None).unwrap();
+
+        let reply = make_dhcp_packet(
+            &dhcp_server,
+            &request,
+            scope,
+            DHCPACK,
+            Ipv4Addr::UNSPECIFIED,
+        )
+        .unwrap();
+        assert_eq!(Some(DHCPACK), reply.message_type());
+        assert_eq!(CLIENT_IP, reply.ciaddr);
+        assert_eq!(Ipv4Addr::UNSPECIFIED, reply.yiaddr);
+        assert_eq!(None, reply.option(Code::IPAddressLeaseTime));
+        assert_eq!(None, reply.option(Code::RenewalTime));
+        assert_eq!(
+            Some(&DhcpOption::Router(vec![Ipv4Addr::new(192, 168, 0, 1)])),
+            reply.option(Code::Router)
+        );
+        assert_eq!(
+            SocketAddr::from((CLIENT_IP, DHCP_CLIENT_PORT)),
+            reply_destination(&request, DHCPACK)
+        );
+
+        // 割り当てる場合はリース期間とT1/T2を含める
+        let reply = make_dhcp_packet(&dhcp_server, &request, scope, DHCPACK, CLIENT_IP).unwrap();
+        assert_eq!(
+            Some(&DhcpOption::IpAddressLeaseTime(300)),
+            reply.option(Code::IPAddressLeaseTime)
+        );
+        assert_eq!(Some(&DhcpOption::RenewalTime(150)), reply.option(Code::RenewalTime));
+        assert_eq!(Some(&DhcpOption::RebindingTime(262)), reply.option(Code::RebindingTime));
+    }
+
+    #[test]
+    fn test_reply_destination() {
+        let mut packet = DhcpMessageBuilder::new(BOOTREQUEST).build();
+        let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT));
+        assert_eq!(broadcast, reply_destination(&packet, DHCPOFFER));
+
+        // RENEWINGのクライアントにはユニキャストで返す
+        packet.ciaddr = CLIENT_IP;
+        assert_eq!(
+            SocketAddr::from((CLIENT_IP, DHCP_CLIENT_PORT)),
+            reply_destination(&packet, DHCPACK)
+        );
+        assert_eq!(broadcast, reply_destination(&packet, DHCPNAK));
+    }
+
+    #[test]
+    fn test_reserved_address_is_offered_to_owner() {
+        let dhcp_server = test_server();
+        let scope = &dhcp_server.scopes()[0];
+        let request = DhcpMessageBuilder::new(BOOTREQUEST)
+            .hardware_address(HTYPE_ETHER, &[0, 0x11, 0x22, 0x33, 0x44, 0x77])
+            .options(vec![
+                DhcpOption::MessageType(DHCPDISCOVER),
+                // 他のIPアドレスを要求しても予約されたIPアドレスにする
+                DhcpOption::RequestedIpAddress(Ipv4Addr::new(192, 168, 0, 30)),
+            ])
+            .build();
+
+        let reserved_ip = Ipv4Addr::new(192, 168, 0, 3);
+        assert_eq!(
+            (reserved_ip, false),
+            select_lease_ip(&dhcp_server, scope, &request).unwrap()
+        );
+        let reply =
+            make_dhcp_packet(&dhcp_server, &request, scope, DHCPOFFER, reserved_ip).unwrap();
+        assert_eq!(
+            Some(&DhcpOption::HostName("printer".to_string())),
+            reply.option(Code::HostName)
+        );
+    }
+
+    #[test]
+    fn test_reservation_overrides_options() {
+        let dhcp_server = test_server();
+        let scope = &dhcp_server.scopes()[0];
+        let client_id = vec![0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x88];
+        let request = DhcpMessageBuilder::new(BOOTREQUEST)
+            .options(vec![DhcpOption::ClientIdentifier(client_id)])
+            .build();
+
+        let reserved_ip = Ipv4Addr::new(192, 168, 0, 200);
+        let reply = make_dhcp_packet(&dhcp_server, &request, scope, DHCPACK, reserved_ip).unwrap();
+        assert_eq!(
+            Some(&DhcpOption::DomainName("lab.example".to_string())),
+            reply.option(Code::DomainName)
+        );
+        assert_eq!(None, reply.option(Code::HostName));
+    }
+
+    #[test]
+    fn test_reply_follows_parameter_request_list() {
+        let dhcp_server = test_server();
+        let scope = &dhcp_server.scopes()[1];
+        let requested = vec![
+            Code::ClasslessStaticRoute as u8,
+            Code::SubnetMask as u8,
+            Code::Router as u8,
+        ];
+        let request = DhcpMessageBuilder::new(BOOTREQUEST)
+            .options(vec![DhcpOption::ParameterRequestList(requested)])
+            .build();
 
-    dhcp_packet.set_option(&mut cursor, Code::End as u8, 0, None);
-    Ok(dhcp_packet)
-}
\ No newline at end of file
+        let leased_ip = Ipv4Addr::new(10, 0, 1, 100);
+        let reply = make_dhcp_packet(&dhcp_server, &request, scope, DHCPACK, leased_ip).unwrap();
+        let codes: Vec<u8> = reply.options.iter().map(|option| option.code()).collect();
+        // リースに必要なオプションは要求に関わらず先頭に含め、残りは要求の順に並べる
+        assert_eq!(
+            vec![
+                Code::MessageType as u8,
+                Code::IPAddressLeaseTime as u8,
+                Code::RenewalTime as u8,
+                Code::RebindingTime as u8,
+                Code::ServerIdentifier as u8,
+                Code::ClasslessStaticRoute as u8,
+                Code::SubnetMask as u8,
+                Code::Router as u8,
+            ],
+            codes
+        );
+        assert_eq!(
+            Some(&DhcpOption::Router(vec![
+                Ipv4Addr::new(10, 0, 1, 1),
+                Ipv4Addr::new(10, 0, 1, 254),
+            ])),
+            reply.option(Code::Router)
+        );
+    }
+
+    #[test]
+    fn test_client_fqdn_reply() {
+        // 動的DNS更新の設定がなければ、登録しないことをNフラグで伝える
+        let dhcp_server = test_server();
+        let scope = &dhcp_server.scopes()[0];
+        let fqdn = |flags| {
+            DhcpOption::ClientFqdn(ClientFqdn { flags, name: "laptop".to_string() })
+        };
+        let request = DhcpMessageBuilder::new(BOOTREQUEST)
+            .options(vec![fqdn(options::FQDN_S | options::FQDN_E)])
+            .build();
+        let leased_ip = Ipv4Addr::new(192, 168, 0, 10);
+        let reply = make_dhcp_packet(&dhcp_server, &request, scope, DHCPACK, leased_ip).unwrap();
+        assert_eq!(
+            Some(&fqdn(options::FQDN_N | options::FQDN_E)),
+            reply.option(Code::ClientFqdn)
+        );
+        let reply =
+            make_dhcp_packet(&dhcp_server, &request, scope, DHCPNAK, Ipv4Addr::UNSPECIFIED)
+                .unwrap();
+        assert_eq!(None, reply.option(Code::ClientFqdn));
+    }
+
+    fn relayed_request(giaddr: Ipv4Addr, relay_info: &[u8]) -> DhcpMessage {
+        DhcpMessageBuilder::new(BOOTREQUEST)
+            .giaddr(giaddr)
+            .options(vec![
+                DhcpOption::MessageType(DHCPREQUEST),
+                DhcpOption::RelayAgentInformation(relay_info.to_vec()),
+            ])
+            .build()
+    }
+
+    #[test]
+    fn test_relayed_reply() {
+        let dhcp_server = test_server();
+        let giaddr = Ipv4Addr::new(10, 0, 1, 1);
+        let relay_info = [relay::AGENT_CIRCUIT_ID, 5, b's', b'w', b'1', b'/', b'1'];
+        let mut request = relayed_request(giaddr, &relay_info);
+        request.ciaddr = Ipv4Addr::new(10, 0, 1, 100);
+        let scope = &dhcp_server.select_scope(&request, None).unwrap();
+        assert_eq!("office", scope.name);
+
+        // リレーエージェントにはDHCPNAKも含めてサーバポートへユニキャストする
+        for message_type in [DHCPOFFER, DHCPACK, DHCPNAK] {
+            assert_eq!(
+                SocketAddr::from((giaddr, DHCP_SERVER_PORT)),
+                reply_destination(&request, message_type)
+            );
+        }
+        let reply =
+            make_dhcp_packet(&dhcp_server, &request, scope, DHCPACK, Ipv4Addr::new(10, 0, 1, 100))
+                .unwrap();
+        assert_eq!(giaddr, reply.giaddr);
+        assert_eq!(0, reply.flags);
+        // オプション82はそのまま返す
+        assert_eq!(Some(&relay_info[..]), reply.relay_agent_information());
+
+        // DHCPNAKはリレーエージェントにブロードキャストさせる
+        let reply =
+            make_dhcp_packet(&dhcp_server, &request, scope, DHCPNAK, Ipv4Addr::UNSPECIFIED)
+                .unwrap();
+        assert_eq!(BROADCAST_FLAG, reply.flags);
+    }
+
+    #[test]
+    fn test_relay_policy() {
+        let dhcp_server = test_server();
+        let giaddr = Ipv4Addr::new(10, 0, 1, 1);
+        let accept = |relay_info: Option<&[u8]>| {
+            let info = relay_info.map(|buf| RelayAgentInformation::parse(buf).unwrap());
+            let office = &dhcp_server.scopes()[1];
+            dhcp_server.relay_policy.accept(office, giaddr, info.as_ref())
+        };
+        assert!(accept(Some(&[relay::AGENT_CIRCUIT_ID, 5, b's', b'w', b'1', b'/', b'1'])));
+        assert!(accept(Some(&[relay::AGENT_CIRCUIT_ID, 2, 0, 1, relay::AGENT_REMOTE_ID, 1, 9])));
+        assert!(!accept(Some(&[relay::AGENT_CIRCUIT_ID, 5, b's', b'w', b'1', b'/', b'2'])));
+        // 許可リストがあるスコープではcircuit-idのないリクエストに応答しない
+        assert!(!accept(None));
+
+        // 許可リストのないスコープは全て応答する
+        let lan = &dhcp_server.scopes()[0];
+        assert!(dhcp_server.relay_policy.accept(lan, giaddr, None));
+    }
+}
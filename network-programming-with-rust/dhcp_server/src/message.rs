@@ -0,0 +1,700 @@
+use std::net::Ipv4Addr;
+
+use super::options::{self, ClientFqdn, Code, DhcpOption};
+
+// [note] 以降のconstはRFC2131で記載の以下図でのDHCPパケットの構成である。
+// https://datatracker.ietf.org/doc/html/rfc2131#autoid-8
+
+/*
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |     op (1)    |   htype (1)   |   hlen (1)    |   hops (1)    |
+   +---------------+---------------+---------------+---------------+
+   |                            xid (4)                            |
+   +-------------------------------+-------------------------------+
+   |           secs (2)            |           flags (2)           |
+   +-------------------------------+-------------------------------+
+   |                          ciaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                          yiaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                          siaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                          giaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                                                               |
+   |                          chaddr  (16)                         |
+   |                                                               |
+   |                                                               |
+   +---------------------------------------------------------------+
+   |                                                               |
+   |                          sname   (64)                         |
+   +---------------------------------------------------------------+
+   |                                                               |
+   |                          file    (128)                        |
+   +---------------------------------------------------------------+
+   |                                                               |
+   |                          options (variable)                   |
+   +---------------------------------------------------------------+
+
+                  Figure 1:  Format of a DHCP message
+ */
+const OP: usize = 0;
+const HTYPE: usize = 1;
+const HLEN: usize = 2;
+const HOPS: usize = 3;
+const XID: usize = 4;
+const SECS: usize = 8;
+const FLAGS: usize = 10;
+const CIADDR: usize = 12;
+const YIADDR: usize = 16;
+const SIADDR: usize = 20;
+const GIADDR: usize = 24;
+const CHADDR: usize = 28;
+const SNAME: usize = 44;
+const FILE: usize = 108;
+const OPTIONS: usize = 236;
+
+const CHADDR_LEN: usize = SNAME - CHADDR;
+const SNAME_LEN: usize = FILE - SNAME;
+const FILE_LEN: usize = OPTIONS - FILE;
+
+// optionsフィールドの先頭に置く値。これがなければDHCPのメッセージではない(RFC2131 3)
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+// イーサネットのハードウェアタイプ(RFC1700)
+pub const HTYPE_ETHER: u8 = 1;
+
+// BOOTPのメッセージの最小長。これより短いと受け付けないリレーエージェントがある(RFC1542 2.1)
+const MINIMUM_MESSAGE_SIZE: usize = 300;
+
+// クライアントが必ず受け取れるIPデータグラムの長さと、そのうちのIPヘッダとUDPヘッダの長さ(RFC2131 2)
+const MINIMUM_DATAGRAM_SIZE: usize = 576;
+const IP_UDP_HEADER_SIZE: usize = 28;
+
+// Option Overload(オプション52)の値。オプションの続きを置いたフィールド(RFC2132 9.3)
+const OVERLOAD_FILE: u8 = 1;
+const OVERLOAD_SNAME: u8 = 2;
+
+/**
+ * DHCPのメッセージを表現する。
+ * 受信したバイト列はparseで検証してから扱い、送信するものはDhcpMessageBuilderで組み立てる。
+ *
+ * [note] sname/fileはNULまでの値を持つ。オプション領域として使われていた場合(Option Overload)は空になり、
+ * そこに置かれていたオプションはoptionsに入る。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhcpMessage {
+    pub op: u8,
+    pub htype: u8,
+    pub hlen: u8,
+    pub hops: u8,
+    pub xid: u32,
+    pub secs: u16,
+    pub flags: u16,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: [u8; CHADDR_LEN],
+    pub sname: Vec<u8>,
+    pub file: Vec<u8>,
+    pub options: Vec<DhcpOption>,
+}
+
+impl DhcpMessage {
+    /**
+     * 受信したバイト列をDHCPのメッセージとして解釈する。
+     * 短すぎる、マジッククッキーがない、オプション領域が壊れている場合はErr。
+     * 値が不正なオプションは無いものとして扱う。
+     */
+    pub fn parse(buf: &[u8]) -> Result<DhcpMessage, failure::Error> {
+        if buf.len() < OPTIONS + MAGIC_COOKIE.len() {
+            return Err(failure::format_err!("message is too short: {} octets", buf.len()));
+        }
+        if buf[OPTIONS..OPTIONS + MAGIC_COOKIE.len()] != MAGIC_COOKIE {
+            return Err(failure::err_msg("message does not have the magic cookie"));
+        }
+        if buf[HLEN] as usize > CHADDR_LEN {
+            return Err(failure::format_err!("invalid hardware address length: {}", buf[HLEN]));
+        }
+
+        let mut raw_options = options::parse_raw_options(&buf[OPTIONS + MAGIC_COOKIE.len()..])?;
+        let mut sname = until_nul(&buf[SNAME..FILE]);
+        let mut file = until_nul(&buf[FILE..OPTIONS]);
+        let overload_code = Code::OptionOverload as u8;
+        if let Some(index) = raw_options.iter().position(|(code, _)| *code == overload_code) {
+            let overload = match raw_options.remove(index).1.as_slice() {
+                [value @ 1..=3] => *value,
+                value => return Err(failure::format_err!("invalid option overload: {:?}", value)),
+            };
+            // [note] 分割されたオプションはoptions, file, snameの順に連結する(RFC3396 6)
+            if overload & OVERLOAD_FILE != 0 {
+                concatenate(&mut raw_options, options::parse_raw_options(&buf[FILE..OPTIONS])?);
+                file.clear();
+            }
+            if overload & OVERLOAD_SNAME != 0 {
+                concatenate(&mut raw_options, options::parse_raw_options(&buf[SNAME..FILE])?);
+                sname.clear();
+            }
+            // sname/fileの中のOption Overloadは無視する
+            raw_options.retain(|(code, _)| *code != overload_code);
+        }
+        let options = raw_options
+            .into_iter()
+            .filter_map(|(code, value)| match DhcpOption::decode(code, &value) {
+                Ok(option) => Some(option),
+                Err(e) => {
+                    debug!("ignored invalid option: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        let mut chaddr = [0u8; CHADDR_LEN];
+        chaddr.copy_from_slice(&buf[CHADDR..SNAME]);
+        Ok(DhcpMessage {
+            op: buf[OP],
+            htype: buf[HTYPE],
+            hlen: buf[HLEN],
+            hops: buf[HOPS],
+            xid: u32::from_be_bytes([buf[XID], buf[XID + 1], buf[XID + 2], buf[XID + 3]]),
+            secs: u16::from_be_bytes([buf[SECS], buf[SECS + 1]]),
+            flags: u16::from_be_bytes([buf[FLAGS], buf[FLAGS + 1]]),
+            ciaddr: ipv4addr_at(buf, CIADDR),
+            yiaddr: ipv4addr_at(buf, YIADDR),
+            siaddr: ipv4addr_at(buf, SIADDR),
+            giaddr: ipv4addr_at(buf, GIADDR),
+            chaddr,
+            sname,
+            file,
+            options,
+        })
+    }
+
+    /**
+     * 送信するバイト列にする。max_sizeはメッセージの最大長(reply_size_limitを参照)。
+     *
+     * [note] オプションは以下のように CodeとLenが先頭にありそれがそれぞれ1オクテットで固定あり、その後に可変の値が続く。
+     * optionsフィールドに収まらない場合はfile、snameフィールドの順にオプションの続きを置き、
+     * Option Overload(オプション52)でそれを知らせる(RFC2131 4.1)。
+     * sname/fileに値がある場合は使えないのでErr。
+     *
+     * ```text
+        Code   Len         Address 1               Address 2
+        +-----+-----+-----+-----+-----+-----+-----+-----+--
+        |  6  |  n  |  a1 |  a2 |  a3 |  a4 |  a1 |  a2 |  ...
+        +-----+-----+-----+-----+-----+-----+-----+-----+--
+     * ```
+     */
+    pub fn to_bytes(&self, max_size: usize) -> Result<Vec<u8>, failure::Error> {
+        if self.hlen as usize > CHADDR_LEN {
+            return Err(failure::format_err!("invalid hardware address length: {}", self.hlen));
+        }
+        if self.sname.len() > SNAME_LEN || self.file.len() > FILE_LEN {
+            return Err(failure::err_msg("sname or file is too long"));
+        }
+
+        let mut buf = vec![0u8; OPTIONS];
+        buf[OP] = self.op;
+        buf[HTYPE] = self.htype;
+        buf[HLEN] = self.hlen;
+        buf[HOPS] = self.hops;
+        buf[XID..SECS].copy_from_slice(&self.xid.to_be_bytes());
+        buf[SECS..FLAGS].copy_from_slice(&self.secs.to_be_bytes());
+        buf[FLAGS..CIADDR].copy_from_slice(&self.flags.to_be_bytes());
+        buf[CIADDR..YIADDR].copy_from_slice(&self.ciaddr.octets());
+        buf[YIADDR..SIADDR].copy_from_slice(&self.yiaddr.octets());
+        buf[SIADDR..GIADDR].copy_from_slice(&self.siaddr.octets());
+        buf[GIADDR..CHADDR].copy_from_slice(&self.giaddr.octets());
+        buf[CHADDR..SNAME].copy_from_slice(&self.chaddr);
+        buf.extend_from_slice(&MAGIC_COOKIE);
+
+        let units = option_units(&self.options);
+        let options_len: usize = units.iter().map(|unit| unit.len()).sum();
+        if buf.len() + options_len < max_size {
+            buf[SNAME..SNAME + self.sname.len()].copy_from_slice(&self.sname);
+            buf[FILE..FILE + self.file.len()].copy_from_slice(&self.file);
+            units.iter().for_each(|unit| buf.extend_from_slice(unit));
+            buf.push(Code::End as u8);
+        } else {
+            if !self.sname.is_empty() || !self.file.is_empty() {
+                return Err(failure::err_msg("options do not fit in the message"));
+            }
+            // 各領域の容量(Endと、optionsフィールドはOption Overloadの分を除く)
+            let capacities = [
+                max_size.saturating_sub(buf.len() + 3 + 1),
+                FILE_LEN - 1,
+                SNAME_LEN - 1,
+            ];
+            let mut areas: [Vec<u8>; 3] = Default::default();
+            let mut area = 0;
+            for unit in units.iter() {
+                while areas[area].len() + unit.len() > capacities[area] {
+                    area += 1;
+                    if area == areas.len() {
+                        return Err(failure::err_msg("options do not fit in the message"));
+                    }
+                }
+                areas[area].extend_from_slice(unit);
+            }
+            let [in_options, in_file, in_sname] = areas;
+            let overload = if in_sname.is_empty() {
+                OVERLOAD_FILE
+            } else {
+                OVERLOAD_FILE | OVERLOAD_SNAME
+            };
+            buf.extend_from_slice(&[Code::OptionOverload as u8, 1, overload]);
+            buf.extend_from_slice(&in_options);
+            buf.push(Code::End as u8);
+            for (start, area) in [(FILE, in_file), (SNAME, in_sname)] {
+                if !area.is_empty() {
+                    buf[start..start + area.len()].copy_from_slice(&area);
+                    buf[start + area.len()] = Code::End as u8;
+                }
+            }
+        }
+        if buf.len() < MINIMUM_MESSAGE_SIZE {
+            buf.resize(MINIMUM_MESSAGE_SIZE, Code::Pad as u8);
+        }
+        Ok(buf)
+    }
+
+    /**
+     * chaddrのうちhlenの長さの部分
+     */
+    pub fn hardware_address(&self) -> &[u8] {
+        &self.chaddr[..(self.hlen as usize).min(CHADDR_LEN)]
+    }
+
+    /**
+     * このメッセージへの応答の最大長。
+     * [note] Maximum DHCP Message Size(オプション57)はIPヘッダとUDPヘッダを含めた長さ。
+     * 指定がなくてもクライアントは576オクテットのデータグラムを受け取れる(RFC2131 2)。
+     */
+    pub fn reply_size_limit(&self) -> usize {
+        let datagram_size = match self.option(Code::MaximumMessageSize) {
+            Some(DhcpOption::MaximumMessageSize(size)) => *size as usize,
+            _ => MINIMUM_DATAGRAM_SIZE,
+        };
+        datagram_size.max(MINIMUM_DATAGRAM_SIZE) - IP_UDP_HEADER_SIZE
+    }
+
+    /**
+     * 指定のコードのオプションを返す
+     */
+    pub fn option(&self, code: Code) -> Option<&DhcpOption> {
+        let code = code as u8;
+        self.options.iter().find(|option| option.code() == code)
+    }
+
+    pub fn message_type(&self) -> Option<u8> {
+        match self.option(Code::MessageType)? {
+            DhcpOption::MessageType(message_type) => Some(*message_type),
+            _ => None,
+        }
+    }
+
+    pub fn requested_ip_address(&self) -> Option<Ipv4Addr> {
+        match self.option(Code::RequestedIpAddress)? {
+            DhcpOption::RequestedIpAddress(ip_addr) => Some(*ip_addr),
+            _ => None,
+        }
+    }
+
+    pub fn server_identifier(&self) -> Option<Ipv4Addr> {
+        match self.option(Code::ServerIdentifier)? {
+            DhcpOption::ServerIdentifier(ip_addr) => Some(*ip_addr),
+            _ => None,
+        }
+    }
+
+    pub fn client_identifier(&self) -> Option<&[u8]> {
+        match self.option(Code::ClientIdentifier)? {
+            DhcpOption::ClientIdentifier(client_id) => Some(client_id),
+            _ => None,
+        }
+    }
+
+    pub fn parameter_request_list(&self) -> Option<&[u8]> {
+        match self.option(Code::ParameterRequestList)? {
+            DhcpOption::ParameterRequestList(codes) => Some(codes),
+            _ => None,
+        }
+    }
+
+    pub fn relay_agent_information(&self) -> Option<&[u8]> {
+        match self.option(Code::RelayAgentInformation)? {
+            DhcpOption::RelayAgentInformation(info) => Some(info),
+            _ => None,
+        }
+    }
+
+    pub fn host_name(&self) -> Option<&str> {
+        match self.option(Code::HostName)? {
+            DhcpOption::HostName(host_name) => Some(host_name),
+            _ => None,
+        }
+    }
+
+    pub fn client_fqdn(&self) -> Option<&ClientFqdn> {
+        match self.option(Code::ClientFqdn)? {
+            DhcpOption::ClientFqdn(fqdn) => Some(fqdn),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * DhcpMessageを組み立てる。
+ * 指定しなかったフィールドは0、ハードウェアタイプはイーサネットになる。
+ */
+pub struct DhcpMessageBuilder {
+    message: DhcpMessage,
+}
+
+impl DhcpMessageBuilder {
+    pub fn new(op: u8) -> DhcpMessageBuilder {
+        DhcpMessageBuilder {
+            message: DhcpMessage {
+                op,
+                htype: HTYPE_ETHER,
+                hlen: 6, //MACアドレスのオクテット長
+                hops: 0,
+                xid: 0,
+                secs: 0,
+                flags: 0,
+                ciaddr: Ipv4Addr::UNSPECIFIED,
+                yiaddr: Ipv4Addr::UNSPECIFIED,
+                siaddr: Ipv4Addr::UNSPECIFIED,
+                giaddr: Ipv4Addr::UNSPECIFIED,
+                chaddr: [0; CHADDR_LEN],
+                sname: Vec::new(),
+                file: Vec::new(),
+                options: Vec::new(),
+            },
+        }
+    }
+
+    pub fn xid(mut self, xid: u32) -> DhcpMessageBuilder {
+        self.message.xid = xid;
+        self
+    }
+
+    pub fn flags(mut self, flags: u16) -> DhcpMessageBuilder {
+        self.message.flags = flags;
+        self
+    }
+
+    pub fn ciaddr(mut self, ciaddr: Ipv4Addr) -> DhcpMessageBuilder {
+        self.message.ciaddr = ciaddr;
+        self
+    }
+
+    pub fn yiaddr(mut self, yiaddr: Ipv4Addr) -> DhcpMessageBuilder {
+        self.message.yiaddr = yiaddr;
+        self
+    }
+
+    pub fn giaddr(mut self, giaddr: Ipv4Addr) -> DhcpMessageBuilder {
+        self.message.giaddr = giaddr;
+        self
+    }
+
+    /**
+     * ハードウェアタイプとアドレスを設定する。アドレスが16オクテットを超える分は切り捨てる。
+     */
+    pub fn hardware_address(mut self, htype: u8, addr: &[u8]) -> DhcpMessageBuilder {
+        let len = addr.len().min(CHADDR_LEN);
+        self.message.htype = htype;
+        self.message.hlen = len as u8;
+        self.message.chaddr = [0; CHADDR_LEN];
+        self.message.chaddr[..len].copy_from_slice(&addr[..len]);
+        self
+    }
+
+    pub fn options(mut self, options: Vec<DhcpOption>) -> DhcpMessageBuilder {
+        self.message.options.extend(options);
+        self
+    }
+
+    pub fn build(self) -> DhcpMessage {
+        self.message
+    }
+}
+
+/**
+ * NULの手前までを返す
+ */
+fn until_nul(field: &[u8]) -> Vec<u8> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    field[..end].to_vec()
+}
+
+fn ipv4addr_at(buf: &[u8], index: usize) -> Ipv4Addr {
+    Ipv4Addr::new(buf[index], buf[index + 1], buf[index + 2], buf[index + 3])
+}
+
+/**
+ * 別の領域のオプションを連結する。同じコードのものは値を後ろに繋げる(RFC3396)。
+ */
+fn concatenate(options: &mut Vec<(u8, Vec<u8>)>, more: Vec<(u8, Vec<u8>)>) {
+    for (code, value) in more {
+        match options.iter_mut().find(|(existing, _)| *existing == code) {
+            Some((_, concatenated)) => concatenated.extend(value),
+            None => options.push((code, value)),
+        }
+    }
+}
+
+/**
+ * オプションを、領域をまたいで置ける単位(Code, Len, 値)ごとのバイト列にする。
+ * Option Overloadはto_bytesが置くので含めない。
+ */
+fn option_units(dhcp_options: &[DhcpOption]) -> Vec<Vec<u8>> {
+    let overload = Code::OptionOverload as u8;
+    let encoded = options::encode_options(dhcp_options);
+    let mut units = Vec::new();
+    let mut index = 0;
+    while index < encoded.len() {
+        let end = index + 2 + encoded[index + 1] as usize;
+        if encoded[index] != overload {
+            units.push(encoded[index..end].to_vec());
+        }
+        index = end;
+    }
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::ClasslessRoute;
+    use ipnetwork::Ipv4Network;
+    use std::ops::Range;
+
+    /**
+     * テスト用の疑似乱数(xorshift)。同じシードからは同じメッセージを作る。
+     */
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, lens: Range<usize>) -> Vec<u8> {
+            let len = lens.start + self.below(lens.len());
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        fn ip(&mut self) -> Ipv4Addr {
+            Ipv4Addr::from(self.next() as u32)
+        }
+
+        fn ips(&mut self) -> Vec<Ipv4Addr> {
+            (0..1 + self.below(4)).map(|_| self.ip()).collect()
+        }
+
+        fn text(&mut self, max_len: usize) -> Vec<u8> {
+            (0..1 + self.below(max_len)).map(|_| b'a' + self.below(26) as u8).collect()
+        }
+    }
+
+    /**
+     * 値が正しいオプションを作る。コードは重複しない。
+     */
+    fn random_options(rng: &mut Rng) -> Vec<DhcpOption> {
+        let mut options: Vec<DhcpOption> = Vec::new();
+        for _ in 0..rng.below(12) {
+            let text = String::from_utf8(rng.text(40)).unwrap();
+            let option = match rng.below(12) {
+                0 => DhcpOption::MessageType(1 + rng.below(8) as u8),
+                1 => DhcpOption::SubnetMask(rng.ip()),
+                2 => DhcpOption::Router(rng.ips()),
+                3 => DhcpOption::DomainNameServer(rng.ips()),
+                4 => DhcpOption::HostName(text),
+                5 => DhcpOption::IpAddressLeaseTime(rng.next() as u32),
+                6 => DhcpOption::ParameterRequestList(rng.bytes(1..21)),
+                7 => DhcpOption::ClientIdentifier(rng.bytes(2..22)),
+                8 => DhcpOption::DomainSearch(vec![format!("{}.example", text)]),
+                9 => DhcpOption::ClasslessStaticRoute(vec![ClasslessRoute {
+                    destination: Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 0), 8).unwrap(),
+                    router: rng.ip(),
+                }]),
+                // 255オクテットを超えて分割されるもの
+                10 => DhcpOption::VendorSpecific(rng.bytes(1..601)),
+                _ => DhcpOption::Unknown(224 + rng.below(30) as u8, rng.bytes(0..300)),
+            };
+            if options.iter().all(|existing| existing.code() != option.code()) {
+                options.push(option);
+            }
+        }
+        options
+    }
+
+    fn random_message(rng: &mut Rng) -> DhcpMessage {
+        let mut message = DhcpMessageBuilder::new(1 + rng.below(2) as u8)
+            .xid(rng.next() as u32)
+            .flags(rng.next() as u16)
+            .ciaddr(rng.ip())
+            .yiaddr(rng.ip())
+            .giaddr(rng.ip())
+            .hardware_address(rng.next() as u8, &rng.bytes(0..CHADDR_LEN + 1))
+            .options(random_options(rng))
+            .build();
+        message.hops = rng.next() as u8;
+        message.secs = rng.next() as u16;
+        message.siaddr = rng.ip();
+        if rng.below(2) == 0 {
+            message.sname = rng.text(SNAME_LEN - 1);
+            message.file = rng.text(FILE_LEN);
+        }
+        message
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let limit = MINIMUM_DATAGRAM_SIZE - IP_UDP_HEADER_SIZE;
+        let mut overloaded = 0;
+        for _ in 0..500 {
+            let message = random_message(&mut rng);
+            // 大きさの制限がなければsname/fileを使わずに収まる
+            let bytes = message.to_bytes(usize::MAX).unwrap();
+            assert!(bytes.len() >= MINIMUM_MESSAGE_SIZE);
+            assert_eq!(message, DhcpMessage::parse(&bytes).unwrap());
+
+            // 576オクテットに収まらない場合はsname/fileにオプションを置く
+            match message.to_bytes(limit) {
+                Ok(overloaded_bytes) => {
+                    assert!(overloaded_bytes.len() <= limit);
+                    assert_eq!(message, DhcpMessage::parse(&overloaded_bytes).unwrap());
+                    if bytes.len() > limit {
+                        overloaded += 1;
+                    }
+                }
+                Err(_) => assert!(bytes.len() > limit),
+            }
+        }
+        assert!(overloaded > 0);
+    }
+
+    #[test]
+    fn test_parse_never_panics() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2000 {
+            let message = random_message(&mut rng);
+            let mut bytes = message.to_bytes(rng.below(700)).unwrap_or_else(|_| {
+                message.to_bytes(usize::MAX).unwrap()
+            });
+            // 壊す: 切り詰める、またはランダムなオクテットを書き換える
+            if rng.below(2) == 0 {
+                bytes.truncate(rng.below(bytes.len() + 1));
+            }
+            for _ in 0..rng.below(8) {
+                let index = rng.below(bytes.len().max(1));
+                if let Some(b) = bytes.get_mut(index) {
+                    *b = rng.next() as u8;
+                }
+            }
+            // 解釈できたものはバイト列に戻して同じになる
+            if let Ok(parsed) = DhcpMessage::parse(&bytes) {
+                let encoded = parsed.to_bytes(usize::MAX).unwrap();
+                assert_eq!(parsed, DhcpMessage::parse(&encoded).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_message() {
+        let message = DhcpMessageBuilder::new(1)
+            .options(vec![DhcpOption::MessageType(1)])
+            .build();
+        let bytes = message.to_bytes(usize::MAX).unwrap();
+        assert!(DhcpMessage::parse(&bytes).is_ok());
+
+        assert!(DhcpMessage::parse(&bytes[..OPTIONS + 3]).is_err());
+        let mut bad_cookie = bytes.clone();
+        bad_cookie[OPTIONS] = 0;
+        assert!(DhcpMessage::parse(&bad_cookie).is_err());
+        let mut bad_hlen = bytes.clone();
+        bad_hlen[HLEN] = 17;
+        assert!(DhcpMessage::parse(&bad_hlen).is_err());
+        // Endのないオプション領域、領域をはみ出すオプション
+        assert!(DhcpMessage::parse(&bytes[..OPTIONS + 4 + 3]).is_err());
+        let mut overrun = bytes[..OPTIONS + 4].to_vec();
+        overrun.extend_from_slice(&[Code::HostName as u8, 10, b'a']);
+        assert!(DhcpMessage::parse(&overrun).is_err());
+    }
+
+    #[test]
+    fn test_hardware_address_other_than_ethernet() {
+        // InfiniBandはchaddrを使わず、hlenを0にしてclient identifierを送る(RFC4390)
+        let message = DhcpMessageBuilder::new(1)
+            .hardware_address(32, &[])
+            .options(vec![DhcpOption::ClientIdentifier(vec![0xff, 0, 0, 0, 1])])
+            .build();
+        let parsed = DhcpMessage::parse(&message.to_bytes(usize::MAX).unwrap()).unwrap();
+        assert_eq!((32, 0), (parsed.htype, parsed.hlen));
+        assert!(parsed.hardware_address().is_empty());
+
+        // 6オクテットより長いハードウェアアドレスもhlenの長さだけ使う
+        let addr = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let message = DhcpMessageBuilder::new(1).hardware_address(15, &addr).build();
+        let parsed = DhcpMessage::parse(&message.to_bytes(usize::MAX).unwrap()).unwrap();
+        assert_eq!(10, parsed.hlen);
+        assert_eq!(&addr[..], parsed.hardware_address());
+    }
+
+    #[test]
+    fn test_parse_option_overload() {
+        let mut buf = vec![0u8; OPTIONS];
+        buf[HLEN] = 6;
+        buf[FILE..FILE + 6].copy_from_slice(&[Code::HostName as u8, 2, b'p', b'c', 255, 0]);
+        // snameにはfileのHostNameの続きとDomainName
+        buf[SNAME..SNAME + 9].copy_from_slice(&[
+            Code::HostName as u8, 1, b'1', //
+            Code::DomainName as u8, 3, b'l', b'a', b'n', //
+            255,
+        ]);
+        buf.extend_from_slice(&MAGIC_COOKIE);
+        buf.extend_from_slice(&[
+            Code::OptionOverload as u8, 1, 3, //
+            Code::MessageType as u8, 1, 3, //
+            255,
+        ]);
+
+        let message = DhcpMessage::parse(&buf).unwrap();
+        assert!(message.sname.is_empty() && message.file.is_empty());
+        assert_eq!(
+            vec![
+                DhcpOption::MessageType(3),
+                DhcpOption::HostName("pc1".to_string()),
+                DhcpOption::DomainName("lan".to_string()),
+            ],
+            message.options
+        );
+
+        // Option Overloadがなければsname/fileはそのままの値
+        let len = buf.len();
+        buf[len - 7..].copy_from_slice(&[Code::MessageType as u8, 1, 3, 255, 0, 0, 0]);
+        let message = DhcpMessage::parse(&buf).unwrap();
+        assert_eq!(vec![DhcpOption::MessageType(3)], message.options);
+        assert_eq!(vec![Code::HostName as u8, 2, b'p', b'c', 255], message.file);
+    }
+
+    #[test]
+    fn test_reply_size_limit() {
+        let mut message = DhcpMessageBuilder::new(1).build();
+        assert_eq!(548, message.reply_size_limit());
+        message.options.push(DhcpOption::MaximumMessageSize(1500));
+        assert_eq!(1472, message.reply_size_limit());
+    }
+}
@@ -0,0 +1,549 @@
+use std::net::Ipv6Addr;
+
+use super::options;
+
+// [note] DHCPv6のメッセージはDHCPv4と違い固定長のフィールドがほとんどなく、
+// メッセージタイプとトランザクションIDの後は全てオプションで表現する(RFC8415 8)。
+// https://datatracker.ietf.org/doc/html/rfc8415#section-8
+
+/*
+    0                   1                   2                   3
+    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |    msg-type   |               transaction-id                  |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |                                                               |
+   .                            options                            .
+   .                 (variable number and length)                  .
+   |                                                               |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+
+              Figure 2: Client/Server Message Format
+ */
+const HEADER_LEN: usize = 4;
+// オプションのoption-codeとoption-lenの長さ(RFC8415 21.1)
+const OPTION_HEADER_LEN: usize = 4;
+
+// メッセージタイプ(RFC8415 7.3)
+pub const SOLICIT: u8 = 1;
+pub const ADVERTISE: u8 = 2;
+pub const REQUEST: u8 = 3;
+pub const CONFIRM: u8 = 4;
+pub const RENEW: u8 = 5;
+pub const REBIND: u8 = 6;
+pub const REPLY: u8 = 7;
+pub const RELEASE: u8 = 8;
+pub const DECLINE: u8 = 9;
+pub const INFORMATION_REQUEST: u8 = 11;
+pub const RELAY_FORW: u8 = 12;
+pub const RELAY_REPL: u8 = 13;
+
+// オプションのコード(RFC8415 21, RFC3646)
+pub const OPTION_CLIENTID: u16 = 1;
+pub const OPTION_SERVERID: u16 = 2;
+pub const OPTION_IA_NA: u16 = 3;
+pub const OPTION_IAADDR: u16 = 5;
+pub const OPTION_ORO: u16 = 6;
+pub const OPTION_PREFERENCE: u16 = 7;
+pub const OPTION_ELAPSED_TIME: u16 = 8;
+pub const OPTION_STATUS_CODE: u16 = 13;
+pub const OPTION_RAPID_COMMIT: u16 = 14;
+pub const OPTION_DNS_SERVERS: u16 = 23;
+pub const OPTION_DOMAIN_LIST: u16 = 24;
+pub const OPTION_IA_PD: u16 = 25;
+pub const OPTION_IAPREFIX: u16 = 26;
+
+// Status Codeオプションの値(RFC8415 21.13)
+pub const STATUS_SUCCESS: u16 = 0;
+pub const STATUS_NO_ADDRS_AVAIL: u16 = 2;
+pub const STATUS_NO_BINDING: u16 = 3;
+pub const STATUS_NOT_ON_LINK: u16 = 4;
+pub const STATUS_NO_PREFIX_AVAIL: u16 = 6;
+
+// DUIDの最大長。2オクテットのタイプと128オクテットまでの値(RFC8415 11.1)
+const MAX_DUID_LEN: usize = 130;
+
+/**
+ * IA_NA(非一時アドレス)とIA_PD(プレフィックス委譲)の値。
+ * IAIDはクライアントがインターフェースごとに決める識別子で、DUIDとの組でバインディングを表す。
+ * optionsにはIAADDRかIAPREFIX、Status Codeが入る。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ia {
+    pub iaid: u32,
+    pub t1: u32,
+    pub t2: u32,
+    pub options: Vec<Dhcpv6Option>,
+}
+
+impl Ia {
+    /**
+     * IA_NAに含まれるアドレス
+     */
+    pub fn addresses(&self) -> impl Iterator<Item = &IaAddress> {
+        self.options.iter().filter_map(|option| match option {
+            Dhcpv6Option::IaAddr(address) => Some(address),
+            _ => None,
+        })
+    }
+
+    /**
+     * IA_PDに含まれるプレフィックス
+     */
+    pub fn prefixes(&self) -> impl Iterator<Item = &IaPrefix> {
+        self.options.iter().filter_map(|option| match option {
+            Dhcpv6Option::IaPrefix(prefix) => Some(prefix),
+            _ => None,
+        })
+    }
+}
+
+/**
+ * IA Address(IAADDR)オプションの値
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct IaAddress {
+    pub address: Ipv6Addr,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub options: Vec<Dhcpv6Option>,
+}
+
+/**
+ * IA Prefix(IAPREFIX)オプションの値(RFC8415 21.22)
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct IaPrefix {
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub prefix_len: u8,
+    pub prefix: Ipv6Addr,
+    pub options: Vec<Dhcpv6Option>,
+}
+
+/**
+ * 型付きのDHCPv6オプション。
+ * アドレスの割り当てとプレフィックス委譲、DNSの設定に使うものを表し、それ以外はUnknownとしてバイト列のまま扱う。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dhcpv6Option {
+    ClientId(Vec<u8>), // DUID
+    ServerId(Vec<u8>), // DUID
+    IaNa(Ia),
+    IaAddr(IaAddress),
+    OptionRequest(Vec<u16>),
+    Preference(u8),
+    ElapsedTime(u16), // 1/100秒単位
+    StatusCode(u16, String),
+    RapidCommit,
+    DnsServers(Vec<Ipv6Addr>),
+    DomainList(Vec<String>),
+    IaPd(Ia),
+    IaPrefix(IaPrefix),
+    Unknown(u16, Vec<u8>),
+}
+
+impl Dhcpv6Option {
+    pub fn code(&self) -> u16 {
+        match self {
+            Dhcpv6Option::ClientId(_) => OPTION_CLIENTID,
+            Dhcpv6Option::ServerId(_) => OPTION_SERVERID,
+            Dhcpv6Option::IaNa(_) => OPTION_IA_NA,
+            Dhcpv6Option::IaAddr(_) => OPTION_IAADDR,
+            Dhcpv6Option::OptionRequest(_) => OPTION_ORO,
+            Dhcpv6Option::Preference(_) => OPTION_PREFERENCE,
+            Dhcpv6Option::ElapsedTime(_) => OPTION_ELAPSED_TIME,
+            Dhcpv6Option::StatusCode(_, _) => OPTION_STATUS_CODE,
+            Dhcpv6Option::RapidCommit => OPTION_RAPID_COMMIT,
+            Dhcpv6Option::DnsServers(_) => OPTION_DNS_SERVERS,
+            Dhcpv6Option::DomainList(_) => OPTION_DOMAIN_LIST,
+            Dhcpv6Option::IaPd(_) => OPTION_IA_PD,
+            Dhcpv6Option::IaPrefix(_) => OPTION_IAPREFIX,
+            Dhcpv6Option::Unknown(code, _) => *code,
+        }
+    }
+
+    /**
+     * オプションの値(option-code, option-lenを除いた部分)をバイト列にする
+     */
+    pub fn encode_value(&self) -> Vec<u8> {
+        match self {
+            Dhcpv6Option::ClientId(duid) | Dhcpv6Option::ServerId(duid) => duid.clone(),
+            Dhcpv6Option::IaNa(ia) | Dhcpv6Option::IaPd(ia) => {
+                let mut buf = Vec::new();
+                buf.extend_from_slice(&ia.iaid.to_be_bytes());
+                buf.extend_from_slice(&ia.t1.to_be_bytes());
+                buf.extend_from_slice(&ia.t2.to_be_bytes());
+                buf.extend(encode_options(&ia.options));
+                buf
+            }
+            Dhcpv6Option::IaAddr(address) => {
+                let mut buf = address.address.octets().to_vec();
+                buf.extend_from_slice(&address.preferred_lifetime.to_be_bytes());
+                buf.extend_from_slice(&address.valid_lifetime.to_be_bytes());
+                buf.extend(encode_options(&address.options));
+                buf
+            }
+            Dhcpv6Option::OptionRequest(codes) => {
+                codes.iter().flat_map(|code| code.to_be_bytes()).collect()
+            }
+            Dhcpv6Option::Preference(i) => vec![*i],
+            Dhcpv6Option::ElapsedTime(i) => i.to_be_bytes().to_vec(),
+            Dhcpv6Option::StatusCode(status, message) => {
+                let mut buf = status.to_be_bytes().to_vec();
+                buf.extend_from_slice(message.as_bytes());
+                buf
+            }
+            Dhcpv6Option::RapidCommit => Vec::new(),
+            Dhcpv6Option::DnsServers(addrs) => {
+                addrs.iter().flat_map(|addr| addr.octets()).collect()
+            }
+            Dhcpv6Option::DomainList(domains) => options::encode_domain_names(domains),
+            Dhcpv6Option::IaPrefix(prefix) => {
+                let mut buf = prefix.preferred_lifetime.to_be_bytes().to_vec();
+                buf.extend_from_slice(&prefix.valid_lifetime.to_be_bytes());
+                buf.push(prefix.prefix_len);
+                buf.extend_from_slice(&prefix.prefix.octets());
+                buf.extend(encode_options(&prefix.options));
+                buf
+            }
+            Dhcpv6Option::Unknown(_, bytes) => bytes.clone(),
+        }
+    }
+
+    /**
+     * コードと値からオプションを解釈する。値の長さがそのオプションとして不正ならErr。
+     * IA_NAなどに含まれるオプションも解釈する。
+     */
+    pub fn decode(code: u16, value: &[u8]) -> Result<Dhcpv6Option, failure::Error> {
+        let too_short = |min: usize| {
+            if value.len() < min {
+                return Err(failure::format_err!(
+                    "option {} must be at least {} bytes, but {}",
+                    code,
+                    min,
+                    value.len()
+                ));
+            }
+            Ok(())
+        };
+        let u32_at = |i: usize| u32::from_be_bytes(value[i..i + 4].try_into().unwrap());
+        let ipv6addr_at = |i: usize| {
+            let octets: [u8; 16] = value[i..i + 16].try_into().unwrap();
+            Ipv6Addr::from(octets)
+        };
+        let duid = || {
+            // DUIDのタイプと少なくとも1オクテットの値
+            if value.len() < 3 || value.len() > MAX_DUID_LEN {
+                return Err(failure::format_err!(
+                    "option {}: invalid DUID length {}",
+                    code,
+                    value.len()
+                ));
+            }
+            Ok(value.to_vec())
+        };
+        let ia = || -> Result<Ia, failure::Error> {
+            too_short(12)?;
+            Ok(Ia {
+                iaid: u32_at(0),
+                t1: u32_at(4),
+                t2: u32_at(8),
+                options: parse_options(&value[12..])?,
+            })
+        };
+
+        let option = match code {
+            OPTION_CLIENTID => Dhcpv6Option::ClientId(duid()?),
+            OPTION_SERVERID => Dhcpv6Option::ServerId(duid()?),
+            OPTION_IA_NA => Dhcpv6Option::IaNa(ia()?),
+            OPTION_IA_PD => Dhcpv6Option::IaPd(ia()?),
+            OPTION_IAADDR => {
+                too_short(24)?;
+                Dhcpv6Option::IaAddr(IaAddress {
+                    address: ipv6addr_at(0),
+                    preferred_lifetime: u32_at(16),
+                    valid_lifetime: u32_at(20),
+                    options: parse_options(&value[24..])?,
+                })
+            }
+            OPTION_IAPREFIX => {
+                too_short(25)?;
+                let prefix_len = value[8];
+                if prefix_len > 128 {
+                    return Err(failure::format_err!(
+                        "option {}: invalid prefix length {}",
+                        code,
+                        prefix_len
+                    ));
+                }
+                Dhcpv6Option::IaPrefix(IaPrefix {
+                    preferred_lifetime: u32_at(0),
+                    valid_lifetime: u32_at(4),
+                    prefix_len,
+                    prefix: ipv6addr_at(9),
+                    options: parse_options(&value[25..])?,
+                })
+            }
+            OPTION_ORO => {
+                if !value.len().is_multiple_of(2) {
+                    return Err(failure::format_err!("option {} must be pairs of octets", code));
+                }
+                Dhcpv6Option::OptionRequest(
+                    value
+                        .chunks(2)
+                        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                        .collect(),
+                )
+            }
+            OPTION_PREFERENCE => Dhcpv6Option::Preference(exact::<1>(code, value)?[0]),
+            OPTION_ELAPSED_TIME => {
+                Dhcpv6Option::ElapsedTime(u16::from_be_bytes(exact::<2>(code, value)?))
+            }
+            OPTION_STATUS_CODE => {
+                too_short(2)?;
+                let message = String::from_utf8(value[2..].to_vec()).map_err(|_| {
+                    failure::format_err!("option {}: message is not a valid string", code)
+                })?;
+                Dhcpv6Option::StatusCode(u16::from_be_bytes([value[0], value[1]]), message)
+            }
+            OPTION_RAPID_COMMIT => {
+                exact::<0>(code, value)?;
+                Dhcpv6Option::RapidCommit
+            }
+            OPTION_DNS_SERVERS => {
+                if value.is_empty() || !value.len().is_multiple_of(16) {
+                    return Err(failure::format_err!(
+                        "option {} must be a multiple of 16 bytes, but {}",
+                        code,
+                        value.len()
+                    ));
+                }
+                Dhcpv6Option::DnsServers((0..value.len()).step_by(16).map(ipv6addr_at).collect())
+            }
+            OPTION_DOMAIN_LIST => {
+                // [note] DHCPv6のドメイン名は圧縮しない形式だが(RFC3646 4)、読む分には区別しない
+                Dhcpv6Option::DomainList(options::decode_domain_names(code as u8, value)?)
+            }
+            _ => Dhcpv6Option::Unknown(code, value.to_vec()),
+        };
+        Ok(option)
+    }
+}
+
+fn exact<const N: usize>(code: u16, value: &[u8]) -> Result<[u8; N], failure::Error> {
+    value.try_into().map_err(|_| {
+        failure::format_err!("option {} must be {} bytes, but {}", code, N, value.len())
+    })
+}
+
+/**
+ * オプションを並べたバイト列にする。DHCPv4と違いEndやPadはない。
+ */
+pub fn encode_options(options: &[Dhcpv6Option]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for option in options.iter() {
+        let value = option.encode_value();
+        buf.extend_from_slice(&option.code().to_be_bytes());
+        buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        buf.extend(value);
+    }
+    buf
+}
+
+/**
+ * オプションを並べたバイト列を解釈する。長さがはみ出していればErr。
+ * 値が不正なオプションは無いものとして扱う。
+ */
+pub fn parse_options(buf: &[u8]) -> Result<Vec<Dhcpv6Option>, failure::Error> {
+    let mut options = Vec::new();
+    let mut index = 0;
+    while index < buf.len() {
+        let header = buf
+            .get(index..index + OPTION_HEADER_LEN)
+            .ok_or_else(|| failure::err_msg("truncated option header"))?;
+        let code = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let start = index + OPTION_HEADER_LEN;
+        let value = buf.get(start..start + len).ok_or_else(|| {
+            failure::format_err!("option {} is longer than the message", code)
+        })?;
+        match Dhcpv6Option::decode(code, value) {
+            Ok(option) => options.push(option),
+            Err(e) => debug!("dropped invalid option: {}", e),
+        }
+        index = start + len;
+    }
+    Ok(options)
+}
+
+/**
+ * クライアントとサーバの間のDHCPv6のメッセージ。
+ * transaction_idは3オクテットで、応答には受信したものをそのまま入れる。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dhcpv6Message {
+    pub msg_type: u8,
+    pub transaction_id: u32,
+    pub options: Vec<Dhcpv6Option>,
+}
+
+impl Dhcpv6Message {
+    pub fn new(msg_type: u8, transaction_id: u32, options: Vec<Dhcpv6Option>) -> Dhcpv6Message {
+        Dhcpv6Message {
+            msg_type,
+            transaction_id: transaction_id & 0x00ff_ffff,
+            options,
+        }
+    }
+
+    /**
+     * 受信したバイト列をDHCPv6のメッセージとして解釈する。
+     * 短すぎる、オプションの長さがはみ出している、リレーエージェントのメッセージの場合はErr。
+     */
+    pub fn parse(buf: &[u8]) -> Result<Dhcpv6Message, failure::Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(failure::format_err!("message is too short: {} octets", buf.len()));
+        }
+        let msg_type = buf[0];
+        if msg_type == RELAY_FORW || msg_type == RELAY_REPL {
+            // [note] リレーエージェントのメッセージはヘッダの形式が違う(RFC8415 9)。現状は対応していない
+            return Err(failure::err_msg("relay agent messages are not supported"));
+        }
+        Ok(Dhcpv6Message {
+            msg_type,
+            transaction_id: u32::from_be_bytes([0, buf[1], buf[2], buf[3]]),
+            options: parse_options(&buf[HEADER_LEN..])?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![self.msg_type];
+        buf.extend_from_slice(&self.transaction_id.to_be_bytes()[1..]);
+        buf.extend(encode_options(&self.options));
+        buf
+    }
+
+    pub fn option(&self, code: u16) -> Option<&Dhcpv6Option> {
+        self.options.iter().find(|option| option.code() == code)
+    }
+
+    /**
+     * クライアントのDUID
+     */
+    pub fn client_id(&self) -> Option<&[u8]> {
+        match self.option(OPTION_CLIENTID) {
+            Some(Dhcpv6Option::ClientId(duid)) => Some(duid),
+            _ => None,
+        }
+    }
+
+    /**
+     * クライアントが選んだサーバのDUID
+     */
+    pub fn server_id(&self) -> Option<&[u8]> {
+        match self.option(OPTION_SERVERID) {
+            Some(Dhcpv6Option::ServerId(duid)) => Some(duid),
+            _ => None,
+        }
+    }
+
+    /**
+     * Option Request(ORO)で要求されたオプションのコード。なければ空
+     */
+    pub fn requested_options(&self) -> &[u16] {
+        match self.option(OPTION_ORO) {
+            Some(Dhcpv6Option::OptionRequest(codes)) => codes,
+            _ => &[],
+        }
+    }
+
+    pub fn has_rapid_commit(&self) -> bool {
+        self.option(OPTION_RAPID_COMMIT).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solicit() -> Dhcpv6Message {
+        Dhcpv6Message::new(
+            SOLICIT,
+            0x12_3456,
+            vec![
+                Dhcpv6Option::ClientId(vec![0, 3, 0, 1, 0, 0x11, 0x22, 0x33, 0x44, 0x55]),
+                Dhcpv6Option::ElapsedTime(0),
+                Dhcpv6Option::RapidCommit,
+                Dhcpv6Option::IaNa(Ia {
+                    iaid: 1,
+                    t1: 0,
+                    t2: 0,
+                    options: vec![Dhcpv6Option::IaAddr(IaAddress {
+                        address: "2001:db8::100".parse().unwrap(),
+                        preferred_lifetime: 0,
+                        valid_lifetime: 0,
+                        options: vec![],
+                    })],
+                }),
+                Dhcpv6Option::IaPd(Ia {
+                    iaid: 2,
+                    t1: 0,
+                    t2: 0,
+                    options: vec![Dhcpv6Option::IaPrefix(IaPrefix {
+                        preferred_lifetime: 0,
+                        valid_lifetime: 0,
+                        prefix_len: 56,
+                        prefix: "2001:db8:100::".parse().unwrap(),
+                        options: vec![],
+                    })],
+                }),
+                Dhcpv6Option::OptionRequest(vec![OPTION_DNS_SERVERS, OPTION_DOMAIN_LIST]),
+                Dhcpv6Option::Unknown(39, vec![0, 4, b'h', b'o', b's', b't']),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let message = solicit();
+        let bytes = message.to_bytes();
+        assert_eq!([SOLICIT, 0x12, 0x34, 0x56], bytes[..4]);
+        // Client Identifierは code 1, len 10 で始まる
+        assert_eq!([0, 1, 0, 10], bytes[4..8]);
+        assert_eq!(message, Dhcpv6Message::parse(&bytes).unwrap());
+
+        let reply = Dhcpv6Message::new(
+            REPLY,
+            message.transaction_id,
+            vec![
+                Dhcpv6Option::StatusCode(STATUS_SUCCESS, "ok".to_string()),
+                Dhcpv6Option::DnsServers(vec!["2001:db8::1".parse().unwrap()]),
+                Dhcpv6Option::DomainList(vec!["example.lan".to_string()]),
+            ],
+        );
+        assert_eq!(reply, Dhcpv6Message::parse(&reply.to_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_message() {
+        let bytes = solicit().to_bytes();
+        assert!(Dhcpv6Message::parse(&bytes[..3]).is_err());
+        // オプションの長さがメッセージをはみ出す
+        assert!(Dhcpv6Message::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Dhcpv6Message::parse(&[SOLICIT, 0, 0, 1, 0, 1]).is_err());
+        assert!(Dhcpv6Message::parse(&[RELAY_FORW, 0, 0, 1]).is_err());
+
+        // 値が不正なオプションは無いものとして扱う
+        let message = Dhcpv6Message::parse(&[
+            SOLICIT, 0, 0, 1, //
+            0, 1, 0, 1, 0, // DUIDが短すぎる
+            0, 14, 0, 1, 0, // Rapid Commitは値を持たない
+            0, 8, 0, 2, 0, 10,
+        ])
+        .unwrap();
+        assert_eq!(vec![Dhcpv6Option::ElapsedTime(10)], message.options);
+        assert_eq!(None, message.client_id());
+        assert!(!message.has_rapid_commit());
+    }
+}
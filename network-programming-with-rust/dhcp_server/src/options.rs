@@ -0,0 +1,761 @@
+use std::net::Ipv4Addr;
+
+use ipnetwork::Ipv4Network;
+
+use super::util;
+
+// [note] 以下のRFCにDHCPのオプションの各コードの定義がある。以下のenum Codeはその一覧。
+// https://datatracker.ietf.org/doc/html/rfc2132#autoid-73
+pub enum Code {
+    Pad = 0,
+    SubnetMask = 1,
+    TimeOffset = 2,
+    Router = 3,
+    TimeServer = 4,
+    Dns = 6,
+    LogServer = 7,
+    HostName = 12,
+    DomainName = 15,
+    IpForwarding = 19,
+    DefaultIpTtl = 23,
+    InterfaceMtu = 26,
+    BroadcastAddress = 28,
+    StaticRoute = 33,
+    NtpServers = 42,
+    VendorSpecific = 43,
+    NetbiosNameServer = 44,
+    RequestedIpAddress = 50,
+    IPAddressLeaseTime = 51,
+    OptionOverload = 52,
+    MessageType = 53,
+    ServerIdentifier = 54,
+    ParameterRequestList = 55,
+    Message = 56,
+    MaximumMessageSize = 57,
+    RenewalTime = 58,
+    RebindingTime = 59,
+    VendorClassIdentifier = 60,
+    ClientIdentifier = 61,
+    TftpServerName = 66,
+    BootfileName = 67,
+    ClientFqdn = 81,
+    RelayAgentInformation = 82,
+    DomainSearch = 119,
+    ClasslessStaticRoute = 121,
+    End = 255,
+}
+
+/**
+ * Classless Static Route(オプション121)の経路
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClasslessRoute {
+    pub destination: Ipv4Network,
+    pub router: Ipv4Addr,
+}
+
+// Client FQDN(オプション81)のフラグ(RFC4702 2.1)
+pub const FQDN_S: u8 = 0x01; // サーバがAレコードを更新する
+pub const FQDN_O: u8 = 0x02; // サーバがクライアントの希望したSを上書きした
+pub const FQDN_E: u8 = 0x04; // 名前がDNSの形式
+pub const FQDN_N: u8 = 0x08; // サーバはDNSを更新しない
+
+/**
+ * Client FQDN(オプション81)の値。
+ * 名前は完全修飾名なら末尾に'.'を付け、部分名(ホスト名だけなど)なら付けない。
+ *
+ * [note] RCODE1, RCODE2は廃止されており、受信時は無視し、送信時は255にする(RFC4702 2.2)。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientFqdn {
+    pub flags: u8,
+    pub name: String,
+}
+
+/**
+ * 型付きのDHCPオプション。
+ * RFC2132の主なオプションと、よく使われる拡張(RFC3397のドメイン検索リスト、RFC3442のClassless Static Route、
+ * RFC4702のClient FQDN)を表す。
+ * それ以外のコードはUnknownとしてバイト列のまま扱う。
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum DhcpOption {
+    SubnetMask(Ipv4Addr),
+    TimeOffset(i32),
+    Router(Vec<Ipv4Addr>),
+    TimeServer(Vec<Ipv4Addr>),
+    DomainNameServer(Vec<Ipv4Addr>),
+    LogServer(Vec<Ipv4Addr>),
+    HostName(String),
+    DomainName(String),
+    IpForwarding(bool),
+    DefaultIpTtl(u8),
+    InterfaceMtu(u16),
+    BroadcastAddress(Ipv4Addr),
+    StaticRoute(Vec<(Ipv4Addr, Ipv4Addr)>), // (宛先, ルータ)
+    NtpServers(Vec<Ipv4Addr>),
+    VendorSpecific(Vec<u8>),
+    NetbiosNameServer(Vec<Ipv4Addr>),
+    RequestedIpAddress(Ipv4Addr),
+    IpAddressLeaseTime(u32),
+    OptionOverload(u8),
+    MessageType(u8),
+    ServerIdentifier(Ipv4Addr),
+    ParameterRequestList(Vec<u8>),
+    Message(String),
+    MaximumMessageSize(u16),
+    RenewalTime(u32),
+    RebindingTime(u32),
+    VendorClassIdentifier(Vec<u8>),
+    ClientIdentifier(Vec<u8>),
+    TftpServerName(String),
+    BootfileName(String),
+    ClientFqdn(ClientFqdn),
+    RelayAgentInformation(Vec<u8>),
+    DomainSearch(Vec<String>),
+    ClasslessStaticRoute(Vec<ClasslessRoute>),
+    Unknown(u8, Vec<u8>),
+}
+
+impl DhcpOption {
+    pub fn code(&self) -> u8 {
+        let code = match self {
+            DhcpOption::SubnetMask(_) => Code::SubnetMask,
+            DhcpOption::TimeOffset(_) => Code::TimeOffset,
+            DhcpOption::Router(_) => Code::Router,
+            DhcpOption::TimeServer(_) => Code::TimeServer,
+            DhcpOption::DomainNameServer(_) => Code::Dns,
+            DhcpOption::LogServer(_) => Code::LogServer,
+            DhcpOption::HostName(_) => Code::HostName,
+            DhcpOption::DomainName(_) => Code::DomainName,
+            DhcpOption::IpForwarding(_) => Code::IpForwarding,
+            DhcpOption::DefaultIpTtl(_) => Code::DefaultIpTtl,
+            DhcpOption::InterfaceMtu(_) => Code::InterfaceMtu,
+            DhcpOption::BroadcastAddress(_) => Code::BroadcastAddress,
+            DhcpOption::StaticRoute(_) => Code::StaticRoute,
+            DhcpOption::NtpServers(_) => Code::NtpServers,
+            DhcpOption::VendorSpecific(_) => Code::VendorSpecific,
+            DhcpOption::NetbiosNameServer(_) => Code::NetbiosNameServer,
+            DhcpOption::RequestedIpAddress(_) => Code::RequestedIpAddress,
+            DhcpOption::IpAddressLeaseTime(_) => Code::IPAddressLeaseTime,
+            DhcpOption::OptionOverload(_) => Code::OptionOverload,
+            DhcpOption::MessageType(_) => Code::MessageType,
+            DhcpOption::ServerIdentifier(_) => Code::ServerIdentifier,
+            DhcpOption::ParameterRequestList(_) => Code::ParameterRequestList,
+            DhcpOption::Message(_) => Code::Message,
+            DhcpOption::MaximumMessageSize(_) => Code::MaximumMessageSize,
+            DhcpOption::RenewalTime(_) => Code::RenewalTime,
+            DhcpOption::RebindingTime(_) => Code::RebindingTime,
+            DhcpOption::VendorClassIdentifier(_) => Code::VendorClassIdentifier,
+            DhcpOption::ClientIdentifier(_) => Code::ClientIdentifier,
+            DhcpOption::TftpServerName(_) => Code::TftpServerName,
+            DhcpOption::BootfileName(_) => Code::BootfileName,
+            DhcpOption::ClientFqdn(_) => Code::ClientFqdn,
+            DhcpOption::RelayAgentInformation(_) => Code::RelayAgentInformation,
+            DhcpOption::DomainSearch(_) => Code::DomainSearch,
+            DhcpOption::ClasslessStaticRoute(_) => Code::ClasslessStaticRoute,
+            DhcpOption::Unknown(code, _) => return *code,
+        };
+        code as u8
+    }
+
+    /**
+     * オプションの値(Code, Lenを除いた部分)をバイト列にする
+     */
+    pub fn encode_value(&self) -> Vec<u8> {
+        match self {
+            DhcpOption::SubnetMask(addr)
+            | DhcpOption::BroadcastAddress(addr)
+            | DhcpOption::RequestedIpAddress(addr)
+            | DhcpOption::ServerIdentifier(addr) => addr.octets().to_vec(),
+            DhcpOption::Router(addrs)
+            | DhcpOption::TimeServer(addrs)
+            | DhcpOption::DomainNameServer(addrs)
+            | DhcpOption::LogServer(addrs)
+            | DhcpOption::NtpServers(addrs)
+            | DhcpOption::NetbiosNameServer(addrs) => {
+                addrs.iter().flat_map(|addr| addr.octets()).collect()
+            }
+            DhcpOption::HostName(text)
+            | DhcpOption::DomainName(text)
+            | DhcpOption::Message(text)
+            | DhcpOption::TftpServerName(text)
+            | DhcpOption::BootfileName(text) => text.as_bytes().to_vec(),
+            DhcpOption::TimeOffset(i) => i.to_be_bytes().to_vec(),
+            DhcpOption::IpForwarding(enabled) => vec![*enabled as u8],
+            DhcpOption::DefaultIpTtl(i)
+            | DhcpOption::OptionOverload(i)
+            | DhcpOption::MessageType(i) => vec![*i],
+            DhcpOption::InterfaceMtu(i) | DhcpOption::MaximumMessageSize(i) => {
+                i.to_be_bytes().to_vec()
+            }
+            DhcpOption::IpAddressLeaseTime(i)
+            | DhcpOption::RenewalTime(i)
+            | DhcpOption::RebindingTime(i) => i.to_be_bytes().to_vec(),
+            DhcpOption::StaticRoute(routes) => routes
+                .iter()
+                .flat_map(|(destination, router)| {
+                    destination.octets().into_iter().chain(router.octets())
+                })
+                .collect(),
+            DhcpOption::VendorSpecific(bytes)
+            | DhcpOption::ParameterRequestList(bytes)
+            | DhcpOption::VendorClassIdentifier(bytes)
+            | DhcpOption::ClientIdentifier(bytes)
+            | DhcpOption::RelayAgentInformation(bytes)
+            | DhcpOption::Unknown(_, bytes) => bytes.clone(),
+            DhcpOption::ClientFqdn(fqdn) => encode_client_fqdn(fqdn),
+            DhcpOption::DomainSearch(domains) => encode_domain_names(domains),
+            DhcpOption::ClasslessStaticRoute(routes) => encode_classless_routes(routes),
+        }
+    }
+
+    /**
+     * コードと値からオプションを解釈する。値の長さがそのオプションとして不正ならErr。
+     */
+    pub fn decode(code: u8, value: &[u8]) -> Result<DhcpOption, failure::Error> {
+        let ip = || exact::<4>(code, value).map(Ipv4Addr::from);
+        let ips = || ip_list(code, value);
+        let text = || -> Result<String, failure::Error> {
+            // [note] 末尾にNULを付けてくるクライアントがある(RFC2132 2)
+            let text = value.iter().rposition(|b| *b != 0).map_or(&[][..], |end| &value[..=end]);
+            non_empty(code, text)?;
+            String::from_utf8(text.to_vec())
+                .map_err(|_| failure::format_err!("option {} is not a valid string", code))
+        };
+        let bytes = || non_empty(code, value).map(|_| value.to_vec());
+
+        let option = match code {
+            c if c == Code::SubnetMask as u8 => DhcpOption::SubnetMask(ip()?),
+            c if c == Code::TimeOffset as u8 => {
+                DhcpOption::TimeOffset(i32::from_be_bytes(exact::<4>(code, value)?))
+            }
+            c if c == Code::Router as u8 => DhcpOption::Router(ips()?),
+            c if c == Code::TimeServer as u8 => DhcpOption::TimeServer(ips()?),
+            c if c == Code::Dns as u8 => DhcpOption::DomainNameServer(ips()?),
+            c if c == Code::LogServer as u8 => DhcpOption::LogServer(ips()?),
+            c if c == Code::HostName as u8 => DhcpOption::HostName(text()?),
+            c if c == Code::DomainName as u8 => DhcpOption::DomainName(text()?),
+            c if c == Code::IpForwarding as u8 => {
+                match exact::<1>(code, value)?[0] {
+                    0 => DhcpOption::IpForwarding(false),
+                    1 => DhcpOption::IpForwarding(true),
+                    i => return Err(failure::format_err!("option {}: invalid value {}", code, i)),
+                }
+            }
+            c if c == Code::DefaultIpTtl as u8 => {
+                DhcpOption::DefaultIpTtl(exact::<1>(code, value)?[0])
+            }
+            c if c == Code::InterfaceMtu as u8 => {
+                let mtu = u16::from_be_bytes(exact::<2>(code, value)?);
+                // 最小のMTUは68(RFC2132 5.1)
+                if mtu < 68 {
+                    return Err(failure::format_err!("option {}: mtu {} is too small", code, mtu));
+                }
+                DhcpOption::InterfaceMtu(mtu)
+            }
+            c if c == Code::BroadcastAddress as u8 => DhcpOption::BroadcastAddress(ip()?),
+            c if c == Code::StaticRoute as u8 => {
+                let addrs = ips()?;
+                if addrs.len() % 2 != 0 {
+                    return Err(failure::format_err!("option {}: routes must be pairs", code));
+                }
+                DhcpOption::StaticRoute(addrs.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+            }
+            c if c == Code::NtpServers as u8 => DhcpOption::NtpServers(ips()?),
+            c if c == Code::VendorSpecific as u8 => DhcpOption::VendorSpecific(bytes()?),
+            c if c == Code::NetbiosNameServer as u8 => DhcpOption::NetbiosNameServer(ips()?),
+            c if c == Code::RequestedIpAddress as u8 => DhcpOption::RequestedIpAddress(ip()?),
+            c if c == Code::IPAddressLeaseTime as u8 => {
+                DhcpOption::IpAddressLeaseTime(u32::from_be_bytes(exact::<4>(code, value)?))
+            }
+            c if c == Code::OptionOverload as u8 => match exact::<1>(code, value)?[0] {
+                i @ 1..=3 => DhcpOption::OptionOverload(i),
+                i => return Err(failure::format_err!("option {}: invalid value {}", code, i)),
+            },
+            c if c == Code::MessageType as u8 => {
+                DhcpOption::MessageType(exact::<1>(code, value)?[0])
+            }
+            c if c == Code::ServerIdentifier as u8 => DhcpOption::ServerIdentifier(ip()?),
+            c if c == Code::ParameterRequestList as u8 => {
+                DhcpOption::ParameterRequestList(bytes()?)
+            }
+            c if c == Code::Message as u8 => DhcpOption::Message(text()?),
+            c if c == Code::MaximumMessageSize as u8 => {
+                let size = u16::from_be_bytes(exact::<2>(code, value)?);
+                // 最小値は576(RFC2132 9.10)
+                if size < 576 {
+                    return Err(failure::format_err!("option {}: size {} is too small", code, size));
+                }
+                DhcpOption::MaximumMessageSize(size)
+            }
+            c if c == Code::RenewalTime as u8 => {
+                DhcpOption::RenewalTime(u32::from_be_bytes(exact::<4>(code, value)?))
+            }
+            c if c == Code::RebindingTime as u8 => {
+                DhcpOption::RebindingTime(u32::from_be_bytes(exact::<4>(code, value)?))
+            }
+            c if c == Code::VendorClassIdentifier as u8 => {
+                DhcpOption::VendorClassIdentifier(bytes()?)
+            }
+            c if c == Code::ClientIdentifier as u8 => {
+                // typeの1オクテットと少なくとも1オクテットの識別子(RFC2132 9.14)
+                if value.len() < 2 {
+                    return Err(failure::format_err!("option {} is too short", code));
+                }
+                DhcpOption::ClientIdentifier(value.to_vec())
+            }
+            c if c == Code::TftpServerName as u8 => DhcpOption::TftpServerName(text()?),
+            c if c == Code::BootfileName as u8 => DhcpOption::BootfileName(text()?),
+            c if c == Code::ClientFqdn as u8 => {
+                DhcpOption::ClientFqdn(decode_client_fqdn(code, value)?)
+            }
+            c if c == Code::RelayAgentInformation as u8 => {
+                DhcpOption::RelayAgentInformation(bytes()?)
+            }
+            c if c == Code::DomainSearch as u8 => {
+                DhcpOption::DomainSearch(decode_domain_names(code, value)?)
+            }
+            c if c == Code::ClasslessStaticRoute as u8 => {
+                DhcpOption::ClasslessStaticRoute(decode_classless_routes(code, value)?)
+            }
+            c if c == Code::Pad as u8 || c == Code::End as u8 => {
+                return Err(failure::format_err!("option code {} has no value", code))
+            }
+            _ => DhcpOption::Unknown(code, value.to_vec()),
+        };
+        Ok(option)
+    }
+}
+
+fn exact<const N: usize>(code: u8, value: &[u8]) -> Result<[u8; N], failure::Error> {
+    value.try_into().map_err(|_| {
+        failure::format_err!("option {} must be {} bytes, but {}", code, N, value.len())
+    })
+}
+
+fn non_empty(code: u8, value: &[u8]) -> Result<(), failure::Error> {
+    if value.is_empty() {
+        return Err(failure::format_err!("option {} must not be empty", code));
+    }
+    Ok(())
+}
+
+fn ip_list(code: u8, value: &[u8]) -> Result<Vec<Ipv4Addr>, failure::Error> {
+    if value.is_empty() || !value.len().is_multiple_of(4) {
+        return Err(failure::format_err!(
+            "option {} must be a multiple of 4 bytes, but {}",
+            code,
+            value.len()
+        ));
+    }
+    Ok(value
+        .chunks(4)
+        .map(|b| Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+        .collect())
+}
+
+/**
+ * ドメイン名の一覧をDNSの形式(RFC1035 3.1)にする。圧縮は行わない。
+ */
+pub fn encode_domain_names(domains: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for domain in domains.iter() {
+        for label in domain.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        buf.push(0);
+    }
+    buf
+}
+
+/**
+ * DNSの形式のドメイン名の一覧を解釈する。圧縮のポインタ(RFC1035 4.1.4)はオプションの値の先頭からのオフセット。
+ */
+pub fn decode_domain_names(code: u8, value: &[u8]) -> Result<Vec<String>, failure::Error> {
+    let invalid = || failure::format_err!("option {}: invalid domain name list", code);
+    let mut domains = Vec::new();
+    let mut index = 0;
+    while index < value.len() {
+        let mut labels: Vec<String> = Vec::new();
+        let mut position = index;
+        let mut next_index = None;
+        let mut jumps = 0;
+        loop {
+            let len = *value.get(position).ok_or_else(invalid)? as usize;
+            if len == 0 {
+                position += 1;
+                break;
+            }
+            if len & 0xc0 == 0xc0 {
+                // ポインタ。先を読み終えたら次のドメイン名はポインタの直後から
+                let low = *value.get(position + 1).ok_or_else(invalid)? as usize;
+                next_index.get_or_insert(position + 2);
+                position = ((len & 0x3f) << 8) | low;
+                jumps += 1;
+                if jumps > value.len() {
+                    return Err(invalid());
+                }
+                continue;
+            }
+            let label = value.get(position + 1..position + 1 + len).ok_or_else(invalid)?;
+            labels.push(String::from_utf8(label.to_vec()).map_err(|_| invalid())?);
+            position += 1 + len;
+        }
+        domains.push(labels.join("."));
+        index = next_index.unwrap_or(position);
+    }
+    Ok(domains)
+}
+
+/**
+ * Client FQDN(RFC4702 2)の形式にする。
+ * Eフラグがあれば名前をDNSの形式にし、部分名は最後の長さ0のラベルを付けない(RFC4702 2.3.1)。
+ */
+fn encode_client_fqdn(fqdn: &ClientFqdn) -> Vec<u8> {
+    let mut buf = vec![fqdn.flags, 255, 255];
+    if fqdn.flags & FQDN_E == 0 {
+        buf.extend_from_slice(fqdn.name.as_bytes());
+        return buf;
+    }
+    for label in fqdn.name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    if fqdn.name.ends_with('.') {
+        buf.push(0);
+    }
+    buf
+}
+
+fn decode_client_fqdn(code: u8, value: &[u8]) -> Result<ClientFqdn, failure::Error> {
+    let invalid = || failure::format_err!("option {}: invalid domain name", code);
+    if value.len() < 3 {
+        return Err(failure::format_err!("option {} is too short", code));
+    }
+    let flags = value[0];
+    let name = &value[3..];
+    if flags & FQDN_E == 0 {
+        // 廃止されたASCIIの形式。末尾にNULを付けてくるクライアントがある
+        let end = name.iter().rposition(|b| *b != 0).map_or(0, |end| end + 1);
+        let name = String::from_utf8(name[..end].to_vec()).map_err(|_| invalid())?;
+        return Ok(ClientFqdn { flags, name });
+    }
+    // [note] このオプションの中では名前の圧縮を使えない(RFC4702 2.3.1)
+    let mut labels = Vec::new();
+    let mut index = 0;
+    let mut fully_qualified = false;
+    while index < name.len() {
+        let len = name[index] as usize;
+        if len == 0 {
+            if index + 1 != name.len() {
+                return Err(invalid());
+            }
+            fully_qualified = true;
+            break;
+        }
+        let label = name.get(index + 1..index + 1 + len).ok_or_else(invalid)?;
+        if len > 63 || label.contains(&b'.') {
+            return Err(invalid());
+        }
+        labels.push(String::from_utf8(label.to_vec()).map_err(|_| invalid())?);
+        index += 1 + len;
+    }
+    let mut name = labels.join(".");
+    if fully_qualified {
+        name.push('.');
+    }
+    Ok(ClientFqdn { flags, name })
+}
+
+/**
+ * Classless Static Route(RFC3442)の形式にする。
+ * 宛先はプレフィックス長の1オクテットと、プレフィックスに必要なオクテットだけを並べる。
+ */
+pub fn encode_classless_routes(routes: &[ClasslessRoute]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for route in routes.iter() {
+        let prefix = route.destination.prefix();
+        buf.push(prefix);
+        let significant = (prefix as usize).div_ceil(8);
+        buf.extend_from_slice(&route.destination.network().octets()[..significant]);
+        buf.extend_from_slice(&route.router.octets());
+    }
+    buf
+}
+
+fn decode_classless_routes(code: u8, value: &[u8]) -> Result<Vec<ClasslessRoute>, failure::Error> {
+    let invalid = || failure::format_err!("option {}: invalid classless static route", code);
+    let mut routes = Vec::new();
+    let mut index = 0;
+    while index < value.len() {
+        let prefix = value[index];
+        if prefix > 32 {
+            return Err(invalid());
+        }
+        let significant = (prefix as usize).div_ceil(8);
+        let destination = value.get(index + 1..index + 1 + significant).ok_or_else(invalid)?;
+        let router = value
+            .get(index + 1 + significant..index + 5 + significant)
+            .ok_or_else(invalid)?;
+        let mut octets = [0u8; 4];
+        octets[..significant].copy_from_slice(destination);
+        // プレフィックスより後ろのビットは無視する
+        let network = Ipv4Network::new(Ipv4Addr::from(octets), prefix).map_err(|_| invalid())?;
+        routes.push(ClasslessRoute {
+            destination: Ipv4Network::new(network.network(), prefix).map_err(|_| invalid())?,
+            router: Ipv4Addr::new(router[0], router[1], router[2], router[3]),
+        });
+        index += 5 + significant;
+    }
+    Ok(routes)
+}
+
+/**
+ * オプションを並べてバイト列にする(Endは含めない)。
+ * 値が255オクテットを超えるオプションは同じコードの複数のオプションに分割する(RFC3396)。
+ */
+pub fn encode_options(options: &[DhcpOption]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for option in options.iter() {
+        let value = option.encode_value();
+        if value.is_empty() {
+            buf.extend_from_slice(&[option.code(), 0]);
+            continue;
+        }
+        for chunk in value.chunks(u8::MAX as usize) {
+            buf.push(option.code());
+            buf.push(chunk.len() as u8);
+            buf.extend_from_slice(chunk);
+        }
+    }
+    buf
+}
+
+/**
+ * オプション領域(マジッククッキーの後)を走査して、コードと値の組を返す。
+ * 同じコードのオプションが複数あれば値を連結する(RFC3396)。
+ * Endがない、または長さが領域をはみ出している場合はErr。
+ */
+pub fn parse_raw_options(buf: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, failure::Error> {
+    let mut options: Vec<(u8, Vec<u8>)> = Vec::new();
+    let mut index = 0;
+    loop {
+        let code = *buf
+            .get(index)
+            .ok_or_else(|| failure::err_msg("options are not terminated by the end option"))?;
+        if code == Code::End as u8 {
+            return Ok(options);
+        }
+        if code == Code::Pad as u8 {
+            index += 1;
+            continue;
+        }
+        let len = *buf
+            .get(index + 1)
+            .ok_or_else(|| failure::format_err!("option {} has no length", code))?
+            as usize;
+        let value = buf
+            .get(index + 2..index + 2 + len)
+            .ok_or_else(|| failure::format_err!("option {} overruns the packet", code))?;
+        match options.iter_mut().find(|(existing, _)| *existing == code) {
+            Some((_, concatenated)) => concatenated.extend_from_slice(value),
+            None => options.push((code, value.to_vec())),
+        }
+        index += 2 + len;
+    }
+}
+
+/**
+ * 応答に含めるオプションを、クライアントのParameter Request List(オプション55)に従って選ぶ(RFC2131 4.3.1)。
+ *
+ * [note] メッセージタイプ・server identifier・リース期間・T1/T2は要求に関わらず含める。
+ * Client FQDNもクライアントが送ってきた場合の応答なので、要求に関わらず含める(RFC4702 4)。
+ * それ以外は要求されたものを要求の順に並べる。リストがなければ全て含める。
+ * オプション82は最後に置く(RFC3046 2.1)。
+ */
+pub fn select_requested_options(
+    options: Vec<DhcpOption>,
+    parameter_request_list: Option<&[u8]>,
+) -> Vec<DhcpOption> {
+    let always = [
+        Code::MessageType as u8,
+        Code::ServerIdentifier as u8,
+        Code::IPAddressLeaseTime as u8,
+        Code::RenewalTime as u8,
+        Code::RebindingTime as u8,
+        Code::ClientFqdn as u8,
+    ];
+    let relay_agent_information = Code::RelayAgentInformation as u8;
+    let (mut selected, rest): (Vec<_>, Vec<_>) = options
+        .into_iter()
+        .partition(|option| always.contains(&option.code()));
+    let (relay, mut rest): (Vec<_>, Vec<_>) = rest
+        .into_iter()
+        .partition(|option| option.code() == relay_agent_information);
+    match parameter_request_list {
+        Some(requested) => {
+            for code in requested.iter() {
+                if let Some(index) = rest.iter().position(|option| option.code() == *code) {
+                    selected.push(rest.remove(index));
+                }
+            }
+        }
+        None => selected.extend(rest),
+    }
+    selected.extend(relay);
+    selected
+}
+
+/**
+ * 設定ファイルの値などから作ったバイト列をオプションとして検証して返す
+ */
+pub fn from_config_value(code: u8, value: &[u8]) -> Result<DhcpOption, failure::Error> {
+    DhcpOption::decode(code, value)
+        .map_err(|e| failure::format_err!("{} (value: {})", e, util::encode_hex(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_options(buf: &[u8]) -> Result<Vec<DhcpOption>, failure::Error> {
+        parse_raw_options(buf)?
+            .into_iter()
+            .map(|(code, value)| DhcpOption::decode(code, &value))
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_decode_options() {
+        let options = vec![
+            DhcpOption::MessageType(2),
+            DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+            DhcpOption::Router(vec![Ipv4Addr::new(192, 168, 0, 1), Ipv4Addr::new(192, 168, 0, 254)]),
+            DhcpOption::DomainName("example.com".to_string()),
+            DhcpOption::InterfaceMtu(1500),
+            DhcpOption::IpAddressLeaseTime(300),
+            DhcpOption::DomainSearch(vec!["eng.example.com".to_string(), "example.com".to_string()]),
+            DhcpOption::ClasslessStaticRoute(vec![
+                ClasslessRoute {
+                    destination: "10.0.0.0/8".parse().unwrap(),
+                    router: Ipv4Addr::new(192, 168, 0, 1),
+                },
+                ClasslessRoute {
+                    destination: "0.0.0.0/0".parse().unwrap(),
+                    router: Ipv4Addr::new(192, 168, 0, 254),
+                },
+            ]),
+            DhcpOption::Unknown(224, vec![1, 2, 3]),
+        ];
+        let mut buf = encode_options(&options);
+        buf.push(Code::End as u8);
+        assert_eq!(options, decode_options(&buf).unwrap());
+        assert_eq!(
+            vec![8, 10, 192, 168, 0, 1, 0, 192, 168, 0, 254],
+            options[7].encode_value()
+        );
+    }
+
+    #[test]
+    fn test_client_fqdn() {
+        let fqdn = |flags, name: &str| ClientFqdn { flags, name: name.to_string() };
+        // DNSの形式。部分名は最後の長さ0のラベルがない
+        let partial = DhcpOption::ClientFqdn(fqdn(FQDN_E, "pc"));
+        assert_eq!(vec![FQDN_E, 255, 255, 2, b'p', b'c'], partial.encode_value());
+        let full = DhcpOption::ClientFqdn(fqdn(FQDN_S | FQDN_E, "pc.example.com."));
+        for option in [partial, full] {
+            let value = option.encode_value();
+            assert_eq!(option, DhcpOption::decode(Code::ClientFqdn as u8, &value).unwrap());
+        }
+        // クライアントはRCODEに0を入れてくる。廃止されたASCIIの形式も受け付ける
+        assert_eq!(
+            DhcpOption::ClientFqdn(fqdn(FQDN_S, "pc.example.com")),
+            DhcpOption::decode(Code::ClientFqdn as u8, b"\x01\0\0pc.example.com\0").unwrap()
+        );
+        assert_eq!(
+            DhcpOption::ClientFqdn(fqdn(FQDN_N | FQDN_E, "")),
+            DhcpOption::decode(Code::ClientFqdn as u8, &[FQDN_N | FQDN_E, 0, 0]).unwrap()
+        );
+        assert!(DhcpOption::decode(Code::ClientFqdn as u8, &[FQDN_E, 0]).is_err());
+        // 圧縮のポインタは使えない
+        assert!(DhcpOption::decode(Code::ClientFqdn as u8, &[FQDN_E, 0, 0, 0xc0, 0]).is_err());
+        assert!(DhcpOption::decode(Code::ClientFqdn as u8, &[FQDN_E, 0, 0, 3, b'p']).is_err());
+    }
+
+    #[test]
+    fn test_long_option_is_split() {
+        // 255オクテットを超えるオプションは分割して送り、受信時は連結する(RFC3396)
+        let option = DhcpOption::VendorSpecific((0..300).map(|i| i as u8).collect());
+        let mut buf = encode_options(std::slice::from_ref(&option));
+        assert_eq!(2 + 255 + 2 + 45, buf.len());
+        assert_eq!((43, 255), (buf[0], buf[1]));
+        assert_eq!((43, 45), (buf[257], buf[258]));
+        buf.push(Code::End as u8);
+        assert_eq!(vec![option], decode_options(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_length_validation() {
+        assert!(DhcpOption::decode(Code::SubnetMask as u8, &[255, 255, 255]).is_err());
+        assert!(DhcpOption::decode(Code::Router as u8, &[192, 168, 0, 1, 0]).is_err());
+        assert!(DhcpOption::decode(Code::InterfaceMtu as u8, &[0, 10]).is_err());
+        assert!(DhcpOption::decode(Code::ClientIdentifier as u8, &[1]).is_err());
+        assert!(DhcpOption::decode(Code::ClasslessStaticRoute as u8, &[24, 10, 0]).is_err());
+        assert_eq!(
+            DhcpOption::HostName("pc".to_string()),
+            DhcpOption::decode(Code::HostName as u8, b"pc\0").unwrap()
+        );
+        // Endがない、長さがはみ出している
+        assert!(decode_options(&[Code::MessageType as u8, 1, 1]).is_err());
+        assert!(decode_options(&[Code::MessageType as u8, 4, 1, 255]).is_err());
+    }
+
+    #[test]
+    fn test_domain_search_compression() {
+        // 2つ目のドメイン名は1つ目の"example.com"をポインタで参照する(RFC3397の例)
+        let mut value = vec![3];
+        value.extend_from_slice(b"eng");
+        value.push(7);
+        value.extend_from_slice(b"example");
+        value.push(3);
+        value.extend_from_slice(b"com");
+        value.push(0);
+        value.push(3);
+        value.extend_from_slice(b"foo");
+        value.extend_from_slice(&[0xc0, 4]);
+        assert_eq!(
+            DhcpOption::DomainSearch(vec![
+                "eng.example.com".to_string(),
+                "foo.example.com".to_string()
+            ]),
+            DhcpOption::decode(Code::DomainSearch as u8, &value).unwrap()
+        );
+        // 自分自身を指すポインタ
+        assert!(DhcpOption::decode(Code::DomainSearch as u8, &[0xc0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_select_requested_options() {
+        let options = vec![
+            DhcpOption::MessageType(5),
+            DhcpOption::ServerIdentifier(Ipv4Addr::new(192, 168, 0, 2)),
+            DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+            DhcpOption::Router(vec![Ipv4Addr::new(192, 168, 0, 1)]),
+            DhcpOption::DomainName("example.com".to_string()),
+            DhcpOption::RelayAgentInformation(vec![1, 1, 0]),
+        ];
+        let codes = |options: Vec<DhcpOption>| -> Vec<u8> {
+            options.iter().map(|option| option.code()).collect()
+        };
+        assert_eq!(
+            vec![53, 54, 3, 1, 82],
+            codes(select_requested_options(options.clone(), Some(&[3, 1, 6])))
+        );
+        assert_eq!(
+            vec![53, 54, 1, 3, 15, 82],
+            codes(select_requested_options(options, None))
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::net::Ipv4Addr;
+
+use super::dhcp::Scope;
+
+// Relay Agent Information(オプション82)のサブオプション(RFC3046 2.0)
+pub const AGENT_CIRCUIT_ID: u8 = 1;
+pub const AGENT_REMOTE_ID: u8 = 2;
+
+/**
+ * リレーエージェントが付加するRelay Agent Information(オプション82)。
+ *
+ * [note] サブオプションもオプションと同じくCode, Len, 値の並び。
+ * サーバは中身を解釈できなくても、受け取ったものをそのまま応答に含めて返す(RFC3046 2.2)。
+ * そのためここでは応答するかの判断に使うだけで、応答には受信したバイト列をそのまま入れる。
+ *
+ * ```text
+    Code   Len     Agent Information Field
+   +------+------+------+------+------+------+--...-+------+
+   |  82  |   N  |  i1  |  i2  |  i3  |  i4  |      |  iN  |
+   +------+------+------+------+------+------+--...-+------+
+ * ```
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayAgentInformation {
+    pub sub_options: Vec<(u8, Vec<u8>)>,
+}
+
+impl RelayAgentInformation {
+    /**
+     * オプション82の値を解析する。サブオプションの長さが値をはみ出していればErr。
+     */
+    pub fn parse(buf: &[u8]) -> Result<RelayAgentInformation, failure::Error> {
+        let mut sub_options = Vec::new();
+        let mut index = 0;
+        while index < buf.len() {
+            let code = buf[index];
+            let len = *buf
+                .get(index + 1)
+                .ok_or_else(|| failure::format_err!("sub-option {} has no length", code))?
+                as usize;
+            let value = buf.get(index + 2..index + 2 + len).ok_or_else(|| {
+                failure::format_err!("sub-option {} is longer than the option", code)
+            })?;
+            sub_options.push((code, value.to_vec()));
+            index += 2 + len;
+        }
+        Ok(RelayAgentInformation { sub_options })
+    }
+
+    pub fn sub_option(&self, code: u8) -> Option<&[u8]> {
+        self.sub_options
+            .iter()
+            .find(|(sub_code, _)| *sub_code == code)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /**
+     * リレーエージェントがクライアントから受信した回線(スイッチのポートなど)
+     */
+    pub fn circuit_id(&self) -> Option<&[u8]> {
+        self.sub_option(AGENT_CIRCUIT_ID)
+    }
+
+    /**
+     * 回線の向こう側の機器(モデムなど)
+     */
+    pub fn remote_id(&self) -> Option<&[u8]> {
+        self.sub_option(AGENT_REMOTE_ID)
+    }
+}
+
+/**
+ * リレーエージェントを経由したリクエストに応答するかを決める。
+ * 既定はスコープに設定された許可リストで判断する(AllowListPolicy)。
+ * 別の基準で判断する場合はこれを実装してDhcpServer::relay_policyに設定する。
+ */
+pub trait RelayAgentPolicy: Send + Sync {
+    /**
+     * giaddrのリレーエージェントから届いたリクエストに応答するならtrue。
+     * infoはオプション82がなければNone。
+     */
+    fn accept(
+        &self,
+        scope: &Scope,
+        giaddr: Ipv4Addr,
+        info: Option<&RelayAgentInformation>,
+    ) -> bool;
+}
+
+/**
+ * スコープの許可リスト(circuit_ids, remote_ids)に含まれるリクエストだけに応答する。
+ * リストが空であれば全て許可する。
+ */
+pub struct AllowListPolicy;
+
+impl RelayAgentPolicy for AllowListPolicy {
+    fn accept(
+        &self,
+        scope: &Scope,
+        _giaddr: Ipv4Addr,
+        info: Option<&RelayAgentInformation>,
+    ) -> bool {
+        let allowed = |list: &[Vec<u8>], id: Option<&[u8]>| {
+            list.is_empty() || id.is_some_and(|id| list.iter().any(|allowed| allowed == id))
+        };
+        allowed(&scope.relay_circuit_ids, info.and_then(|info| info.circuit_id()))
+            && allowed(&scope.relay_remote_ids, info.and_then(|info| info.remote_id()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_relay_agent_information() {
+        let buf = [
+            AGENT_CIRCUIT_ID, 4, b'p', b'o', b'r', b't', //
+            AGENT_REMOTE_ID, 2, 0xab, 0xcd, //
+            9, 0, // 未知のサブオプションもそのまま保持する
+        ];
+        let info = RelayAgentInformation::parse(&buf).unwrap();
+        assert_eq!(Some(&b"port"[..]), info.circuit_id());
+        assert_eq!(Some(&[0xab, 0xcd][..]), info.remote_id());
+        assert_eq!((9, vec![]), info.sub_options[2]);
+
+        assert!(RelayAgentInformation::parse(&[AGENT_CIRCUIT_ID, 4, b'p']).is_err());
+        assert!(RelayAgentInformation::parse(&[AGENT_CIRCUIT_ID]).is_err());
+    }
+}
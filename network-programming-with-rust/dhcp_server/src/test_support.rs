@@ -0,0 +1,59 @@
+//! テストで使う設定とDHCPサーバ。
+//! ライブラリ内のテストに加えてmain.rsのテスト(バイナリクレート)からも使うので、cfg(test)にせず公開している。
+
+use crate::config::Config;
+use crate::database::MemoryLeaseStore;
+use crate::dhcp::DhcpServer;
+
+pub const TEST_CONFIG: &str = r#"
+    server_identifier = "192.168.0.2"
+    decline_time = 0
+    server_duid = "00:03:00:01:00:11:22:33:44:01"
+
+    [[scope]]
+    name = "lan"
+    subnet = "192.168.0.0/24"
+    routers = ["192.168.0.1"]
+    dns_servers = ["192.168.0.1"]
+    lease_time = 300
+    reservations = [
+        { mac = "00:11:22:33:44:77", ip = "192.168.0.3", hostname = "printer" },
+        { client_id = "01:00:11:22:33:44:88", ip = "192.168.0.200", options = [
+            { code = 15, text = "lab.example" },
+        ] },
+    ]
+
+    [[scope]]
+    name = "office"
+    subnet = "10.0.1.0/24"
+    server_identifier = "10.0.1.2"
+    ranges = [{ start = "10.0.1.100", end = "10.0.1.109" }]
+    exclusions = ["10.0.1.105"]
+    routers = ["10.0.1.1", "10.0.1.254"]
+    options = [
+        { code = 15, text = "office.example" },
+        { code = 26, u16 = 1500 },
+        { code = 43, hex = "0104c0a80001" },
+        { code = 121, routes = [{ destination = "172.16.0.0/12", router = "10.0.1.254" }] },
+    ]
+    relay_circuit_ids = [{ text = "sw1/1" }, { hex = "0001" }]
+
+    [[scope6]]
+    name = "lan6"
+    prefix = "2001:db8:1::/64"
+    ranges = [{ start = "2001:db8:1::100", end = "2001:db8:1::103" }]
+    dns_servers = ["2001:db8:1::1"]
+    domain_search = ["example.lan"]
+    preferred_lifetime = 300
+    valid_lifetime = 600
+    pd_pool = "2001:db8:100::/54"
+    pd_prefix_length = 56
+"#;
+
+/**
+ * テスト用の環境情報とメモリ上のリースの保存先でDHCPサーバを作る
+ */
+pub fn test_server() -> DhcpServer {
+    let config = Config::parse(TEST_CONFIG).unwrap();
+    DhcpServer::from_config(&config, Box::new(MemoryLeaseStore::default())).unwrap()
+}
@@ -0,0 +1,361 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::options;
+
+// TSIGのリソースレコードのタイプとクラス(RFC8945 4.2)
+const TYPE_TSIG: u16 = 250;
+const CLASS_ANY: u16 = 255;
+// 対応する唯一のアルゴリズム
+pub const HMAC_SHA256: &str = "hmac-sha256";
+// 署名した時刻と受信した時刻のずれとして許す秒数(RFC8945 10の推奨値)
+const FUDGE: u16 = 300;
+// DNSメッセージのヘッダの長さとARCOUNTの位置
+const HEADER_LEN: usize = 12;
+const ARCOUNT: usize = 10;
+
+// TSIGのエラー(RFC8945 3)
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+const BADTRUNC: u16 = 22;
+
+/**
+ * HMAC-SHA256(RFC2104)
+ */
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    // HMACはどの長さの鍵でも作れる
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/**
+ * 名前を比較や署名に使う正規の形式(小文字、圧縮なし)のDNSの形式にする(RFC4034 6.2)
+ */
+fn canonical_name(name: &str) -> Vec<u8> {
+    options::encode_domain_names(&[name.to_ascii_lowercase()])
+}
+
+/**
+ * メッセージのoffsetから始まる名前を読み飛ばし、その後ろの位置を返す。圧縮のポインタで終わってもよい
+ */
+fn skip_name(message: &[u8], mut offset: usize) -> Result<usize, failure::Error> {
+    loop {
+        let len = *message
+            .get(offset)
+            .ok_or_else(|| failure::err_msg("truncated domain name"))? as usize;
+        match len {
+            0 => return Ok(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(offset + 2),
+            len => offset += 1 + len,
+        }
+    }
+}
+
+/**
+ * メッセージのoffsetから始まる圧縮されていない名前を読む。TSIGの名前は圧縮できない(RFC8945 4.2)
+ */
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize), failure::Error> {
+    let invalid = || failure::err_msg("invalid domain name in TSIG");
+    let mut labels = Vec::new();
+    loop {
+        let len = *message.get(offset).ok_or_else(invalid)? as usize;
+        if len == 0 {
+            return Ok((labels.join("."), offset + 1));
+        }
+        if len > 63 {
+            return Err(invalid());
+        }
+        let label = message.get(offset + 1..offset + 1 + len).ok_or_else(invalid)?;
+        labels.push(String::from_utf8(label.to_vec()).map_err(|_| invalid())?);
+        offset += 1 + len;
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Result<u16, failure::Error> {
+    message
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| failure::err_msg("message is truncated"))
+}
+
+/**
+ * 受信したメッセージの末尾のTSIGのリソースレコード
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct TsigRecord {
+    start: usize, // メッセージ中のTSIGのレコードの位置
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other_data: Vec<u8>,
+}
+
+impl TsigRecord {
+    /**
+     * 追加情報セクションの最後のレコードがTSIGならそれを返す
+     */
+    fn find(message: &[u8]) -> Result<Option<TsigRecord>, failure::Error> {
+        if message.len() < HEADER_LEN {
+            return Err(failure::err_msg("message is too short"));
+        }
+        let counts: Vec<u16> = (4..HEADER_LEN)
+            .step_by(2)
+            .map(|offset| read_u16(message, offset))
+            .collect::<Result<_, _>>()?;
+        if counts[3] == 0 {
+            return Ok(None);
+        }
+        let mut offset = HEADER_LEN;
+        for _ in 0..counts[0] {
+            offset = skip_name(message, offset)? + 4;
+        }
+        let records = counts[1] as usize + counts[2] as usize + counts[3] as usize - 1;
+        for _ in 0..records {
+            offset = skip_name(message, offset)? + 8;
+            offset += 2 + read_u16(message, offset)? as usize;
+        }
+
+        let start = offset;
+        let (key_name, offset) = match read_name(message, start) {
+            Ok(name) => name,
+            // 圧縮された名前を持つTSIG以外のレコード
+            Err(_) => return Ok(None),
+        };
+        if read_u16(message, offset)? != TYPE_TSIG {
+            return Ok(None);
+        }
+        let rdata_start = offset + 10;
+        let rdata_end = rdata_start + read_u16(message, offset + 8)? as usize;
+        if rdata_end != message.len() {
+            return Err(failure::err_msg("TSIG is not the last record"));
+        }
+        let (algorithm, offset) = read_name(message, rdata_start)?;
+        let time = message
+            .get(offset..offset + 6)
+            .ok_or_else(|| failure::err_msg("TSIG is truncated"))?;
+        let time_signed = time.iter().fold(0u64, |time, b| (time << 8) | *b as u64);
+        let fudge = read_u16(message, offset + 6)?;
+        let mac_size = read_u16(message, offset + 8)? as usize;
+        let mac_end = offset + 10 + mac_size;
+        let mac = message
+            .get(offset + 10..mac_end)
+            .ok_or_else(|| failure::err_msg("TSIG is truncated"))?
+            .to_vec();
+        let original_id = read_u16(message, mac_end)?;
+        let error = read_u16(message, mac_end + 2)?;
+        let other_len = read_u16(message, mac_end + 4)? as usize;
+        let other_data = message
+            .get(mac_end + 6..mac_end + 6 + other_len)
+            .ok_or_else(|| failure::err_msg("TSIG is truncated"))?
+            .to_vec();
+        Ok(Some(TsigRecord {
+            start,
+            key_name,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other_data,
+        }))
+    }
+}
+
+/**
+ * TSIGの鍵。名前とHMAC-SHA256の共有鍵
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TsigKey {
+    pub name: String,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    /**
+     * メッセージに署名し、TSIGのレコードを加えたメッセージとMACを返す。
+     * 応答に署名する場合はrequest_macに要求のMACを渡す(RFC8945 5.3)。
+     */
+    pub fn sign(
+        &self,
+        message: &[u8],
+        request_mac: Option<&[u8]>,
+        time_signed: u64,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let original_id = u16::from_be_bytes([message[0], message[1]]);
+        let mac = self
+            .digest(message, request_mac, time_signed, FUDGE, 0, &[])
+            .to_vec();
+
+        let mut rdata = canonical_name(HMAC_SHA256);
+        rdata.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&mac);
+        rdata.extend_from_slice(&original_id.to_be_bytes());
+        rdata.extend_from_slice(&[0, 0, 0, 0]); // ErrorとOther Len
+
+        let mut signed = message.to_vec();
+        let arcount = read_u16(message, ARCOUNT).unwrap_or(0) + 1;
+        signed[ARCOUNT..ARCOUNT + 2].copy_from_slice(&arcount.to_be_bytes());
+        signed.extend_from_slice(&canonical_name(&self.name));
+        signed.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+        signed.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        signed.extend_from_slice(&0u32.to_be_bytes());
+        signed.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        signed.extend_from_slice(&rdata);
+        (signed, mac)
+    }
+
+    /**
+     * 受信したメッセージの署名を確かめ、そのMACを返す。
+     * 応答を確かめる場合はrequest_macに要求のMACを渡す。nowは現在のUNIX時間。
+     */
+    pub fn verify(
+        &self,
+        message: &[u8],
+        request_mac: Option<&[u8]>,
+        now: u64,
+    ) -> Result<Vec<u8>, failure::Error> {
+        let tsig = TsigRecord::find(message)?
+            .ok_or_else(|| failure::err_msg("message is not signed with TSIG"))?;
+        if !tsig.key_name.eq_ignore_ascii_case(self.name.trim_end_matches('.')) {
+            let key_name = tsig.key_name;
+            return Err(failure::format_err!("message is signed with unknown key {}", key_name));
+        }
+        if !tsig.algorithm.eq_ignore_ascii_case(HMAC_SHA256) {
+            return Err(failure::format_err!("unsupported TSIG algorithm {}", tsig.algorithm));
+        }
+        // [note] BADSIG・BADKEYの応答にはMACがない(RFC8945 5.3.2)
+        match tsig.error {
+            0 => {}
+            BADSIG => return Err(failure::err_msg("TSIG error: BADSIG")),
+            BADKEY => return Err(failure::err_msg("TSIG error: BADKEY")),
+            BADTIME => return Err(failure::err_msg("TSIG error: BADTIME")),
+            BADTRUNC => return Err(failure::err_msg("TSIG error: BADTRUNC")),
+            error => return Err(failure::format_err!("TSIG error: {}", error)),
+        }
+
+        // TSIGを除き、ARCOUNTとIDを署名した時の値に戻したものが署名の対象
+        let mut unsigned = message[..tsig.start].to_vec();
+        unsigned[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        let arcount = read_u16(message, ARCOUNT)? - 1;
+        unsigned[ARCOUNT..ARCOUNT + 2].copy_from_slice(&arcount.to_be_bytes());
+        let expected = self.digest(
+            &unsigned,
+            request_mac,
+            tsig.time_signed,
+            tsig.fudge,
+            tsig.error,
+            &tsig.other_data,
+        );
+        // 比較にかかる時間から一致した長さが分からないように全て比べる
+        let matched = tsig.mac.len() == expected.len()
+            && tsig.mac.iter().zip(expected.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+        if !matched {
+            return Err(failure::err_msg("TSIG signature does not match"));
+        }
+        if now.abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            return Err(failure::format_err!(
+                "TSIG time {} is out of the allowed range from {}",
+                tsig.time_signed,
+                now
+            ));
+        }
+        Ok(tsig.mac)
+    }
+
+    /**
+     * MACを計算する(RFC8945 4.3)。対象は(応答なら要求のMAC、)メッセージ、TSIGの変数の順に並べたもの
+     */
+    fn digest(
+        &self,
+        message: &[u8],
+        request_mac: Option<&[u8]>,
+        time_signed: u64,
+        fudge: u16,
+        error: u16,
+        other_data: &[u8],
+    ) -> [u8; 32] {
+        let mut data = Vec::new();
+        if let Some(request_mac) = request_mac {
+            data.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+            data.extend_from_slice(request_mac);
+        }
+        data.extend_from_slice(message);
+        data.extend_from_slice(&canonical_name(&self.name));
+        data.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&canonical_name(HMAC_SHA256));
+        data.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+        data.extend_from_slice(&fudge.to_be_bytes());
+        data.extend_from_slice(&error.to_be_bytes());
+        data.extend_from_slice(&(other_data.len() as u16).to_be_bytes());
+        data.extend_from_slice(other_data);
+        hmac_sha256(&self.secret, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC4231 4.2, 4.3, 4.7のテストケース
+        assert_eq!(
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            util::encode_hex(&hmac_sha256(&[0x0b; 20], b"Hi There"))
+        );
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            util::encode_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?"))
+        );
+        let data = b"This is a test using a larger than block-size key and a larger than \
+                     block-size data. The key needs to be hashed before being used by the \
+                     HMAC algorithm.";
+        assert_eq!(
+            "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            util::encode_hex(&hmac_sha256(&[0xaa; 131], data))
+        );
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = TsigKey {
+            name: "dhcp-key".to_string(),
+            secret: util::decode_base64("c2VjcmV0LWtleQ==").unwrap(),
+        };
+        assert_eq!(b"secret-key".to_vec(), key.secret);
+        // ヘッダとゾーンのセクションだけのメッセージ
+        let mut message = vec![0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&[7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0, 0, 6, 0, 1]);
+
+        let now = 1_700_000_000;
+        let (signed, request_mac) = key.sign(&message, None, now);
+        assert_eq!(1, read_u16(&signed, ARCOUNT).unwrap());
+        assert_eq!(request_mac, key.verify(&signed, None, now + 10).unwrap());
+        // 時刻のずれ、鍵・内容の違い
+        assert!(key.verify(&signed, None, now + 301).unwrap_err().to_string().contains("time"));
+        let other = TsigKey { secret: b"other".to_vec(), ..key.clone() };
+        assert!(other.verify(&signed, None, now).is_err());
+        let mut tampered = signed.clone();
+        tampered[HEADER_LEN + 1] = b'E';
+        assert!(key.verify(&tampered, None, now).is_err());
+        assert!(key.verify(&message, None, now).is_err());
+
+        // 応答は要求のMACも含めて署名する
+        let mut response = message.clone();
+        response[2] |= 0x80;
+        let (signed_response, _) = key.sign(&response, Some(&request_mac), now);
+        assert!(key.verify(&signed_response, Some(&request_mac), now).is_ok());
+        assert!(key.verify(&signed_response, None, now).is_err());
+    }
+}
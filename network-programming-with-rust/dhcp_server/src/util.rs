@@ -1,17 +1,23 @@
-use std::collections::HashMap;
-use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
-use std::{fs, io};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use byteorder::{BigEndian, WriteBytesExt};
+use pnet::datalink;
 use pnet::packet::icmp::echo_request::{EchoRequestPacket, MutableEchoRequestPacket};
 use pnet::packet::icmp::IcmpTypes;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::Packet;
 use pnet::transport::{self, icmp_packet_iter, TransportChannelType, TransportProtocol::Ipv4};
 use pnet::util::checksum;
+use socket2::{Domain, Protocol, Socket, Type};
+
+// DHCPv6のリレーエージェントとサーバが参加するマルチキャストグループ(RFC8415 7.1)
+const ALL_DHCP_RELAY_AGENTS_AND_SERVERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+const DHCPV6_SERVER_PORT: u16 = 547;
+// DUID-LL(リンク層アドレスによるDUID)のタイプとイーサネットのハードウェアタイプ(RFC8415 11.4)
+const DUID_LL: u16 = 3;
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
 
 /**
  * ICMP echoリクエストのバッファを作成する。
@@ -66,7 +72,6 @@ pub fn is_ipaddr_available(target_ip: Ipv4Addr) -> Result<(), failure::Error> {
                 }
                 _ => {
                     // Channel送信できた場合は何もせず終了
-                    return;
                 }
             }
         }
@@ -86,83 +91,152 @@ pub fn is_ipaddr_available(target_ip: Ipv4Addr) -> Result<(), failure::Error> {
 }
 
 /**
- * スライスをIpv4アドレスに変換して返す
+ * バイト列を16進数の文字列にする
  */
-pub fn u8_to_ipv4addr(buf: &[u8]) -> Option<Ipv4Addr> {
-    if buf.len() == 4 {
-        Some(Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]))
-    } else {
-        None
-    }
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 /**
- * .envから環境情報を読んでハッシュマップを返す
+ * 16進数の文字列をバイト列にする。"01:aa:bb" のような ':' 区切りも受け付ける。
  */
-pub fn load_env() -> HashMap<String, String> {
-    let contents = fs::read_to_string(".env").expect("Failed to read env file");
-    let lines: Vec<_> = contents.split('\n').collect();
-    let mut map = HashMap::new();
-    for line in lines {
-        let elm: Vec<_> = line.split('=').map(str::trim).collect();
-        if elm.len() == 2 {
-            map.insert(elm[0].to_string(), elm[1].to_string());
-        }
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, failure::Error> {
+    let digits: String = hex.chars().filter(|c| *c != ':').collect();
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return Err(failure::format_err!("invalid hex string: {:?}", hex));
     }
-    map
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| failure::format_err!("invalid hex string: {:?}", hex))
+        })
+        .collect()
 }
 
 /**
- * 固定されたアドレス情報を返す
+ * Base64(RFC4648 4)の文字列をバイト列にする。TSIGの鍵などに使う
  */
-pub fn obtain_static_addresses(
-    env: &HashMap<String, String>,
-) -> Result<HashMap<String, Ipv4Addr>, AddrParseError> {
-    let network_addr: Ipv4Addr = env
-        .get("NETWORK_ADDR")
-        .expect("Missing network_addr")
-        .parse()?;
-
-    let subnet_mask: Ipv4Addr = env
-        .get("SUBNET_MASK")
-        .expect("Missing subnet_mask")
-        .parse()?;
-
-    let dhcp_server_address = env
-        .get("SERVER_IDENTIFIER")
-        .expect("Missing server_identifier")
-        .parse()?;
-
-    let default_gateway = env
-        .get("DEFAULT_GATEWAY")
-        .expect("Missing default_gateway")
-        .parse()?;
-
-    let dns_addr = env.get("DNS_SERVER").expect("Missing dns_server").parse()?;
-
-    let mut map = HashMap::new();
-    map.insert("network_addr".to_string(), network_addr);
-    map.insert("subnet_mask".to_string(), subnet_mask);
-    map.insert("dhcp_server_addr".to_string(), dhcp_server_address);
-    map.insert("default_gateway".to_string(), default_gateway);
-    map.insert("dns_addr".to_string(), dns_addr);
-    Ok(map)
+pub fn decode_base64(text: &str) -> Result<Vec<u8>, failure::Error> {
+    let invalid = || failure::format_err!("invalid base64 string: {:?}", text);
+    let text = text.trim();
+    if !text.len().is_multiple_of(4) {
+        return Err(invalid());
+    }
+    let padding = text.bytes().rev().take_while(|b| *b == b'=').count();
+    if padding > 2 {
+        return Err(invalid());
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits: u32 = 0;
+    for (i, b) in text.bytes().take(text.len() - padding).enumerate() {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(invalid()),
+        };
+        bits = (bits << 6) | value as u32;
+        if i % 4 == 3 {
+            bytes.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+        }
+    }
+    // 末尾の'='で埋めた分
+    match padding {
+        1 => bytes.extend_from_slice(&(bits << 6).to_be_bytes()[1..3]),
+        2 => bytes.push((bits << 12).to_be_bytes()[1]),
+        _ => {}
+    }
+    Ok(bytes)
 }
 
 /**
- * u32をビッグエンディアンでバイト列ベクタに変換
+ * 現在時刻をUNIX時間(秒)で返す
  */
-pub fn make_big_endian_vec_from_u32(i: u32) -> Result<Vec<u8>, io::Error> {
-    let mut v = Vec::new();
-    v.write_u32::<BigEndian>(i)?;
-    Ok(v)
+pub fn unix_time_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970")
+        .as_secs() as i64
 }
 
 /**
- * ブロードキャストでDHCPクライアントにデータを送信する。
+ * DHCPクライアント(リレーされた場合はリレーエージェント)にデータを送信する。
  */
-pub fn send_dhcp_broadcast_response(soc: &UdpSocket, data: &[u8]) -> Result<(), failure::Error> {
-    let destination: SocketAddr = "255.255.255.255:68".parse()?;
+pub fn send_dhcp_response(
+    soc: &UdpSocket,
+    data: &[u8],
+    destination: SocketAddr,
+) -> Result<(), failure::Error> {
     soc.send_to(data, destination)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/**
+ * DHCPサーバのポート(67)でリクエストを受け付けるソケットを作る。
+ * interfaceを指定した場合はそのインターフェースで受信したものだけを受け付ける(SO_BINDTODEVICE)。
+ *
+ * [note] インターフェースごとに0.0.0.0:67へbindするのでSO_REUSEADDRを設定する。
+ */
+pub fn bind_dhcp_socket(interface: Option<&str>) -> Result<UdpSocket, failure::Error> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if let Some(interface) = interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    // [note] Udpソケットを(IPレイヤーでの)ブロードキャストに設定する。
+    // → クライアントはIPアドレスが設定されていないので必然的にブロードキャストになる。
+    // ref from Rust std library
+    // > Sets the value of the `SO_BROADCAST` option for this socket.
+    // > When enabled, this socket is allowed to send packets to a broadcast address.
+    socket.set_broadcast(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 67)).into())?;
+    Ok(socket.into())
+}
+
+/**
+ * DHCPv6のサーバのポート(547)でリクエストを受け付けるソケットを作る。
+ * クライアントはff02::1:2へ送ってくるので、interface(指定がなければOSが選ぶもの)でそのグループに参加する。
+ */
+pub fn bind_dhcpv6_socket(interface: Option<&str>) -> Result<UdpSocket, failure::Error> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    let index = match interface {
+        Some(interface) => {
+            socket.bind_device(Some(interface.as_bytes()))?;
+            datalink::interfaces()
+                .into_iter()
+                .find(|iface| iface.name == interface)
+                .map(|iface| iface.index)
+                .ok_or_else(|| failure::format_err!("interface {} is not found", interface))?
+        }
+        None => 0,
+    };
+    socket.join_multicast_v6(&ALL_DHCP_RELAY_AGENTS_AND_SERVERS, index)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, DHCPV6_SERVER_PORT)).into())?;
+    Ok(socket.into())
+}
+
+/**
+ * インターフェースのMACアドレスからDUID-LLを作る。
+ * interfaceを指定しなければループバック以外でMACアドレスを持つ最初のインターフェースを使う。
+ */
+pub fn link_layer_duid(interface: Option<&str>) -> Result<Vec<u8>, failure::Error> {
+    let mac_addr = datalink::interfaces()
+        .into_iter()
+        .filter(|iface| match interface {
+            Some(interface) => iface.name == interface,
+            None => !iface.is_loopback(),
+        })
+        .filter_map(|iface| iface.mac)
+        .find(|mac_addr| !mac_addr.is_zero())
+        .ok_or_else(|| failure::err_msg("no interface has a MAC address for the server DUID"))?;
+    let mut duid = DUID_LL.to_be_bytes().to_vec();
+    duid.extend_from_slice(&HARDWARE_TYPE_ETHERNET.to_be_bytes());
+    duid.extend_from_slice(&mac_addr.octets());
+    Ok(duid)
+}
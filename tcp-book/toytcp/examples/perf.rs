@@ -0,0 +1,285 @@
+//! iperfのようにデータを流し続けてスループットとRTTを計測する
+//!
+//! ```text
+//! perf server <addr> <port> [--kernel]
+//! perf client <addr> <port> [--time <秒> | --bytes <バイト数>] [--kernel]
+//! ```
+//!
+//! `--kernel` を付けるとToyTCPの代わりにカーネルのTCP(std::net)で同じ計測を行う。
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use std::{env, mem};
+use toytcp::tcp::TCP;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// どれだけ送るか
+#[derive(Clone, Copy)]
+enum Limit {
+    Duration(Duration),
+    Bytes(u64),
+}
+
+impl Limit {
+    fn reached(&self, started: Instant, sent: u64) -> bool {
+        match *self {
+            Limit::Duration(duration) => started.elapsed() >= duration,
+            Limit::Bytes(bytes) => sent >= bytes,
+        }
+    }
+
+    fn next_chunk(&self, sent: u64) -> usize {
+        match *self {
+            Limit::Duration(_) => CHUNK_SIZE,
+            Limit::Bytes(bytes) => (bytes - sent).min(CHUNK_SIZE as u64) as usize,
+        }
+    }
+}
+
+/// 計測結果。カーネルのTCPで取れない値はNone
+#[derive(Default)]
+struct Report {
+    bytes: u64,
+    elapsed: Duration,
+    segments_sent: Option<u64>,
+    retransmissions: Option<u64>,
+    rtt_samples: Vec<Duration>,
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let usage = "usage: perf (server|client) <addr> <port> [--time <secs> | --bytes <n>] [--kernel]";
+    let mode = args.get(1).context(usage)?;
+    let addr: Ipv4Addr = args.get(2).context(usage)?.parse()?;
+    let port: u16 = args.get(3).context(usage)?.parse()?;
+
+    let mut limit = Limit::Duration(DEFAULT_DURATION);
+    let mut kernel = false;
+    let mut options = args[4..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--time" => {
+                let secs: f64 = options.next().context(usage)?.parse()?;
+                limit = Limit::Duration(Duration::from_secs_f64(secs));
+            }
+            "--bytes" => limit = Limit::Bytes(options.next().context(usage)?.parse()?),
+            "--kernel" => kernel = true,
+            _ => anyhow::bail!(usage),
+        }
+    }
+
+    let report = match (mode.as_str(), kernel) {
+        ("server", false) => toytcp_server(addr, port)?,
+        ("server", true) => kernel_server(addr, port)?,
+        ("client", false) => toytcp_client(addr, port, limit)?,
+        ("client", true) => kernel_client(addr, port, limit)?,
+        _ => anyhow::bail!(usage),
+    };
+    print_report(if kernel { "kernel" } else { "toytcp" }, &report);
+    Ok(())
+}
+
+fn toytcp_server(addr: Ipv4Addr, port: u16) -> Result<Report> {
+    let tcp = TCP::new();
+    let listening_socket = tcp.listen(addr, port)?;
+    let sock_id = tcp.accept(listening_socket)?;
+    let started = Instant::now();
+    let mut meter = IntervalMeter::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        let n = tcp.recv(sock_id, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        received += n as u64;
+        meter.add(n as u64);
+    }
+    let elapsed = started.elapsed();
+    tcp.close(sock_id)?;
+    Ok(Report {
+        bytes: received,
+        elapsed,
+        ..Default::default()
+    })
+}
+
+fn toytcp_client(addr: Ipv4Addr, port: u16, limit: Limit) -> Result<Report> {
+    let tcp = TCP::new();
+    let sock_id = tcp.connect(addr, port)?;
+    let started = Instant::now();
+    let mut meter = IntervalMeter::new();
+    let buffer = vec![0x5a; CHUNK_SIZE];
+    let mut sent = 0;
+    while !limit.reached(started, sent) {
+        let size = limit.next_chunk(sent);
+        tcp.send(sock_id, &buffer[..size])?;
+        sent += size as u64;
+        meter.add(size as u64);
+    }
+    // [note] closeはソケットを破棄するので先に統計を取っておく
+    let stats = tcp.stats(sock_id)?;
+    // 相手のFINを受け取るまで(全てのデータが確認応答されるまで)を計測時間に含める
+    tcp.close(sock_id)?;
+    Ok(Report {
+        bytes: sent,
+        elapsed: started.elapsed(),
+        segments_sent: Some(stats.segments_sent),
+        retransmissions: Some(stats.retransmissions),
+        rtt_samples: stats.rtt_samples,
+    })
+}
+
+fn kernel_server(addr: Ipv4Addr, port: u16) -> Result<Report> {
+    let listener = TcpListener::bind((addr, port))?;
+    let (mut stream, _) = listener.accept()?;
+    let started = Instant::now();
+    let mut meter = IntervalMeter::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        received += n as u64;
+        meter.add(n as u64);
+    }
+    let elapsed = started.elapsed();
+    stream.shutdown(Shutdown::Both)?;
+    Ok(Report {
+        bytes: received,
+        elapsed,
+        ..Default::default()
+    })
+}
+
+fn kernel_client(addr: Ipv4Addr, port: u16, limit: Limit) -> Result<Report> {
+    let mut stream = TcpStream::connect((addr, port))?;
+    let started = Instant::now();
+    let mut meter = IntervalMeter::new();
+    let buffer = vec![0x5a; CHUNK_SIZE];
+    let mut sent = 0;
+    let mut rtt_samples = Vec::new();
+    while !limit.reached(started, sent) {
+        let size = limit.next_chunk(sent);
+        stream.write_all(&buffer[..size])?;
+        sent += size as u64;
+        meter.add(size as u64);
+        // カーネルはセグメントごとのRTTを公開しないので、平滑化されたRTTを送信のたびに記録する
+        rtt_samples.push(Duration::from_micros(tcp_info(&stream)?.tcpi_rtt as u64));
+    }
+    let info = tcp_info(&stream)?;
+    // toytcpのcloseと同じく、相手が閉じるまで待つ
+    stream.shutdown(Shutdown::Write)?;
+    stream.read_to_end(&mut Vec::new())?;
+    Ok(Report {
+        bytes: sent,
+        elapsed: started.elapsed(),
+        segments_sent: Some(info.tcpi_segs_out as u64),
+        retransmissions: Some(info.tcpi_total_retrans as u64),
+        rtt_samples,
+    })
+}
+
+/// カーネルのTCPの統計情報を取得する (Linux の TCP_INFO)
+fn tcp_info(stream: &TcpStream) -> Result<libc::tcp_info> {
+    // [note] tcp_infoは整数だけの構造体なので0で初期化してよい
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error()).context("getsockopt(TCP_INFO)");
+    }
+    Ok(info)
+}
+
+/// REPORT_INTERVALごとにその間のスループットを表示する
+struct IntervalMeter {
+    started: Instant,
+    interval_started: Instant,
+    bytes: u64,
+}
+
+impl IntervalMeter {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            interval_started: now,
+            bytes: 0,
+        }
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let elapsed = self.interval_started.elapsed();
+        if elapsed < REPORT_INTERVAL {
+            return;
+        }
+        let from = self.interval_started.duration_since(self.started).as_secs_f64();
+        println!(
+            "[{:6.2}-{:6.2} sec] {:>10} bytes  {:>8.2} Mbits/sec",
+            from,
+            from + elapsed.as_secs_f64(),
+            self.bytes,
+            mbps(self.bytes, elapsed)
+        );
+        self.interval_started = Instant::now();
+        self.bytes = 0;
+    }
+}
+
+fn mbps(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0
+}
+
+/// 最近傍順位法でパーセンタイルを求める。samplesはソート済みであること
+fn percentile(samples: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * samples.len() as f64).ceil() as usize;
+    samples[rank.clamp(1, samples.len()) - 1]
+}
+
+fn print_report(stack: &str, report: &Report) {
+    println!("---- {} ----", stack);
+    println!(
+        "transferred: {} bytes in {:.2} sec",
+        report.bytes,
+        report.elapsed.as_secs_f64()
+    );
+    println!("goodput:     {:.2} Mbits/sec", mbps(report.bytes, report.elapsed));
+    if let (Some(segments), Some(retransmissions)) = (report.segments_sent, report.retransmissions)
+    {
+        println!(
+            "segments:    {} sent, {} retransmitted ({:.2}%)",
+            segments,
+            retransmissions,
+            retransmissions as f64 * 100.0 / segments.max(1) as f64
+        );
+    }
+    if !report.rtt_samples.is_empty() {
+        let mut samples = report.rtt_samples.clone();
+        samples.sort();
+        println!(
+            "rtt:         p50 {:?}  p90 {:?}  p99 {:?}  max {:?}  ({} samples)",
+            percentile(&samples, 50.0),
+            percentile(&samples, 90.0),
+            percentile(&samples, 99.0),
+            samples[samples.len() - 1],
+            samples.len()
+        );
+    }
+}
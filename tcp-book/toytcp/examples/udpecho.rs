@@ -0,0 +1,25 @@
+use anyhow::{Result, Ok};
+use std::{env, net::Ipv4Addr, str};
+use toytcp::tcp::TCP;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let addr: Ipv4Addr = args[1].parse()?;
+    let port: u16 = args[2].parse()?;
+    udp_echo_server(addr, port)?;
+    Ok(())
+}
+
+fn udp_echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
+    // [note] UDPのデータグラムもTCPの受信スレッドで受け取る
+    let tcp = TCP::new();
+    let udp = tcp.udp()?;
+    let sock_id = udp.bind(local_addr, local_port)?;
+    dbg!("bound", sock_id);
+    let mut buffer = [0; 65535];
+    loop {
+        let (nbytes, remote_addr, remote_port) = udp.recv_from(sock_id, &mut buffer)?;
+        print!("{}:{} > {}", remote_addr, remote_port, str::from_utf8(&buffer[..nbytes])?);
+        udp.send_to(sock_id, &buffer[..nbytes], remote_addr, remote_port)?;
+    }
+}
@@ -0,0 +1,553 @@
+//! packetdrillのようなスクリプトでTCPの状態遷移を検証するテストランナー
+//!
+//! スクリプトは1行に1つ、時刻と操作を書く。`#` 以降はコメント。
+//!
+//! ```text
+//! 0      listen()
+//! 0      accept() &                           # &を付けたAPI呼び出しはバックグラウンドで実行する
+//! 0      < S 0:0(0) win 4380 <mss 1460>       # 相手から届くセグメント
+//! 0      > S. 0:0(0) ack 1 <mss 1460>         # ToyTCPが送信するはずのセグメント
+//! 0.1    < . 1:1(0) ack 1 win 4380
+//! +0     join                                 # 最も古いバックグラウンドの呼び出しの完了を待つ
+//! +0     recv(100) = 10                       # 戻り値(受信したサイズ)を確認する
+//! ```
+//!
+//! * 時刻は秒で、`+` を付けると直前の行からの相対時刻。実際には待たず、時計(ManualClock)を進める。
+//!   時刻が進んだ時だけタイマースレッドの代わりに再送処理を実行する。
+//! * セグメントの書式は `フラグ 開始:終了(長さ) [ack N] [win N] [<オプション,...>]`。
+//!   フラグは S(SYN) F(FIN) P(PSH) R(RST) .(ACK) E(ECE) W(CWR)。オプションは `mss N` と `fo [クッキー(16進)]`。
+//! * 相手側のシーケンス番号はスクリプトに書いた値そのまま、ToyTCP側は最初に送信したSYNの
+//!   シーケンス番号を0とした相対値で書く(ToyTCPの初期シーケンス番号は乱数のため)。
+//! * 期待するセグメントでは、省略したack・win・オプションは確認しない。ペイロードは長さだけ確認する。
+//! * API呼び出しは listen() accept() connect() send(N) recv(N) close()。
+//!   `= N` で戻り値、`= err` でエラーになることを確認する。
+//!
+//! [note] ToyTCPのイベント通知は1つ分しか保持しないので、バックグラウンドの呼び出しが待っている
+//! イベントを後続のセグメントで上書きしないよう、次のセグメントを送る前に join で完了を待つこと。
+use crate::packet::{TCPPacket, TCPPacketBuilder, TcpOption};
+use crate::socket::tests::RecordingSender;
+use crate::socket::{Clock, SockID};
+use crate::tcp::TCP;
+use crate::tcpflags;
+use anyhow::{Context, Result};
+use pnet::packet::Packet;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+const LOCAL_PORT: u16 = 8080;
+const REMOTE_PORT: u16 = 50000;
+const DEFAULT_WINDOW: u16 = 4380;
+// 別スレッドで動くAPI呼び出しやセグメントの送信を待つ時間(実時間)
+const WAIT_TIMEOUT: Duration = Duration::from_secs(2);
+// スクリプトの最後に、期待していないセグメントが送られてこないか確認する時間(実時間)
+const QUIET_PERIOD: Duration = Duration::from_millis(50);
+
+/// 手動で進める時計
+struct ManualClock(Mutex<SystemTime>);
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug)]
+struct Segment {
+    direction: Direction,
+    flag: u8,
+    seq: u32,
+    len: usize,
+    ack: Option<u32>,
+    window: Option<u16>,
+    options: Option<Vec<TcpOption>>,
+}
+
+#[derive(Debug)]
+enum Expect {
+    Ok,
+    Value(usize),
+    Err,
+}
+
+#[derive(Debug)]
+enum Call {
+    Listen,
+    Accept,
+    Connect,
+    Send(usize),
+    Recv(usize),
+    Close,
+}
+
+#[derive(Debug)]
+enum Action {
+    Segment(Segment),
+    Call {
+        call: Call,
+        expect: Expect,
+        background: bool,
+    },
+    Join,
+}
+
+/// API呼び出しの結果。recvは受信したサイズを返す。
+type CallResult = Result<Option<usize>>;
+
+struct PendingCall {
+    line: usize,
+    expect: Expect,
+    result: Receiver<CallResult>,
+}
+
+struct Runner {
+    tcp: Arc<TCP>,
+    sender: Arc<RecordingSender>,
+    clock: Arc<ManualClock>,
+    now: Duration,
+    local_iss: Option<u32>,
+    listening_sock: Arc<Mutex<Option<SockID>>>,
+    connection_sock: Arc<Mutex<Option<SockID>>>,
+    pending: VecDeque<PendingCall>,
+}
+
+/// スクリプトを実行し、期待と異なる動作をした行をエラーとして返す
+pub(crate) fn run_script(script: &str) -> Result<()> {
+    let mut runner = Runner::new();
+    for (index, line) in script.lines().enumerate() {
+        let line_number = index + 1;
+        let content = line.split('#').next().unwrap().trim();
+        if content.is_empty() {
+            continue;
+        }
+        runner
+            .step(line_number, content)
+            .with_context(|| format!("line {}: {}", line_number, content))?;
+    }
+    runner.finish()
+}
+
+impl Runner {
+    fn new() -> Self {
+        let sender = Arc::new(RecordingSender::default());
+        let clock = Arc::new(ManualClock(Mutex::new(UNIX_EPOCH + Duration::from_secs(1))));
+        let tcp = Arc::new(TCP::from_sender_and_clock(sender.clone(), clock.clone()));
+        Self {
+            tcp,
+            sender,
+            clock,
+            now: Duration::ZERO,
+            local_iss: None,
+            listening_sock: Arc::new(Mutex::new(None)),
+            connection_sock: Arc::new(Mutex::new(None)),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn step(&mut self, line: usize, content: &str) -> Result<()> {
+        let (time, rest) = content
+            .split_once(char::is_whitespace)
+            .context("missing action")?;
+        self.advance_to(parse_time(time, self.now)?);
+        match parse_action(rest.trim())? {
+            Action::Segment(segment) if segment.direction == Direction::Inbound => {
+                self.inject(&segment)
+            }
+            Action::Segment(segment) => self.expect_segment(&segment),
+            Action::Call {
+                call,
+                expect,
+                background,
+            } => {
+                let result = self.spawn_call(call);
+                let pending = PendingCall {
+                    line,
+                    expect,
+                    result,
+                };
+                if background {
+                    self.pending.push_back(pending);
+                    Ok(())
+                } else {
+                    check_call(pending)
+                }
+            }
+            Action::Join => check_call(self.pending.pop_front().context("no pending call")?),
+        }
+    }
+
+    /// 時計を進め、時刻が進んだ場合はタイマースレッドの代わりに再送処理を行う
+    fn advance_to(&mut self, time: Duration) {
+        if time <= self.now {
+            return;
+        }
+        *self.clock.0.lock().unwrap() += time - self.now;
+        self.now = time;
+        self.tcp.retransmit_expired_segments();
+    }
+
+    fn inject(&mut self, segment: &Segment) -> Result<()> {
+        let local_iss = self.local_iss.unwrap_or(0);
+        let mut builder = TCPPacketBuilder::new(REMOTE_PORT, LOCAL_PORT)
+            .seq(segment.seq)
+            .ack(segment.ack.map_or(0, |ack| ack.wrapping_add(local_iss)))
+            .flag(segment.flag)
+            .window(segment.window.unwrap_or(DEFAULT_WINDOW))
+            .payload(&vec![0; segment.len]);
+        for option in segment.options.iter().flatten() {
+            builder = builder.option(option.clone());
+        }
+        let packet = builder.build(REMOTE_ADDR, LOCAL_ADDR);
+        self.tcp
+            .handle_segment(LOCAL_ADDR, REMOTE_ADDR, packet.packet(), false)
+    }
+
+    fn expect_segment(&mut self, expected: &Segment) -> Result<()> {
+        let packet = self
+            .next_outbound(WAIT_TIMEOUT)
+            .context("expected an outbound segment but nothing was sent")?;
+        if self.local_iss.is_none() && packet.get_flag() & tcpflags::SYN > 0 {
+            self.local_iss = Some(packet.get_seq());
+        }
+        let actual = self.to_script_segment(&packet);
+        let matches = expected.flag == actual.flag
+            && expected.seq == actual.seq
+            && expected.len == actual.len
+            && (expected.ack.is_none() || expected.ack == actual.ack)
+            && (expected.window.is_none() || expected.window == actual.window)
+            && (expected.options.is_none() || expected.options == actual.options);
+        if !matches {
+            anyhow::bail!(
+                "unexpected outbound segment: {}",
+                format_segment(&actual)
+            );
+        }
+        Ok(())
+    }
+
+    /// 送信されたセグメントをスクリプトの表現(ToyTCP側のシーケンス番号は相対値)に変換する
+    fn to_script_segment(&self, packet: &TCPPacket) -> Segment {
+        Segment {
+            direction: Direction::Outbound,
+            flag: packet.get_flag(),
+            seq: packet.get_seq().wrapping_sub(self.local_iss.unwrap_or(0)),
+            len: packet.payload().len(),
+            ack: Some(packet.get_ack()),
+            window: Some(packet.get_window_size()),
+            options: Some(packet.options()),
+        }
+    }
+
+    /// 送信されたセグメントを1つ取り出す。別スレッドが送信する場合があるので少し待つ。
+    fn next_outbound(&self, timeout: Duration) -> Option<TCPPacket> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            {
+                let mut sent = self.sender.0.lock().unwrap();
+                if !sent.is_empty() {
+                    return Some(sent.remove(0).0);
+                }
+            }
+            if std::time::Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn spawn_call(&self, call: Call) -> Receiver<CallResult> {
+        let (tx, rx) = mpsc::channel();
+        let tcp = self.tcp.clone();
+        let listening_sock = self.listening_sock.clone();
+        let connection_sock = self.connection_sock.clone();
+        thread::spawn(move || {
+            let sock = |slot: &Mutex<Option<SockID>>| -> Result<SockID> {
+                (*slot.lock().unwrap()).context("no socket")
+            };
+            let result = match call {
+                Call::Listen => tcp.listen(LOCAL_ADDR, LOCAL_PORT).map(|id| {
+                    *listening_sock.lock().unwrap() = Some(id);
+                    None
+                }),
+                Call::Accept => sock(&listening_sock)
+                    .and_then(|id| tcp.accept(id))
+                    .map(|id| {
+                        *connection_sock.lock().unwrap() = Some(id);
+                        None
+                    }),
+                Call::Connect => tcp
+                    .connect_from(LOCAL_ADDR, LOCAL_PORT, REMOTE_ADDR, REMOTE_PORT)
+                    .map(|id| {
+                        *connection_sock.lock().unwrap() = Some(id);
+                        None
+                    }),
+                Call::Send(len) => sock(&connection_sock).and_then(|id| {
+                    let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                    tcp.send(id, &data).map(|_| None)
+                }),
+                Call::Recv(len) => sock(&connection_sock).and_then(|id| {
+                    let mut buffer = vec![0; len];
+                    tcp.recv(id, &mut buffer).map(Some)
+                }),
+                Call::Close => sock(&connection_sock)
+                    .or_else(|_| sock(&listening_sock))
+                    .and_then(|id| tcp.close(id))
+                    .map(|_| None),
+            };
+            let _ = tx.send(result);
+        });
+        rx
+    }
+
+    /// 全てのバックグラウンドの呼び出しが終わり、期待していないセグメントが送信されていないことを確認する
+    fn finish(mut self) -> Result<()> {
+        if let Some(pending) = self.pending.pop_front() {
+            anyhow::bail!("line {}: call was never joined", pending.line);
+        }
+        if let Some(packet) = self.next_outbound(QUIET_PERIOD) {
+            anyhow::bail!(
+                "unexpected outbound segment at end of script: {}",
+                format_segment(&self.to_script_segment(&packet))
+            );
+        }
+        Ok(())
+    }
+}
+
+fn check_call(pending: PendingCall) -> Result<()> {
+    let result = pending
+        .result
+        .recv_timeout(WAIT_TIMEOUT)
+        .with_context(|| format!("call on line {} did not return", pending.line))?;
+    match (&pending.expect, &result) {
+        (Expect::Ok, Ok(_)) | (Expect::Err, Err(_)) => Ok(()),
+        (Expect::Value(expected), Ok(Some(actual))) if expected == actual => Ok(()),
+        _ => anyhow::bail!(
+            "call on line {} returned {:?}, expected {:?}",
+            pending.line,
+            result,
+            pending.expect
+        ),
+    }
+}
+
+fn parse_time(token: &str, now: Duration) -> Result<Duration> {
+    let (relative, value) = match token.strip_prefix('+') {
+        Some(value) => (true, value),
+        None => (false, token),
+    };
+    let seconds = Duration::from_secs_f64(value.parse().context("invalid time")?);
+    Ok(if relative { now + seconds } else { seconds })
+}
+
+fn parse_action(s: &str) -> Result<Action> {
+    if s == "join" {
+        return Ok(Action::Join);
+    }
+    if let Some(rest) = s.strip_prefix('<') {
+        return Ok(Action::Segment(parse_segment(Direction::Inbound, rest.trim())?));
+    }
+    if let Some(rest) = s.strip_prefix('>') {
+        return Ok(Action::Segment(parse_segment(Direction::Outbound, rest.trim())?));
+    }
+    parse_call(s)
+}
+
+fn parse_call(s: &str) -> Result<Action> {
+    let (s, background) = match s.strip_suffix('&') {
+        Some(s) => (s.trim(), true),
+        None => (s, false),
+    };
+    let (call, expect) = match s.split_once('=') {
+        Some((call, expect)) => (call.trim(), expect.trim()),
+        None => (s, ""),
+    };
+    let expect = match expect {
+        "" => Expect::Ok,
+        "err" => Expect::Err,
+        value => Expect::Value(value.parse().context("invalid return value")?),
+    };
+    let (name, args) = call
+        .strip_suffix(')')
+        .and_then(|call| call.split_once('('))
+        .context("invalid call")?;
+    let size = || -> Result<usize> { args.trim().parse().context("invalid size") };
+    let call = match name {
+        "listen" => Call::Listen,
+        "accept" => Call::Accept,
+        "connect" => Call::Connect,
+        "send" => Call::Send(size()?),
+        "recv" => Call::Recv(size()?),
+        "close" => Call::Close,
+        _ => anyhow::bail!("unknown call: {}", name),
+    };
+    Ok(Action::Call {
+        call,
+        expect,
+        background,
+    })
+}
+
+/// `S. 0:0(0) ack 1 win 4380 <mss 1460>` の形式のセグメントを解析する
+fn parse_segment(direction: Direction, s: &str) -> Result<Segment> {
+    let (fields, options) = match s.split_once('<') {
+        Some((fields, options)) => {
+            let options = options.strip_suffix('>').context("unterminated options")?;
+            (fields, Some(parse_options(options)?))
+        }
+        None => (s, None),
+    };
+    let mut tokens = fields.split_whitespace();
+    let flag = parse_flags(tokens.next().context("missing flags")?)?;
+    let (seq, end, len) = parse_range(tokens.next().context("missing sequence range")?)?;
+    if end.wrapping_sub(seq) as usize != len {
+        anyhow::bail!("sequence range does not match length {}", len);
+    }
+    let mut segment = Segment {
+        direction,
+        flag,
+        seq,
+        len,
+        ack: None,
+        window: None,
+        options,
+    };
+    while let Some(key) = tokens.next() {
+        let value = tokens.next().context(format!("missing value for {}", key))?;
+        match key {
+            "ack" => segment.ack = Some(value.parse().context("invalid ack")?),
+            "win" => segment.window = Some(value.parse().context("invalid window")?),
+            _ => anyhow::bail!("unknown field: {}", key),
+        }
+    }
+    Ok(segment)
+}
+
+fn parse_flags(s: &str) -> Result<u8> {
+    s.chars().try_fold(0, |flag, c| {
+        Ok(flag
+            | match c {
+                'S' => tcpflags::SYN,
+                'F' => tcpflags::FIN,
+                'P' => tcpflags::PSH,
+                'R' => tcpflags::RST,
+                '.' => tcpflags::ACK,
+                'E' => tcpflags::ECE,
+                'W' => tcpflags::CWR,
+                _ => anyhow::bail!("unknown flag: {}", c),
+            })
+    })
+}
+
+/// `開始:終了(長さ)` を解析する
+fn parse_range(s: &str) -> Result<(u32, u32, usize)> {
+    let (range, len) = s
+        .strip_suffix(')')
+        .and_then(|s| s.split_once('('))
+        .context("invalid sequence range")?;
+    let (start, end) = range.split_once(':').context("invalid sequence range")?;
+    Ok((start.parse()?, end.parse()?, len.parse()?))
+}
+
+fn parse_options(s: &str) -> Result<Vec<TcpOption>> {
+    s.split(',')
+        .map(|option| {
+            let mut tokens = option.split_whitespace();
+            match (tokens.next(), tokens.next()) {
+                (Some("mss"), Some(value)) => Ok(TcpOption::Mss(value.parse()?)),
+                (Some("fo"), cookie) => Ok(TcpOption::FastOpenCookie(parse_hex(
+                    cookie.unwrap_or(""),
+                )?)),
+                _ => anyhow::bail!("unknown option: {}", option.trim()),
+            }
+        })
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = s.get(i..i + 2).context("invalid hex")?;
+            Ok(u8::from_str_radix(byte, 16)?)
+        })
+        .collect()
+}
+
+fn format_segment(segment: &Segment) -> String {
+    let mut flags = String::new();
+    for (flag, c) in [
+        (tcpflags::SYN, 'S'),
+        (tcpflags::FIN, 'F'),
+        (tcpflags::PSH, 'P'),
+        (tcpflags::RST, 'R'),
+        (tcpflags::ACK, '.'),
+        (tcpflags::ECE, 'E'),
+        (tcpflags::CWR, 'W'),
+    ] {
+        if segment.flag & flag > 0 {
+            flags.push(c);
+        }
+    }
+    format!(
+        "{} {}:{}({}) ack {} win {} {:?}",
+        flags,
+        segment.seq,
+        segment.seq.wrapping_add(segment.len as u32),
+        segment.len,
+        segment.ack.unwrap_or(0),
+        segment.window.unwrap_or(0),
+        segment.options.as_deref().unwrap_or(&[]),
+    )
+}
+
+/// tests/scripts にあるスクリプトごとにテストを生成する
+macro_rules! script_tests {
+    ($($name:ident => $file:literal,)*) => {
+        $(
+            #[test]
+            fn $name() {
+                run_script(include_str!(concat!("../tests/scripts/", $file))).unwrap();
+            }
+        )*
+    };
+}
+
+script_tests! {
+    test_passive_open => "passive_open.pkt",
+    test_active_open => "active_open.pkt",
+    test_simultaneous_open => "simultaneous_open.pkt",
+    test_simultaneous_open_ack => "simultaneous_open_ack.pkt",
+    test_receive_data => "receive_data.pkt",
+    test_send_data => "send_data.pkt",
+    test_retransmission => "retransmission.pkt",
+    test_out_of_order => "out_of_order.pkt",
+    test_duplicate_segment => "duplicate_segment.pkt",
+    test_active_close => "active_close.pkt",
+    test_passive_close => "passive_close.pkt",
+    test_simultaneous_close => "simultaneous_close.pkt",
+    test_close_retransmits_fin => "close_retransmits_fin.pkt",
+    test_close_listener => "close_listener.pkt",
+}
+
+#[test]
+fn test_runner_reports_mismatch() {
+    let script = "\
+0 listen()
+0 < S 0:0(0) win 4380 <mss 1460>
+0 > S. 0:0(0) ack 2
+";
+    let error = format!("{:#}", run_script(script).unwrap_err());
+    assert!(error.starts_with("line 3:"), "{}", error);
+}
@@ -0,0 +1,144 @@
+//! cargo-fuzz のターゲット(fuzz/fuzz_targets)から呼ぶためのエントリポイント
+//!
+//! [note] ハンドラやソケットはクレート外に公開していないので、ファジング用の処理はクレート内に置いている。
+//! 通常の利用者には不要なので、`fuzzing` featureを有効にしたときだけコンパイルする。
+use crate::packet::{TCPPacket, TCPPacketBuilder};
+use crate::socket::{SegmentSender, Socket, TcpStatus};
+use crate::tcp::TCP;
+use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
+use pnet::util;
+use std::cmp;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+const LOCAL_PORT: u16 = 40000;
+const REMOTE_PORT: u16 = 50000;
+const LOCAL_ISS: u32 = 1000;
+const REMOTE_ISS: u32 = 5000;
+
+const STATES: [TcpStatus; 9] = [
+    TcpStatus::Listen,
+    TcpStatus::SynSent,
+    TcpStatus::SynRcvd,
+    TcpStatus::Established,
+    TcpStatus::FinWait1,
+    TcpStatus::FinWait2,
+    TcpStatus::TimeWait,
+    TcpStatus::CloseWait,
+    TcpStatus::LastAck,
+];
+
+/// 送信したセグメントを捨てるだけの送信機構
+struct NullSender;
+
+impl SegmentSender for NullSender {
+    fn send_segment(
+        &self,
+        packet: &TCPPacket,
+        _remote_addr: Ipv4Addr,
+        _ect: bool,
+    ) -> io::Result<usize> {
+        Ok(packet.packet().len())
+    }
+}
+
+/// 任意のバイト列をパースし、成功した場合は組み立て直しても同じ内容になることを確かめる
+pub fn parse_segment(data: &[u8]) {
+    let packet = match TCPPacket::parse(data) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    let mut builder = TCPPacketBuilder::new(packet.get_src(), packet.get_dest())
+        .seq(packet.get_seq())
+        .ack(packet.get_ack())
+        .flag(packet.get_flag())
+        .window(packet.get_window_size())
+        .payload(packet.payload());
+    for option in packet.options() {
+        builder = builder.option(option);
+    }
+    let rebuilt = TCPPacket::parse(builder.build(LOCAL_ADDR, REMOTE_ADDR).packet())
+        .expect("rebuilt segment must be parsable");
+    assert_eq!(packet.get_src(), rebuilt.get_src());
+    assert_eq!(packet.get_dest(), rebuilt.get_dest());
+    assert_eq!(packet.get_seq(), rebuilt.get_seq());
+    assert_eq!(packet.get_ack(), rebuilt.get_ack());
+    assert_eq!(packet.get_flag(), rebuilt.get_flag());
+    assert_eq!(packet.get_window_size(), rebuilt.get_window_size());
+    assert_eq!(packet.options(), rebuilt.options());
+    assert_eq!(packet.payload(), rebuilt.payload());
+    assert!(rebuilt.is_correct_checksum(LOCAL_ADDR, REMOTE_ADDR));
+}
+
+/// 先頭1byteで選んだ状態のソケットを用意し、残りを長さ1byte+セグメントの列として順に受信させる
+///
+/// [note] ほとんどの入力がソケットの検索やチェックサムで弾かれないように、
+/// ポート番号とチェックサムはソケットに合わせて書き換えてから渡す。
+pub fn feed_state_handler(data: &[u8]) {
+    let (&selector, mut rest) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let tcp = TCP::from_sender(Arc::new(NullSender));
+    prepare_socket(&tcp, STATES[selector as usize % STATES.len()].clone());
+
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = cmp::min(len as usize, tail.len());
+        let mut segment = tail[..len].to_vec();
+        rest = &tail[len..];
+        if segment.len() >= 18 {
+            segment[0..2].copy_from_slice(&REMOTE_PORT.to_be_bytes());
+            segment[2..4].copy_from_slice(&LOCAL_PORT.to_be_bytes());
+            segment[16..18].copy_from_slice(&[0, 0]);
+            let checksum = util::ipv4_checksum(
+                &segment,
+                8,
+                &[],
+                &REMOTE_ADDR,
+                &LOCAL_ADDR,
+                IpNextHeaderProtocols::Tcp,
+            );
+            segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+        }
+        let _ = tcp.handle_segment(LOCAL_ADDR, REMOTE_ADDR, &segment, false);
+    }
+    tcp.retransmit_expired_segments();
+}
+
+fn prepare_socket(tcp: &TCP, status: TcpStatus) {
+    let sender: Arc<dyn SegmentSender> = Arc::new(NullSender);
+    if status == TcpStatus::Listen {
+        tcp.listen(LOCAL_ADDR, LOCAL_PORT).unwrap();
+        return;
+    }
+    let mut socket = Socket::new(
+        LOCAL_ADDR,
+        REMOTE_ADDR,
+        LOCAL_PORT,
+        REMOTE_PORT,
+        status.clone(),
+        sender.clone(),
+    );
+    socket.send_param.initial_seq = LOCAL_ISS;
+    socket.send_param.unacked_seq = LOCAL_ISS;
+    socket.send_param.next = LOCAL_ISS + 1;
+    if status != TcpStatus::SynSent {
+        socket.recv_param.initial_seq = REMOTE_ISS;
+        socket.recv_param.next = REMOTE_ISS + 1;
+    }
+    if status == TcpStatus::SynRcvd {
+        let listening_socket = Socket::new(
+            LOCAL_ADDR,
+            Ipv4Addr::UNSPECIFIED,
+            LOCAL_PORT,
+            0,
+            TcpStatus::Listen,
+            sender,
+        );
+        socket.listening_socket = Some(tcp.insert_socket(listening_socket));
+    }
+    tcp.insert_socket(socket);
+}
@@ -0,0 +1,199 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::net::Ipv4Addr;
+
+const ICMP_HEADER_SIZE: usize = 8;
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const TCP_PROTOCOL: u8 = 6;
+
+const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+const CODE_NETWORK_UNREACHABLE: u8 = 0;
+const CODE_HOST_UNREACHABLE: u8 = 1;
+const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+const CODE_PORT_UNREACHABLE: u8 = 3;
+const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+
+/// ICMPで通知された、コネクションを続けられなくなるエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnreachableError {
+    Network(Ipv4Addr),
+    Host(Ipv4Addr),
+    Protocol(Ipv4Addr),
+    Port(Ipv4Addr, u16),
+}
+
+impl UnreachableError {
+    /// RFC 1122 4.2.3.9 に従い、どの状態でもコネクションを中断すべきエラー(hard error)かを返す
+    ///
+    /// [note] Network/Host Unreachable は経路の一時的な問題の可能性があるので、
+    /// 接続確立前(SYNSENT)の場合のみ接続失敗として扱う。
+    pub fn is_hard(&self) -> bool {
+        matches!(
+            self,
+            UnreachableError::Protocol(_) | UnreachableError::Port(_, _)
+        )
+    }
+}
+
+impl Display for UnreachableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnreachableError::Network(addr) => write!(f, "network unreachable: {}", addr),
+            UnreachableError::Host(addr) => write!(f, "host unreachable: {}", addr),
+            UnreachableError::Protocol(addr) => write!(f, "protocol unreachable: {}", addr),
+            UnreachableError::Port(addr, port) => {
+                write!(
+                    f,
+                    "connection refused (port unreachable): {}:{}",
+                    addr, port
+                )
+            }
+        }
+    }
+}
+
+impl Error for UnreachableError {}
+
+/// ToyTCPが扱うICMPメッセージの種類
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IcmpKind {
+    /// Fragmentation Needed and DF set (Path MTU Discovery, RFC 1191)
+    FragmentationNeeded {
+        next_hop_mtu: u16,
+    },
+    Unreachable(UnreachableError),
+}
+
+/// ICMPエラーメッセージと、その原因となった(自分が送信した)TCPセグメントの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IcmpMessage {
+    pub kind: IcmpKind,
+    pub src_addr: Ipv4Addr,
+    pub dest_addr: Ipv4Addr,
+    pub src_port: u16,
+    pub dest_port: u16,
+    pub seq: u32,
+}
+
+/*
+    Destination Unreachable Message (RFC 792, RFC 1191)
+
+    0                   1                   2                   3
+    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |   Type = 3    |     Code      |          Checksum             |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |           unused = 0          |         Next-Hop MTU          |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |      Internet Header + 64 bits of Original Datagram Data      |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+*/
+
+/// ICMPメッセージ(IPヘッダを除いた部分)を解析する。
+/// TCPセグメントに対するDestination Unreachable以外はNoneを返す。
+pub fn parse(buf: &[u8]) -> Option<IcmpMessage> {
+    if buf.len() < ICMP_HEADER_SIZE || buf[0] != ICMP_DESTINATION_UNREACHABLE {
+        return None;
+    }
+    let original = &buf[ICMP_HEADER_SIZE..];
+    if original.len() < IPV4_MIN_HEADER_SIZE || original[0] >> 4 != 4 {
+        return None;
+    }
+    let ip_header_len = (original[0] & 0x0f) as usize * 4;
+    if ip_header_len < IPV4_MIN_HEADER_SIZE
+        || original[9] != TCP_PROTOCOL
+        // 元のデータグラムの先頭64bit(ポート番号とシーケンス番号)が必要
+        || original.len() < ip_header_len + 8
+    {
+        return None;
+    }
+    let src_addr = Ipv4Addr::new(original[12], original[13], original[14], original[15]);
+    let dest_addr = Ipv4Addr::new(original[16], original[17], original[18], original[19]);
+    let tcp = &original[ip_header_len..];
+    let src_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+    let dest_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+    let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+
+    let kind = match buf[1] {
+        CODE_FRAGMENTATION_NEEDED => IcmpKind::FragmentationNeeded {
+            next_hop_mtu: u16::from_be_bytes([buf[6], buf[7]]),
+        },
+        CODE_NETWORK_UNREACHABLE => IcmpKind::Unreachable(UnreachableError::Network(dest_addr)),
+        CODE_HOST_UNREACHABLE => IcmpKind::Unreachable(UnreachableError::Host(dest_addr)),
+        CODE_PROTOCOL_UNREACHABLE => IcmpKind::Unreachable(UnreachableError::Protocol(dest_addr)),
+        CODE_PORT_UNREACHABLE => {
+            IcmpKind::Unreachable(UnreachableError::Port(dest_addr, dest_port))
+        }
+        _ => return None,
+    };
+    Some(IcmpMessage {
+        kind,
+        src_addr,
+        dest_addr,
+        src_port,
+        dest_port,
+        seq,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn unreachable(code: u8, mtu: u16, seq: u32) -> Vec<u8> {
+        let mut buf = vec![ICMP_DESTINATION_UNREACHABLE, code, 0, 0, 0, 0];
+        buf.extend_from_slice(&mtu.to_be_bytes());
+        // 元のIPヘッダ (10.0.0.1 -> 10.0.1.1, TCP)
+        buf.extend_from_slice(&[0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, TCP_PROTOCOL, 0, 0]);
+        buf.extend_from_slice(&[10, 0, 0, 1, 10, 0, 1, 1]);
+        // 元のTCPヘッダの先頭8byte
+        buf.extend_from_slice(&40000u16.to_be_bytes());
+        buf.extend_from_slice(&80u16.to_be_bytes());
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf
+    }
+
+    /// 10.0.0.1:40000 から 10.0.1.1:80 に送ったシーケンス番号 seq のセグメントへの Port Unreachable
+    pub(crate) fn port_unreachable(seq: u32) -> Vec<u8> {
+        unreachable(CODE_PORT_UNREACHABLE, 0, seq)
+    }
+
+    /// 10.0.0.1:40000 から 10.0.1.1:80 に送ったシーケンス番号 seq のセグメントへの Fragmentation Needed
+    pub(crate) fn fragmentation_needed(next_hop_mtu: u16, seq: u32) -> Vec<u8> {
+        unreachable(CODE_FRAGMENTATION_NEEDED, next_hop_mtu, seq)
+    }
+
+    #[test]
+    fn test_parse_fragmentation_needed() {
+        let message = parse(&fragmentation_needed(1280, 1234)).unwrap();
+        assert_eq!(
+            IcmpMessage {
+                kind: IcmpKind::FragmentationNeeded { next_hop_mtu: 1280 },
+                src_addr: Ipv4Addr::new(10, 0, 0, 1),
+                dest_addr: Ipv4Addr::new(10, 0, 1, 1),
+                src_port: 40000,
+                dest_port: 80,
+                seq: 1234,
+            },
+            message
+        );
+    }
+
+    #[test]
+    fn test_parse_port_unreachable() {
+        let message = parse(&port_unreachable(1234)).unwrap();
+        let error = UnreachableError::Port(Ipv4Addr::new(10, 0, 1, 1), 80);
+        assert!(error.is_hard());
+        assert_eq!(IcmpKind::Unreachable(error), message.kind);
+    }
+
+    #[test]
+    fn test_parse_ignores_other_messages() {
+        // Echo Reply
+        assert_eq!(None, parse(&[0, 0, 0, 0, 0, 0, 0, 0]));
+        // 元のデータグラムが途中で切れている
+        let mut truncated = unreachable(CODE_HOST_UNREACHABLE, 0, 1234);
+        truncated.truncate(ICMP_HEADER_SIZE + IPV4_MIN_HEADER_SIZE + 4);
+        assert_eq!(None, parse(&truncated));
+    }
+}
@@ -1,4 +1,13 @@
+#[cfg(test)]
+mod conformance;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+pub mod icmp;
 mod packet;
+mod port;
+pub mod route;
 mod socket;
 pub mod tcp;
-mod tcpflags;
\ No newline at end of file
+mod tcpflags;
+pub mod udp;
@@ -1,10 +1,21 @@
 use crate::tcpflags;
-use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
+use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
 use pnet::util;
 
-use std::fmt::{self, Debug};
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
 use std::net::Ipv4Addr;
 const TCP_HEADER_SIZE: usize = 20;
+const TCP_MAX_HEADER_SIZE: usize = 60;
+
+// TCPオプションの種別 (https://www.iana.org/assignments/tcp-parameters/tcp-parameters.xhtml)
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const OPTION_SACK_PERMITTED: u8 = 4;
+const OPTION_TIMESTAMPS: u8 = 8;
+const OPTION_FAST_OPEN: u8 = 34;
 
 //
 // TCP Header Format
@@ -33,6 +44,127 @@ const TCP_HEADER_SIZE: usize = 20;
                             TCP Header Format
 */
 
+/// 受信したセグメントの解析に失敗した時のエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// 固定長ヘッダ(20byte)にも満たない
+    Truncated(usize),
+    /// Data Offsetが5(20byte)未満
+    InvalidDataOffset(u8),
+    /// Data Offsetが示すヘッダ長がセグメント長を超えている
+    HeaderOutOfRange { header_len: usize, len: usize },
+    /// オプションの長さフィールドが不正
+    MalformedOption { kind: u8 },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated(len) => write!(f, "segment too short: {} bytes", len),
+            ParseError::InvalidDataOffset(offset) => write!(f, "invalid data offset: {}", offset),
+            ParseError::HeaderOutOfRange { header_len, len } => write!(
+                f,
+                "header length {} exceeds segment length {}",
+                header_len, len
+            ),
+            ParseError::MalformedOption { kind } => write!(f, "malformed option: kind {}", kind),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// TCPヘッダのオプション
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Timestamps { value: u32, echo_reply: u32 },
+    /// TCP Fast Openのクッキー (RFC 7413)。空の場合はクッキーの要求を表す。
+    FastOpenCookie(Vec<u8>),
+    /// ToyTCPが解釈しないオプション
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    /// オプションをエンコードする。
+    /// lengthは1byteなので、データが253byteを超えるオプションはエンコードできずNoneを返す。
+    fn encode(&self) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self {
+            TcpOption::Mss(mss) => {
+                buf.extend_from_slice(&[OPTION_MSS, 4]);
+                buf.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                buf.extend_from_slice(&[OPTION_WINDOW_SCALE, 3, *shift]);
+            }
+            TcpOption::SackPermitted => buf.extend_from_slice(&[OPTION_SACK_PERMITTED, 2]),
+            TcpOption::Timestamps { value, echo_reply } => {
+                buf.extend_from_slice(&[OPTION_TIMESTAMPS, 10]);
+                buf.extend_from_slice(&value.to_be_bytes());
+                buf.extend_from_slice(&echo_reply.to_be_bytes());
+            }
+            TcpOption::FastOpenCookie(cookie) => {
+                buf.extend_from_slice(&[OPTION_FAST_OPEN, u8::try_from(cookie.len() + 2).ok()?]);
+                buf.extend_from_slice(cookie);
+            }
+            TcpOption::Unknown { kind, data } => {
+                buf.extend_from_slice(&[*kind, u8::try_from(data.len() + 2).ok()?]);
+                buf.extend_from_slice(data);
+            }
+        }
+        Some(buf)
+    }
+}
+
+/// オプション領域をパースする。END以降は無視する。
+fn parse_options(mut buf: &[u8]) -> Result<Vec<TcpOption>, ParseError> {
+    let mut options = Vec::new();
+    while let Some(&kind) = buf.first() {
+        match kind {
+            OPTION_END => break,
+            OPTION_NOP => {
+                buf = &buf[1..];
+                continue;
+            }
+            _ => {}
+        }
+        // [note] END/NOP以外は kind(1byte), length(1byte), data の形式。lengthはkindとlength自身を含む。
+        let len = *buf.get(1).ok_or(ParseError::MalformedOption { kind })? as usize;
+        if len < 2 || len > buf.len() {
+            return Err(ParseError::MalformedOption { kind });
+        }
+        let data = &buf[2..len];
+        let option = match (kind, data.len()) {
+            (OPTION_MSS, 2) => TcpOption::Mss(u16::from_be_bytes([data[0], data[1]])),
+            (OPTION_WINDOW_SCALE, 1) => TcpOption::WindowScale(data[0]),
+            (OPTION_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (OPTION_TIMESTAMPS, 8) => TcpOption::Timestamps {
+                value: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                echo_reply: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            },
+            // クッキーの長さは4〜16byteの偶数
+            (OPTION_FAST_OPEN, len) if len == 0 || ((4..=16).contains(&len) && len % 2 == 0) => {
+                TcpOption::FastOpenCookie(data.to_vec())
+            }
+            (OPTION_MSS, _)
+            | (OPTION_FAST_OPEN, _)
+            | (OPTION_WINDOW_SCALE, _)
+            | (OPTION_SACK_PERMITTED, _)
+            | (OPTION_TIMESTAMPS, _) => return Err(ParseError::MalformedOption { kind }),
+            _ => TcpOption::Unknown {
+                kind,
+                data: data.to_vec(),
+            },
+        };
+        options.push(option);
+        buf = &buf[len..];
+    }
+    Ok(options)
+}
+
 #[derive(Clone)]
 pub struct TCPPacket {
     buffer: Vec<u8>,
@@ -45,6 +177,31 @@ impl TCPPacket {
         }
     }
 
+    /// ネットワークから受け取ったバイト列を検証してTCPPacketに変換する
+    ///
+    /// [note] ヘッダ長・Data Offset・オプションを検証しておくことで、以降のgetterが
+    /// 不正なセグメントでpanicすることはなくなる。
+    pub fn parse(buf: &[u8]) -> Result<Self, ParseError> {
+        if buf.len() < TCP_HEADER_SIZE {
+            return Err(ParseError::Truncated(buf.len()));
+        }
+        let data_offset = buf[12] >> 4;
+        let header_len = data_offset as usize * 4;
+        if header_len < TCP_HEADER_SIZE {
+            return Err(ParseError::InvalidDataOffset(data_offset));
+        }
+        if header_len > buf.len() {
+            return Err(ParseError::HeaderOutOfRange {
+                header_len,
+                len: buf.len(),
+            });
+        }
+        parse_options(&buf[TCP_HEADER_SIZE..header_len])?;
+        Ok(Self {
+            buffer: buf.to_vec(),
+        })
+    }
+
     pub fn get_src(&self) -> u16 {
         u16::from_be_bytes([self.buffer[0], self.buffer[1]])
     }
@@ -71,6 +228,15 @@ impl TCPPacket {
         ])
     }
 
+    pub fn get_data_offset(&self) -> u8 {
+        self.buffer[12] >> 4
+    }
+
+    /// オプションを含むヘッダ長
+    pub fn header_len(&self) -> usize {
+        checked_header_len(self.get_data_offset(), self.buffer.len())
+    }
+
     pub fn get_flag(&self) -> u8 {
         self.buffer[13]
     }
@@ -116,14 +282,35 @@ impl TCPPacket {
     }
 
     pub fn set_payload(&mut self, payload: &[u8]) {
-        self.buffer[TCP_HEADER_SIZE..TCP_HEADER_SIZE + payload.len() as usize]
-            .copy_from_slice(payload)
+        let header_len = self.header_len();
+        self.buffer[header_len..header_len + payload.len()].copy_from_slice(payload)
+    }
+
+    /// ヘッダのオプションを返す。parse済みのセグメントであれば必ず解釈できる。
+    pub fn options(&self) -> Vec<TcpOption> {
+        parse_options(&self.buffer[TCP_HEADER_SIZE..self.header_len()]).unwrap_or_default()
+    }
+
+    /// MSSオプションの値を返す
+    pub fn get_mss(&self) -> Option<u16> {
+        self.options().into_iter().find_map(|option| match option {
+            TcpOption::Mss(mss) => Some(mss),
+            _ => None,
+        })
+    }
+
+    /// TCP Fast Openのクッキーオプションの値を返す
+    pub fn get_fast_open_cookie(&self) -> Option<Vec<u8>> {
+        self.options().into_iter().find_map(|option| match option {
+            TcpOption::FastOpenCookie(cookie) => Some(cookie),
+            _ => None,
+        })
     }
 
     pub fn is_correct_checksum(&self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> bool {
         self.get_checksum()
             == util::ipv4_checksum(
-                &self.packet(),
+                self.packet(),
                 8,
                 &[],
                 &local_addr,
@@ -142,7 +329,7 @@ impl Packet for TCPPacket {
 
     /// Retrieve the payload for the packet.
     fn payload(&self) -> &[u8] {
-        &self.buffer[TCP_HEADER_SIZE..]
+        &self.buffer[self.header_len()..]
     }
 }
 
@@ -162,12 +349,235 @@ impl Debug for TCPPacket {
     }
 }
 
-// [note] pnet から来るTcpPacketをToyTCPのTCPPacketに変換するために必要。
-// ↑ pnet側へはIPレイヤー以下を任せているはずだが、TCPパケット解析部分はpnetでもやってしまっているようだ。
-impl<'a> From<TcpPacket<'a>> for TCPPacket {
-    fn from(packet: TcpPacket<'a>) -> Self {
-        Self { 
-            buffer: packet.packet().to_vec(),
+/// Data Offsetからヘッダ長を求める。new直後などData Offset未設定の場合は固定長ヘッダとみなす。
+fn checked_header_len(data_offset: u8, len: usize) -> usize {
+    let header_len = data_offset as usize * 4;
+    if header_len < TCP_HEADER_SIZE || header_len > len {
+        TCP_HEADER_SIZE
+    } else {
+        header_len
+    }
+}
+
+/// 送信するセグメントを組み立てるビルダー
+///
+/// [note] オプションを付ける場合はData Offsetやパディングの計算が必要になるので、
+/// TCPPacket::new と各setterを直接使う代わりにこちらを使う。
+#[derive(Debug, Clone, Default)]
+pub struct TCPPacketBuilder {
+    src: u16,
+    dest: u16,
+    seq: u32,
+    ack: u32,
+    flag: u8,
+    window: u16,
+    options: Vec<TcpOption>,
+    payload: Vec<u8>,
+}
+
+impl TCPPacketBuilder {
+    pub fn new(src: u16, dest: u16) -> Self {
+        Self {
+            src,
+            dest,
+            ..Default::default()
         }
     }
-}
\ No newline at end of file
+
+    pub fn seq(mut self, seq: u32) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    pub fn ack(mut self, ack: u32) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    pub fn flag(mut self, flag: u8) -> Self {
+        self.flag = flag;
+        self
+    }
+
+    pub fn window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn option(mut self, option: TcpOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// セグメントを組み立て、擬似ヘッダを含めたチェックサムを計算して返す
+    ///
+    /// オプションが40byteを超える場合は入り切る分だけ詰める。
+    /// エンコードできない長さのオプションも入り切らないものとして扱う。
+    pub fn build(self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> TCPPacket {
+        let mut options = Vec::new();
+        for option in &self.options {
+            let encoded = match option.encode() {
+                Some(encoded)
+                    if TCP_HEADER_SIZE + options.len() + encoded.len() <= TCP_MAX_HEADER_SIZE =>
+                {
+                    encoded
+                }
+                _ => break,
+            };
+            options.extend(encoded);
+        }
+        // ヘッダ長は4byteの倍数である必要があるのでENDでパディングする
+        while options.len() % 4 != 0 {
+            options.push(OPTION_END);
+        }
+        let header_len = TCP_HEADER_SIZE + options.len();
+
+        let mut packet = TCPPacket::new(options.len() + self.payload.len());
+        packet.set_src(self.src);
+        packet.set_dest(self.dest);
+        packet.set_seq(self.seq);
+        packet.set_ack(self.ack);
+        packet.set_data_offset((header_len / 4) as u8);
+        packet.set_flag(self.flag);
+        packet.set_window_size(self.window);
+        packet.buffer[TCP_HEADER_SIZE..header_len].copy_from_slice(&options);
+        packet.set_payload(&self.payload);
+        packet.set_checksum(util::ipv4_checksum(
+            packet.packet(),
+            8,
+            &[],
+            &local_addr,
+            &remote_addr,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+    #[test]
+    fn test_build_and_parse() {
+        let packet = TCPPacketBuilder::new(40000, 80)
+            .seq(100)
+            .ack(200)
+            .flag(tcpflags::SYN | tcpflags::ACK)
+            .window(4380)
+            .option(TcpOption::Mss(1460))
+            .option(TcpOption::WindowScale(7))
+            .payload(b"hello")
+            .build(LOCAL, REMOTE);
+        assert_eq!(28, packet.header_len());
+
+        let parsed = TCPPacket::parse(packet.packet()).unwrap();
+        assert_eq!(40000, parsed.get_src());
+        assert_eq!(80, parsed.get_dest());
+        assert_eq!(100, parsed.get_seq());
+        assert_eq!(200, parsed.get_ack());
+        assert_eq!(tcpflags::SYN | tcpflags::ACK, parsed.get_flag());
+        assert_eq!(4380, parsed.get_window_size());
+        assert_eq!(
+            vec![TcpOption::Mss(1460), TcpOption::WindowScale(7)],
+            parsed.options()
+        );
+        assert_eq!(Some(1460), parsed.get_mss());
+        assert_eq!(b"hello", parsed.payload());
+        assert!(parsed.is_correct_checksum(LOCAL, REMOTE));
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_header() {
+        assert_eq!(
+            Err(ParseError::Truncated(19)),
+            TCPPacket::parse(&[0; 19]).map(|_| ())
+        );
+
+        let mut buf = TCPPacketBuilder::new(1, 2).build(LOCAL, REMOTE).packet().to_vec();
+        buf[12] = 4 << 4;
+        assert_eq!(
+            Err(ParseError::InvalidDataOffset(4)),
+            TCPPacket::parse(&buf).map(|_| ())
+        );
+        buf[12] = 6 << 4;
+        assert_eq!(
+            Err(ParseError::HeaderOutOfRange {
+                header_len: 24,
+                len: 20
+            }),
+            TCPPacket::parse(&buf).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_options() {
+        let mut buf = TCPPacketBuilder::new(1, 2)
+            .option(TcpOption::Mss(1460))
+            .build(LOCAL, REMOTE)
+            .packet()
+            .to_vec();
+        buf[21] = 0; // MSSの長さを0にする
+        assert_eq!(
+            Err(ParseError::MalformedOption { kind: OPTION_MSS }),
+            TCPPacket::parse(&buf).map(|_| ())
+        );
+        buf[21] = 3; // MSSは長さ4固定
+        assert_eq!(
+            Err(ParseError::MalformedOption { kind: OPTION_MSS }),
+            TCPPacket::parse(&buf).map(|_| ())
+        );
+        buf[21] = 8; // オプション領域をはみ出す
+        assert_eq!(
+            Err(ParseError::MalformedOption { kind: OPTION_MSS }),
+            TCPPacket::parse(&buf).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_payload_skips_options() {
+        let packet = TCPPacketBuilder::new(1, 2)
+            .option(TcpOption::SackPermitted)
+            .option(TcpOption::Timestamps {
+                value: 1,
+                echo_reply: 0,
+            })
+            .option(TcpOption::Unknown {
+                kind: 30,
+                data: vec![1, 2, 3],
+            })
+            .option(TcpOption::FastOpenCookie(vec![1; 8]))
+            .payload(&[9; 3])
+            .build(LOCAL, REMOTE);
+        let parsed = TCPPacket::parse(packet.packet()).unwrap();
+        assert_eq!(4, parsed.options().len());
+        assert_eq!(Some(vec![1; 8]), parsed.get_fast_open_cookie());
+        assert_eq!(&[9; 3], parsed.payload());
+    }
+
+    #[test]
+    fn test_build_skips_oversized_options() {
+        // lengthの1byteに収まらないオプションはエンコードしない
+        let oversized = TcpOption::Unknown {
+            kind: 30,
+            data: vec![0; 254],
+        };
+        assert_eq!(None, oversized.encode());
+        assert_eq!(None, TcpOption::FastOpenCookie(vec![0; 300]).encode());
+        let packet = TCPPacketBuilder::new(1, 2)
+            .option(oversized)
+            .payload(&[9; 3])
+            .build(LOCAL, REMOTE);
+        let parsed = TCPPacket::parse(packet.packet()).unwrap();
+        assert!(parsed.options().is_empty());
+        assert_eq!(&[9; 3], parsed.payload());
+    }
+}
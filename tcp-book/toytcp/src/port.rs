@@ -0,0 +1,201 @@
+//! ローカルポートの予約とエフェメラルポートの割り当て
+use anyhow::Result;
+use rand::Rng;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+
+pub const EPHEMERAL_PORTS: Range<u16> = 40000..60000;
+
+/// あるアドレス・ポートを使っているソケットの情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    users: usize,    // 予約しているソケットの数。reuse_addrの場合は複数になりうる
+    reuse: bool,     // 全ての利用者がreuse_addrを指定している
+    listening: bool, // リスニングソケットが使っている
+}
+
+/// ローカルアドレスごとに使用中のポートを管理する
+///
+/// [note] ポートが使用中かどうかはアドレスごとのHashMapで O(1) で確認できる。
+/// 0.0.0.0 に予約したポートは全てのアドレスで使用中として扱う。
+pub struct PortAllocator {
+    bound: HashMap<Ipv4Addr, HashMap<u16, Usage>>,
+    next_ephemeral: u32,
+    secret: RandomState,
+}
+
+impl Default for PortAllocator {
+    fn default() -> Self {
+        Self {
+            bound: HashMap::new(),
+            next_ephemeral: rand::thread_rng().gen(),
+            secret: RandomState::new(),
+        }
+    }
+}
+
+impl PortAllocator {
+    fn usage(&self, addr: Ipv4Addr, port: u16) -> Option<&Usage> {
+        self.bound.get(&addr).and_then(|ports| ports.get(&port))
+    }
+
+    /// 指定のアドレス・ポートを新たに予約できるかを返す
+    ///
+    /// [note] SO_REUSEADDR と同じく、双方がreuseを指定していて、どちらもリスニングソケットで
+    /// なければ同じポートを共有できる(接続先が異なればコネクションは区別できる)。
+    fn is_available(&self, addr: Ipv4Addr, port: u16, reuse: bool, listening: bool) -> bool {
+        let shareable = |usage: &Usage| reuse && usage.reuse && !listening && !usage.listening;
+        if addr.is_unspecified() {
+            // 0.0.0.0 への予約は全てのアドレスの予約と衝突する
+            return self
+                .bound
+                .values()
+                .filter_map(|ports| ports.get(&port))
+                .all(shareable);
+        }
+        [addr, Ipv4Addr::UNSPECIFIED]
+            .iter()
+            .filter_map(|&addr| self.usage(addr, port))
+            .all(shareable)
+    }
+
+    /// 指定のアドレス・ポートを予約する
+    pub fn bind(&mut self, addr: Ipv4Addr, port: u16, reuse: bool, listening: bool) -> Result<()> {
+        if !self.is_available(addr, port, reuse, listening) {
+            anyhow::bail!("address already in use: {}:{}", addr, port);
+        }
+        let usage = self
+            .bound
+            .entry(addr)
+            .or_default()
+            .entry(port)
+            .or_insert(Usage {
+                users: 0,
+                reuse,
+                listening,
+            });
+        usage.users += 1;
+        usage.reuse &= reuse;
+        usage.listening |= listening;
+        Ok(())
+    }
+
+    /// 予約を1つ解放する
+    pub fn release(&mut self, addr: Ipv4Addr, port: u16) {
+        let Some(ports) = self.bound.get_mut(&addr) else {
+            return;
+        };
+        if let Some(usage) = ports.get_mut(&port) {
+            usage.users -= 1;
+            if usage.users == 0 {
+                ports.remove(&port);
+            }
+        }
+        if ports.is_empty() {
+            self.bound.remove(&addr);
+        }
+    }
+
+    pub fn is_bound(&self, addr: Ipv4Addr, port: u16) -> bool {
+        self.usage(addr, port).is_some()
+    }
+
+    /// エフェメラルポートを割り当てて予約する
+    ///
+    /// [note] RFC 6056 3.3.3 Algorithm 3 (Simple Hash-Based Port Selection)。
+    /// 接続先ごとに秘密の鍵を使ったハッシュで開始位置(offset)を決め、そこから順に空きポートを探す。
+    /// 接続先ごとの開始位置は推測できないが、同じ接続先へのポートは順に使われるので再利用までの間隔が長くなる。
+    pub fn allocate(
+        &mut self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        remote_port: u16,
+    ) -> Result<u16> {
+        let num_ephemeral = EPHEMERAL_PORTS.len() as u32;
+        let offset = self.secret.hash_one((local_addr, remote_addr, remote_port)) as u32;
+        for _ in 0..num_ephemeral {
+            let port = EPHEMERAL_PORTS.start
+                + (offset.wrapping_add(self.next_ephemeral) % num_ephemeral) as u16;
+            self.next_ephemeral = self.next_ephemeral.wrapping_add(1);
+            // エフェメラルポートはreuse_addrでも共有しない
+            if self.is_available(local_addr, port, false, false) {
+                self.bind(local_addr, port, false, false)?;
+                return Ok(port);
+            }
+        }
+        anyhow::bail!("no available port found: {}", local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const OTHER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+    #[test]
+    fn test_bind_conflicts_per_address() {
+        let mut ports = PortAllocator::default();
+        ports.bind(ADDR, 8080, false, true).unwrap();
+        assert!(ports.bind(ADDR, 8080, false, false).is_err());
+        // 別のアドレスなら同じポートを使える
+        ports.bind(OTHER_ADDR, 8080, false, false).unwrap();
+        // 0.0.0.0 は全てのアドレスと衝突する
+        assert!(ports.bind(Ipv4Addr::UNSPECIFIED, 8080, false, false).is_err());
+        ports.bind(Ipv4Addr::UNSPECIFIED, 9090, false, false).unwrap();
+        assert!(ports.bind(ADDR, 9090, false, false).is_err());
+
+        ports.release(ADDR, 8080);
+        ports.release(OTHER_ADDR, 8080);
+        ports.bind(Ipv4Addr::UNSPECIFIED, 8080, false, false).unwrap();
+    }
+
+    #[test]
+    fn test_bind_reuse_addr() {
+        let mut ports = PortAllocator::default();
+        ports.bind(ADDR, 8080, true, false).unwrap();
+        ports.bind(ADDR, 8080, true, false).unwrap();
+        // どちらかがreuseを指定していない場合やリスニングソケットとは共有できない
+        assert!(ports.bind(ADDR, 8080, false, false).is_err());
+        assert!(ports.bind(ADDR, 8080, true, true).is_err());
+
+        ports.release(ADDR, 8080);
+        assert!(ports.is_bound(ADDR, 8080));
+        ports.release(ADDR, 8080);
+        assert!(!ports.is_bound(ADDR, 8080));
+    }
+
+    #[test]
+    fn test_allocate_sequential_per_destination() {
+        let mut ports = PortAllocator::default();
+        let first = ports.allocate(ADDR, REMOTE_ADDR, 80).unwrap();
+        let second = ports.allocate(ADDR, REMOTE_ADDR, 80).unwrap();
+        assert!(EPHEMERAL_PORTS.contains(&first));
+        assert_eq!(
+            (first - EPHEMERAL_PORTS.start + 1) % EPHEMERAL_PORTS.len() as u16,
+            second - EPHEMERAL_PORTS.start
+        );
+
+        // 使用中のポートは飛ばす
+        let next = EPHEMERAL_PORTS.start
+            + (second - EPHEMERAL_PORTS.start + 1) % EPHEMERAL_PORTS.len() as u16;
+        ports.bind(ADDR, next, false, true).unwrap();
+        let third = ports.allocate(ADDR, REMOTE_ADDR, 80).unwrap();
+        assert_ne!(next, third);
+        assert!(ports.is_bound(ADDR, third));
+    }
+
+    #[test]
+    fn test_allocate_exhausted() {
+        let mut ports = PortAllocator::default();
+        for port in EPHEMERAL_PORTS {
+            ports.bind(Ipv4Addr::UNSPECIFIED, port, false, false).unwrap();
+        }
+        assert!(ports.allocate(ADDR, REMOTE_ADDR, 80).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use pnet::datalink::{self, NetworkInterface};
+use pnet::ipnetwork::{IpNetwork, Ipv4Network};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+
+const PROC_NET_ROUTE: &str = "/proc/net/route";
+const RTF_UP: u16 = 0x0001;
+
+/// 経路探索に失敗した時のエラー
+#[derive(Debug)]
+pub enum RouteError {
+    /// 宛先へ到達する経路がルーティングテーブルに存在しない
+    NoRoute(Ipv4Addr),
+    /// 経路は見つかったが出力インタフェースにIPv4アドレスが付与されていない
+    NoSourceAddr { dest: Ipv4Addr, interface: String },
+    /// /proc/net/route の読み込みに失敗した
+    Io(io::Error),
+}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::NoRoute(dest) => write!(f, "no route to host: {}", dest),
+            RouteError::NoSourceAddr { dest, interface } => write!(
+                f,
+                "no ipv4 address on interface {} to reach {}",
+                interface, dest
+            ),
+            RouteError::Io(e) => write!(f, "failed to read {}: {}", PROC_NET_ROUTE, e),
+        }
+    }
+}
+
+impl Error for RouteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RouteError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RouteError {
+    fn from(e: io::Error) -> Self {
+        RouteError::Io(e)
+    }
+}
+
+/// /proc/net/route の1行分の経路エントリ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub interface: String,
+    pub destination: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub metric: u32,
+}
+
+impl RouteEntry {
+    fn prefix_len(&self) -> u32 {
+        u32::from(self.mask).count_ones()
+    }
+
+    fn matches(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & u32::from(self.mask) == u32::from(self.destination)
+    }
+}
+
+/// /proc/net/route の内容をパースして有効(RTF_UP)な経路の一覧を返す
+///
+/// [note] 各アドレスはネットワークバイトオーダーのバイト列をホストのu32として16進表記したもの。
+/// そのため to_ne_bytes でメモリ上の並びに戻せばそのままオクテット列になる。
+///
+/// Iface   Destination Gateway  Flags RefCnt Use Metric Mask     MTU Window IRTT
+/// eth0    00000000    0100A8C0 0003  0      0   100    00000000 0   0      0
+pub fn parse_route_table(contents: &str) -> Vec<RouteEntry> {
+    contents
+        .lines()
+        .skip(1) // ヘッダ行
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                return None;
+            }
+            let flags = u16::from_str_radix(fields[3], 16).ok()?;
+            if flags & RTF_UP == 0 {
+                return None;
+            }
+            Some(RouteEntry {
+                interface: fields[0].to_string(),
+                destination: parse_hex_addr(fields[1])?,
+                gateway: parse_hex_addr(fields[2])?,
+                mask: parse_hex_addr(fields[7])?,
+                metric: fields[6].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+fn parse_hex_addr(s: &str) -> Option<Ipv4Addr> {
+    let raw = u32::from_str_radix(s, 16).ok()?;
+    Some(Ipv4Addr::from(raw.to_ne_bytes()))
+}
+
+/// 宛先に対して最長一致(同じ長さならメトリックが小さい方)の経路を選ぶ
+pub fn lookup_route(routes: &[RouteEntry], dest: Ipv4Addr) -> Option<&RouteEntry> {
+    routes.iter().filter(|r| r.matches(dest)).max_by(|a, b| {
+        a.prefix_len()
+            .cmp(&b.prefix_len())
+            .then(b.metric.cmp(&a.metric))
+    })
+}
+
+/// 宛先IPアドレスに対する送信元インタフェースのIPアドレスを取得する
+///
+/// [note] 以前は `ip route get` コマンドの出力を解析していたが、iproute2のバージョンや
+/// コマンドが存在しない最小構成のコンテナで動かなくなるため、/proc/net/route と
+/// インタフェースに付与されたアドレスからカーネルと同じように送信元アドレスを選択する。
+pub fn get_source_addr_to(dest: Ipv4Addr) -> Result<Ipv4Addr, RouteError> {
+    let interfaces = datalink::interfaces();
+
+    // 自ホストのアドレス宛てならそのアドレス自身を使う(localテーブルは/proc/net/routeに現れない)
+    if interface_addrs(&interfaces).any(|net| net.ip() == dest) {
+        return Ok(dest);
+    }
+    if dest.is_loopback() {
+        return interfaces
+            .iter()
+            .filter(|iface| iface.is_loopback())
+            .flat_map(ipv4_networks)
+            .map(|net| net.ip())
+            .next()
+            .ok_or(RouteError::NoRoute(dest));
+    }
+
+    let routes = parse_route_table(&fs::read_to_string(PROC_NET_ROUTE)?);
+    let route = lookup_route(&routes, dest).ok_or(RouteError::NoRoute(dest))?;
+    let iface = interfaces
+        .iter()
+        .find(|iface| iface.name == route.interface)
+        .ok_or_else(|| RouteError::NoSourceAddr {
+            dest,
+            interface: route.interface.clone(),
+        })?;
+
+    // 直接接続なら宛先、ゲートウェイ経由ならゲートウェイと同じサブネットのアドレスを優先する
+    let next_hop = if route.gateway.is_unspecified() {
+        dest
+    } else {
+        route.gateway
+    };
+    let addrs: Vec<_> = ipv4_networks(iface).collect();
+    let src = addrs
+        .iter()
+        .find(|net| net.contains(next_hop))
+        .or_else(|| addrs.first())
+        .map(|net| net.ip())
+        .ok_or_else(|| RouteError::NoSourceAddr {
+            dest,
+            interface: route.interface.clone(),
+        })?;
+    dbg!("source addr", src);
+    Ok(src)
+}
+
+fn ipv4_networks(iface: &NetworkInterface) -> impl Iterator<Item = Ipv4Network> + '_ {
+    iface.ips.iter().filter_map(|ip| match ip {
+        IpNetwork::V4(net) => Some(*net),
+        IpNetwork::V6(_) => None,
+    })
+}
+
+fn interface_addrs(interfaces: &[NetworkInterface]) -> impl Iterator<Item = Ipv4Network> + '_ {
+    interfaces.iter().flat_map(ipv4_networks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTE_TABLE: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+veth1\t0000000A\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+veth1\t0001000A\t0100000A\t0003\t0\t0\t0\t00FFFFFF\t0\t0\t0
+down0\t0002000A\t00000000\t0000\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+
+    #[test]
+    fn test_parse_route_table() {
+        let routes = parse_route_table(ROUTE_TABLE);
+        assert_eq!(4, routes.len());
+        assert_eq!(
+            RouteEntry {
+                interface: "eth0".to_string(),
+                destination: Ipv4Addr::new(0, 0, 0, 0),
+                gateway: Ipv4Addr::new(192, 168, 0, 1),
+                mask: Ipv4Addr::new(0, 0, 0, 0),
+                metric: 100,
+            },
+            routes[0]
+        );
+        assert_eq!(Ipv4Addr::new(255, 255, 255, 0), routes[1].mask);
+    }
+
+    #[test]
+    fn test_lookup_route_longest_prefix() {
+        let routes = parse_route_table(ROUTE_TABLE);
+        let route = lookup_route(&routes, Ipv4Addr::new(10, 0, 1, 1)).unwrap();
+        assert_eq!("veth1", route.interface);
+        assert_eq!(Ipv4Addr::new(10, 0, 0, 1), route.gateway);
+        let route = lookup_route(&routes, Ipv4Addr::new(8, 8, 8, 8)).unwrap();
+        assert_eq!("eth0", route.interface);
+        // RTF_UPが立っていない経路は使わない
+        let route = lookup_route(&routes, Ipv4Addr::new(10, 0, 2, 1)).unwrap();
+        assert_eq!(Ipv4Addr::new(0, 0, 0, 0), route.destination);
+    }
+
+    #[test]
+    fn test_lookup_route_no_default() {
+        let routes = parse_route_table(ROUTE_TABLE)
+            .into_iter()
+            .filter(|r| !r.destination.is_unspecified())
+            .collect::<Vec<_>>();
+        assert_eq!(None, lookup_route(&routes, Ipv4Addr::new(8, 8, 8, 8)));
+    }
+}
@@ -1,15 +1,26 @@
-use crate::packet::TCPPacket;
+use crate::icmp::UnreachableError;
+use crate::packet::{TCPPacket, TCPPacketBuilder, TcpOption};
 use crate::tcpflags;
 use anyhow::{Context, Result, Ok};
 use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
 use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
-use pnet::util;
+use std::cmp;
 use std::collections::VecDeque;
 use std::fmt::{self, Display};
+use std::io;
 use std::net::{IpAddr, Ipv4Addr};
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const SOCKET_BUFFER_SIZE: usize = 4380;
+const INITIAL_CWND: u32 = 14600; // 初期輻輳ウィンドウ (RFC 6928)
+const ECT0: libc::c_int = 0x02; // IPヘッダのECNフィールド ECT(0) (RFC 3168)
+pub const MSS: usize = 1460; // 自分が受け取れるMSS(SYNで広告する値)であり、送信時の初期値
+const IP_TCP_HEADER_SIZE: usize = 40;
+const MIN_PATH_MTU: usize = 68; // RFC 791 で全てのホストが扱えるとされるMTU
+const MAX_RTT_SAMPLES: usize = 1 << 16; // 記録するRTTの最大数。長時間の計測でメモリを使い切らないようにする
+// Next-Hop MTUを通知しない古いルータ向けのMTUの候補 (RFC 1191 Section 7)
+const MTU_PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
 pub struct SockID(pub Ipv4Addr, pub Ipv4Addr, pub u16, pub u16);
@@ -25,6 +36,17 @@ pub struct Socket {
     pub recv_param: RecvParam,
     pub status: TcpStatus,
 
+    // 送信するセグメントのペイロードの最大長。相手のMSSオプションとPath MTU Discoveryで小さくなる。
+    pub mss: usize,
+    // ICMPで通知された接続エラー。connect/sendの呼び出し元に返す。
+    pub error: Option<UnreachableError>,
+    pub ecn: EcnState,
+    pub stats: SocketStats,
+
+    // TCP Fast Open (RFC 7413)
+    pub syn_data: Vec<u8>,      // SYNに載せて送ったデータ。相手が受け取らなかった場合は確立後に送り直す(クライアントのみ)
+    pub accepted_on_syn: bool,  // SYNのデータを受け入れ、ハンドシェイク完了前にacceptへ渡した(サーバのみ)
+
     // Section 3.8.1 受信バッファ
     pub recv_buffer: Vec<u8>, // [note] 受信したデータを一度にすべて処理しようとすると問題が生じるので通常ソケットは受信バッファを持つ
 
@@ -35,7 +57,89 @@ pub struct Socket {
     pub connected_connection_queue: VecDeque<SockID>, // 接続済みソケットを保持するキュー．リスニングソケットのみ使用
     pub listening_socket: Option<SockID>, // 生成元のリスニングソケット．接続済みソケットのみ使用
 
-    pub sender: TransportSender, // 送信機構
+    pub sender: Arc<dyn SegmentSender>, // 送信機構
+    pub clock: Arc<dyn Clock>,          // 再送タイマーが参照する時計
+}
+
+/// セグメントをネットワークへ送り出す送信機構
+///
+/// [note] 通常はrawソケット(RawSender)を使うが、テストやファジングではrawソケットを
+/// 開かずに送信されたセグメントを記録するだけの実装に差し替えられるようにしている。
+pub trait SegmentSender: Send + Sync {
+    /// ectがtrueの場合はIPヘッダのECNフィールドにECT(0)を付けて送信する
+    fn send_segment(&self, packet: &TCPPacket, remote_addr: Ipv4Addr, ect: bool)
+        -> io::Result<usize>;
+}
+
+/// 再送タイマーが参照する現在時刻の取得元
+///
+/// [note] 通常はシステム時刻(SystemClock)を使うが、スクリプトによるテストでは
+/// 実際に待たずに時間を進められる時計に差し替える。
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// rawソケット(IPレイヤより上をToyTCPが作る)による送信機構。全ソケットで共有する。
+///
+/// [note] Layer4のrawソケットではIPヘッダはカーネルが作るので、ECT(0)を付けるかどうかは
+/// ソケットのIP_TOSで決まる。そのためIP_TOSを設定したソケットと設定していないソケットを使い分ける。
+pub struct RawSender {
+    plain: Mutex<TransportSender>,
+    ect: Mutex<TransportSender>,
+}
+
+impl RawSender {
+    pub fn new() -> Result<Self> {
+        let plain = Self::open()?;
+        let ect = Self::open()?;
+        let ret = unsafe {
+            libc::setsockopt(
+                ect.socket.fd,
+                libc::IPPROTO_IP,
+                libc::IP_TOS,
+                &ECT0 as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error()).context("failed to set IP_TOS");
+        }
+        Ok(Self {
+            plain: Mutex::new(plain),
+            ect: Mutex::new(ect),
+        })
+    }
+
+    fn open() -> Result<TransportSender> {
+        let (sender, _) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
+        )?;
+        Ok(sender)
+    }
+}
+
+impl SegmentSender for RawSender {
+    fn send_segment(
+        &self,
+        packet: &TCPPacket,
+        remote_addr: Ipv4Addr,
+        ect: bool,
+    ) -> io::Result<usize> {
+        let sender = if ect { &self.ect } else { &self.plain };
+        sender
+            .lock()
+            .unwrap()
+            .send_to(packet.clone(), IpAddr::V4(remote_addr))
+    }
 }
 
 /*
@@ -111,6 +215,38 @@ pub struct SendParam {
     pub next: u32,        // 次の送信(予定)
     pub window: u16,      // 送信ウィンドウ [note] 送信先が適量のデータを受け取れるように制御するためのウィンドウ
     pub initial_seq: u32, // 初期送信seq
+    pub cwnd: u32,        // 輻輳ウィンドウ [note] ネットワークが混雑しないように送信側が自分で制限するウィンドウ
+    pub ssthresh: u32,    // スロースタートから輻輳回避に切り替える閾値
+}
+
+/// ECN (Explicit Congestion Notification, RFC 3168) の状態
+#[derive(Clone, Debug, Default)]
+pub struct EcnState {
+    pub enabled: bool,     // SYNのやり取りでECNが使えることを合意できた
+    pub ece_pending: bool, // CEマークを受け取ったので、CWRが来るまでACKにECEを付ける(受信側)
+    pub cwr_pending: bool, // ECEに応じてcwndを下げたので、次のデータセグメントにCWRを付ける(送信側)
+    pub recover: u32,      // cwndを下げた時点のsend_param.next。ここまでACKされるまでは再度下げない
+}
+
+/// コネクションの送信に関する統計情報。性能の計測に使う。
+#[derive(Clone, Debug, Default)]
+pub struct SocketStats {
+    pub segments_sent: u64,         // 送信したセグメント数(再送を除く)
+    pub retransmissions: u64,       // 再送したセグメント数
+    pub rtt_samples: Vec<Duration>, // 再送していないセグメントのRTT(Karnのアルゴリズム)。最初のMAX_RTT_SAMPLES個
+}
+
+impl SocketStats {
+    /// 確認応答されたセグメントの送信時刻からRTTを記録する
+    pub fn record_rtt(&mut self, sent: SystemTime, now: SystemTime) {
+        // [note] Path MTU Discoveryで分割し直したセグメントは送信時刻がUNIX_EPOCHになっているので除く
+        if sent == UNIX_EPOCH || self.rtt_samples.len() >= MAX_RTT_SAMPLES {
+            return;
+        }
+        if let std::result::Result::Ok(rtt) = now.duration_since(sent) {
+            self.rtt_samples.push(rtt);
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -143,10 +279,10 @@ pub struct RetransmissionQueueEntry {
 }
 
 impl RetransmissionQueueEntry {
-    fn new(packet: TCPPacket) -> Self {
+    fn new(packet: TCPPacket, now: SystemTime) -> Self {
         Self {
             packet,
-            latest_transmission_time: SystemTime::now(),
+            latest_transmission_time: now,
             transmission_count: 1,
         }
     }
@@ -175,11 +311,9 @@ impl Socket {
         local_port: u16,
         remote_port: u16,
         status: TcpStatus,
-    ) -> Result<Self> {
-        let (sender, _) = transport::transport_channel(65535,
-            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
-        )?;
-        Ok(Self { 
+        sender: Arc<dyn SegmentSender>,
+    ) -> Self {
+        Self { 
             local_addr, 
             remote_addr, 
             local_port, 
@@ -189,6 +323,8 @@ impl Socket {
                 next: 0,
                 window: SOCKET_BUFFER_SIZE as u16,
                 initial_seq: 0,
+                cwnd: INITIAL_CWND,
+                ssthresh: u32::MAX,
             },
             recv_param: RecvParam { 
                 next: 0,
@@ -197,12 +333,19 @@ impl Socket {
                 tail: 0,
             },
             status,
+            mss: MSS,
+            error: None,
+            ecn: EcnState::default(),
+            stats: SocketStats::default(),
+            syn_data: Vec::new(),
+            accepted_on_syn: false,
             recv_buffer: vec![0; SOCKET_BUFFER_SIZE],
             retransmission_queue: VecDeque::new(),
             connected_connection_queue: VecDeque::new(),
             listening_socket: None,
             sender,
-        })
+            clock: Arc::new(SystemClock),
+        }
     }
 
     pub fn send_tcp_packet(
@@ -212,47 +355,166 @@ impl Socket {
         flag: u8,
         payload: &[u8],
     ) -> Result<usize> {
-        let mut tcp_packet = TCPPacket::new(payload.len());
-        tcp_packet.set_src(self.local_port);
-        tcp_packet.set_dest(self.remote_port);
-
-        tcp_packet.set_seq(seq);
-        tcp_packet.set_ack(ack);
-        tcp_packet.set_data_offset(5); // オプションフィールドは使わないので固定
-
-        tcp_packet.set_flag(flag);
-
-        tcp_packet.set_window_size(self.recv_param.window);
-        tcp_packet.set_payload(payload);
-        tcp_packet.set_checksum(util::ipv4_checksum(
-            &tcp_packet.packet(),
-            8,
-            &[],
-            &self.local_addr,
-            &self.remote_addr,
-            IpNextHeaderProtocols::Tcp,
-        ));
+        self.send_tcp_packet_with_options(seq, ack, flag, Vec::new(), payload)
+    }
+
+    /// オプション付きのセグメントを送信する。SYNやSYN|ACKでMSSなどを伝えるために使う。
+    pub fn send_tcp_packet_with_options(
+        &mut self,
+        seq: u32,
+        ack: u32,
+        flag: u8,
+        options: Vec<TcpOption>,
+        payload: &[u8],
+    ) -> Result<usize> {
+        // [note] ECNの合意後は、データセグメントにECT(0)を付け、ACKでCEマークを受け取ったことを伝える。
+        // SYNや純粋なACKにはECTを付けない (RFC 3168 6.1.1, 6.1.4)
+        let mut flag = flag;
+        let mut ect = false;
+        if self.ecn.enabled && flag & tcpflags::SYN == 0 {
+            if self.ecn.ece_pending && flag & tcpflags::ACK > 0 {
+                flag |= tcpflags::ECE;
+            }
+            if !payload.is_empty() {
+                ect = true;
+                if self.ecn.cwr_pending {
+                    flag |= tcpflags::CWR;
+                    self.ecn.cwr_pending = false;
+                }
+            }
+        }
+        let mut builder = TCPPacketBuilder::new(self.local_port, self.remote_port)
+            .seq(seq)
+            .ack(ack)
+            .flag(flag)
+            .window(self.recv_param.window)
+            .payload(payload);
+        for option in options {
+            builder = builder.option(option);
+        }
+        let tcp_packet = builder.build(self.local_addr, self.remote_addr);
 
         let sent_size = self
             .sender
-            .send_to(tcp_packet.clone(), IpAddr::V4(self.remote_addr))
+            .send_segment(&tcp_packet, self.remote_addr, ect)
             .context(format!("failed to send: \n{:?}", tcp_packet))?;
 
         dbg!("sent", &tcp_packet);
+        self.stats.segments_sent += 1;
 
         // [note] 【再送制御】
         // Payloadが存在しない通常の"応答としてのACK"を再送すること(ACKのACKが来ることを期待すること)は無いので、
         // ここで早期returnをしている。
         // なぜなら、通信双方で応答に対する応答を期待してしまうと、無限にそのやり取りをすることになるので、
         // ACKのACKは返さないことを仕様で決めている。
-        if payload.is_empty() && tcp_packet.get_flag() == tcpflags::ACK {
+        if payload.is_empty() && tcp_packet.get_flag() & !tcpflags::ECE == tcpflags::ACK {
             return Ok(sent_size);
         }
         self.retransmission_queue
-            .push_back(RetransmissionQueueEntry::new(tcp_packet));
+            .push_back(RetransmissionQueueEntry::new(tcp_packet, self.clock.now()));
         Ok(sent_size)
     }
 
+    /// 新たにACKされたバイト数だけ輻輳ウィンドウを広げる (RFC 5681)
+    pub fn increase_cwnd(&mut self, acked: u32) {
+        let mss = self.mss as u32;
+        if self.send_param.cwnd < self.send_param.ssthresh {
+            // スロースタート
+            self.send_param.cwnd += cmp::min(acked, mss);
+        } else {
+            // 輻輳回避: 1RTTあたり1MSSずつ
+            self.send_param.cwnd += cmp::max(1, mss * mss / self.send_param.cwnd);
+        }
+    }
+
+    /// ECEの付いたACKを受け取った時に輻輳ウィンドウを半分にする (RFC 3168 6.1.2)
+    ///
+    /// [note] 1RTTの間に何度ECEを受け取っても下げるのは1回だけにする。
+    pub fn react_to_ece(&mut self) {
+        if !self.ecn.enabled
+            || (self.send_param.unacked_seq.wrapping_sub(self.ecn.recover) as i32) < 0
+        {
+            return;
+        }
+        let flight_size = self
+            .send_param
+            .next
+            .wrapping_sub(self.send_param.unacked_seq);
+        self.send_param.ssthresh = cmp::max(flight_size / 2, 2 * self.mss as u32);
+        self.send_param.cwnd = self.send_param.ssthresh;
+        self.ecn.recover = self.send_param.next;
+        self.ecn.cwr_pending = true;
+        dbg!("ece received: reduce cwnd", self.send_param.cwnd);
+    }
+
+    /// 送信中(未ACK)のデータ量を考慮して、今送信できるバイト数を返す
+    pub fn sendable_size(&self) -> usize {
+        let flight_size = self
+            .send_param
+            .next
+            .wrapping_sub(self.send_param.unacked_seq);
+        cmp::min(
+            self.send_param.window as u32,
+            self.send_param.cwnd.saturating_sub(flight_size),
+        ) as usize
+    }
+
+    /// ICMP Fragmentation Needed で通知された経路のMTUに合わせてMSSを下げる (RFC 1191)
+    ///
+    /// [note] 再送キューに残っているセグメントは通知されたMTUを超えていて届かないので、
+    /// 新しいMSSで分割し直し、次のタイマー処理ですぐに再送されるようにする。
+    pub fn update_path_mtu(&mut self, next_hop_mtu: usize) {
+        let next_hop_mtu = if next_hop_mtu == 0 {
+            // 現在のMTUより小さい候補のうち最大のものを使う
+            let current_mtu = self.mss + IP_TCP_HEADER_SIZE;
+            MTU_PLATEAUS
+                .into_iter()
+                .find(|&mtu| mtu < current_mtu)
+                .unwrap_or(MIN_PATH_MTU)
+        } else {
+            next_hop_mtu
+        };
+        let mss = cmp::max(next_hop_mtu, MIN_PATH_MTU) - IP_TCP_HEADER_SIZE;
+        if mss >= self.mss {
+            return;
+        }
+        dbg!("path mtu discovery: lower mss", self.mss, mss);
+        self.mss = mss;
+
+        let queue = std::mem::take(&mut self.retransmission_queue);
+        for entry in queue {
+            let payload = entry.packet.payload();
+            if payload.len() <= mss {
+                self.retransmission_queue.push_back(RetransmissionQueueEntry {
+                    latest_transmission_time: UNIX_EPOCH,
+                    ..entry
+                });
+                continue;
+            }
+            let chunks: Vec<_> = payload.chunks(mss).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let offset = (i * mss) as u32;
+                let mut flag = entry.packet.get_flag();
+                if i + 1 != chunks.len() {
+                    // FINは最後のセグメントにだけ付ける
+                    flag &= !(tcpflags::FIN | tcpflags::PSH);
+                }
+                let packet = TCPPacketBuilder::new(self.local_port, self.remote_port)
+                    .seq(entry.packet.get_seq().wrapping_add(offset))
+                    .ack(entry.packet.get_ack())
+                    .flag(flag)
+                    .window(self.recv_param.window)
+                    .payload(chunk)
+                    .build(self.local_addr, self.remote_addr);
+                self.retransmission_queue.push_back(RetransmissionQueueEntry {
+                    packet,
+                    latest_transmission_time: UNIX_EPOCH,
+                    transmission_count: entry.transmission_count,
+                });
+            }
+        }
+    }
+
     pub fn get_sock_id(&self) -> SockID {
         SockID(
             self.local_addr,
@@ -261,4 +523,139 @@ impl Socket {
             self.remote_port,
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// 送信されたセグメントとECTを付けたかどうかを記録するだけの送信機構
+    #[derive(Default)]
+    pub(crate) struct RecordingSender(pub Mutex<Vec<(TCPPacket, bool)>>);
+
+    impl SegmentSender for RecordingSender {
+        fn send_segment(
+            &self,
+            packet: &TCPPacket,
+            _remote_addr: Ipv4Addr,
+            ect: bool,
+        ) -> io::Result<usize> {
+            self.0.lock().unwrap().push((packet.clone(), ect));
+            std::result::Result::Ok(packet.packet().len())
+        }
+    }
+
+    fn established_socket() -> Socket {
+        let mut socket = Socket::new(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 1, 1),
+            40000,
+            80,
+            TcpStatus::Established,
+            Arc::new(RecordingSender::default()),
+        );
+        socket.send_param.next = 1000;
+        socket
+    }
+
+    #[test]
+    fn test_update_path_mtu_resegments_queue() {
+        let mut socket = established_socket();
+        socket
+            .send_tcp_packet(1000, 1, tcpflags::ACK | tcpflags::FIN, &[7; 1000])
+            .unwrap();
+
+        socket.update_path_mtu(576);
+        assert_eq!(536, socket.mss);
+        let segments: Vec<_> = socket
+            .retransmission_queue
+            .iter()
+            .map(|entry| {
+                (
+                    entry.packet.get_seq(),
+                    entry.packet.get_flag(),
+                    entry.packet.payload().len(),
+                    entry.latest_transmission_time,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (1000, tcpflags::ACK, 536, UNIX_EPOCH),
+                (1536, tcpflags::ACK | tcpflags::FIN, 464, UNIX_EPOCH),
+            ],
+            segments
+        );
+        // MTUが大きくなる通知では変えない
+        socket.update_path_mtu(1500);
+        assert_eq!(536, socket.mss);
+    }
+
+    #[test]
+    fn test_ecn_marks_and_flags() {
+        let sender = Arc::new(RecordingSender::default());
+        let mut socket = established_socket();
+        socket.sender = sender.clone();
+        socket.ecn.enabled = true;
+        socket.ecn.ece_pending = true;
+        socket.ecn.cwr_pending = true;
+
+        socket.send_tcp_packet(1000, 1, tcpflags::ACK, &[]).unwrap();
+        socket.send_tcp_packet(1000, 1, tcpflags::ACK, &[1; 10]).unwrap();
+        socket.send_tcp_packet(1010, 1, tcpflags::ACK, &[1; 10]).unwrap();
+
+        let sent: Vec<_> = sender
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(packet, ect)| (packet.get_flag(), *ect))
+            .collect();
+        assert_eq!(
+            vec![
+                // 純粋なACKにはECTを付けない
+                (tcpflags::ACK | tcpflags::ECE, false),
+                // CWRは次のデータセグメントに1回だけ付ける
+                (tcpflags::ACK | tcpflags::ECE | tcpflags::CWR, true),
+                (tcpflags::ACK | tcpflags::ECE, true),
+            ],
+            sent
+        );
+        // ECEだけのACKは再送キューに入れない
+        assert_eq!(2, socket.retransmission_queue.len());
+    }
+
+    #[test]
+    fn test_react_to_ece_once_per_window() {
+        let mut socket = established_socket();
+        socket.ecn.enabled = true;
+        socket.ecn.recover = 1000;
+        socket.send_param.unacked_seq = 1000;
+        socket.send_param.next = 1000 + 8 * MSS as u32;
+
+        socket.react_to_ece();
+        assert_eq!(4 * MSS as u32, socket.send_param.cwnd);
+        assert_eq!(socket.send_param.next, socket.ecn.recover);
+        assert!(socket.ecn.cwr_pending);
+
+        // recoverまでACKされるまでは再度下げない
+        socket.send_param.unacked_seq += MSS as u32;
+        socket.react_to_ece();
+        assert_eq!(4 * MSS as u32, socket.send_param.cwnd);
+        // 送信中のデータ(7MSS)がcwndを超えているので送信できない
+        assert_eq!(0, socket.sendable_size());
+
+        // 輻輳回避中はACKごとに少しずつ広げる
+        socket.increase_cwnd(MSS as u32);
+        assert_eq!(4 * MSS as u32 + MSS as u32 / 4, socket.send_param.cwnd);
+    }
+
+    #[test]
+    fn test_update_path_mtu_without_next_hop_mtu() {
+        let mut socket = established_socket();
+        socket.update_path_mtu(0);
+        assert_eq!(1492 - IP_TCP_HEADER_SIZE, socket.mss);
+        socket.update_path_mtu(0);
+        assert_eq!(1006 - IP_TCP_HEADER_SIZE, socket.mss);
+    }
+}
@@ -1,23 +1,29 @@
-use crate::packet::TCPPacket;
-use crate::socket::{SockID, Socket, TcpStatus};
+use crate::icmp::{self, IcmpKind};
+use crate::packet::{TCPPacket, TcpOption};
+use crate::port::PortAllocator;
+use crate::route;
+use crate::socket::{Clock, RawSender, SegmentSender, SockID, Socket, SystemClock, TcpStatus, MSS};
+pub use crate::socket::SocketStats;
 use crate::tcpflags;
+use crate::udp::{RawDatagramSender, UDP};
 use anyhow::{Context, Result, Ok};
-use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
+use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, Packet};
 use pnet::transport::{self, TransportChannelType};
-use rand::{rngs::ThreadRng, Rng};
+use rand::Rng;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::process::Command;
-use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
-use std::time::{Duration, SystemTime};
-use std::{cmp, ops::Range, str, thread};
+use std::hash::BuildHasher;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock, RwLockWriteGuard, Weak};
+use std::time::Duration;
+use std::{cmp, thread};
 
 const UNDETERMINED_IP_ADDR: std::net::Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const UNDETERMINED_PORT: u16 = 0;
 const MAX_TRANSMITTION: u8 = 5;
 const RETRANSMITTION_TIMEOUT: u64 = 3;
-const MSS: usize = 1460;
-const PORT_RANGE: Range<u16> = 40000..60000;
+const DEFAULT_MSS: usize = 536; // 相手がMSSオプションを付けてこなかった場合の値 (RFC 1122 4.2.2.6)
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TCPEventKind {
@@ -25,6 +31,7 @@ pub enum TCPEventKind {
     Acked,
     DataArrived,
     ConnectionClosed,
+    ConnectionError, // ICMPでエラーが通知された。待機中の処理はソケットのerrorを確認する
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -48,20 +55,36 @@ pub struct TCP {
     // 「コネクションを確立した」「ペイロードを受信した」といったイベントを他のスレッドから
     // 受け取るまで待機する処理のために、Condvarを利用する。
     event_condvar: (Mutex<Option<TCPEvent>>, Condvar),
+
+    // ローカルポートの予約状況。ロックはsocketsの後に取る
+    ports: Mutex<PortAllocator>,
+    // bind・listen・connectでSO_REUSEADDRのようにポートの共有を許すかどうか
+    reuse_addr: AtomicBool,
+
+    // 受信スレッドを共有するUDP。udp()を初めて呼んだ時に生成する
+    udp: OnceLock<Arc<UDP>>,
+
+    // 全ソケットで共有する送信機構と時計
+    sender: Arc<dyn SegmentSender>,
+    clock: Arc<dyn Clock>,
+
+    // ECN(RFC 3168)をSYNで要求・受け入れるかどうか
+    ecn: AtomicBool,
+
+    // TCP Fast Open (RFC 7413) を使うかどうか
+    fast_open: AtomicBool,
+    // サーバとしてクッキーを生成するための秘密の鍵。プロセスごとに乱数で決まる。
+    fast_open_key: RandomState,
+    // クライアントとしてサーバから受け取ったクッキーのキャッシュ(宛先アドレスごと)
+    fast_open_cookies: Mutex<HashMap<Ipv4Addr, Vec<u8>>>,
 }
 
 impl TCP {
     pub fn new() -> Arc<Self> {
-        // let sockets = HashMap::new();
-        // let tcp = Self { sockets };
-        // tcp
-        let sockets = RwLock::new(HashMap::new());
+        let sender = RawSender::new().expect("failed to open raw socket");
 
         // パケットの送信用スレッド(この関数で返す先のメインスレッド)
-        let tcp = Arc::new(Self {
-            sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
-        });
+        let tcp = Self::with_sender(Arc::new(sender));
 
         // パケットの受信用スレッドの生成
         let cloned_tcp = tcp.clone();
@@ -69,15 +92,124 @@ impl TCP {
             cloned_tcp.receive_handler().unwrap();
         });
 
-        // Section 3.7.4 再送処理用のタイマー用スレッドの生成
+        // ICMPエラー(Path MTU Discovery, 到達不能通知)の受信用スレッドの生成
         let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
-            cloned_tcp.timer();
+            cloned_tcp.icmp_receive_handler().unwrap();
         });
 
         tcp
     }
 
+    /// 指定の送信機構を使うTCPを生成する。受信スレッドは起動しないので、
+    /// 受信したセグメントは handle_segment で渡す。
+    pub(crate) fn with_sender(sender: Arc<dyn SegmentSender>) -> Arc<Self> {
+        let tcp = Arc::new(Self::from_sender(sender));
+
+        // Section 3.7.4 再送処理用のタイマー用スレッドの生成
+        // [note] Weakで持たせておき、TCPが破棄されたらスレッドも終了するようにする。
+        let weak_tcp = Arc::downgrade(&tcp);
+        std::thread::spawn(move || {
+            Self::timer(weak_tcp);
+        });
+
+        tcp
+    }
+
+    pub(crate) fn from_sender(sender: Arc<dyn SegmentSender>) -> Self {
+        Self::from_sender_and_clock(sender, Arc::new(SystemClock))
+    }
+
+    /// 送信機構と時計を指定してTCPを生成する。タイマースレッドも起動しないので、
+    /// 時計を進めたら retransmit_expired_segments を呼ぶ。
+    pub(crate) fn from_sender_and_clock(
+        sender: Arc<dyn SegmentSender>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            sockets: RwLock::new(HashMap::new()),
+            event_condvar: (Mutex::new(None), Condvar::new()),
+            ports: Mutex::new(PortAllocator::default()),
+            reuse_addr: AtomicBool::new(false),
+            udp: OnceLock::new(),
+            sender,
+            clock,
+            ecn: AtomicBool::new(false),
+            fast_open: AtomicBool::new(false),
+            fast_open_key: RandomState::new(),
+            fast_open_cookies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// TCPと受信スレッドを共有するUDPを返す
+    ///
+    /// [note] UDPの送信用のrawソケットは初めて呼んだ時に開く。それまでに受信したUDPのデータグラムは捨てる。
+    pub fn udp(&self) -> Result<Arc<UDP>> {
+        if let Some(udp) = self.udp.get() {
+            return Ok(udp.clone());
+        }
+        let sender = RawDatagramSender::new()?;
+        Ok(self
+            .udp
+            .get_or_init(|| Arc::new(UDP::from_sender(Arc::new(sender))))
+            .clone())
+    }
+
+    /// ECNを使うかどうかを設定する。以降のconnectでECNを要求し、listenしているソケットで受け入れる。
+    ///
+    /// [note] ルータ(AQM)がECT付きのパケットを破棄せずにCEマークを付けるようになるので、
+    /// tcでECNマーキングを有効にした環境で輻輳制御の挙動を観察できる。
+    pub fn set_ecn(&self, enabled: bool) {
+        self.ecn.store(enabled, Ordering::Relaxed);
+    }
+
+    /// 以降のbind・listen・connectで、ローカルのアドレスとポートを他のソケットと共有できるようにする。
+    ///
+    /// [note] SO_REUSEADDRと同じく、共有する全てのソケットがこの設定で予約していて、
+    /// かつリスニングソケットでない場合にだけ共有できる。
+    pub fn set_reuse_addr(&self, enabled: bool) {
+        self.reuse_addr.store(enabled, Ordering::Relaxed);
+    }
+
+    /// TCP Fast Openを使うかどうかを設定する。
+    /// クライアントでは connect_with_data でクッキーを要求・利用し、サーバではlistenしている
+    /// ソケットでクッキーを発行してSYNに載ったデータを受け入れる。
+    pub fn set_fast_open(&self, enabled: bool) {
+        self.fast_open.store(enabled, Ordering::Relaxed);
+    }
+
+    /// クライアントのアドレスに対するTFOクッキーを生成する
+    ///
+    /// [note] RFC 7413 4.1.2 ではクライアントのIPアドレスをサーバだけが知る鍵で暗号化した値を使う。
+    /// ここでは鍵付きのハッシュ(SipHash)で代用している。鍵はプロセス起動時に決まるので再起動で無効になる。
+    fn fast_open_cookie(&self, client_addr: Ipv4Addr) -> Vec<u8> {
+        self.fast_open_key
+            .hash_one(client_addr)
+            .to_be_bytes()
+            .to_vec()
+    }
+
+    /// このTCPの送信機構と時計を使うソケットを生成する
+    fn new_socket(
+        &self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        local_port: u16,
+        remote_port: u16,
+        status: TcpStatus,
+    ) -> Socket {
+        let mut socket = Socket::new(
+            local_addr,
+            remote_addr,
+            local_port,
+            remote_port,
+            status,
+            self.sender.clone(),
+        );
+        socket.clock = self.clock.clone();
+        socket
+    }
+
     /// タイマースレッド用の関数
     /// 全てのソケットの再送キューを見て，タイムアウトしているパケットを再送する
     /// 
@@ -88,84 +220,111 @@ impl TCP {
     /// RFC1122及びRFC6298ではRTOの決定方法について記述されており，基本的には継続的にRTTを計測し，
     /// その値を元にタイムアウト時間を動的に決定するといった手法が取られます．ToyTCPではそこまでは行わず，定数秒でタイムアウトするようにしています．
     /// > Teruya Ono. Rust TCP Book (Japanese Edition) (pp. 115-116). Kindle Edition. 
-    fn timer(&self) {
+    fn timer(tcp: Weak<Self>) {
         dbg!("begin timer thread");
-        loop {
-            let mut table = self.sockets.write().unwrap();
-            for (sock_id, socket) in table.iter_mut() {
-                while let Some(mut item) = socket.retransmission_queue.pop_front() {
-                    // 再送キューからackされたセグメントを除去する
-                    // established state以外の時に送信されたセグメントを除去するために必要
-                    if socket.send_param.unacked_seq > item.packet.get_seq() {
-                        // ackされてる
-                        dbg!("successfully acked", item.packet.get_seq());
-                        socket.send_param.window += item.packet.payload().len() as u16;
-                        self.publish_event(*sock_id, TCPEventKind::Acked);
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && socket.status == TcpStatus::LastAck
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
-                        continue;
-                    }
-                    // タイムアウトを確認
-                    if item.latest_transmission_time.elapsed().unwrap()
-                        < Duration::from_secs(RETRANSMITTION_TIMEOUT)
-                        // [note]             ↑RTO(タイムアウト時間は固定にしている。実際のTCP仕様と実装ではRTT(普段のレイテンシ値)により動的に決めている)
+        while let Some(tcp) = tcp.upgrade() {
+            tcp.retransmit_expired_segments();
+            // Arcを外して待機する
+            drop(tcp);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// 全てのソケットの再送キューを1回走査してタイムアウトしているセグメントを再送する
+    pub(crate) fn retransmit_expired_segments(&self) {
+        let mut table = self.sockets.write().unwrap();
+        for (sock_id, socket) in table.iter_mut() {
+            while let Some(mut item) = socket.retransmission_queue.pop_front() {
+                // 再送キューからackされたセグメントを除去する
+                // established state以外の時に送信されたセグメントを除去するために必要
+                if seq_lt(item.packet.get_seq(), socket.send_param.unacked_seq) {
+                    // ackされてる
+                    dbg!("successfully acked", item.packet.get_seq());
+                    socket.send_param.window = socket
+                        .send_param
+                        .window
+                        .saturating_add(item.packet.payload().len() as u16);
+                    self.publish_event(*sock_id, TCPEventKind::Acked);
+                    if item.packet.get_flag() & tcpflags::FIN > 0
+                        && socket.status == TcpStatus::LastAck
                     {
-                        // 取り出したエントリがタイムアウトしてないなら，キューの以降のエントリもタイムアウトしてない
-                        // 先頭に戻す
-                        socket.retransmission_queue.push_front(item);
-                        break;
+                        self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
+                    }
+                    continue;
+                }
+                // タイムアウトを確認
+                let elapsed = socket
+                    .clock
+                    .now()
+                    .duration_since(item.latest_transmission_time)
+                    .unwrap_or_default();
+                if elapsed < Duration::from_secs(RETRANSMITTION_TIMEOUT)
+                    // [note]             ↑RTO(タイムアウト時間は固定にしている。実際のTCP仕様と実装ではRTT(普段のレイテンシ値)により動的に決めている)
+                {
+                    // 取り出したエントリがタイムアウトしてないなら，キューの以降のエントリもタイムアウトしてない
+                    // 先頭に戻す
+                    socket.retransmission_queue.push_front(item);
+                    break;
+                }
+                // ackされてなければ再送
+                if item.transmission_count < MAX_TRANSMITTION {
+                    // 再送 [note] 再送するセグメントにはECTを付けない (RFC 3168 6.1.5)
+                    dbg!("retransmit");
+                    let sent = socket
+                        .sender
+                        .send_segment(&item.packet, socket.remote_addr, false);
+                    if let Err(error) = sent {
+                        dbg!("failed to retransmit", error);
                     }
-                    // ackされてなければ再送
-                    if item.transmission_count < MAX_TRANSMITTION {
-                        // 再送
-                        dbg!("retransmit");
-                        socket
-                            .sender
-                            .send_to(item.packet.clone(), IpAddr::V4(socket.remote_addr))
-                            .context("failed to retransmit")
-                            .unwrap();
-                        item.transmission_count += 1;
-                        item.latest_transmission_time = SystemTime::now();
-                        socket.retransmission_queue.push_back(item);
-                        break;
-                    } else {
-                        dbg!("reached MAX_TRANSMITTION");
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && (socket.status == TcpStatus::LastAck
-                                || socket.status == TcpStatus::FinWait1
-                                || socket.status == TcpStatus::FinWait2)
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
+                    item.transmission_count += 1;
+                    socket.stats.retransmissions += 1;
+                    item.latest_transmission_time = socket.clock.now();
+                    socket.retransmission_queue.push_back(item);
+                    break;
+                } else {
+                    dbg!("reached MAX_TRANSMITTION");
+                    if item.packet.get_flag() & tcpflags::FIN > 0
+                        && (socket.status == TcpStatus::LastAck
+                            || socket.status == TcpStatus::FinWait1
+                            || socket.status == TcpStatus::FinWait2)
+                    {
+                        self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
                     }
                 }
             }
-            // ロックを外して待機する
-            drop(table);
-            thread::sleep(Duration::from_millis(100));
         }
-    }    
+    }
 
     /// リスニングソケットを生成してソケットIDを返す
     /// 
     /// [note] listenはサーバ側アプリケーションが初めに呼ぶメソッド。
     pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
-        let socket = Socket::new(
+        let reuse = self.reuse_addr.load(Ordering::Relaxed);
+        self.ports
+            .lock()
+            .unwrap()
+            .bind(local_addr, local_port, reuse, true)?;
+        let socket = self.new_socket(
             local_addr,
             UNDETERMINED_IP_ADDR, // まだ接続先IPアドレスは未定
             local_port,
             UNDETERMINED_PORT, // まだ接続先ポート番号は未定
             TcpStatus::Listen,
-        )?;
+        );
         let mut lock = self.sockets.write().unwrap();
         let sock_id = socket.get_sock_id();
         lock.insert(sock_id, socket); // リスニングソケット(唯一)もソケットテーブルに登録する
         Ok(sock_id)
     }
 
+    /// ソケットをソケットテーブルに直接登録する。ファジングで任意の状態のソケットを用意するために使う。
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub(crate) fn insert_socket(&self, socket: Socket) -> SockID {
+        let sock_id = socket.get_sock_id();
+        self.sockets.write().unwrap().insert(sock_id, socket);
+        sock_id
+    }
+
     /// 接続済みソケットが生成されるまで待機し，生成されたらそのIDを返す
     /// 
     /// [note] acceptはサーバ側アプリケーションがlisten後に呼ぶメソッド。acceptから返ったときTCP接続済みの口をアプリは手に入る。
@@ -185,44 +344,182 @@ impl TCP {
             .context("no connected socket")?)
     }
 
-    // TCP接続のためにローカルポート番号をランダム関数を利用して選ぶ
-    fn select_unused_port(&self, rng: &mut ThreadRng) -> Result<u16> {
-        for _ in 0..(PORT_RANGE.end - PORT_RANGE.start) { // [note] ここは別にRANGE分試行しなくても良いはず
-            let local_port_from_random = rng.gen_range(PORT_RANGE);
-            let table = self.sockets.read().unwrap();
-            if table.keys().all(|k| k.2 != local_port_from_random) {
-                // 既存のどのsocketsのポートとも重複していなければそれに決定する
-                return Ok(local_port_from_random);
+    /// ローカルのアドレスとポートを予約し、connect_bound で使うソケットIDを返す
+    ///
+    /// [note] local_portに UNDETERMINED_PORT(0) を渡した場合はエフェメラルポートを割り当てる。
+    /// local_addrに UNDETERMINED_IP_ADDR(0.0.0.0) を渡した場合はconnect時に経路表から決める。
+    /// 接続せずに予約を解放する場合は close を呼ぶ。
+    pub fn bind(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
+        let reuse = self.reuse_addr.load(Ordering::Relaxed);
+        let mut ports = self.ports.lock().unwrap();
+        let local_port = if local_port == UNDETERMINED_PORT {
+            ports.allocate(local_addr, UNDETERMINED_IP_ADDR, UNDETERMINED_PORT)?
+        } else {
+            ports.bind(local_addr, local_port, reuse, false)?;
+            local_port
+        };
+        Ok(SockID(local_addr, UNDETERMINED_IP_ADDR, local_port, UNDETERMINED_PORT))
+    }
+
+    /// 送信元のアドレスとポートを決めて予約する
+    fn reserve_local_endpoint(
+        &self,
+        local_addr: Ipv4Addr,
+        local_port: u16,
+        addr: Ipv4Addr,
+        port: u16,
+    ) -> Result<(Ipv4Addr, u16)> {
+        let local_addr = if local_addr == UNDETERMINED_IP_ADDR {
+            route::get_source_addr_to(addr)?
+        } else {
+            local_addr
+        };
+        let reuse = self.reuse_addr.load(Ordering::Relaxed);
+        let mut ports = self.ports.lock().unwrap();
+        let local_port = if local_port == UNDETERMINED_PORT {
+            ports.allocate(local_addr, addr, port)?
+        } else {
+            ports.bind(local_addr, local_port, reuse, false)?;
+            local_port
+        };
+        Ok((local_addr, local_port))
+    }
+
+    /// ソケットをテーブルから取り除き、ソケットが予約していたポートを解放する
+    ///
+    /// [note] acceptで生成したソケットはリスニングソケットのポートを使っているので、予約は持っていない。
+    fn remove_socket(&self, table: &mut HashMap<SockID, Socket>, sock_id: SockID) {
+        if let Some(socket) = table.remove(&sock_id) {
+            if socket.listening_socket.is_none() {
+                self.ports
+                    .lock()
+                    .unwrap()
+                    .release(socket.local_addr, socket.local_port);
             }
         }
-        anyhow::bail!("no available port found.")
     }
 
     // ターゲットに接続し、接続済みソケットIDを返す
+    // 送信元アドレスは経路表から、送信元ポートは未使用のものをランダムに選ぶ
     pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockID> {
+        self.connect_from(UNDETERMINED_IP_ADDR, UNDETERMINED_PORT, addr, port)
+    }
+
+    /// 送信元アドレスとポートを指定してターゲットに接続し、接続済みソケットIDを返す
+    ///
+    /// [note] local_addrに UNDETERMINED_IP_ADDR(0.0.0.0)、local_portに UNDETERMINED_PORT(0) を
+    /// 渡した場合は connect と同じように自動で決定する。
+    pub fn connect_from(
+        &self,
+        local_addr: Ipv4Addr,
+        local_port: u16,
+        addr: Ipv4Addr,
+        port: u16,
+    ) -> Result<SockID> {
+        let (local_addr, local_port) =
+            self.reserve_local_endpoint(local_addr, local_port, addr, port)?;
+        self.open_connection(local_addr, local_port, addr, port, &[])
+    }
+
+    /// bind で予約したアドレスとポートからターゲットに接続し、接続済みソケットIDを返す
+    pub fn connect_bound(&self, bound_sock_id: SockID, addr: Ipv4Addr, port: u16) -> Result<SockID> {
+        let SockID(bound_addr, _, local_port, _) = bound_sock_id;
+        let mut ports = self.ports.lock().unwrap();
+        if !ports.is_bound(bound_addr, local_port) {
+            anyhow::bail!("socket is not bound: {:?}", bound_sock_id);
+        }
+        let local_addr = if bound_addr == UNDETERMINED_IP_ADDR {
+            // 0.0.0.0 で予約していた場合は送信元アドレスを決めて予約し直す
+            let local_addr = route::get_source_addr_to(addr)?;
+            let reuse = self.reuse_addr.load(Ordering::Relaxed);
+            ports.release(bound_addr, local_port);
+            if let Err(error) = ports.bind(local_addr, local_port, reuse, false) {
+                ports.bind(bound_addr, local_port, reuse, false)?;
+                return Err(error);
+            }
+            local_addr
+        } else {
+            bound_addr
+        };
+        drop(ports);
+        self.open_connection(local_addr, local_port, addr, port, &[])
+    }
+
+    /// ターゲットに接続し、dataを送信してから接続済みソケットIDを返す
+    ///
+    /// [note] TCP Fast Openが有効で、宛先のクッキーを持っていればdataの先頭をSYNに載せて送る(0-RTT)。
+    /// クッキーを持っていなければSYNでクッキーを要求し、dataは接続確立後に通常どおり送る。
+    pub fn connect_with_data(&self, addr: Ipv4Addr, port: u16, data: &[u8]) -> Result<SockID> {
+        let (local_addr, local_port) =
+            self.reserve_local_endpoint(UNDETERMINED_IP_ADDR, UNDETERMINED_PORT, addr, port)?;
+        self.open_connection(local_addr, local_port, addr, port, data)
+    }
+
+    /// 予約済みの送信元アドレスとポートからSYNを送って接続する。失敗した場合は予約を解放する。
+    fn open_connection(
+        &self,
+        local_addr: Ipv4Addr,
+        local_port: u16,
+        addr: Ipv4Addr,
+        port: u16,
+        data: &[u8],
+    ) -> Result<SockID> {
         let mut rng = rand::thread_rng();
-        let mut socket = Socket::new(
-            get_source_addr_to(addr)?,
+        // [note] SYNを送ってからソケットテーブルに登録するまでの間に応答(や同時オープンの相手のSYN)が
+        // 届いても取りこぼさないよう、登録が終わるまでテーブルのロックを持っておく。
+        let mut table = self.sockets.write().unwrap();
+        if table.contains_key(&SockID(local_addr, addr, local_port, port)) {
+            self.ports.lock().unwrap().release(local_addr, local_port);
+            anyhow::bail!("address already in use: {}:{}", local_addr, local_port);
+        }
+        let mut socket = self.new_socket(
+            local_addr,
             addr,
-            self.select_unused_port(&mut rng)?,
+            local_port,
             port,
             TcpStatus::SynSent,
-        )?;
+        );
 
         // [note] TCPシーケンス番号予測攻撃を避けるために、初期シーケンス番号は乱数を用いて生成する。
         socket.send_param.initial_seq = rng.gen_range(1..1 << 31);
 
         // 生成したソケットを使って初期TCP送信する
-        socket.send_tcp_packet(
+        // ECNを使う場合はSYNにECEとCWRを立てて要求する (ECN-setup SYN)
+        let mut flag = tcpflags::SYN;
+        if self.ecn.load(Ordering::Relaxed) {
+            flag |= tcpflags::ECE | tcpflags::CWR;
+        }
+        let mut options = vec![TcpOption::Mss(MSS as u16)];
+        // TFO: クッキーがあればデータをSYNに載せ、なければ空のクッキーオプションで要求する。
+        // 相手のMSSはまだ分からないので、SYNに載せるのはDEFAULT_MSSまでにする。
+        if self.fast_open.load(Ordering::Relaxed) && !data.is_empty() {
+            match self.fast_open_cookies.lock().unwrap().get(&addr) {
+                Some(cookie) => {
+                    options.push(TcpOption::FastOpenCookie(cookie.clone()));
+                    socket.syn_data = data[..cmp::min(data.len(), DEFAULT_MSS)].to_vec();
+                }
+                None => options.push(TcpOption::FastOpenCookie(Vec::new())),
+            }
+        }
+        let syn_data = socket.syn_data.clone();
+        let sent = socket.send_tcp_packet_with_options(
             socket.send_param.initial_seq,
             0,
-            tcpflags::SYN,
-            &[],
+            flag,
+            options,
+            &syn_data,
         );
+        if let Err(error) = sent {
+            self.ports.lock().unwrap().release(local_addr, local_port);
+            return Err(error);
+        }
 
         // TCP初期送信(SYN)後に、ソケット上のデータを更新する。
         socket.send_param.unacked_seq = socket.send_param.initial_seq; // TCP仕様のソケット情報の更新
-        socket.send_param.next = socket.send_param.initial_seq + 1;    // TCP仕様のソケット情報の更新
+        socket.send_param.next = socket
+            .send_param
+            .initial_seq
+            .wrapping_add(1 + syn_data.len() as u32); // TCP仕様のソケット情報の更新
         /* ↑のnextを +1 している箇所に関して
         【書籍】
         SYNセグメントを送信する際にsend_param.nextを1つ進める:SYNセグメントはペイロードを持たないため，
@@ -232,7 +529,6 @@ impl TCP {
         */
 
         // ソケット群へこの新規のソケットを追加する。
-        let mut table = self.sockets.write().unwrap();
         let sock_id = socket.get_sock_id();
         table.insert(sock_id, socket);
 
@@ -241,6 +537,20 @@ impl TCP {
 
         // コネクション確立が成功するまで待ってから呼び出し元へソケットデータを返す。
         self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
+
+        // ICMPで到達不能が通知されていれば接続失敗としてソケットを破棄する
+        let mut table = self.sockets.write().unwrap();
+        if let Some(error) = table.get(&sock_id).and_then(|socket| socket.error.clone()) {
+            self.remove_socket(&mut table, sock_id);
+            return Err(error.into());
+        }
+        drop(table);
+
+        // SYNに載せられなかった残りのデータを送信する
+        // (SYNのデータが受け取られなかった場合は synsent_handler で送り直している)
+        if syn_data.len() < data.len() {
+            self.send(sock_id, &data[syn_data.len()..])?;
+        }
         Ok(sock_id)
     }
 
@@ -253,7 +563,9 @@ impl TCP {
         let mut event = lock.lock().unwrap();
         loop {
             if let Some(ref e) = *event {
-                if e.sock_id == sock_id && e.kind == kind {
+                if e.sock_id == sock_id
+                    && (e.kind == kind || e.kind == TCPEventKind::ConnectionError)
+                {
                     break;
                 }
             }
@@ -279,14 +591,19 @@ impl TCP {
                 .get_mut(&sock_id)
                 .context(format!("no such socket: {:?}", sock_id))?;
 
+            if let Some(error) = &socket.error {
+                return Err(error.clone().into());
+            }
+
             let mut send_size = cmp::min(
                 // [note] ref: https://www.infraexpert.com/info/5adsl.htm
                 // > MSSはMTUからTCP/IPヘッダ（40byte）をマイナスした値で、「MSS = MTU - 40」となります。
                 // > MTUとは一回のデータ転送で送信可能なIPデータグラムの最大値のことです。EthernetLANでは
                 // > Ethernetフレームが最大1518byteなので、Ethernetヘッダ（14byte）と FCS（4byte）を除く
                 // > 1500byteがMTUサイズとなります。
-                MSS,
-                cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
+                // [note] 経路上にMTUの小さいリンクがあればPath MTU Discoveryでsocket.mssが下がる。
+                socket.mss,
+                cmp::min(socket.sendable_size(), buffer.len() - cursor),
             );
 
             while send_size == 0 {
@@ -301,10 +618,13 @@ impl TCP {
                 socket = table
                     .get_mut(&sock_id)
                     .context(format!("no such socket: {:?}", sock_id))?;
+                if let Some(error) = &socket.error {
+                    return Err(error.clone().into());
+                }
                 // [note]受信がされウィンドウサイズが復活したので、送信サイズを再計算する
                 send_size = cmp::min(
-                    MSS,
-                    cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
+                    socket.mss,
+                    cmp::min(socket.sendable_size(), buffer.len() - cursor),
                 );
             }
             dbg!("current window size", socket.send_param.window);
@@ -326,7 +646,7 @@ impl TCP {
             // 【書籍】
             // > 送信後はそのペイロードのサイズ分だけsocket.send_param.nextを進めています
             // > Teruya Ono. Rust TCP Book (Japanese Edition) (p. 105). Kindle Edition. 
-            socket.send_param.next += send_size as u32;
+            socket.send_param.next = socket.send_param.next.wrapping_add(send_size as u32);
 
             // 【書籍】3.7.6 スライディングウィンドウ
             // [note] 送った分だけウィンドウサイズを減らしていく (余裕がなくなった)
@@ -346,82 +666,191 @@ impl TCP {
     fn receive_handler(&self) -> Result<()> {
         dbg!("begin recv thread");
 
-        // 初めにIPレイヤのパケットを受け取る口を用意する。
-        let (_, mut receiver) = transport::transport_channel(
+        // 初めにIPレイヤのパケットを受け取る口を用意する。UDPも同じスレッドで受け取る。
+        let (_, mut tcp_receiver) = transport::transport_channel(
             65535,
             TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp), // IPアドレスが必要なので，IPパケットレベルで取得．
-        )
-        .unwrap();
-        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
+        )?;
+        let (_, mut udp_receiver) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Udp),
+        )?;
+        let mut fds = [
+            libc::pollfd {
+                fd: tcp_receiver.socket.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: udp_receiver.socket.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
 
         // [note] ループで永続的にIPレイヤの口からパケットを受け付け→取得する
         loop {
-            // 受信Waitをする
-            let (packet, remote_addr) = match packet_iter.next() {
-                std::result::Result::Ok((p, r)) => (p, r),
-                Err(_) => continue,
-            };
-            let local_addr = packet.get_destination();
-
-            // pnet(PureなIP/TCPにあたる)のTcpPacketから自作のtcp::TCPPacketに変換する
-            let tcp_packet = match TcpPacket::new(packet.payload()) {
-                Some(p) => p,
-                None => {
+            // 受信Waitをする。どちらかのrawソケットにパケットが届くまで待つ
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                continue;
+            }
+            for (pollfd, receiver) in fds.iter().zip([&mut tcp_receiver, &mut udp_receiver]) {
+                if pollfd.revents & libc::POLLIN == 0 {
                     continue;
                 }
-            };
-            let packet = TCPPacket::from(tcp_packet); // 変換処理
-            let remote_addr = match remote_addr {
-                IpAddr::V4(addr) => addr,
-                _ => {
-                    continue;
+                if let std::result::Result::Ok((packet, _)) =
+                    transport::ipv4_packet_iter(receiver).next()
+                {
+                    self.handle_ip_packet(&packet);
                 }
-            };
+            }
+        }
+    }
 
-            // [note] TCPPacketに記述されている情報から対応するTCPソケットを紐付ける
-            let mut table = self.sockets.write().unwrap();
-            let socket = match table.get_mut(&SockID( // [note] 既存の作成済みソケットに関わる受信パケットであるか判断
+    /// 受信したIPパケットをプロトコルごとに振り分ける
+    fn handle_ip_packet(&self, packet: &Ipv4Packet) {
+        let local_addr = packet.get_destination();
+        let remote_addr = packet.get_source();
+        let result = match packet.get_next_level_protocol() {
+            IpNextHeaderProtocols::Tcp => {
+                // [note] IPヘッダのECNフィールドが CE(0b11) ならルータで輻輳が起きている
+                let ce = packet.get_ecn() == 3;
+                self.handle_segment(local_addr, remote_addr, packet.payload(), ce)
+            }
+            IpNextHeaderProtocols::Udp => match self.udp.get() {
+                Some(udp) => udp.handle_datagram(local_addr, remote_addr, packet.payload()),
+                None => return,
+            },
+            _ => return,
+        };
+        if let Err(error) = result {
+            dbg!(error);
+        }
+    }
+
+    /// 受信したTCPセグメントのバイト列を検証し、対応するソケットの状態に応じたハンドラへ渡す
+    ///
+    /// [note] 不正なセグメントは受信スレッドを落とさずにエラーとして返す。
+    pub(crate) fn handle_segment(
+        &self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        segment: &[u8],
+        ce: bool,
+    ) -> Result<()> {
+        // 受け取ったバイト列を検証して自作のtcp::TCPPacketに変換する
+        let packet = TCPPacket::parse(segment)?;
+
+        // [note] TCPPacketに記述されている情報から対応するTCPソケットを紐付ける
+        let mut table = self.sockets.write().unwrap();
+        let socket = match table.get_mut(&SockID( // [note] 既存の作成済みソケットに関わる受信パケットであるか判断
+            local_addr,
+            remote_addr,
+            packet.get_dest(),
+            packet.get_src(),
+        )) {
+            Some(socket) => socket, // 接続済みソケット
+            None => match table.get_mut(&SockID( // [note] 既存の作成済みでは無いならばリスニングソケット(初期接続)であるか判断
                 local_addr,
-                remote_addr,
+                UNDETERMINED_IP_ADDR,
                 packet.get_dest(),
-                packet.get_src(),
+                UNDETERMINED_PORT,
             )) {
-                Some(socket) => socket, // 接続済みソケット
-                None => match table.get_mut(&SockID( // [note] 既存の作成済みでは無いならばリスニングソケット(初期接続)であるか判断
-                    local_addr,
-                    UNDETERMINED_IP_ADDR,
-                    packet.get_dest(),
-                    UNDETERMINED_PORT,
-                )) {
-                    Some(socket) => socket, // リスニングソケット
-                    None => continue,       // どのソケットにも該当しないものは無視
-                },
-            };
+                Some(socket) => socket, // リスニングソケット
+                None => return Ok(()),  // どのソケットにも該当しないものは無視
+            },
+        };
+
+        // [note] チェックサム処理
+        if !packet.is_correct_checksum(local_addr, remote_addr) {
+            anyhow::bail!("invalid checksum");
+        }
 
-            // [note] チェックサム処理
-            if !packet.is_correct_checksum(local_addr, remote_addr) {
-                dbg!("invalid checksum");
-                continue;
+        // [note] ECN: CEマークを受け取ったらCWRが来るまでACKにECEを付け続ける (RFC 3168 6.1.3)
+        if socket.ecn.enabled {
+            if packet.get_flag() & tcpflags::CWR > 0 {
+                socket.ecn.ece_pending = false;
+            }
+            if ce {
+                dbg!("ce received");
+                socket.ecn.ece_pending = true;
             }
+        }
 
-            // [note] 受信したパケットとその受信したパケットに対応するソケットを引数にして、ソケットのステータス状況に応じてハンドリングする
-            let sock_id = socket.get_sock_id();
-            if let Err(error) = match socket.status {
-                TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
-                TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
-                TcpStatus::SynSent => self.synsent_handler(socket, &packet),
-                TcpStatus::Established => self.established_handler(socket, &packet),
-                TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
-                TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
-                _ => {
-                    dbg!("not implemented state");
-                    Ok(())
+        // [note] 受信したパケットとその受信したパケットに対応するソケットを引数にして、ソケットのステータス状況に応じてハンドリングする
+        let sock_id = socket.get_sock_id();
+        match socket.status {
+            TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
+            TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
+            TcpStatus::SynSent => self.synsent_handler(socket, &packet),
+            TcpStatus::Established => self.established_handler(socket, &packet),
+            TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
+            TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
+            _ => {
+                dbg!("not implemented state");
+                Ok(())
+            }
+        }
+    }
+
+    /// ICMP受信スレッド用の関数
+    /// [やっていること] 自分が送信したTCPセグメントに対するICMPエラーを受け取り、該当するソケットへ反映する。
+    fn icmp_receive_handler(&self) -> Result<()> {
+        dbg!("begin icmp recv thread");
+        let (_, mut receiver) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Icmp),
+        )?;
+        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
+        loop {
+            let (packet, _) = match packet_iter.next() {
+                std::result::Result::Ok((p, r)) => (p, r),
+                Err(_) => continue,
+            };
+            self.handle_icmp(packet.payload());
+        }
+    }
+
+    /// ICMPメッセージを解析して、対応するソケットのMSSを下げる、またはエラーを通知する
+    pub(crate) fn handle_icmp(&self, message: &[u8]) {
+        let message = match icmp::parse(message) {
+            Some(message) => message,
+            None => return,
+        };
+        // ICMPに含まれるのは自分が送信したセグメントなので、送信元が自分側になる
+        let sock_id = SockID(
+            message.src_addr,
+            message.dest_addr,
+            message.src_port,
+            message.dest_port,
+        );
+        let mut table = self.sockets.write().unwrap();
+        let socket = match table.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+        // [note] 偽造されたICMPでコネクションを切られないように、
+        // 送信済みで未ACKのシーケンス番号(SND.UNA <= SEG.SEQ < SND.NXT)を含むものだけを受け入れる (RFC 5927)
+        if message.seq.wrapping_sub(socket.send_param.unacked_seq)
+            >= socket.send_param.next.wrapping_sub(socket.send_param.unacked_seq)
+        {
+            dbg!("icmp for out of window segment", message.seq);
+            return;
+        }
+        dbg!("icmp", &message.kind);
+        match message.kind {
+            IcmpKind::FragmentationNeeded { next_hop_mtu } => {
+                socket.update_path_mtu(next_hop_mtu as usize);
+            }
+            IcmpKind::Unreachable(error) => {
+                if error.is_hard() || socket.status == TcpStatus::SynSent {
+                    socket.error = Some(error);
+                    self.publish_event(sock_id, TCPEventKind::ConnectionError);
                 }
-            } {
-                dbg!(error);
             }
         }
-    }    
+    }
 
     /*
     【書籍】
@@ -457,26 +886,58 @@ impl TCP {
             // 後に接続済みソケットとなるソケットを新たに生成する
             // [note] Listenしていて新しくクライアントからSYNが来た時点で、
             // 相手のIPとポートとわかっているので、接続完了後に利用するソケットを作って処理の最後にソケットテーブルに入れておく。
-            let mut connection_socket = Socket::new(
+            let mut connection_socket = self.new_socket(
                 listening_socket.local_addr,
                 remote_addr,
                 listening_socket.local_port,
                 packet.get_src(),
                 TcpStatus::SynRcvd,
-            )?;
+            );
 
             // [note] TCPの仕様に従って接続完了後ソケットの情報の初期設定をしておく。
-            connection_socket.recv_param.next = packet.get_seq() + 1;
+            connection_socket.recv_param.next = packet.get_seq().wrapping_add(1);
             connection_socket.recv_param.initial_seq = packet.get_seq();
             connection_socket.send_param.initial_seq = rand::thread_rng().gen_range(1..1 << 31);
             connection_socket.send_param.window = packet.get_window_size();
-            connection_socket.send_tcp_packet(
+            connection_socket.mss = peer_mss(packet);
+
+            // ECN-setup SYN (ECEとCWRが立っている) ならECEを立てたSYN|ACKで受け入れる
+            let mut flag = tcpflags::SYN | tcpflags::ACK;
+            let ecn_setup = tcpflags::ECE | tcpflags::CWR;
+            if self.ecn.load(Ordering::Relaxed) && packet.get_flag() & ecn_setup == ecn_setup {
+                flag |= tcpflags::ECE;
+                connection_socket.ecn.enabled = true;
+                connection_socket.ecn.recover = connection_socket.send_param.initial_seq;
+            }
+
+            // TFO: 正しいクッキー付きのSYNならデータを受け取ってすぐにacceptへ渡す。
+            // クッキーが無い(要求のみ)か正しくない場合はSYN|ACKで新しいクッキーを渡し、SYNのデータは確認応答しない。
+            let mut options = vec![TcpOption::Mss(MSS as u16)];
+            if self.fast_open.load(Ordering::Relaxed) {
+                if let Some(cookie) = packet.get_fast_open_cookie() {
+                    let valid_cookie = self.fast_open_cookie(remote_addr);
+                    if cookie != valid_cookie {
+                        options.push(TcpOption::FastOpenCookie(valid_cookie));
+                    } else if !packet.payload().is_empty() {
+                        let len = cmp::min(packet.payload().len(), connection_socket.recv_buffer.len());
+                        connection_socket.recv_buffer[..len].copy_from_slice(&packet.payload()[..len]);
+                        connection_socket.recv_param.window -= len as u16;
+                        connection_socket.recv_param.next =
+                            connection_socket.recv_param.next.wrapping_add(len as u32);
+                        connection_socket.accepted_on_syn = true;
+                    }
+                }
+            }
+            connection_socket.recv_param.tail = connection_socket.recv_param.next;
+            connection_socket.send_tcp_packet_with_options(
                 connection_socket.send_param.initial_seq,
                 connection_socket.recv_param.next,
-                tcpflags::SYN | tcpflags::ACK,
+                flag,
+                options,
                 &[],
             )?;
-            connection_socket.send_param.next = connection_socket.send_param.initial_seq + 1;
+            connection_socket.send_param.next =
+                connection_socket.send_param.initial_seq.wrapping_add(1);
             connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
 
             // [note] synrcvd_handler でリスニングソケットが持つ接続キューへここで生成したソケットをEnqueueするため、
@@ -484,7 +945,16 @@ impl TCP {
             connection_socket.listening_socket = Some(listening_socket.get_sock_id());
 
             dbg!("status: listen -> ", &connection_socket.status);
-            table.insert(connection_socket.get_sock_id(), connection_socket);
+            let connection_sock_id = connection_socket.get_sock_id();
+            let accepted_on_syn = connection_socket.accepted_on_syn;
+            table.insert(connection_sock_id, connection_socket);
+
+            // [note] SYNのデータを受け取った場合はハンドシェイクの完了を待たずにacceptへ渡す(RFC 7413 4.2.2)
+            if accepted_on_syn {
+                let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+                listening_socket.connected_connection_queue.push_back(connection_sock_id);
+                self.publish_event(listening_socket_id, TCPEventKind::ConnectionCompleted);
+            }
         }
         Ok(())
     }
@@ -500,18 +970,40 @@ impl TCP {
         dbg!("synrcvd handler");
         let socket = table.get_mut(&connecting_sock_id).unwrap();
 
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
+        if packet.get_flag() & tcpflags::ACK > 0 && acks_unacked_seq(socket, packet) {
             // [note]通信ソケットの状態を更新する
-            socket.recv_param.next = packet.get_seq();
             socket.send_param.unacked_seq = packet.get_ack();
+            self.delete_acked_segment_from_retransmission_queue(socket);
+            socket.send_param.window = packet.get_window_size();
             socket.status = TcpStatus::Established;
             dbg!("status: synrcvd ->", &socket.status);
 
-            if let Some(id) = socket.listening_socket {
-                let listening_socket = table.get_mut(&id).unwrap();
+            if packet.get_flag() & tcpflags::SYN > 0 {
+                // 同時オープンで相手のSYN|ACKが届いた。相手もこれでESTABLISHEDになるが、念のためACKを返す
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+            } else if !packet.payload().is_empty() {
+                // ACKにデータが載っていれば受信バッファに入れる
+                self.process_payload(socket, packet)?;
+            }
+
+            // SYNのデータを受け取った時点でacceptへ渡したソケットは再びEnqueueしない
+            if socket.accepted_on_syn {
+                return Ok(());
+            }
+            let Some(listening_sock_id) = socket.listening_socket else {
+                // [note] 同時オープン(SYNSENTから遷移してきた)の場合はリスニングソケットが無いので、
+                // connect で待っている呼び出し元に直接通知する
+                let syn_data = std::mem::take(&mut socket.syn_data);
+                self.resend_syn_data(socket, &syn_data)?;
+                self.publish_event(connecting_sock_id, TCPEventKind::ConnectionCompleted);
+                return Ok(());
+            };
+            if let Some(listening_socket) = table.get_mut(&listening_sock_id) {
                 // [note] accept メソッドに教えてあげる(通知する)ために、
                 // ① リスニングソケットに接続済みソケットをEnqueueし、
                 // ② TCPが持つ接続イベントを発火させる。
@@ -542,49 +1034,113 @@ impl TCP {
 
         > Teruya Ono. Rust TCP Book (Japanese Edition) (pp. 79-80). Kindle Edition. 
          */
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-            && packet.get_flag() & tcpflags::SYN > 0
+        if packet.get_flag() & tcpflags::SYN == 0 {
+            return Ok(());
+        }
+        // [note] SYNSENTでは自分のSYNを確認応答していないACK(ack == ISS)も受け付けられない (RFC 793 3.9)
+        let acks_syn = packet.get_flag() & tcpflags::ACK > 0;
+        if acks_syn && !acks_unacked_seq(socket, packet) {
+            return Ok(());
+        }
+        socket.recv_param.next = packet.get_seq().wrapping_add(1);
+        socket.recv_param.initial_seq = packet.get_seq();
+        socket.send_param.window = packet.get_window_size();
+        socket.mss = peer_mss(packet);
+
+        if !acks_syn {
+            // [note] 同時オープン (RFC 793 3.4 Figure 8)
+            // 相手も同時にconnectしていて、自分のSYNより先に相手のSYNが届いた。
+            // SYN|ACKを返してSYNRCVDへ遷移し、相手からの確認応答でESTABLISHEDになる。
+            // SYNに載せたデータ(TFO)は相手が受け取っていないので、確立後に送り直す。
+            socket.status = TcpStatus::SynRcvd;
+            socket.send_param.next = socket.send_param.initial_seq.wrapping_add(1);
+            socket.send_tcp_packet_with_options(
+                socket.send_param.initial_seq,
+                socket.recv_param.next,
+                tcpflags::SYN | tcpflags::ACK,
+                vec![TcpOption::Mss(MSS as u16)],
+                &[],
+            )?;
+            dbg!("status: synsent ->", &socket.status);
+            return Ok(());
+        }
+
+        socket.send_param.unacked_seq = packet.get_ack();
+        // ECEだけが立ったSYN|ACKなら相手はECNを受け入れた
+        if self.ecn.load(Ordering::Relaxed)
+            && packet.get_flag() & (tcpflags::ECE | tcpflags::CWR) == tcpflags::ECE
         {
-            socket.recv_param.next = packet.get_seq() + 1;
-            socket.recv_param.initial_seq = packet.get_seq();
-            socket.send_param.unacked_seq = packet.get_ack();
-            socket.send_param.window = packet.get_window_size();
-            if socket.send_param.unacked_seq > socket.send_param.initial_seq {
-                // [note] 【ここのスコープが正常系】SYNSENT状態で待ち受けていて、
-                // ちゃんと相手から期待どおりSYN|ACKセグメントがきたとき
+            socket.ecn.enabled = true;
+            socket.ecn.recover = socket.send_param.initial_seq;
+        }
+        let resend_data = self.update_fast_open_state(socket, packet);
 
-                // ソケットのステータスを変更
-                socket.status = TcpStatus::Established;
+        // [note] 【ここが正常系】SYNSENT状態で待ち受けていて、ちゃんと相手から期待どおりSYN|ACKセグメントがきたとき
+        // ソケットのステータスを変更
+        socket.status = TcpStatus::Established;
 
-                // 相手へACKで返す
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
+        // 相手へACKで返す
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        )?;
+        dbg!("status: synsent ->", &socket.status);
 
-                // 送信側のスレッドに対して相手から期待どおりにSYN|ACKが返ってソケットステータスをEstablishedにしたことを通知する。
-                // これによって、送信側のスレッドにて送信リクエストをしたアプリケーション側へ処理を返すことができる。
-                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            } else {
-                // [note] どういう状況のスコープか理解できていない。ソケットをコネクション構築OKにはせずACK送信している。
-                socket.status = TcpStatus::SynRcvd;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
-            }
+        // SYNのデータが確認応答されなかった場合は通常のセグメントとして送り直す
+        self.resend_syn_data(socket, &resend_data)?;
+
+        // 送信側のスレッドに対して相手から期待どおりにSYN|ACKが返ってソケットステータスをEstablishedにしたことを通知する。
+        // これによって、送信側のスレッドにて送信リクエストをしたアプリケーション側へ処理を返すことができる。
+        self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
+        Ok(())
+    }
+
+    /// SYNに載せたが相手が受け取らなかったデータ(TFO)を、通常のセグメントとして送り直す
+    fn resend_syn_data(&self, socket: &mut Socket, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
         }
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            tcpflags::ACK,
+            data,
+        )?;
+        socket.send_param.next = socket.send_param.next.wrapping_add(data.len() as u32);
+        socket.send_param.window = socket.send_param.window.saturating_sub(data.len() as u16);
         Ok(())
     }
 
+    /// SYN|ACKに載ったTFOクッキーをキャッシュし、SYNのデータが確認応答されたかを確認する。
+    /// 確認応答されなかった場合は、送り直すべきデータを返す。
+    ///
+    /// [note] SYN|ACKはSYN(とデータ)を確認応答するので、再送キューのSYNを先に取り除いておく。
+    fn update_fast_open_state(&self, socket: &mut Socket, packet: &TCPPacket) -> Vec<u8> {
+        let mut cookies = self.fast_open_cookies.lock().unwrap();
+        let cookie = packet.get_fast_open_cookie().filter(|c| !c.is_empty());
+        if let Some(cookie) = &cookie {
+            cookies.insert(socket.remote_addr, cookie.clone());
+        }
+        if socket.syn_data.is_empty() {
+            return Vec::new();
+        }
+        let syn_data = std::mem::take(&mut socket.syn_data);
+        self.delete_acked_segment_from_retransmission_queue(socket);
+        socket.send_param.window = packet.get_window_size();
+        if packet.get_ack() != socket.send_param.initial_seq.wrapping_add(1) {
+            return Vec::new();
+        }
+        // フォールバック: サーバがクッキーを受け入れなかった。新しいクッキーが無ければキャッシュも捨てる。
+        dbg!("fast open data not acked");
+        if cookie.is_none() {
+            cookies.remove(&socket.remote_addr);
+        }
+        socket.send_param.next = packet.get_ack();
+        syn_data
+    }
+
     /// 指定のソケットIDにイベントを発行する
     fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
         let (lock, cvar) = &self.event_condvar;
@@ -596,13 +1152,11 @@ impl TCP {
     /// SYNSENT状態のソケットに到着したパケットの処理
     /// [note] Payloadのやり取りをしているということ
     fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        if socket.send_param.unacked_seq < packet.get_ack() 
-            && packet.get_ack() <= socket.send_param.next
-        {
+        if acks_unacked_seq(socket, packet) {
             // 【正常ケース】送信したパケットに対して正しくACKが返ってきたスコープ
-            socket.send_param.unacked_seq = packet.get_ack();
+            self.update_unacked_seq(socket, packet);
             self.delete_acked_segment_from_retransmission_queue(socket); // 再送キューにあるエントリを外す
-        } else if socket.send_param.next < packet.get_ack() {
+        } else if seq_lt(socket.send_param.next, packet.get_ack()) {
             // 未送信セグメントに対するACKは破棄する
             return Ok(());
         }
@@ -610,11 +1164,14 @@ impl TCP {
             // ACKが立っていないパケットは破棄
             return Ok(());
         }
+        if packet.get_flag() & tcpflags::ECE > 0 {
+            socket.react_to_ece();
+        }
         if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
+            self.process_payload(socket, packet)?;
         }
         if packet.get_flag() & tcpflags::FIN > 0 {
-            socket.recv_param.next = packet.get_seq() + 1;
+            socket.recv_param.next = packet.get_seq().wrapping_add(1);
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
@@ -627,14 +1184,32 @@ impl TCP {
         Ok(())
     }
 
+    /// 新たにACKされた分だけSND.UNAを進め、輻輳ウィンドウを広げる
+    fn update_unacked_seq(&self, socket: &mut Socket, packet: &TCPPacket) {
+        let acked = packet.get_ack().wrapping_sub(socket.send_param.unacked_seq);
+        socket.send_param.unacked_seq = packet.get_ack();
+        // ECEが付いている場合は react_to_ece でcwndを下げるので広げない
+        if packet.get_flag() & tcpflags::ECE == 0 {
+            socket.increase_cwnd(acked);
+        }
+    }
+
     /// ACKが正しく返ってきたときの内部処理
     fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) {
         dbg!("ack accept", socket.send_param.unacked_seq);
         while let Some(item) = socket.retransmission_queue.pop_front() {
-            if socket.send_param.unacked_seq > item.packet.get_seq() {
+            if seq_lt(item.packet.get_seq(), socket.send_param.unacked_seq) {
                 dbg!("successfully acked", item.packet.get_seq());
+                // [note] 再送したセグメントはどの送信に対するACKか区別できないのでRTTを測らない
+                if item.transmission_count == 1 {
+                    let now = socket.clock.now();
+                    socket.stats.record_rtt(item.latest_transmission_time, now);
+                }
                 // [note] 送信先からACKが正しく返ってきたので、ウィンドウサイズを増やす。(余裕ができた)
-                socket.send_param.window += item.packet.payload().len() as u16;
+                socket.send_param.window = socket
+                    .send_param
+                    .window
+                    .saturating_add(item.packet.payload().len() as u16);
                 self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
             } else {
                 // ackされてない．戻す．
@@ -691,20 +1266,42 @@ impl TCP {
     /// * socket.recv_param.window  - ソケットで持つ現状のウィンドウサイズ
     /// * packet.get_seq()          - 受信したパケットが示すPayloadの位置
     fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        // [note] 既に受信済みのセグメント(再送されてきた重複セグメント)はバッファに入れずにACKだけ返し直す
+        let distance = packet.get_seq().wrapping_sub(socket.recv_param.next);
+        if (distance as i32) < 0 {
+            dbg!("duplicate segment", packet.get_seq());
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+            return Ok(());
+        }
         // バッファにおける読み込みのヘッド位置．
-        let offset = socket.recv_buffer.len() - socket.recv_param.window as usize
-            + (packet.get_seq() - socket.recv_param.next) as usize;
-        let copy_size = cmp::min(packet.payload().len(), socket.recv_buffer.len() - offset);
-        socket.recv_buffer[offset..offset + copy_size]
-            .copy_from_slice(&packet.payload()[..copy_size]);
-        socket.recv_param.tail =
-            cmp::max(socket.recv_param.tail, packet.get_seq() + copy_size as u32); // ロス再送の際穴埋めされるためにmaxをとる
+        let offset = (socket.recv_buffer.len() - socket.recv_param.window as usize)
+            .saturating_add(distance as usize);
+        let copy_size = cmp::min(
+            packet.payload().len(),
+            socket.recv_buffer.len().saturating_sub(offset),
+        );
+        if copy_size > 0 {
+            socket.recv_buffer[offset..offset + copy_size]
+                .copy_from_slice(&packet.payload()[..copy_size]);
+        }
+        let tail = packet.get_seq().wrapping_add(copy_size as u32);
+        if seq_lt(socket.recv_param.tail, tail) {
+            socket.recv_param.tail = tail; // ロス再送の際穴埋めされるためにmaxをとる
+        }
 
         if packet.get_seq() == socket.recv_param.next {
             // 順序入れ替わり無しの場合のみrecv_param.nextを進められる
             socket.recv_param.next = socket.recv_param.tail;
             // [note] ↓ソケット受信バッファに新たにデータが来たのでウィンドウサイズを下げる(余裕がなくなった)
-            socket.recv_param.window -= (socket.recv_param.tail - packet.get_seq()) as u16;
+            socket.recv_param.window = socket
+                .recv_param
+                .window
+                .saturating_sub(socket.recv_param.tail.wrapping_sub(packet.get_seq()) as u16);
         }
         if copy_size > 0 {
             // 受信バッファにコピーが成功
@@ -722,19 +1319,45 @@ impl TCP {
         Ok(())
     }
 
+    /// コネクションの統計情報(送信セグメント数・再送数・RTT)を返す
+    pub fn stats(&self, sock_id: SockID) -> Result<SocketStats> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        Ok(socket.stats.clone())
+    }
+
     /// 接続を閉じる．
     pub fn close(&self, sock_id: SockID) -> Result<()> {
         let mut table = self.sockets.write().unwrap();
-        let mut socket = table
+        if !table.contains_key(&sock_id) {
+            // bind で予約しただけのソケットはポートを解放する
+            let SockID(local_addr, remote_addr, local_port, remote_port) = sock_id;
+            let mut ports = self.ports.lock().unwrap();
+            if remote_addr == UNDETERMINED_IP_ADDR
+                && remote_port == UNDETERMINED_PORT
+                && ports.is_bound(local_addr, local_port)
+            {
+                ports.release(local_addr, local_port);
+                return Ok(());
+            }
+        }
+        let socket = table
             .get_mut(&sock_id)
             .context(format!("no such socket: {:?}", sock_id))?;
+        if socket.status == TcpStatus::Listen {
+            // リスニングソケットには相手がいないのでFINは送らずに破棄する
+            self.remove_socket(&mut table, sock_id);
+            return Ok(());
+        }
         socket.send_tcp_packet(
             socket.send_param.next,
             socket.recv_param.next,
             tcpflags::FIN | tcpflags::ACK,
             &[],
         )?;
-        socket.send_param.next += 1;
+        socket.send_param.next = socket.send_param.next.wrapping_add(1);
         match socket.status {
             TcpStatus::Established => {
                 socket.status = TcpStatus::FinWait1;
@@ -742,7 +1365,7 @@ impl TCP {
                 drop(table);
                 self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
                 let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
+                self.remove_socket(&mut table, sock_id);
                 dbg!("closed & removed", sock_id);
             }
             TcpStatus::CloseWait => {
@@ -751,12 +1374,9 @@ impl TCP {
                 drop(table);
                 self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
                 let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
+                self.remove_socket(&mut table, sock_id);
                 dbg!("closed & removed", sock_id);
             }
-            TcpStatus::Listen => {
-                table.remove(&sock_id);
-            }
             _ => return Ok(()),
         }
         Ok(())
@@ -771,12 +1391,10 @@ impl TCP {
     /// FINWAIT1 or FINWAIT2状態のソケットに到着したパケットの処理
     fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
         dbg!("finwait handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
+        if acks_unacked_seq(socket, packet) {
+            self.update_unacked_seq(socket, packet);
             self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
+        } else if seq_lt(socket.send_param.next, packet.get_ack()) {
             // 未送信セグメントに対するackは破棄
             return Ok(());
         }
@@ -784,8 +1402,11 @@ impl TCP {
             // ACKが立っていないパケットは破棄
             return Ok(());
         }
+        if packet.get_flag() & tcpflags::ECE > 0 {
+            socket.react_to_ece();
+        }
         if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
+            self.process_payload(socket, packet)?;
         }
 
         if socket.status == TcpStatus::FinWait1
@@ -798,41 +1419,539 @@ impl TCP {
 
         if packet.get_flag() & tcpflags::FIN > 0 {
             // 本来はCLOSING stateも考慮する必要があるが省略
-            socket.recv_param.next += 1;
+            socket.recv_param.next = socket.recv_param.next.wrapping_add(1);
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
                 tcpflags::ACK,
                 &[],
             )?;
+            socket.status = TcpStatus::TimeWait;
+            dbg!("status: finwait ->", &socket.status);
             self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
         }
         Ok(())
     }
 }
 
-/// 宛先IPアドレスに対する送信元インタフェースのIPアドレスを取得する
-/// 
-/// [note] 以下のように ip route コマンドを叩いた結果を取得している。
-/// 
-/// $ ip route get 10.0.0.1
-/// 10.0.0.1 via 192.168.64.1 dev enp0s1 src 192.168.64.7 uid 1000
-/// 
-/// iproute2-ss180129で動作を確認．バージョンによって挙動が変わるかも
-fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(format!("ip route get {} | grep src", addr))
-        .output()?;
-    let mut output = str::from_utf8(&output.stdout)?
-        .trim()
-        .split_ascii_whitespace();
-    while let Some(s) = output.next() {
-        if s == "src" {
-            break;
-        }
-    }
-    let ip = output.next().context("failed to get src ip")?;
-    dbg!("source addr", ip);
-    ip.parse().context("failed to parse source ip")
-}
\ No newline at end of file
+/// シーケンス番号の比較。2^32で一周するので、差を符号付きで見て a が b より前かを判定する (RFC 1982)
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// 確認応答番号が送信済みで未ACKの範囲 (SND.UNA < SEG.ACK <= SND.NXT) にあるか (RFC 793 3.9)
+fn acks_unacked_seq(socket: &Socket, packet: &TCPPacket) -> bool {
+    seq_lt(socket.send_param.unacked_seq, packet.get_ack())
+        && !seq_lt(socket.send_param.next, packet.get_ack())
+}
+
+/// SYNで受け取った相手のMSSオプションから、送信に使うMSSを決める
+fn peer_mss(packet: &TCPPacket) -> usize {
+    match packet.get_mss() {
+        Some(mss) => cmp::min(MSS, mss as usize),
+        None => DEFAULT_MSS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icmp::UnreachableError;
+    use crate::packet::TCPPacketBuilder;
+    use crate::socket::tests::RecordingSender;
+    use std::io;
+    use std::sync::mpsc;
+    use std::sync::{Barrier, OnceLock};
+
+    const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+    fn receive(tcp: &TCP, builder: TCPPacketBuilder) {
+        let segment = builder.build(REMOTE_ADDR, LOCAL_ADDR);
+        tcp.handle_segment(LOCAL_ADDR, REMOTE_ADDR, segment.packet(), false)
+            .unwrap();
+    }
+
+    fn fast_open_server() -> (TCP, Arc<RecordingSender>, SockID) {
+        let sender = Arc::new(RecordingSender::default());
+        let tcp = TCP::from_sender(sender.clone());
+        tcp.set_fast_open(true);
+        let listening_sock_id = tcp.listen(LOCAL_ADDR, 80).unwrap();
+        (tcp, sender, listening_sock_id)
+    }
+
+    #[test]
+    fn test_fast_open_server_issues_cookie() {
+        let (tcp, sender, listening_sock_id) = fast_open_server();
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(50000, 80)
+                .seq(5000)
+                .flag(tcpflags::SYN)
+                .option(TcpOption::FastOpenCookie(Vec::new())),
+        );
+
+        let (syn_ack, _) = sender.0.lock().unwrap().pop().unwrap();
+        assert_eq!(5001, syn_ack.get_ack());
+        assert_eq!(
+            Some(tcp.fast_open_cookie(REMOTE_ADDR)),
+            syn_ack.get_fast_open_cookie()
+        );
+        // ハンドシェイクが完了するまではacceptに渡さない
+        let table = tcp.sockets.read().unwrap();
+        assert!(table[&listening_sock_id].connected_connection_queue.is_empty());
+    }
+
+    #[test]
+    fn test_fast_open_server_accepts_syn_data() {
+        let (tcp, sender, listening_sock_id) = fast_open_server();
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(50000, 80)
+                .seq(5000)
+                .flag(tcpflags::SYN)
+                .option(TcpOption::FastOpenCookie(tcp.fast_open_cookie(REMOTE_ADDR)))
+                .payload(b"hello"),
+        );
+
+        let (syn_ack, _) = sender.0.lock().unwrap().pop().unwrap();
+        assert_eq!(5006, syn_ack.get_ack());
+        assert_eq!(None, syn_ack.get_fast_open_cookie());
+        let sock_id = tcp.accept(listening_sock_id).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(5, tcp.recv(sock_id, &mut buffer).unwrap());
+        assert_eq!(b"hello", &buffer[..5]);
+
+        // ハンドシェイクを完了させても二重にacceptへ渡さない
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(50000, 80)
+                .seq(5006)
+                .ack(syn_ack.get_seq() + 1)
+                .flag(tcpflags::ACK),
+        );
+        let table = tcp.sockets.read().unwrap();
+        assert_eq!(TcpStatus::Established, table[&sock_id].status);
+        assert!(table[&listening_sock_id].connected_connection_queue.is_empty());
+    }
+
+    #[test]
+    fn test_fast_open_server_rejects_invalid_cookie() {
+        let (tcp, sender, _) = fast_open_server();
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(50000, 80)
+                .seq(5000)
+                .flag(tcpflags::SYN)
+                .option(TcpOption::FastOpenCookie(vec![0; 8]))
+                .payload(b"hello"),
+        );
+
+        let (syn_ack, _) = sender.0.lock().unwrap().pop().unwrap();
+        // SYNのデータは確認応答せず、正しいクッキーを渡し直す
+        assert_eq!(5001, syn_ack.get_ack());
+        assert_eq!(
+            Some(tcp.fast_open_cookie(REMOTE_ADDR)),
+            syn_ack.get_fast_open_cookie()
+        );
+    }
+
+    #[test]
+    fn test_fast_open_client_falls_back_when_data_not_acked() {
+        let sender = Arc::new(RecordingSender::default());
+        let tcp = TCP::from_sender(sender.clone());
+        tcp.set_fast_open(true);
+        tcp.fast_open_cookies
+            .lock()
+            .unwrap()
+            .insert(REMOTE_ADDR, vec![1; 8]);
+
+        let mut socket = Socket::new(
+            LOCAL_ADDR,
+            REMOTE_ADDR,
+            40000,
+            80,
+            TcpStatus::SynSent,
+            sender.clone(),
+        );
+        socket.send_param.initial_seq = 1000;
+        socket.send_param.unacked_seq = 1000;
+        socket.syn_data = b"hello".to_vec();
+        socket
+            .send_tcp_packet(1000, 0, tcpflags::SYN, b"hello")
+            .unwrap();
+        socket.send_param.next = 1006;
+        let sock_id = tcp.insert_socket(socket);
+
+        // サーバはSYNだけを確認応答し、クッキーも返さなかった
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(80, 40000)
+                .seq(5000)
+                .ack(1001)
+                .flag(tcpflags::SYN | tcpflags::ACK)
+                .window(1000),
+        );
+
+        let (resent, _) = sender.0.lock().unwrap().pop().unwrap();
+        assert_eq!(1001, resent.get_seq());
+        assert_eq!(b"hello", resent.payload());
+        let table = tcp.sockets.read().unwrap();
+        let socket = &table[&sock_id];
+        assert_eq!(TcpStatus::Established, socket.status);
+        assert_eq!(1006, socket.send_param.next);
+        assert_eq!(1, socket.retransmission_queue.len());
+        assert!(tcp.fast_open_cookies.lock().unwrap().is_empty());
+    }
+
+    fn established_socket(tcp: &TCP) -> SockID {
+        let mut socket = tcp.new_socket(LOCAL_ADDR, REMOTE_ADDR, 40000, 80, TcpStatus::Established);
+        socket.send_param.initial_seq = 1000;
+        socket.send_param.unacked_seq = 1001;
+        socket.send_param.next = 1001;
+        socket.recv_param.next = 5001;
+        tcp.insert_socket(socket)
+    }
+
+    #[test]
+    fn test_ecn_setup_syn_gets_ece_syn_ack() {
+        let sender = Arc::new(RecordingSender::default());
+        let tcp = TCP::from_sender(sender.clone());
+        tcp.set_ecn(true);
+        tcp.listen(LOCAL_ADDR, 80).unwrap();
+
+        // ECN-setup SYN にはECEだけを立てたSYN|ACKを返す
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(50000, 80)
+                .seq(5000)
+                .flag(tcpflags::SYN | tcpflags::ECE | tcpflags::CWR),
+        );
+        let (syn_ack, _) = sender.0.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            tcpflags::SYN | tcpflags::ACK | tcpflags::ECE,
+            syn_ack.get_flag()
+        );
+        let sock_id = SockID(LOCAL_ADDR, REMOTE_ADDR, 80, 50000);
+        assert!(tcp.sockets.read().unwrap()[&sock_id].ecn.enabled);
+
+        // ECNを要求しないSYNにはECEを立てない
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(50001, 80)
+                .seq(7000)
+                .flag(tcpflags::SYN),
+        );
+        let (syn_ack, _) = sender.0.lock().unwrap().pop().unwrap();
+        assert_eq!(tcpflags::SYN | tcpflags::ACK, syn_ack.get_flag());
+        let sock_id = SockID(LOCAL_ADDR, REMOTE_ADDR, 80, 50001);
+        assert!(!tcp.sockets.read().unwrap()[&sock_id].ecn.enabled);
+    }
+
+    /// ECNの設定が ecn_a と ecn_b の2つのTCPで接続し、双方の接続済みソケットの ecn.enabled を返す
+    fn negotiate_ecn(ecn_a: bool, ecn_b: bool) -> (bool, bool) {
+        let link_a = Arc::new(Link::default());
+        let link_b = Arc::new(Link::default());
+        let tcp_a = TCP::with_sender(link_a.clone());
+        let tcp_b = TCP::with_sender(link_b.clone());
+        tcp_a.set_ecn(ecn_a);
+        tcp_b.set_ecn(ecn_b);
+        // 片方からの接続なので、相手のSYNを待たずに配送する
+        let barrier = Arc::new(Barrier::new(1));
+        link_a.attach(tcp_b.clone(), LOCAL_ADDR, REMOTE_ADDR, barrier.clone());
+        link_b.attach(tcp_a.clone(), REMOTE_ADDR, LOCAL_ADDR, barrier);
+
+        let listening_sock_id = tcp_b.listen(REMOTE_ADDR, 80).unwrap();
+        let sock_a = tcp_a
+            .connect_from(LOCAL_ADDR, 40000, REMOTE_ADDR, 80)
+            .unwrap();
+        let sock_b = tcp_b.accept(listening_sock_id).unwrap();
+        let enabled_a = tcp_a.sockets.read().unwrap()[&sock_a].ecn.enabled;
+        let enabled_b = tcp_b.sockets.read().unwrap()[&sock_b].ecn.enabled;
+        (enabled_a, enabled_b)
+    }
+
+    #[test]
+    fn test_ecn_negotiation_between_two_peers() {
+        assert_eq!((true, true), negotiate_ecn(true, true));
+        // 相手がECNを使わなければどちらも無効のまま
+        assert_eq!((false, false), negotiate_ecn(true, false));
+        assert_eq!((false, false), negotiate_ecn(false, true));
+    }
+
+    #[test]
+    fn test_sequence_numbers_wrap_around() {
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(!seq_lt(0, u32::MAX));
+        assert!(!seq_lt(1, 1));
+
+        // 送信したデータのシーケンス番号が2^32を跨ぐ
+        let tcp = TCP::from_sender(Arc::new(RecordingSender::default()));
+        let sock_id = established_socket(&tcp);
+        {
+            let mut table = tcp.sockets.write().unwrap();
+            let socket = table.get_mut(&sock_id).unwrap();
+            socket.send_param.unacked_seq = u32::MAX - 4;
+            socket.send_param.next = u32::MAX - 4;
+        }
+        tcp.send(sock_id, &[1; 10]).unwrap();
+        assert_eq!(5, tcp.sockets.read().unwrap()[&sock_id].send_param.next);
+
+        // 一周した確認応答番号も送信済みの範囲として受け付ける
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(80, 40000)
+                .seq(5001)
+                .ack(5)
+                .flag(tcpflags::ACK)
+                .window(4380),
+        );
+        let table = tcp.sockets.read().unwrap();
+        let socket = &table[&sock_id];
+        assert_eq!(5, socket.send_param.unacked_seq);
+        assert!(socket.retransmission_queue.is_empty());
+    }
+
+    #[test]
+    fn test_icmp_unreachable_fails_connect() {
+        let sender = Arc::new(RecordingSender::default());
+        let tcp = Arc::new(TCP::from_sender(sender.clone()));
+        let connecting = {
+            let tcp = tcp.clone();
+            thread::spawn(move || tcp.connect_from(LOCAL_ADDR, 40000, REMOTE_ADDR, 80))
+        };
+        let syn_seq = loop {
+            if let Some((syn, _)) = sender.0.lock().unwrap().first() {
+                break syn.get_seq();
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        tcp.handle_icmp(&icmp::tests::port_unreachable(syn_seq));
+        let error = connecting.join().unwrap().unwrap_err();
+        assert_eq!(
+            Some(&UnreachableError::Port(REMOTE_ADDR, 80)),
+            error.downcast_ref::<UnreachableError>()
+        );
+        assert!(tcp.sockets.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_icmp_outside_window_is_ignored() {
+        let tcp = TCP::from_sender(Arc::new(RecordingSender::default()));
+        let sock_id = established_socket(&tcp);
+        // 未ACKのセグメントがなければ SND.UNA == SND.NXT のものも受け入れない
+        tcp.handle_icmp(&icmp::tests::port_unreachable(1001));
+        tcp.handle_icmp(&icmp::tests::fragmentation_needed(576, 1001));
+
+        tcp.send(sock_id, &[1; 10]).unwrap();
+        // 既にACKされたもの、まだ送信していないもの
+        tcp.handle_icmp(&icmp::tests::port_unreachable(1000));
+        tcp.handle_icmp(&icmp::tests::port_unreachable(1011));
+        tcp.handle_icmp(&icmp::tests::fragmentation_needed(576, 1011));
+
+        let table = tcp.sockets.read().unwrap();
+        let socket = &table[&sock_id];
+        assert_eq!(None, socket.error);
+        assert_eq!(MSS, socket.mss);
+        assert!(tcp.event_condvar.0.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_icmp_fragmentation_needed_lowers_mss() {
+        let tcp = TCP::from_sender(Arc::new(RecordingSender::default()));
+        let sock_id = established_socket(&tcp);
+        tcp.send(sock_id, &[1; 10]).unwrap();
+
+        tcp.handle_icmp(&icmp::tests::fragmentation_needed(576, 1001));
+        let table = tcp.sockets.read().unwrap();
+        let socket = &table[&sock_id];
+        assert_eq!(576 - 40, socket.mss);
+        assert_eq!(None, socket.error);
+    }
+
+    #[test]
+    fn test_stats_count_retransmissions_and_rtt() {
+        let tcp = TCP::from_sender(Arc::new(RecordingSender::default()));
+        let sock_id = established_socket(&tcp);
+        tcp.send(sock_id, &[1; 10]).unwrap();
+        tcp.send(sock_id, &[2; 10]).unwrap();
+
+        // 1つ目のセグメントだけタイムアウトさせて再送する
+        tcp.sockets.write().unwrap().get_mut(&sock_id).unwrap().retransmission_queue[0]
+            .latest_transmission_time -= Duration::from_secs(RETRANSMITTION_TIMEOUT);
+        tcp.retransmit_expired_segments();
+        receive(
+            &tcp,
+            TCPPacketBuilder::new(80, 40000)
+                .seq(5001)
+                .ack(1021)
+                .flag(tcpflags::ACK)
+                .window(4380),
+        );
+
+        let stats = tcp.stats(sock_id).unwrap();
+        assert_eq!(2, stats.segments_sent);
+        assert_eq!(1, stats.retransmissions);
+        // 再送したセグメントのRTTは測らない
+        assert_eq!(1, stats.rtt_samples.len());
+    }
+
+    /// 2つのTCPをつなぐ片方向のリンク。送信されたセグメントを別スレッドで相手のTCPに渡す。
+    ///
+    /// [note] SegmentSenderはTCPの生成時に渡すので、配送先のTCPは後から attach でつなぐ。
+    #[derive(Default)]
+    struct Link(OnceLock<Mutex<mpsc::Sender<Vec<u8>>>>);
+
+    impl Link {
+        /// 送信元 local のセグメントを peer に届ける。最初のセグメントを受け取った後、
+        /// barrierで両方向のSYNが揃うのを待ってから配送を始める。
+        fn attach(&self, peer: Arc<TCP>, local: Ipv4Addr, remote: Ipv4Addr, barrier: Arc<Barrier>) {
+            let (tx, rx) = mpsc::channel::<Vec<u8>>();
+            thread::spawn(move || {
+                for (i, segment) in rx.into_iter().enumerate() {
+                    if i == 0 {
+                        barrier.wait();
+                    }
+                    let _ = peer.handle_segment(remote, local, &segment, false);
+                }
+            });
+            let _ = self.0.set(Mutex::new(tx));
+        }
+    }
+
+    impl SegmentSender for Link {
+        fn send_segment(
+            &self,
+            packet: &TCPPacket,
+            _remote_addr: Ipv4Addr,
+            _ect: bool,
+        ) -> io::Result<usize> {
+            if let Some(tx) = self.0.get() {
+                let _ = tx.lock().unwrap().send(packet.packet().to_vec());
+            }
+            std::result::Result::Ok(packet.packet().len())
+        }
+    }
+
+    #[test]
+    fn test_connect_from_bound_port() {
+        let sender = Arc::new(RecordingSender::default());
+        let tcp = TCP::from_sender(sender.clone());
+        let bound = tcp.bind(LOCAL_ADDR, UNDETERMINED_PORT).unwrap();
+        let local_port = bound.2;
+        assert!(crate::port::EPHEMERAL_PORTS.contains(&local_port));
+        assert!(tcp.listen(LOCAL_ADDR, local_port).is_err());
+
+        let sock_id = thread::scope(|scope| {
+            let connecting = scope.spawn(|| tcp.connect_bound(bound, REMOTE_ADDR, 80));
+            let syn = loop {
+                if let Some((syn, _)) = sender.0.lock().unwrap().pop() {
+                    break syn;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+            assert_eq!(local_port, syn.get_src());
+            receive(
+                &tcp,
+                TCPPacketBuilder::new(80, local_port)
+                    .seq(5000)
+                    .ack(syn.get_seq() + 1)
+                    .flag(tcpflags::SYN | tcpflags::ACK),
+            );
+            connecting.join().unwrap().unwrap()
+        });
+        assert_eq!(SockID(LOCAL_ADDR, REMOTE_ADDR, local_port, 80), sock_id);
+        // 接続後もポートは予約されたまま
+        assert!(tcp.listen(LOCAL_ADDR, local_port).is_err());
+    }
+
+    #[test]
+    fn test_reuse_addr_shares_port() {
+        let tcp = TCP::from_sender(Arc::new(RecordingSender::default()));
+        let first = tcp.bind(LOCAL_ADDR, 8080).unwrap();
+        assert!(tcp.bind(LOCAL_ADDR, 8080).is_err());
+
+        tcp.set_reuse_addr(true);
+        assert!(tcp.bind(LOCAL_ADDR, 8080).is_err());
+        tcp.close(first).unwrap();
+        let first = tcp.bind(LOCAL_ADDR, 8080).unwrap();
+        let second = tcp.bind(LOCAL_ADDR, 8080).unwrap();
+        // リスニングソケットとは共有できない
+        assert!(tcp.listen(LOCAL_ADDR, 8080).is_err());
+
+        tcp.close(first).unwrap();
+        tcp.close(second).unwrap();
+        let listening_sock_id = tcp.listen(LOCAL_ADDR, 8080).unwrap();
+        tcp.close(listening_sock_id).unwrap();
+        assert!(!tcp.ports.lock().unwrap().is_bound(LOCAL_ADDR, 8080));
+    }
+
+    #[test]
+    fn test_udp_shares_receive_path() {
+        use crate::udp::{self, tests::RecordingDatagramSender};
+        use pnet::packet::ipv4::MutableIpv4Packet;
+
+        let tcp = TCP::from_sender(Arc::new(RecordingSender::default()));
+        let udp = tcp
+            .udp
+            .get_or_init(|| Arc::new(UDP::from_sender(Arc::new(RecordingDatagramSender::default()))))
+            .clone();
+        let sock_id = udp.bind(LOCAL_ADDR, 5353).unwrap();
+
+        let datagram = udp::build_datagram(REMOTE_ADDR, 53, LOCAL_ADDR, 5353, b"hello");
+        let mut buffer = vec![0; 20 + datagram.len()];
+        let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length((20 + datagram.len()) as u16);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        packet.set_source(REMOTE_ADDR);
+        packet.set_destination(LOCAL_ADDR);
+        packet.set_payload(&datagram);
+        tcp.handle_ip_packet(&packet.to_immutable());
+
+        let mut buffer = [0; 16];
+        assert_eq!(
+            (5, REMOTE_ADDR, 53),
+            udp.recv_from(sock_id, &mut buffer).unwrap()
+        );
+        assert_eq!(b"hello", &buffer[..5]);
+    }
+
+    #[test]
+    fn test_simultaneous_open_between_two_peers() {
+        let link_a = Arc::new(Link::default());
+        let link_b = Arc::new(Link::default());
+        let tcp_a = TCP::with_sender(link_a.clone());
+        let tcp_b = TCP::with_sender(link_b.clone());
+        let barrier = Arc::new(Barrier::new(2));
+        link_a.attach(tcp_b.clone(), LOCAL_ADDR, REMOTE_ADDR, barrier.clone());
+        link_b.attach(tcp_a.clone(), REMOTE_ADDR, LOCAL_ADDR, barrier);
+
+        // 互いに相手のアドレスとポートを指定して同時にconnectする
+        let cloned_tcp_b = tcp_b.clone();
+        let connect_b = thread::spawn(move || {
+            cloned_tcp_b.connect_from(REMOTE_ADDR, 50000, LOCAL_ADDR, 40000)
+        });
+        let sock_a = tcp_a
+            .connect_from(LOCAL_ADDR, 40000, REMOTE_ADDR, 50000)
+            .unwrap();
+        let sock_b = connect_b.join().unwrap().unwrap();
+
+        for (tcp, sock_id) in [(&tcp_a, sock_a), (&tcp_b, sock_b)] {
+            let table = tcp.sockets.read().unwrap();
+            assert_eq!(TcpStatus::Established, table[&sock_id].status);
+            assert_eq!(
+                table[&sock_id].send_param.initial_seq.wrapping_add(1),
+                table[&sock_id].send_param.unacked_seq
+            );
+        }
+
+        // 1つのコネクションとしてデータをやり取りできる
+        tcp_a.send(sock_a, b"hello").unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(5, tcp_b.recv(sock_b, &mut buffer).unwrap());
+        assert_eq!(b"hello", &buffer[..5]);
+    }
+}
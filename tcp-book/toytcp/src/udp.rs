@@ -0,0 +1,361 @@
+//! TCPと比較するための簡易なUDP (RFC 768)
+//!
+//! [note] パケットの受信はTCPの受信スレッドが行い、UDPのデータグラムは handle_datagram に渡される。
+//! 送信にはUDP用のrawソケットを使う。
+use crate::port::PortAllocator;
+use crate::route;
+use anyhow::{Context, Result};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::util;
+use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Condvar, Mutex};
+
+const UDP_HEADER_SIZE: usize = 8;
+const MAX_PAYLOAD_SIZE: usize = 65507; // IPv4で送れる最大のペイロード (65535 - IPヘッダ20 - UDPヘッダ8)
+const RECV_QUEUE_SIZE: usize = 64; // ソケットごとに溜めておくデータグラムの数。超えた分は破棄する
+
+/// UDPソケットのID。データグラムは宛先の(アドレス, ポート)でソケットに振り分ける
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
+pub struct UdpSockID(pub Ipv4Addr, pub u16);
+
+/// データグラムをネットワークへ送り出す送信機構
+pub trait DatagramSender: Send + Sync {
+    /// datagramはUDPヘッダを含む
+    fn send_datagram(&self, datagram: &[u8], remote_addr: Ipv4Addr) -> io::Result<usize>;
+}
+
+/// rawソケット(IPレイヤより上をToyTCPが作る)によるUDPの送信機構
+pub struct RawDatagramSender(Mutex<TransportSender>);
+
+impl RawDatagramSender {
+    pub fn new() -> Result<Self> {
+        let (sender, _) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Udp)),
+        )?;
+        Ok(Self(Mutex::new(sender)))
+    }
+}
+
+impl DatagramSender for RawDatagramSender {
+    fn send_datagram(&self, datagram: &[u8], remote_addr: Ipv4Addr) -> io::Result<usize> {
+        let packet = UdpPacket::new(datagram).expect("datagram shorter than udp header");
+        self.0.lock().unwrap().send_to(packet, IpAddr::V4(remote_addr))
+    }
+}
+
+/// 受信したデータグラム
+struct Datagram {
+    payload: Vec<u8>,
+    remote_addr: Ipv4Addr,
+    remote_port: u16,
+}
+
+#[derive(Default)]
+struct UdpSocket {
+    recv_queue: VecDeque<Datagram>,
+}
+
+pub struct UDP {
+    sockets: Mutex<HashMap<UdpSockID, UdpSocket>>,
+    // データグラムが届いたらrecv_fromで待機しているスレッドを起こす
+    arrived: Condvar,
+    // TCPとは別のポート空間
+    ports: Mutex<PortAllocator>,
+    sender: Arc<dyn DatagramSender>,
+}
+
+impl UDP {
+    pub(crate) fn from_sender(sender: Arc<dyn DatagramSender>) -> Self {
+        Self {
+            sockets: Mutex::new(HashMap::new()),
+            arrived: Condvar::new(),
+            ports: Mutex::new(PortAllocator::default()),
+            sender,
+        }
+    }
+
+    /// ローカルのアドレスとポートでデータグラムを受け取るソケットを作る
+    ///
+    /// [note] local_portに0を渡した場合はエフェメラルポートを割り当てる。
+    /// local_addrに 0.0.0.0 を渡した場合は全てのアドレス宛てのデータグラムを受け取る。
+    pub fn bind(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<UdpSockID> {
+        let mut ports = self.ports.lock().unwrap();
+        let local_port = if local_port == 0 {
+            ports.allocate(local_addr, Ipv4Addr::UNSPECIFIED, 0)?
+        } else {
+            ports.bind(local_addr, local_port, false, false)?;
+            local_port
+        };
+        let sock_id = UdpSockID(local_addr, local_port);
+        self.sockets
+            .lock()
+            .unwrap()
+            .insert(sock_id, UdpSocket::default());
+        Ok(sock_id)
+    }
+
+    /// データグラムを送信する
+    pub fn send_to(
+        &self,
+        sock_id: UdpSockID,
+        buffer: &[u8],
+        addr: Ipv4Addr,
+        port: u16,
+    ) -> Result<usize> {
+        if !self.sockets.lock().unwrap().contains_key(&sock_id) {
+            anyhow::bail!("no such socket: {:?}", sock_id);
+        }
+        if buffer.len() > MAX_PAYLOAD_SIZE {
+            anyhow::bail!("message too long: {} bytes", buffer.len());
+        }
+        let UdpSockID(local_addr, local_port) = sock_id;
+        let local_addr = if local_addr.is_unspecified() {
+            route::get_source_addr_to(addr)?
+        } else {
+            local_addr
+        };
+        let datagram = build_datagram(local_addr, local_port, addr, port, buffer);
+        self.sender
+            .send_datagram(&datagram, addr)
+            .context(format!("failed to send datagram to {}:{}", addr, port))?;
+        Ok(buffer.len())
+    }
+
+    /// データグラムを1つ受け取り、(受け取ったバイト数, 送信元アドレス, 送信元ポート)を返す
+    ///
+    /// [note] recvfrom(2)と同じく、bufferに入りきらない部分は捨てられる。
+    pub fn recv_from(&self, sock_id: UdpSockID, buffer: &mut [u8]) -> Result<(usize, Ipv4Addr, u16)> {
+        let mut sockets = self.sockets.lock().unwrap();
+        loop {
+            let socket = sockets
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?;
+            if let Some(datagram) = socket.recv_queue.pop_front() {
+                let size = datagram.payload.len().min(buffer.len());
+                buffer[..size].copy_from_slice(&datagram.payload[..size]);
+                return Ok((size, datagram.remote_addr, datagram.remote_port));
+            }
+            sockets = self.arrived.wait(sockets).unwrap();
+        }
+    }
+
+    /// ソケットを破棄してポートを解放する
+    pub fn close(&self, sock_id: UdpSockID) -> Result<()> {
+        self.sockets
+            .lock()
+            .unwrap()
+            .remove(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        self.ports.lock().unwrap().release(sock_id.0, sock_id.1);
+        // 待機中のrecv_fromにソケットがなくなったことを知らせる
+        self.arrived.notify_all();
+        Ok(())
+    }
+
+    /// 受信したデータグラム(UDPヘッダ以降)を宛先のソケットの受信キューに入れる
+    pub(crate) fn handle_datagram(
+        &self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        datagram: &[u8],
+    ) -> Result<()> {
+        let packet = UdpPacket::new(datagram).context("datagram too short")?;
+        let length = packet.get_length() as usize;
+        if length < UDP_HEADER_SIZE || length > datagram.len() {
+            anyhow::bail!("invalid udp length: {}", length);
+        }
+        // [note] IPv4ではチェックサム0は送信側が計算しなかったことを表す
+        if packet.get_checksum() != 0
+            && packet.get_checksum() != checksum(&datagram[..length], remote_addr, local_addr)
+        {
+            anyhow::bail!("invalid checksum from {}:{}", remote_addr, packet.get_source());
+        }
+
+        let mut sockets = self.sockets.lock().unwrap();
+        // 宛先アドレスに一致するソケットがなければ 0.0.0.0 にbindしたソケットへ
+        let socket = [local_addr, Ipv4Addr::UNSPECIFIED]
+            .iter()
+            .find_map(|&addr| {
+                let sock_id = UdpSockID(addr, packet.get_destination());
+                sockets.contains_key(&sock_id).then_some(sock_id)
+            })
+            .and_then(|sock_id| sockets.get_mut(&sock_id))
+            .context(format!(
+                "no socket bound to {}:{}",
+                local_addr,
+                packet.get_destination()
+            ))?;
+        if socket.recv_queue.len() >= RECV_QUEUE_SIZE {
+            anyhow::bail!("receive queue is full, dropped datagram");
+        }
+        socket.recv_queue.push_back(Datagram {
+            payload: datagram[UDP_HEADER_SIZE..length].to_vec(),
+            remote_addr,
+            remote_port: packet.get_source(),
+        });
+        self.arrived.notify_all();
+        Ok(())
+    }
+}
+
+/// 疑似ヘッダを含めたチェックサムを計算する。datagramのチェックサムフィールド(4ワード目)は飛ばす
+///
+/// [note] 計算結果が0の場合はチェックサムなしと区別するため0xffffにする (RFC 768)
+fn checksum(datagram: &[u8], src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> u16 {
+    match util::ipv4_checksum(
+        datagram,
+        3,
+        &[],
+        &src_addr,
+        &dst_addr,
+        IpNextHeaderProtocols::Udp,
+    ) {
+        0 => 0xffff,
+        sum => sum,
+    }
+}
+
+pub(crate) fn build_datagram(
+    src_addr: Ipv4Addr,
+    src_port: u16,
+    dst_addr: Ipv4Addr,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut buffer = vec![0; UDP_HEADER_SIZE + payload.len()];
+    let mut packet = MutableUdpPacket::new(&mut buffer).unwrap();
+    packet.set_source(src_port);
+    packet.set_destination(dst_port);
+    packet.set_length((UDP_HEADER_SIZE + payload.len()) as u16);
+    packet.set_payload(payload);
+    let sum = udp::ipv4_checksum(&packet.to_immutable(), &src_addr, &dst_addr);
+    packet.set_checksum(if sum == 0 { 0xffff } else { sum });
+    buffer
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::thread;
+
+    const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+    /// 送信したデータグラムを記録するだけの送信機構
+    #[derive(Default)]
+    pub(crate) struct RecordingDatagramSender(pub Mutex<Vec<(Vec<u8>, Ipv4Addr)>>);
+
+    impl DatagramSender for RecordingDatagramSender {
+        fn send_datagram(&self, datagram: &[u8], remote_addr: Ipv4Addr) -> io::Result<usize> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((datagram.to_vec(), remote_addr));
+            Ok(datagram.len())
+        }
+    }
+
+    #[test]
+    fn test_send_to_builds_datagram() {
+        let sender = Arc::new(RecordingDatagramSender::default());
+        let udp = UDP::from_sender(sender.clone());
+        let sock_id = udp.bind(LOCAL_ADDR, 5353).unwrap();
+        assert_eq!(5, udp.send_to(sock_id, b"hello", REMOTE_ADDR, 53).unwrap());
+
+        let (datagram, addr) = sender.0.lock().unwrap().pop().unwrap();
+        assert_eq!(REMOTE_ADDR, addr);
+        let packet = UdpPacket::new(&datagram).unwrap();
+        assert_eq!(5353, packet.get_source());
+        assert_eq!(53, packet.get_destination());
+        assert_eq!(13, packet.get_length());
+        assert_eq!(b"hello", &datagram[UDP_HEADER_SIZE..]);
+        assert_eq!(
+            packet.get_checksum(),
+            checksum(&datagram, LOCAL_ADDR, REMOTE_ADDR)
+        );
+    }
+
+    #[test]
+    fn test_recv_from_demultiplexes_by_local_endpoint() {
+        let udp = UDP::from_sender(Arc::new(RecordingDatagramSender::default()));
+        let specific = udp.bind(LOCAL_ADDR, 5353).unwrap();
+        let wildcard = udp.bind(Ipv4Addr::UNSPECIFIED, 6000).unwrap();
+        assert!(udp.bind(LOCAL_ADDR, 6000).is_err());
+
+        let to_specific = build_datagram(REMOTE_ADDR, 53, LOCAL_ADDR, 5353, b"one");
+        let to_wildcard = build_datagram(REMOTE_ADDR, 54, LOCAL_ADDR, 6000, b"two");
+        let unbound = build_datagram(REMOTE_ADDR, 55, LOCAL_ADDR, 7000, b"three");
+        udp.handle_datagram(LOCAL_ADDR, REMOTE_ADDR, &to_wildcard)
+            .unwrap();
+        udp.handle_datagram(LOCAL_ADDR, REMOTE_ADDR, &to_specific)
+            .unwrap();
+        assert!(udp.handle_datagram(LOCAL_ADDR, REMOTE_ADDR, &unbound).is_err());
+
+        let mut buffer = [0; 16];
+        assert_eq!(
+            (3, REMOTE_ADDR, 53),
+            udp.recv_from(specific, &mut buffer).unwrap()
+        );
+        assert_eq!(b"one", &buffer[..3]);
+        assert_eq!(
+            (3, REMOTE_ADDR, 54),
+            udp.recv_from(wildcard, &mut buffer).unwrap()
+        );
+        assert_eq!(b"two", &buffer[..3]);
+    }
+
+    #[test]
+    fn test_handle_datagram_validates_checksum() {
+        let udp = UDP::from_sender(Arc::new(RecordingDatagramSender::default()));
+        let sock_id = udp.bind(LOCAL_ADDR, 5353).unwrap();
+
+        let mut datagram = build_datagram(REMOTE_ADDR, 53, LOCAL_ADDR, 5353, b"hello");
+        datagram[UDP_HEADER_SIZE] ^= 0xff;
+        assert!(udp.handle_datagram(LOCAL_ADDR, REMOTE_ADDR, &datagram).is_err());
+        // 送信元アドレスが違えば疑似ヘッダが変わるのでチェックサムが合わない
+        let datagram = build_datagram(REMOTE_ADDR, 53, LOCAL_ADDR, 5353, b"hello");
+        assert!(udp.handle_datagram(LOCAL_ADDR, LOCAL_ADDR, &datagram).is_err());
+        assert!(udp.handle_datagram(LOCAL_ADDR, REMOTE_ADDR, &datagram[..4]).is_err());
+
+        // チェックサム0は検証しない
+        let mut datagram = build_datagram(REMOTE_ADDR, 53, LOCAL_ADDR, 5353, b"hello");
+        datagram[6..8].copy_from_slice(&[0, 0]);
+        udp.handle_datagram(LOCAL_ADDR, REMOTE_ADDR, &datagram)
+            .unwrap();
+        let mut buffer = [0; 4];
+        // 入りきらない部分は捨てる
+        assert_eq!(
+            (4, REMOTE_ADDR, 53),
+            udp.recv_from(sock_id, &mut buffer).unwrap()
+        );
+        assert_eq!(b"hell", &buffer);
+    }
+
+    #[test]
+    fn test_recv_from_waits_for_datagram() {
+        let udp = UDP::from_sender(Arc::new(RecordingDatagramSender::default()));
+        let sock_id = udp.bind(LOCAL_ADDR, 0).unwrap();
+        thread::scope(|scope| {
+            let receiving = scope.spawn(|| {
+                let mut buffer = [0; 16];
+                udp.recv_from(sock_id, &mut buffer).map(|(size, _, _)| size)
+            });
+            let datagram = build_datagram(REMOTE_ADDR, 53, LOCAL_ADDR, sock_id.1, b"hello");
+            udp.handle_datagram(LOCAL_ADDR, REMOTE_ADDR, &datagram)
+                .unwrap();
+            assert_eq!(5, receiving.join().unwrap().unwrap());
+        });
+
+        // closeすると待機中のrecv_fromはエラーを返す
+        thread::scope(|scope| {
+            let receiving = scope.spawn(|| udp.recv_from(sock_id, &mut [0; 16]));
+            udp.close(sock_id).unwrap();
+            assert!(receiving.join().unwrap().is_err());
+        });
+    }
+}